@@ -0,0 +1,19 @@
+//! Timing loop shared by the `benches/` of the crates, included with `#[path]`.
+use std::time::{Duration, Instant};
+
+/// Run `f` `iterations` times after a warm up call and print the time per iteration. Panics if
+/// it's over `budget`, so a regression fails `cargo bench`. Budgets are loose enough for a slow
+/// release build machine, they only catch regressions of an order of magnitude.
+pub fn bench(name: &str, iterations: u32, budget: Duration, mut f: impl FnMut(u32)) {
+    f(0);
+    let start = Instant::now();
+    for i in 0..iterations {
+        f(i);
+    }
+    let per_iter = start.elapsed() / iterations;
+    println!("{name:<40} {per_iter:>12?}/iter");
+    assert!(
+        per_iter <= budget,
+        "{name}: {per_iter:?}/iter is over the budget of {budget:?}/iter"
+    );
+}
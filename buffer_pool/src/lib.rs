@@ -0,0 +1,167 @@
+//! Global pool of byte buffers, so hot paths don't hit the allocator for every packet or
+//! connection.
+//!
+//! Buffers are grouped in power of two size classes from 512 bytes to 128 KiB, each class has a
+//! freelist per shard and threads are spread over the shards to avoid contention. Larger buffers
+//! are allocated and freed as usual.
+use parking_lot::{const_mutex, Mutex};
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const MIN_CLASS_SHIFT: u32 = 9;
+const MAX_CLASS_SHIFT: u32 = 17;
+const CLASSES: usize = (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize;
+const SHARDS: usize = 16;
+/// Max bytes of idle buffers kept in each freelist.
+const MAX_IDLE_BYTES: usize = 512 * 1024;
+/// Min number of idle buffers kept in each freelist, for the large classes.
+const MIN_IDLE_BUFFERS: usize = 4;
+
+type FreeList = Mutex<Vec<Box<[u8]>>>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_LIST: FreeList = const_mutex(Vec::new());
+#[allow(clippy::declare_interior_mutable_const)]
+const CLASS: [FreeList; SHARDS] = [FREE_LIST; SHARDS];
+static POOL: [[FreeList; SHARDS]; CLASSES] = [CLASS; CLASSES];
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: Cell<usize> = Cell::new(NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS);
+}
+
+fn class_of(capacity: usize) -> Option<usize> {
+    let shift = capacity
+        .max(1 << MIN_CLASS_SHIFT)
+        .next_power_of_two()
+        .trailing_zeros();
+    (shift <= MAX_CLASS_SHIFT).then_some((shift - MIN_CLASS_SHIFT) as usize)
+}
+
+fn class_size(class: usize) -> usize {
+    1 << (class as u32 + MIN_CLASS_SHIFT)
+}
+
+fn free_list(class: usize) -> &'static FreeList {
+    &POOL[class][SHARD.with(Cell::get)]
+}
+
+/// Take a buffer of at least `capacity` bytes, with unspecified content.
+fn take(capacity: usize) -> Box<[u8]> {
+    let Some(class) = class_of(capacity) else {
+        return vec![0; capacity].into_boxed_slice();
+    };
+    match free_list(class).lock().pop() {
+        Some(buf) => buf,
+        None => vec![0; class_size(class)].into_boxed_slice(),
+    }
+}
+
+/// Return a buffer taken by [`take`] to its freelist, or free it if the freelist is full.
+fn recycle(buf: Box<[u8]>) {
+    let Some(class) = class_of(buf.len()).filter(|c| class_size(*c) == buf.len()) else {
+        return;
+    };
+    let max_idle = (MAX_IDLE_BYTES / buf.len()).max(MIN_IDLE_BUFFERS);
+    let mut free_list = free_list(class).lock();
+    if free_list.len() < max_idle {
+        free_list.push(buf);
+    }
+}
+
+/// A fixed size buffer from the pool, returned to the pool when dropped.
+///
+/// The content of a new buffer is unspecified, it may contain data of the previous user.
+pub struct Buffer {
+    buf: Box<[u8]>,
+    len: usize,
+}
+
+impl Buffer {
+    pub fn new(len: usize) -> Buffer {
+        Buffer {
+            buf: take(len),
+            len,
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        recycle(std::mem::take(&mut self.buf));
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+/// Take an empty `Vec` with at least `capacity` bytes of capacity from the pool, for code that
+/// builds data by appending. Give it back with [`recycle_vec`] when done.
+pub fn take_vec(capacity: usize) -> Vec<u8> {
+    let mut vec = Vec::from(take(capacity));
+    vec.clear();
+    vec
+}
+
+/// Return a `Vec` to the pool. `Vec`s not taken by [`take_vec`] are accepted too, as long as the
+/// capacity matches a size class.
+pub fn recycle_vec(mut vec: Vec<u8>) {
+    let capacity = vec.capacity();
+    if class_of(capacity).map_or(false, |c| class_size(c) == capacity) {
+        vec.resize(capacity, 0);
+        recycle(vec.into_boxed_slice());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_of() {
+        assert_eq!(class_of(0), Some(0));
+        assert_eq!(class_of(512), Some(0));
+        assert_eq!(class_of(513), Some(1));
+        assert_eq!(class_of(1500), Some(2));
+        assert_eq!(class_size(2), 2048);
+        assert_eq!(class_of(128 * 1024), Some(CLASSES - 1));
+        assert_eq!(class_of(128 * 1024 + 1), None);
+    }
+
+    #[test]
+    fn test_reuse() {
+        let mut buf = Buffer::new(1500);
+        assert_eq!(buf.len(), 1500);
+        buf[0] = 1;
+        let ptr = buf.as_ptr();
+        drop(buf);
+        // Tests run on their own threads, so the freelist of this shard is not shared.
+        let buf = Buffer::new(2000);
+        assert_eq!(buf.len(), 2000);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        let large = Buffer::new(1024 * 1024);
+        assert_eq!(large.len(), 1024 * 1024);
+
+        let mut vec = take_vec(100);
+        assert!(vec.is_empty());
+        assert_eq!(vec.capacity(), 512);
+        vec.extend_from_slice(b"hello");
+        let ptr = vec.as_ptr();
+        recycle_vec(vec);
+        assert_eq!(take_vec(512).as_ptr(), ptr);
+    }
+}
@@ -0,0 +1,59 @@
+//! Rule matching benchmarks, run with `cargo bench -p config`.
+#[path = "../../benches/harness.rs"]
+mod harness;
+
+use config::rule::{Action, ProxyRules, Rule};
+use harness::bench;
+use std::hint::black_box;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+fn rules(count: usize) -> ProxyRules {
+    let mut rules: Vec<Rule> = (0..count)
+        .map(|i| match i % 3 {
+            0 => Rule::DomainSuffix(format!("domain{i}.com"), Action::Proxy),
+            1 => Rule::Domain(format!("www.domain{i}.com"), Action::Direct),
+            _ => Rule::DomainKeyword(format!("keyword{i}"), Action::Proxy),
+        })
+        .collect();
+    rules.push("IP-CIDR,11.0.0.0/16,REJECT".parse().unwrap());
+    rules.push(Rule::Match(Action::Direct));
+    ProxyRules::new(rules)
+}
+
+fn main() {
+    let ip = Ipv4Addr::new(11, 0, 0, 10);
+    for (count, budget) in [
+        (100, Duration::from_micros(50)),
+        (10_000, Duration::from_millis(5)),
+    ] {
+        let rules = rules(count);
+        bench(
+            &format!("action_for_domain {count} rules, hit"),
+            1000,
+            Duration::from_micros(50),
+            |_| {
+                black_box(rules.action_for_domain(Some(black_box("a.domain0.com")), None));
+            },
+        );
+        bench(
+            &format!("action_for_domain {count} rules, miss"),
+            1000,
+            budget,
+            |_| {
+                black_box(
+                    rules.action_for_domain(Some(black_box("example.com")), Some(IpAddr::V4(ip))),
+                );
+            },
+        );
+        // Cached after the first lookup.
+        bench(
+            &format!("action_for_fake_ip {count} rules"),
+            1000,
+            Duration::from_micros(50),
+            |_| {
+                black_box(rules.action_for_fake_ip(black_box("example.com"), ip));
+            },
+        );
+    }
+}
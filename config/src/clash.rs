@@ -0,0 +1,363 @@
+//! Convert Clash config (`proxies`, `proxy-groups`, `rules`, `dns`) to seeker config.
+//!
+//! Seeker has no proxy groups, all supported proxies are added to `servers`. A rule targeting a
+//! proxy or a group becomes `PROXY`, unless every member of the group resolves to `DIRECT` or
+//! `REJECT`. Unsupported proxies and rules are skipped with a warning.
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
+
+const MAX_GROUP_DEPTH: usize = 8;
+const SUPPORTED_RULES: [&str; 6] = [
+    "DOMAIN",
+    "DOMAIN-SUFFIX",
+    "DOMAIN-KEYWORD",
+    "IP-CIDR",
+    "SRC-IP-CIDR",
+    "GEOIP",
+];
+
+#[derive(Deserialize)]
+struct ClashConfig {
+    #[serde(default)]
+    proxies: Vec<ClashProxy>,
+    #[serde(default, rename = "proxy-groups")]
+    proxy_groups: Vec<ClashProxyGroup>,
+    #[serde(default)]
+    rules: Vec<String>,
+    dns: Option<ClashDns>,
+}
+
+#[derive(Deserialize)]
+struct ClashProxy {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    server: String,
+    port: u16,
+    cipher: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    tls: bool,
+    plugin: Option<String>,
+    #[serde(rename = "plugin-opts")]
+    plugin_opts: Option<ClashPluginOpts>,
+}
+
+#[derive(Deserialize)]
+struct ClashPluginOpts {
+    mode: Option<String>,
+    host: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClashProxyGroup {
+    name: String,
+    #[serde(default)]
+    proxies: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ClashDns {
+    #[serde(default)]
+    nameserver: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Server {
+    name: String,
+    addr: String,
+    protocol: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    obfs: Option<Obfs>,
+}
+
+#[derive(Serialize)]
+struct Obfs {
+    mode: &'static str,
+    host: String,
+}
+
+/// Convert a Clash config file to seeker config yaml containing `servers`, `rules` and
+/// `dns_servers`, which can be used with `include` in seeker config.
+pub fn convert_clash_config<R: Read>(reader: R) -> io::Result<String> {
+    let clash: ClashConfig = serde_yaml::from_reader(reader).map_err(invalid_data)?;
+    serde_yaml::to_string(&convert_clash(clash)?).map_err(invalid_data)
+}
+
+/// Replace Clash keys in `value` with the converted seeker ones and keep the other keys.
+pub(crate) fn convert(value: Value) -> io::Result<Value> {
+    let Value::Mapping(mut mapping) = value else {
+        return Err(invalid_data("clash config must be a mapping"));
+    };
+    let mut clash = Mapping::new();
+    for key in ["proxies", "proxy-groups", "rules", "dns"] {
+        if let Some(v) = mapping.remove(key) {
+            let _ = clash.insert(key.into(), v);
+        }
+    }
+    let clash: ClashConfig = serde_yaml::from_value(Value::Mapping(clash)).map_err(invalid_data)?;
+    let Value::Mapping(converted) = convert_clash(clash)? else {
+        unreachable!("converted clash config is a mapping");
+    };
+    for (k, v) in converted {
+        match (mapping.get_mut(&k), v) {
+            (Some(Value::Sequence(existing)), Value::Sequence(v)) => existing.extend(v),
+            (Some(_), _) => {}
+            (None, v) => {
+                let _ = mapping.insert(k, v);
+            }
+        }
+    }
+    Ok(Value::Mapping(mapping))
+}
+
+fn convert_clash(clash: ClashConfig) -> io::Result<Value> {
+    let groups: HashMap<&str, &ClashProxyGroup> = clash
+        .proxy_groups
+        .iter()
+        .map(|g| (g.name.as_str(), g))
+        .collect();
+    let servers: Vec<Server> = clash.proxies.iter().filter_map(convert_proxy).collect();
+    let rules: Vec<String> = clash
+        .rules
+        .iter()
+        .filter_map(|r| convert_rule(r, &groups))
+        .collect();
+
+    let mut mapping = Mapping::new();
+    let _ = mapping.insert(
+        "servers".into(),
+        serde_yaml::to_value(servers).map_err(invalid_data)?,
+    );
+    let _ = mapping.insert(
+        "rules".into(),
+        serde_yaml::to_value(rules).map_err(invalid_data)?,
+    );
+    if let Some(dns) = clash.dns {
+        let dns_servers: Vec<String> = dns
+            .nameserver
+            .iter()
+            .map(String::as_str)
+            .filter_map(convert_dns)
+            .collect();
+        let _ = mapping.insert(
+            "dns_servers".into(),
+            serde_yaml::to_value(dns_servers).map_err(invalid_data)?,
+        );
+    }
+    Ok(Value::Mapping(mapping))
+}
+
+fn convert_proxy(proxy: &ClashProxy) -> Option<Server> {
+    let protocol = match (proxy.kind.as_str(), proxy.tls) {
+        ("ss", _) => "Shadowsocks",
+        ("socks5", _) => "Socks5",
+        ("http", false) => "Http",
+        ("http", true) => "Https",
+        (kind, _) => {
+            tracing::warn!(
+                "skip clash proxy `{}`: unsupported type {}",
+                proxy.name,
+                kind
+            );
+            return None;
+        }
+    };
+    let obfs = match (proxy.plugin.as_deref(), &proxy.plugin_opts) {
+        (None, _) => None,
+        (Some("obfs"), Some(opts)) => {
+            let mode = match opts.mode.as_deref() {
+                Some("http") | None => "Http",
+                Some("tls") => "Tls",
+                Some(mode) => {
+                    tracing::warn!(
+                        "skip clash proxy `{}`: unsupported obfs {}",
+                        proxy.name,
+                        mode
+                    );
+                    return None;
+                }
+            };
+            Some(Obfs {
+                mode,
+                host: opts.host.clone().unwrap_or_default(),
+            })
+        }
+        (Some(plugin), _) => {
+            tracing::warn!(
+                "skip clash proxy `{}`: unsupported plugin {}",
+                proxy.name,
+                plugin
+            );
+            return None;
+        }
+    };
+    Some(Server {
+        name: proxy.name.clone(),
+        addr: format!("{}:{}", proxy.server, proxy.port),
+        protocol,
+        username: proxy.username.clone(),
+        password: proxy.password.clone(),
+        method: proxy.cipher.clone(),
+        obfs,
+    })
+}
+
+fn convert_rule(rule: &str, groups: &HashMap<&str, &ClashProxyGroup>) -> Option<String> {
+    let segments: Vec<&str> = rule.split(',').map(str::trim).collect();
+    let converted = match segments.as_slice() {
+        ["MATCH" | "FINAL", target, ..] => {
+            format!("MATCH,{}", action_for_target(target, groups, 0))
+        }
+        [kind, criteria, target, ..] if SUPPORTED_RULES.contains(kind) => {
+            format!("{kind},{criteria},{}", action_for_target(target, groups, 0))
+        }
+        _ => {
+            tracing::warn!("skip unsupported clash rule: {}", rule);
+            return None;
+        }
+    };
+    Some(converted)
+}
+
+fn action_for_target(
+    target: &str,
+    groups: &HashMap<&str, &ClashProxyGroup>,
+    depth: usize,
+) -> &'static str {
+    match target {
+        "DIRECT" => "DIRECT",
+        "REJECT" | "REJECT-DROP" => "REJECT",
+        name => match groups.get(name) {
+            Some(group) if depth < MAX_GROUP_DEPTH && !group.proxies.is_empty() => {
+                let mut actions = group
+                    .proxies
+                    .iter()
+                    .map(|p| action_for_target(p, groups, depth + 1));
+                let first = actions.next().unwrap_or("PROXY");
+                if actions.all(|a| a == first) {
+                    first
+                } else {
+                    "PROXY"
+                }
+            }
+            _ => "PROXY",
+        },
+    }
+}
+
+fn convert_dns(nameserver: &str) -> Option<String> {
+    if let Ok(ip) = nameserver.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, 53).to_string());
+    }
+    if nameserver.parse::<SocketAddr>().is_ok() {
+        return Some(nameserver.to_string());
+    }
+    if let Some(addr) = nameserver.strip_prefix("tcp://") {
+        if addr.parse::<IpAddr>().is_ok() {
+            return Some(format!("tcp://{addr}:53"));
+        }
+        return Some(nameserver.to_string());
+    }
+    tracing::warn!("skip unsupported clash nameserver: {}", nameserver);
+    None
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASH_CONFIG: &str = r#"
+port: 7890
+dns:
+  nameserver:
+    - 114.114.114.114
+    - tcp://1.1.1.1
+    - https://doh.pub/dns-query
+proxies:
+  - name: ss1
+    type: ss
+    server: ss.example.com
+    port: 8388
+    cipher: chacha20-ietf
+    password: password
+    plugin: obfs
+    plugin-opts:
+      mode: tls
+      host: bing.com
+  - name: http1
+    type: http
+    server: 127.0.0.1
+    port: 443
+    tls: true
+  - name: vmess1
+    type: vmess
+    server: vmess.example.com
+    port: 443
+proxy-groups:
+  - name: Proxy
+    type: select
+    proxies:
+      - ss1
+      - http1
+  - name: Domestic
+    type: select
+    proxies:
+      - DIRECT
+rules:
+  - DOMAIN-SUFFIX,google.com,Proxy
+  - DOMAIN,ad.example.com,REJECT
+  - IP-CIDR,10.0.0.0/8,DIRECT,no-resolve
+  - GEOIP,CN,Domestic
+  - DST-PORT,22,DIRECT
+  - MATCH,Proxy
+"#;
+
+    #[test]
+    fn test_convert_clash_config() {
+        let value: Value = serde_yaml::from_str(CLASH_CONFIG).unwrap();
+        let value = convert(value).unwrap();
+        let expected: Value = serde_yaml::from_str(
+            r#"
+port: 7890
+servers:
+  - name: ss1
+    addr: ss.example.com:8388
+    protocol: Shadowsocks
+    password: password
+    method: chacha20-ietf
+    obfs:
+      mode: Tls
+      host: bing.com
+  - name: http1
+    addr: 127.0.0.1:443
+    protocol: Https
+rules:
+  - DOMAIN-SUFFIX,google.com,PROXY
+  - DOMAIN,ad.example.com,REJECT
+  - IP-CIDR,10.0.0.0/8,DIRECT
+  - GEOIP,CN,DIRECT
+  - MATCH,PROXY
+dns_servers:
+  - 114.114.114.114:53
+  - tcp://1.1.1.1:53
+"#,
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+}
@@ -0,0 +1,183 @@
+//! Support for splitting config into multiple files with the `include` key.
+//!
+//! ```yaml
+//! include:
+//!   - servers.yml
+//!   - rules/*.yml
+//! ```
+//!
+//! Paths are relative to the file containing the `include` key, and `*`/`?` wildcards are
+//! allowed in the file name. Included files are merged in the listed order (wildcard matches are
+//! sorted by name), and the including file is merged last:
+//! mappings are merged recursively, sequences are concatenated and other values are overridden.
+//! So rules in included files come before rules in the main file.
+//!
+//! Configs not read from a file, eg. fetched from `--config-url`, can't use `include`.
+use serde_yaml::Value;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+const INCLUDE_KEY: &str = "include";
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Read yaml from `reader` and resolve includes relative to `base_dir`, includes are rejected
+/// without `base_dir`.
+pub(crate) fn load<R: Read>(reader: R, base_dir: Option<&Path>) -> io::Result<Value> {
+    let value: Value = serde_yaml::from_reader(reader).map_err(invalid_data)?;
+    match base_dir {
+        Some(base_dir) => resolve(value, base_dir, 0),
+        None if value.get(INCLUDE_KEY).is_some() => Err(invalid_data(
+            "include is only allowed in config files, not in remote configs",
+        )),
+        None => Ok(value),
+    }
+}
+
+fn resolve(mut value: Value, base_dir: &Path, depth: usize) -> io::Result<Value> {
+    let includes = match value.as_mapping_mut() {
+        Some(mapping) => mapping.remove(INCLUDE_KEY),
+        None => None,
+    };
+    let patterns = match includes {
+        None => return Ok(value),
+        Some(Value::String(pattern)) => vec![pattern],
+        Some(Value::Sequence(seq)) => seq
+            .into_iter()
+            .map(|v| match v {
+                Value::String(pattern) => Ok(pattern),
+                v => Err(invalid_data(format!("invalid include: {v:?}"))),
+            })
+            .collect::<io::Result<_>>()?,
+        Some(v) => return Err(invalid_data(format!("invalid include: {v:?}"))),
+    };
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(invalid_data("includes are nested too deep"));
+    }
+
+    let mut merged = Value::Null;
+    for pattern in patterns {
+        for path in expand(base_dir, &pattern)? {
+            let file = File::open(&path).map_err(|e| {
+                io::Error::new(e.kind(), format!("open include {}: {e}", path.display()))
+            })?;
+            let included: Value = serde_yaml::from_reader(file)
+                .map_err(|e| invalid_data(format!("parse include {}: {e}", path.display())))?;
+            if included.is_null() {
+                continue;
+            }
+            let dir = path.parent().unwrap_or(base_dir);
+            merge(&mut merged, resolve(included, dir, depth + 1)?);
+        }
+    }
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merge `overlay` into `base`.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        let _ = base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(overlay)) => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn expand(base_dir: &Path, pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let path = base_dir.join(pattern);
+    let Some(file_pattern) = path.file_name().and_then(|n| n.to_str()) else {
+        return Err(invalid_data(format!("invalid include: {pattern}")));
+    };
+    if !file_pattern.contains(['*', '?']) {
+        return Ok(vec![path]);
+    }
+    let dir = path.parent().unwrap_or(base_dir);
+    let mut paths = vec![];
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let matched = entry
+            .file_name()
+            .to_str()
+            .map(|name| wildcard_match(file_pattern.as_bytes(), name.as_bytes()))
+            .unwrap_or(false);
+        if matched && entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match(b"*.yml", b"rules.yml"));
+        assert!(wildcard_match(b"rule?.yml", b"rule1.yml"));
+        assert!(!wildcard_match(b"*.yml", b"rules.yaml"));
+        assert!(!wildcard_match(b"rule?.yml", b"rule.yml"));
+    }
+
+    #[test]
+    fn test_load_includes() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("rules"))?;
+        fs::write(
+            dir.path().join("rules/b.yml"),
+            "rules:\n  - 'DOMAIN,b.com,PROXY'\n",
+        )?;
+        fs::write(
+            dir.path().join("rules/a.yml"),
+            "include: ../servers.yml\nrules:\n  - 'DOMAIN,a.com,PROXY'\n",
+        )?;
+        fs::write(
+            dir.path().join("servers.yml"),
+            "servers:\n  - name: a\ngateway_mode: true\n",
+        )?;
+        let main = "include:\n  - rules/*.yml\ngateway_mode: false\nrules:\n  - 'MATCH,DIRECT'\n";
+
+        let value = load(main.as_bytes(), Some(dir.path()))?;
+        let expected: Value = serde_yaml::from_str(
+            "servers:\n  - name: a\ngateway_mode: false\nrules:\n  - 'DOMAIN,a.com,PROXY'\n  - 'DOMAIN,b.com,PROXY'\n  - 'MATCH,DIRECT'\n",
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+
+        assert!(load("include: missing.yml".as_bytes(), Some(dir.path())).is_err());
+        assert!(load("include: servers.yml".as_bytes(), None).is_err());
+        assert_eq!(
+            load("gateway_mode: true".as_bytes(), None)?,
+            serde_yaml::from_str::<Value>("gateway_mode: true").unwrap()
+        );
+        Ok(())
+    }
+}
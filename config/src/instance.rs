@@ -0,0 +1,67 @@
+//! Name of the running seeker, set by `--instance`, so several of them can run on one host, eg.
+//! one per network namespace. The files seeker keeps in the current directory get the name as a
+//! suffix, eg. `seeker-work.sqlite`, so the instances don't share the store, routes, pid file and
+//! control socket.
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+
+static INSTANCE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set the name of this instance, before the config is loaded.
+pub fn set_instance(name: &str) -> io::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid instance name {name:?}, use letters, digits, `-` and `_`"),
+        ));
+    }
+    *INSTANCE.lock().unwrap_or_else(|e| e.into_inner()) = Some(name.to_string());
+    Ok(())
+}
+
+pub fn instance() -> Option<String> {
+    INSTANCE.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// `path` with the instance name inserted before the extension, eg. `seeker.sqlite` becomes
+/// `seeker-work.sqlite`. Unchanged if no instance is set.
+pub fn instance_path(path: &str) -> String {
+    with_instance(path, instance().as_deref())
+}
+
+fn with_instance(path: &str, instance: Option<&str>) -> String {
+    let Some(instance) = instance else {
+        return path.to_string();
+    };
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.contains('/') => {
+            format!("{stem}-{instance}.{ext}")
+        }
+        _ => format!("{path}-{instance}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_instance() {
+        assert_eq!(with_instance("seeker.sqlite", None), "seeker.sqlite");
+        assert_eq!(
+            with_instance("seeker.sqlite", Some("work")),
+            "seeker-work.sqlite"
+        );
+        assert_eq!(
+            with_instance("./run/seeker.sock", Some("a")),
+            "./run/seeker-a.sock"
+        );
+        assert_eq!(with_instance("seeker", Some("a")), "seeker-a");
+        assert!(set_instance("a/b").is_err());
+        assert!(set_instance("").is_err());
+    }
+}
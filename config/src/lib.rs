@@ -1,24 +1,52 @@
+mod clash;
+mod include;
+mod instance;
+mod migrate;
 pub mod rule;
 mod server_config;
-pub use server_config::{DnsServerAddr, ServerConfig, ServerProtocol};
+mod template;
+mod uci;
+mod validate;
+pub use clash::convert_clash_config;
+pub use instance::{instance, instance_path, set_instance};
+pub use migrate::{migrate_config, CONFIG_VERSION};
+pub use server_config::{
+    DnsServerAddr, ServerConfig, ServerProtocol, TlsCipherOrder, ENCRYPTED_SECRET_PREFIX,
+    ESCAPED_SECRET_PREFIX,
+};
 pub use socks5_client::Address;
 
-use rule::ProxyRules;
+use rule::{Action, ProxyRules, RuleOptions};
 use serde::Deserialize;
 use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io;
 use std::io::{ErrorKind, Read};
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use store::Store;
+use tcp_connection::{PortRange, TcpOptions};
 
 use crate::rule::Rule;
 
+const CONFIG_FORMAT_KEY: &str = "config_format";
+
+/// Path of the sqlite db used by [`Store`].
+pub const DEFAULT_STORE_PATH: &str = "seeker.sqlite";
+/// Path of the file recording the routes added to the TUN device, for removing them after a
+/// crash.
+pub const DEFAULT_ROUTES_PATH: &str = "seeker.routes";
+/// Path of the DNS servers saved before taking over the system DNS on macOS, for restoring them
+/// after a crash.
+pub const DEFAULT_DNS_BACKUP_PATH: &str = "seeker.dns";
+pub const DEFAULT_PID_PATH: &str = "seeker.pid";
+pub const DEFAULT_CONTROL_SOCKET_PATH: &str = "seeker.sock";
+
 const URL_SAFE_ENGINE: base64::engine::fast_portable::FastPortable =
     base64::engine::fast_portable::FastPortable::from(
         &base64::alphabet::STANDARD,
@@ -35,34 +63,610 @@ pub struct Config {
     pub dns_start_ip: Ipv4Addr,
     #[serde(default)]
     pub dns_servers: Vec<DnsServerAddr>,
+    /// Named upstreams, the domains of rules with `dns=<name>` are resolved by them instead of
+    /// `dns_servers`.
+    #[serde(default)]
+    pub dns_groups: HashMap<String, DnsGroupConfig>,
+    #[serde(default)]
+    pub dns_steering: DnsSteeringConfig,
     #[serde(default)]
     pub redir_mode: bool,
     pub tun_bypass_direct: bool,
+    /// Connections to private, loopback and link-local addresses go direct without matching the
+    /// rules, false to send them by the rules, eg. to reach a remote LAN through the proxy.
+    #[serde(default = "default_bypass_lan")]
+    pub bypass_lan: bool,
+    /// The /96 prefix of the NAT64 gateway, eg. `64:ff9b::`, direct connections to IPv4
+    /// addresses are also tried through it on IPv6-only networks.
+    #[serde(default)]
+    pub nat64_prefix: Option<Ipv6Addr>,
     pub tun_name: String,
     pub tun_ip: Ipv4Addr,
-    #[serde(default)]
-    pub verbose: bool,
     #[serde(with = "ipv4_cidr")]
     pub tun_cidr: Ipv4Cidr,
+    /// MTU of the TUN device. Use the system default if not set.
+    pub tun_mtu: Option<u16>,
+    /// Clamp MSS of tcp connections through the TUN device to this value.
+    pub tcp_mss: Option<u16>,
+    /// Read and write the TUN device through io_uring on linux 5.6+, tcp relays don't use it.
+    #[serde(default)]
+    pub tun_io_uring: bool,
+    /// Queues of the TUN device on linux, each read by its own thread. Defaults to 1.
+    #[serde(default)]
+    pub tun_queues: Option<usize>,
+    /// Open the TUN device with checksum offload and TSO on linux, so tcp segments come in
+    /// super-packets of up to 64KB.
+    #[serde(default)]
+    pub tun_offload: bool,
     #[serde(with = "rules")]
     pub rules: ProxyRules,
     pub dns_listen: String,
     #[serde(default)]
+    pub dns_access: AccessConfig,
+    #[serde(default)]
     pub gateway_mode: bool,
-    #[serde(with = "duration", default = "default_connect_timeout")]
+    #[serde(with = "duration", default = "default_query_timeout")]
     pub ping_timeout: Duration,
     pub ping_urls: Vec<PingURL>,
-    #[serde(with = "duration", default = "default_connect_timeout")]
+    #[serde(with = "duration", default = "default_query_timeout")]
     pub dns_timeout: Duration,
+    /// Forward answers of record types seeker doesn't parse, eg. CAA, TLSA and HTTPS, with their
+    /// data as it is. They are dropped if false.
+    #[serde(default = "default_dns_passthrough_unknown")]
+    pub dns_passthrough_unknown: bool,
+    /// Records answered by seeker's DNS server itself, before /etc/hosts and the rules.
+    #[serde(default)]
+    pub dns_records: Vec<DnsRecordConfig>,
+    #[serde(default)]
+    pub dns_hijack: DnsHijackConfig,
+    #[serde(default)]
+    pub ntp: NtpConfig,
     #[serde(with = "duration", default = "default_ping_timeout")]
     pub probe_timeout: Duration,
+    /// Timeout of each attempt to connect a tcp stream, failed attempts are retried up to
+    /// `max_connect_errors` times.
     #[serde(with = "duration", default = "default_connect_timeout")]
     pub connect_timeout: Duration,
+    #[serde(default)]
+    timeouts: Timeouts,
     #[serde(with = "duration", default = "default_read_timeout")]
     pub read_timeout: Duration,
     #[serde(with = "duration", default = "default_write_timeout")]
     pub write_timeout: Duration,
     pub max_connect_errors: usize,
+    /// Buffer size of each direction when relaying tcp streams.
+    #[serde(default = "default_tcp_buffer_size")]
+    pub tcp_buffer_size: usize,
+    /// Buffer size for udp packets.
+    #[serde(default = "default_udp_buffer_size")]
+    pub udp_buffer_size: usize,
+    /// Idle timeout of the udp sessions recognized as QUIC connections, which often stay quiet
+    /// for longer than the udp read timeout.
+    #[serde(with = "duration", default = "default_quic_timeout")]
+    pub quic_timeout: Duration,
+    /// Relay direct tcp connections with splice(2) on linux, without copying to user space.
+    #[serde(default)]
+    pub tcp_splice: bool,
+    /// Retry proxied tcp connections once when they're reset before anything was received.
+    #[serde(default = "default_retry_on_reset")]
+    pub retry_on_reset: bool,
+    /// Threads of the async executor, defaults to the number of CPUs.
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    #[serde(default)]
+    pub connection_pool: ConnectionPoolConfig,
+    #[serde(default)]
+    pub http_reuse: HttpReuseConfig,
+    #[serde(default)]
+    pub proxy_fallback: ProxyFallbackConfig,
+    #[serde(default)]
+    tcp_options: TcpOptionsConfig,
+    #[serde(default)]
+    pub flow_control: FlowControlConfig,
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    #[serde(default)]
+    pub dns_rate_limit: DnsRateLimitConfig,
+    #[serde(default)]
+    pub kill_switch: KillSwitchConfig,
+    #[serde(default)]
+    pub reject_page: RejectPageConfig,
+    #[serde(default)]
+    pub tunnels: Vec<TunnelConfig>,
+    #[serde(default)]
+    pub chaos: Vec<ChaosConfig>,
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    /// User to switch to after setting up the TUN device, linux only.
+    #[serde(default)]
+    pub run_as: Option<RunAsConfig>,
+    /// Restrict the files and syscalls seeker can use once it starts, linux only.
+    #[serde(default)]
+    pub sandbox: bool,
+    #[serde(default)]
+    inbounds: Inbounds,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+    /// Name of the applied profile.
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// A named set of servers, rules and dns settings overriding the top level ones.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Profile {
+    servers: Option<Arc<Vec<ServerConfig>>>,
+    #[serde(default, deserialize_with = "rules::deserialize_optional")]
+    rules: Option<ProxyRules>,
+    dns_servers: Option<Vec<DnsServerAddr>>,
+}
+
+/// Timeouts by where connections go, under `timeouts`.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Timeouts {
+    /// Timeout of each attempt to connect a direct tcp stream, eg. longer for slow sites without
+    /// waiting as long for the servers. The tcp `connect_timeout` if not set.
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    direct: Option<Duration>,
+    /// Timeout of each attempt to connect a proxied tcp stream, the handshake with the server
+    /// included. The tcp `connect_timeout` if not set.
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    proxy: Option<Duration>,
+    /// Udp sessions without packets for this long are dropped from the NAT table, QUIC ones
+    /// have `quic_timeout`. The udp `read_timeout` if not set.
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    udp_idle: Option<Duration>,
+}
+
+/// Overrides of timeouts and buffer sizes for each inbound.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Inbounds {
+    #[serde(default)]
+    tcp: InboundOverrides,
+    #[serde(default)]
+    udp: InboundOverrides,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct InboundOverrides {
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    connect_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    read_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    write_timeout: Option<Duration>,
+    buffer_size: Option<usize>,
+}
+
+/// Pre-established connections to the selected shadowsocks server, so new proxied connections
+/// skip the handshake with the server.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ConnectionPoolConfig {
+    /// Number of idle connections to keep, 0 disables the pool.
+    #[serde(default)]
+    pub size: usize,
+    /// Idle connections are dropped after this long, it should be shorter than the idle timeout
+    /// of the server.
+    #[serde(with = "duration", default = "default_pool_idle_timeout")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        ConnectionPoolConfig {
+            size: 0,
+            idle_timeout: default_pool_idle_timeout(),
+        }
+    }
+}
+
+/// Direct plain HTTP connections kept open after the application closes them, so the next
+/// connection to the same host skips the handshake with it.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct HttpReuseConfig {
+    /// Idle connections kept for each host, 0 disables the reuse.
+    #[serde(default)]
+    pub size: usize,
+    /// Idle connections kept for all hosts.
+    #[serde(default = "default_http_reuse_max_total")]
+    pub max_total: usize,
+    /// Idle connections are closed after this long, it should be shorter than the keep-alive
+    /// timeout of the servers.
+    #[serde(with = "duration", default = "default_http_reuse_idle_timeout")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for HttpReuseConfig {
+    fn default() -> Self {
+        HttpReuseConfig {
+            size: 0,
+            max_total: default_http_reuse_max_total(),
+            idle_timeout: default_http_reuse_idle_timeout(),
+        }
+    }
+}
+
+/// Destinations failing to connect through the proxy again and again are connected directly
+/// for a while, eg. sites blocking the IPs of the servers.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ProxyFallbackConfig {
+    /// Failures in a row before a destination is connected directly, 0 disables the fallback.
+    #[serde(default)]
+    pub failures: u64,
+    /// How long the destination is connected directly, the proxy is tried again after it.
+    #[serde(with = "duration", default = "default_proxy_fallback_cooldown")]
+    pub cooldown: Duration,
+}
+
+impl Default for ProxyFallbackConfig {
+    fn default() -> Self {
+        ProxyFallbackConfig {
+            failures: 0,
+            cooldown: default_proxy_fallback_cooldown(),
+        }
+    }
+}
+
+/// Socket options of outgoing tcp connections.
+#[derive(Clone, Debug, Deserialize)]
+struct TcpOptionsConfig {
+    #[serde(default = "default_nodelay")]
+    nodelay: bool,
+    #[serde(default)]
+    fast_open: bool,
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    keepalive: Option<Duration>,
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    keepalive_interval: Option<Duration>,
+    /// Keepalive of connections to proxy servers, overriding `keepalive` for them.
+    #[serde(default, deserialize_with = "duration::deserialize_optional")]
+    server_keepalive: Option<Duration>,
+    /// Local ports of the connections, for firewalls only letting out connections from them.
+    #[serde(default)]
+    source_ports: Option<PortRange>,
+}
+
+impl Default for TcpOptionsConfig {
+    fn default() -> Self {
+        TcpOptionsConfig {
+            nodelay: default_nodelay(),
+            fast_open: false,
+            keepalive: None,
+            keepalive_interval: None,
+            server_keepalive: None,
+            source_ports: None,
+        }
+    }
+}
+
+/// Limits of the data queued in the kernel for each relayed tcp connection. Without them the
+/// socket buffers grow to several megabytes while the outbound is slow, the limits make the
+/// application slow down instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct FlowControlConfig {
+    /// Receive buffer size of connections accepted from the TUN device.
+    #[serde(default)]
+    pub tun_recv_buffer: Option<usize>,
+    /// Unsent bytes queued in an outgoing connection before it stops being writable, linux and
+    /// macos only.
+    #[serde(default)]
+    pub notsent_lowat: Option<u32>,
+}
+
+/// Rate limit of new tcp connections. Connections over the limit are closed right after being
+/// accepted, so a connection storm from a scan or a P2P app can't starve the existing ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct AdmissionConfig {
+    /// New connections accepted per second, unlimited if not set.
+    #[serde(default)]
+    pub connections_per_second: Option<u32>,
+    /// Connections accepted at once after being idle, defaults to `connections_per_second`.
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+/// Clients allowed to query the DNS server, eg. only the LAN when it listens on all addresses of a
+/// gateway. A client in `deny` is rejected, the others are allowed if `allow` is empty or has
+/// them. Only IPv4 clients can be listed, IPv6 ones are rejected once `allow` is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct AccessConfig {
+    #[serde(default, with = "ipv4_cidrs")]
+    pub allow: Vec<Ipv4Cidr>,
+    #[serde(default, with = "ipv4_cidrs")]
+    pub deny: Vec<Ipv4Cidr>,
+}
+
+impl AccessConfig {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let IpAddr::V4(ip) = ip.to_canonical() else {
+            return self.allow.is_empty();
+        };
+        let contains = |cidr: &Ipv4Cidr| cidr.contains_addr(&ip.into());
+        !self.deny.iter().any(contains)
+            && (self.allow.is_empty() || self.allow.iter().any(contains))
+    }
+}
+
+/// Rate limit of the DNS queries of each client ip, eg. of a misbehaving IoT device in gateway
+/// mode. Queries over the limit are dropped without an answer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct DnsRateLimitConfig {
+    /// Queries answered per second for each client, unlimited if not set.
+    #[serde(default)]
+    pub queries_per_second: Option<u32>,
+    /// Queries answered at once after being idle, defaults to `queries_per_second`.
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+/// Firewall rules dropping traffic that leaves through physical interfaces, except to the servers
+/// and DNS upstreams, linux only. DIRECT connections are blocked too unless to the LAN with
+/// `allow_lan`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct KillSwitchConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Allow private, link local and multicast addresses.
+    #[serde(default)]
+    pub allow_lan: bool,
+}
+
+/// A page answering plain HTTP requests to rejected sites instead of resetting the connections,
+/// so users behind a gateway can tell a block from a broken site. Connections to other ports are
+/// still reset.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct RejectPageConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// HTML of the page, `{host}` and `{rule}` are replaced with the blocked host and the rule
+    /// matching it. A built in page is used if not set.
+    #[serde(default)]
+    pub html: Option<String>,
+}
+
+/// Answer DNS queries through the TUN device to any server, eg. apps hard coding 8.8.8.8, with
+/// the embedded resolver instead of relaying them, so the answers follow the rules too.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct DnsHijackConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// DNS servers routed into the TUN device, only queries reaching it are answered. They can't
+    /// be the upstreams in `dns_servers`.
+    #[serde(default)]
+    pub servers: Vec<Ipv4Addr>,
+}
+
+/// NTP servers are resolved to their real IPs, so the clock can be synced at boot before the
+/// proxy works. seeker checks its clock against them too, AEAD ciphers and TLS handshakes fail
+/// confusingly when it's wrong.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct NtpConfig {
+    /// Domains or IPs, subdomains of the domains are NTP servers too, eg. 0.pool.ntp.org.
+    #[serde(default = "default_ntp_servers")]
+    pub servers: Vec<String>,
+    /// How often the clock is checked after startup, 0s checks it only at startup.
+    #[serde(with = "duration", default = "default_clock_check_interval")]
+    pub check_interval: Duration,
+    /// Offsets of the clock from the NTP servers larger than this are reported.
+    #[serde(with = "duration", default = "default_max_clock_offset")]
+    pub max_offset: Duration,
+}
+
+impl Default for NtpConfig {
+    fn default() -> Self {
+        NtpConfig {
+            servers: default_ntp_servers(),
+            check_interval: default_clock_check_interval(),
+            max_offset: default_max_clock_offset(),
+        }
+    }
+}
+
+/// An external command or webhook run on an event, eg. to alert when a server goes down or to
+/// tell home automation a device connected somewhere.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct HookConfig {
+    pub event: HookEvent,
+    /// `connection` hooks only run for connections to these domains and their subdomains.
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// `usage` hooks run once a month for each server whose month to date traffic reaches it.
+    #[serde(default)]
+    pub bytes: Option<u64>,
+    /// The program and its args, `{event}`, `{server}`, `{domain}` and `{bytes}` in the args
+    /// are replaced by those of the event.
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// Url POSTed `body` to.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Template of the POST body like the args of `command`, the event as JSON if not set.
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// A connection to one of `domains` is made.
+    Connection,
+    /// A server stops answering the pings.
+    ServerDown,
+    /// A server answers the pings again.
+    ServerUp,
+    /// The traffic through a server this month reaches `bytes`.
+    Usage,
+}
+
+impl std::fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            HookEvent::Connection => "connection",
+            HookEvent::ServerDown => "server_down",
+            HookEvent::ServerUp => "server_up",
+            HookEvent::Usage => "usage",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A local port forwarded to `remote` through `action`, replacing `ssh -L`, eg. 127.0.0.1:5432
+/// to db.internal:5432 through the proxy.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct TunnelConfig {
+    pub listen: SocketAddr,
+    /// host:port, the host is resolved by the server for PROXY.
+    #[serde(with = "address")]
+    pub remote: Address,
+    /// DIRECT or PROXY.
+    #[serde(with = "action", default = "default_tunnel_action")]
+    pub action: Action,
+}
+
+/// The upstreams of a named dns group, eg. a `fast` one of the ISP and a `clean` one queried
+/// through the proxy.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DnsGroupConfig {
+    pub servers: Vec<DnsServerAddr>,
+    /// Query the servers over tcp through the selected proxy server, so answers can't be
+    /// tampered with on the way. The servers must be `tcp://` ones of ip addresses.
+    #[serde(default)]
+    pub proxy: bool,
+}
+
+impl DnsGroupConfig {
+    /// Addresses of the `tcp://` servers of ips, the ones that can be queried through the proxy.
+    pub fn tcp_servers(&self) -> Vec<SocketAddr> {
+        self.servers
+            .iter()
+            .filter_map(|server| match server {
+                DnsServerAddr::TcpSocketAddr(url) if url.scheme() == "tcp" => {
+                    let ip = match url.host()? {
+                        url::Host::Ipv4(ip) => IpAddr::V4(ip),
+                        url::Host::Ipv6(ip) => IpAddr::V6(ip),
+                        url::Host::Domain(_) => return None,
+                    };
+                    Some(SocketAddr::new(ip, url.port().unwrap_or(53)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Resolve the direct domains through `dns_servers` and `groups` at once and answer with the
+/// ips connected fastest, for CDNs placing users badly by the location of their resolvers. Off
+/// while `groups` is empty.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct DnsSteeringConfig {
+    /// Names of `dns_groups` queried besides `dns_servers`.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Tcp port connected to measure the latency of the answered ips.
+    #[serde(default = "default_dns_steering_port")]
+    pub port: u16,
+    /// Ips not connected in time count as unreachable, it's also the longest answers wait.
+    #[serde(with = "duration", default = "default_dns_steering_timeout")]
+    pub timeout: Duration,
+    /// How long the latency measured of a subnet, /24 for IPv4 and /64 for IPv6, is reused.
+    #[serde(with = "duration", default = "default_dns_steering_cache_ttl")]
+    pub cache_ttl: Duration,
+}
+
+impl Default for DnsSteeringConfig {
+    fn default() -> Self {
+        DnsSteeringConfig {
+            groups: Vec::new(),
+            port: default_dns_steering_port(),
+            timeout: default_dns_steering_timeout(),
+            cache_ttl: default_dns_steering_cache_ttl(),
+        }
+    }
+}
+
+/// An authoritative record of seeker's DNS server, eg. for the names of a homelab, so they resolve
+/// without running another DNS server. Queries of a name with records never reach the upstreams,
+/// types it has no records of get an empty answer.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct DnsRecordConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub data: DnsRecordData,
+    #[serde(with = "duration", default = "default_dns_record_ttl")]
+    pub ttl: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "UPPERCASE")]
+pub enum DnsRecordData {
+    A {
+        value: Ipv4Addr,
+    },
+    Aaaa {
+        value: Ipv6Addr,
+    },
+    /// The target is resolved by seeker too, it may be a record of `dns_records` or a proxied
+    /// domain.
+    Cname {
+        value: String,
+    },
+    Txt {
+        value: String,
+    },
+    Srv {
+        #[serde(default)]
+        priority: u16,
+        #[serde(default)]
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+}
+
+/// Latency, jitter and loss added to the relayed connections matching all the set filters, for
+/// testing how apps and the failover behave over degraded proxies. Only meant for debugging, the
+/// first matching entry applies.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ChaosConfig {
+    /// Name of the server the connections go through.
+    #[serde(default)]
+    pub server: Option<String>,
+    /// DIRECT or PROXY.
+    #[serde(default, deserialize_with = "action::deserialize_optional")]
+    pub action: Option<Action>,
+    /// The remote domain or its parent domain, eg. the domain of a rule.
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Added to the data of each direction.
+    #[serde(with = "duration", default)]
+    pub latency: Duration,
+    /// The latency varies by up to this much either way.
+    #[serde(with = "duration", default)]
+    pub jitter: Duration,
+    /// Probability from 0 to 1 of dropping a datagram, or of delaying TCP data as long as a
+    /// retransmission.
+    #[serde(default)]
+    pub loss: f64,
+}
+
+/// The unprivileged user seeker runs as once the TUN device, routes and DNS are set up. Only
+/// `CAP_NET_ADMIN` is kept to restore routes on exit.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct RunAsConfig {
+    /// User name or uid.
+    pub user: String,
+    /// Group name or gid, defaults to the primary group of `user`.
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// Timeouts and buffer size for connections accepted by an inbound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InboundConfig {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub buffer_size: usize,
 }
 
 impl Debug for Config {
@@ -73,22 +677,59 @@ impl Debug for Config {
             .field("geo_ip", &self.geo_ip)
             .field("dns_start_ip", &self.dns_start_ip)
             .field("dns_servers", &self.dns_servers)
+            .field("dns_groups", &self.dns_groups)
+            .field("dns_steering", &self.dns_steering)
             .field("tun_bypass_direct", &self.tun_bypass_direct)
+            .field("bypass_lan", &self.bypass_lan)
+            .field("nat64_prefix", &self.nat64_prefix)
             .field("tun_name", &self.tun_name)
             .field("tun_ip", &self.tun_ip)
-            .field("verbose", &self.verbose)
             .field("tun_cidr", &self.tun_cidr)
+            .field("tun_mtu", &self.tun_mtu)
+            .field("tun_io_uring", &self.tun_io_uring)
+            .field("tun_queues", &self.tun_queues)
+            .field("tun_offload", &self.tun_offload)
+            .field("tcp_mss", &self.tcp_mss)
             .field("rules", &self.rules)
             .field("dns_listen", &self.dns_listen)
+            .field("dns_access", &self.dns_access)
             .field("gateway_mode", &self.gateway_mode)
             .field("ping_timeout", &self.ping_timeout)
             .field("ping_urls", &self.ping_urls)
             .field("dns_timeout", &self.dns_timeout)
+            .field("dns_passthrough_unknown", &self.dns_passthrough_unknown)
+            .field("dns_records", &self.dns_records)
+            .field("dns_hijack", &self.dns_hijack)
+            .field("ntp", &self.ntp)
             .field("probe_timeout", &self.probe_timeout)
             .field("connect_timeout", &self.connect_timeout)
+            .field("timeouts", &self.timeouts)
             .field("read_timeout", &self.read_timeout)
             .field("write_timeout", &self.write_timeout)
             .field("max_connect_errors", &self.max_connect_errors)
+            .field("tcp_buffer_size", &self.tcp_buffer_size)
+            .field("udp_buffer_size", &self.udp_buffer_size)
+            .field("quic_timeout", &self.quic_timeout)
+            .field("tcp_splice", &self.tcp_splice)
+            .field("retry_on_reset", &self.retry_on_reset)
+            .field("worker_threads", &self.worker_threads)
+            .field("connection_pool", &self.connection_pool)
+            .field("http_reuse", &self.http_reuse)
+            .field("proxy_fallback", &self.proxy_fallback)
+            .field("tcp_options", &self.tcp_options)
+            .field("flow_control", &self.flow_control)
+            .field("admission", &self.admission)
+            .field("dns_rate_limit", &self.dns_rate_limit)
+            .field("kill_switch", &self.kill_switch)
+            .field("reject_page", &self.reject_page)
+            .field("tunnels", &self.tunnels)
+            .field("hooks", &self.hooks)
+            .field("chaos", &self.chaos)
+            .field("run_as", &self.run_as)
+            .field("sandbox", &self.sandbox)
+            .field("inbounds", &self.inbounds)
+            .field("profiles", &self.profiles.keys())
+            .field("profile", &self.profile)
             .finish()
     }
 }
@@ -124,12 +765,81 @@ impl PingURL {
 fn default_read_timeout() -> Duration {
     Duration::from_secs(30)
 }
+fn default_quic_timeout() -> Duration {
+    Duration::from_secs(120)
+}
 fn default_write_timeout() -> Duration {
     Duration::from_secs(30)
 }
-fn default_connect_timeout() -> Duration {
+fn default_query_timeout() -> Duration {
     Duration::from_millis(100)
 }
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+fn default_tcp_buffer_size() -> usize {
+    16 * 1024
+}
+fn default_udp_buffer_size() -> usize {
+    // Larger than any datagram, so nothing is truncated.
+    64 * 1024
+}
+fn default_ntp_servers() -> Vec<String> {
+    [
+        "pool.ntp.org",
+        "time.apple.com",
+        "time.windows.com",
+        "ntp.aliyun.com",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+fn default_clock_check_interval() -> Duration {
+    Duration::from_secs(3600)
+}
+fn default_max_clock_offset() -> Duration {
+    Duration::from_secs(60)
+}
+fn default_dns_passthrough_unknown() -> bool {
+    true
+}
+fn default_dns_record_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+fn default_bypass_lan() -> bool {
+    true
+}
+fn default_retry_on_reset() -> bool {
+    true
+}
+fn default_tunnel_action() -> Action {
+    Action::Proxy
+}
+
+fn default_nodelay() -> bool {
+    true
+}
+fn default_pool_idle_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+fn default_http_reuse_max_total() -> usize {
+    64
+}
+fn default_http_reuse_idle_timeout() -> Duration {
+    Duration::from_secs(4)
+}
+fn default_proxy_fallback_cooldown() -> Duration {
+    Duration::from_secs(600)
+}
+fn default_dns_steering_port() -> u16 {
+    443
+}
+fn default_dns_steering_timeout() -> Duration {
+    Duration::from_millis(300)
+}
+fn default_dns_steering_cache_ttl() -> Duration {
+    Duration::from_secs(10 * 60)
+}
 fn default_ping_timeout() -> Duration {
     Duration::from_secs(3)
 }
@@ -150,6 +860,27 @@ mod ipv4_cidr {
     }
 }
 
+mod ipv4_cidrs {
+    use crate::parse_cidr;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+    use smoltcp::wire::Ipv4Cidr;
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Ipv4Cidr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| {
+                parse_cidr(s).map_err(|_| {
+                    Error::invalid_value(serde::de::Unexpected::Str(s), &"192.168.1.0/24")
+                })
+            })
+            .collect()
+    }
+}
+
 mod duration {
     use serde::de::Error;
     use serde::{Deserialize, Deserializer};
@@ -165,7 +896,11 @@ mod duration {
                 chars.push(c);
             }
         }
-        let n: u64 = num.into_iter().collect::<String>().parse().unwrap();
+        let n: u64 = num
+            .into_iter()
+            .collect::<String>()
+            .parse()
+            .map_err(|_| format!("invalid value: {s}, expected 10s or 10ms"))?;
         match chars.into_iter().collect::<String>().as_str() {
             "s" => Ok(Duration::from_secs(n)),
             "ms" => Ok(Duration::from_millis(n)),
@@ -181,10 +916,48 @@ mod duration {
         parse_duration(&s)
             .map_err(|_| Error::invalid_value(serde::de::Unexpected::Str(&s), &"10s or 10ms"))
     }
+
+    pub fn deserialize_optional<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserialize(deserializer).map(Some)
+    }
+}
+
+mod address {
+    use crate::Address;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Address, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| Error::invalid_value(serde::de::Unexpected::Str(&s), &"host:port"))
+    }
+}
+
+mod action {
+    use crate::rule::Action;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Action, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| Error::invalid_value(serde::de::Unexpected::Str(&s), &"DIRECT or PROXY"))
+    }
 }
 
 mod rules {
     use crate::rule::{ProxyRules, Rule};
+    use serde::de::Error;
     use serde::{Deserialize, Deserializer};
     use std::str::FromStr;
 
@@ -193,12 +966,19 @@ mod rules {
         D: Deserializer<'de>,
     {
         let rules: Vec<String> = Vec::deserialize(deserializer)?;
-        let rs: Vec<Rule> = rules
+        let rs = rules
             .into_iter()
-            .map(|s| Rule::from_str(&s).unwrap())
-            .collect();
+            .map(|s| Rule::from_str(&s).map_err(D::Error::custom))
+            .collect::<Result<Vec<Rule>, _>>()?;
         Ok(ProxyRules::new(rs))
     }
+
+    pub fn deserialize_optional<'de, D>(deserializer: D) -> Result<Option<ProxyRules>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserialize(deserializer).map(Some)
+    }
 }
 
 fn parse_cidr(s: &str) -> Result<Ipv4Cidr, &str> {
@@ -208,28 +988,78 @@ fn parse_cidr(s: &str) -> Result<Ipv4Cidr, &str> {
     }
     let addr = segments[0];
     let len = segments[1];
-    let addr: Ipv4Addr = addr.parse().unwrap();
-    let prefix = len.parse().unwrap();
+    let (Ok(addr), Ok(prefix)) = (addr.parse::<Ipv4Addr>(), len.parse::<u8>()) else {
+        return Err("invalid cidr");
+    };
+    if prefix > 32 {
+        return Err("invalid cidr prefix length");
+    }
     Ok(Ipv4Cidr::new(Ipv4Address::from(addr), prefix))
 }
 
 impl Config {
     pub fn from_config_file(path: &str) -> io::Result<Self> {
+        Config::from_config_file_with_profile(path, None)
+    }
+
+    pub fn from_config_file_with_profile(path: &str, profile: Option<&str>) -> io::Result<Self> {
         let file = File::open(path)?;
-        Config::from_reader(file)
+        let base_dir = Path::new(path).parent().unwrap_or_else(|| Path::new("."));
+        Config::from_reader_in_dir(file, Some(base_dir), profile)
     }
 
     pub fn from_reader<R: Read>(reader: R) -> io::Result<Self> {
-        let mut conf: Config =
-            serde_yaml::from_reader(reader).expect("serde yaml deserialize error");
+        Config::from_reader_with_profile(reader, None)
+    }
+
+    /// Load config and apply the named profile from `profiles` on top of it.
+    ///
+    /// The config can't use `include` as there is no file to resolve its paths against.
+    pub fn from_reader_with_profile<R: Read>(reader: R, profile: Option<&str>) -> io::Result<Self> {
+        Config::from_reader_in_dir(reader, None, profile)
+    }
+
+    fn from_reader_in_dir<R: Read>(
+        reader: R,
+        base_dir: Option<&Path>,
+        profile: Option<&str>,
+    ) -> io::Result<Self> {
+        let mut value = template::render(include::load(reader, base_dir)?)?;
+        uci::apply(&mut value, base_dir.unwrap_or_else(|| Path::new(".")))?;
+        for warning in migrate::migrate(&mut value)? {
+            eprintln!("Config: {warning}. Run `seeker migrate-config` to upgrade the config.");
+        }
+        let format = value
+            .as_mapping_mut()
+            .and_then(|m| m.remove(CONFIG_FORMAT_KEY));
+        match format.as_ref().and_then(serde_yaml::Value::as_str) {
+            None | Some("seeker") => {}
+            Some("clash") => value = clash::convert(value)?,
+            Some(format) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported config_format: {format}"),
+                ))
+            }
+        }
+        let mut conf: Config = serde_yaml::from_value(value)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("invalid config: {e}")))?;
+        if let Some(name) = profile {
+            conf.apply_profile(name)?;
+        }
         if conf.servers.is_empty() {
             return Err(io::Error::new(
                 ErrorKind::InvalidData,
                 "servers can not be empty.",
             ));
         };
+        conf.validate()?;
 
-        Store::setup_global("seeker.sqlite", conf.dns_start_ip);
+        // Already set up when the config is reloaded by a running seeker.
+        if Store::try_global().is_none() {
+            Store::setup_global(instance_path(DEFAULT_STORE_PATH), conf.dns_start_ip);
+            Store::global().set_fake_ip_end(conf.last_fake_ip());
+        }
 
         conf.load_remote_servers();
         conf.add_proxy_servers_to_direct_rules();
@@ -237,6 +1067,151 @@ impl Config {
         Ok(conf)
     }
 
+    /// Names of all profiles defined in config.
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    fn apply_profile(&mut self, name: &str) -> io::Result<()> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "profile `{name}` not found, available: {:?}",
+                    self.profile_names()
+                ),
+            ));
+        };
+        if let Some(servers) = profile.servers {
+            self.servers = servers;
+        }
+        if let Some(rules) = profile.rules {
+            self.rules = rules;
+        }
+        if let Some(dns_servers) = profile.dns_servers {
+            self.dns_servers = dns_servers;
+        }
+        self.profile = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Socket options of outgoing tcp connections.
+    pub fn tcp_options(&self) -> TcpOptions {
+        TcpOptions {
+            nodelay: self.tcp_options.nodelay,
+            fast_open: self.tcp_options.fast_open,
+            keepalive: self.tcp_options.keepalive,
+            keepalive_interval: self.tcp_options.keepalive_interval,
+            server_keepalive: self.tcp_options.server_keepalive,
+            notsent_lowat: self.flow_control.notsent_lowat,
+            source_ports: self.tcp_options.source_ports,
+        }
+    }
+
+    /// The last fake IP, the host address before the broadcast address of `tun_cidr`.
+    pub fn last_fake_ip(&self) -> Ipv4Addr {
+        let mask = u32::MAX
+            .checked_shl(32 - self.tun_cidr.prefix_len() as u32)
+            .unwrap_or(0);
+        let network = u32::from(Ipv4Addr::from(self.tun_cidr.address().0)) & mask;
+        Ipv4Addr::from((network | !mask).saturating_sub(1))
+    }
+
+    /// Whether connections to `ip` go direct by `bypass_lan`. Addresses in `tun_cidr` are fake
+    /// IPs, they are never bypassed.
+    pub fn is_bypassed_lan(&self, ip: IpAddr) -> bool {
+        if !self.bypass_lan {
+            return false;
+        }
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                (ip.is_private() || ip.is_loopback() || ip.is_link_local())
+                    && !self.tun_cidr.contains_addr(&ip.into())
+            }
+            IpAddr::V6(ip) => {
+                let first = ip.segments()[0];
+                // Unique local fc00::/7 and link-local fe80::/10.
+                ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+            }
+        }
+    }
+
+    /// Whether DNS queries from `client` are answered by `dns_access`. The machine itself, over
+    /// loopback or from the TUN device, is always answered.
+    pub fn is_dns_client_allowed(&self, client: IpAddr) -> bool {
+        let own = match client.to_canonical() {
+            IpAddr::V4(ip) => ip.is_loopback() || self.tun_cidr.contains_addr(&ip.into()),
+            IpAddr::V6(ip) => ip.is_loopback(),
+        };
+        own || self.dns_access.allows(client)
+    }
+
+    /// Timeouts and buffer size for tcp connections.
+    pub fn tcp_inbound(&self) -> InboundConfig {
+        self.inbound_config(&self.inbounds.tcp, self.tcp_buffer_size)
+    }
+
+    /// Timeouts and buffer size for udp sessions.
+    pub fn udp_inbound(&self) -> InboundConfig {
+        self.inbound_config(&self.inbounds.udp, self.udp_buffer_size)
+    }
+
+    /// Timeout of each attempt to connect a tcp stream by `action`, the `timeout=` of the rule
+    /// goes first.
+    pub fn tcp_connect_timeout(&self, action: Action, options: RuleOptions) -> Duration {
+        let by_action = match action {
+            Action::Direct => self.timeouts.direct,
+            Action::Proxy => self.timeouts.proxy,
+            _ => None,
+        };
+        options
+            .connect_timeout
+            .or(by_action)
+            .unwrap_or_else(|| self.tcp_inbound().connect_timeout)
+    }
+
+    /// Timeout of each attempt to set up a udp socket, the `timeout=` of the rule goes first.
+    pub fn udp_connect_timeout(&self, options: RuleOptions) -> Duration {
+        options
+            .connect_timeout
+            .unwrap_or_else(|| self.udp_inbound().connect_timeout)
+    }
+
+    /// Idle timeout of the udp sessions other than QUIC ones.
+    pub fn udp_idle_timeout(&self) -> Duration {
+        self.timeouts
+            .udp_idle
+            .unwrap_or_else(|| self.udp_inbound().read_timeout)
+    }
+
+    fn inbound_config(&self, overrides: &InboundOverrides, buffer_size: usize) -> InboundConfig {
+        InboundConfig {
+            connect_timeout: overrides.connect_timeout.unwrap_or(self.connect_timeout),
+            read_timeout: overrides.read_timeout.unwrap_or(self.read_timeout),
+            write_timeout: overrides.write_timeout.unwrap_or(self.write_timeout),
+            buffer_size: overrides.buffer_size.unwrap_or(buffer_size),
+        }
+    }
+
+    /// Whether any server credential in config is encrypted.
+    pub fn has_encrypted_secrets(&self) -> bool {
+        self.servers.iter().any(|s| s.has_encrypted_secrets())
+    }
+
+    /// Decrypt all encrypted server credentials in place, and unescape the ones starting with
+    /// `enc::`.
+    pub fn decrypt_secrets<E>(
+        &mut self,
+        decrypt: impl Fn(&str) -> Result<String, E>,
+    ) -> Result<(), E> {
+        for server in Arc::make_mut(&mut self.servers).iter_mut() {
+            server.decrypt_secrets(&decrypt)?;
+        }
+        Ok(())
+    }
+
     fn add_proxy_servers_to_direct_rules(&mut self) {
         let mut rules = vec![];
         for server in self.servers.iter() {
@@ -334,4 +1309,273 @@ mod tests {
         assert_eq!(servers.len(), 13);
         Ok(())
     }
+
+    #[test]
+    fn test_inbound_overrides() {
+        let data = r#"
+dns_start_ip: 11.0.0.10
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+ping_urls: []
+max_connect_errors: 2
+connect_timeout: 2s
+read_timeout: 30s
+write_timeout: 5s
+tcp_buffer_size: 8192
+inbounds:
+  udp:
+    read_timeout: 10s
+    buffer_size: 4096
+servers: []
+rules: []
+"#;
+        let conf: Config = serde_yaml::from_str(data).unwrap();
+        assert_eq!(
+            conf.tcp_inbound(),
+            InboundConfig {
+                connect_timeout: Duration::from_secs(2),
+                read_timeout: Duration::from_secs(30),
+                write_timeout: Duration::from_secs(5),
+                buffer_size: 8192,
+            }
+        );
+        assert_eq!(
+            conf.udp_inbound(),
+            InboundConfig {
+                connect_timeout: Duration::from_secs(2),
+                read_timeout: Duration::from_secs(10),
+                write_timeout: Duration::from_secs(5),
+                buffer_size: 4096,
+            }
+        );
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let data = r#"
+dns_start_ip: 11.0.0.10
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+ping_urls: []
+max_connect_errors: 2
+connect_timeout: 2s
+timeouts:
+  proxy: 8s
+  udp_idle: 60s
+inbounds:
+  udp:
+    read_timeout: 10s
+servers: []
+rules: []
+"#;
+        let mut conf: Config = serde_yaml::from_str(data).unwrap();
+        let options = RuleOptions::default();
+        let secs = Duration::from_secs;
+        assert_eq!(conf.tcp_connect_timeout(Action::Direct, options), secs(2));
+        assert_eq!(conf.tcp_connect_timeout(Action::Proxy, options), secs(8));
+        assert_eq!(conf.udp_connect_timeout(options), secs(2));
+        assert_eq!(conf.udp_idle_timeout(), secs(60));
+        let slow = RuleOptions {
+            connect_timeout: Some(secs(20)),
+            ..Default::default()
+        };
+        assert_eq!(conf.tcp_connect_timeout(Action::Proxy, slow), secs(20));
+        assert_eq!(conf.udp_connect_timeout(slow), secs(20));
+        conf.timeouts.udp_idle = None;
+        assert_eq!(conf.udp_idle_timeout(), secs(10));
+
+        // Kept by the migration of configs without a version.
+        let mut value: serde_yaml::Value =
+            serde_yaml::from_str(&data.replace("proxy: 8s", "direct: 3s")).unwrap();
+        assert!(migrate::migrate(&mut value).unwrap().is_empty());
+        let conf: Config = serde_yaml::from_value(value).unwrap();
+        assert_eq!(conf.tcp_connect_timeout(Action::Direct, options), secs(3));
+    }
+
+    #[test]
+    fn test_tunnels() {
+        let data = r#"
+dns_start_ip: 11.0.0.10
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+ping_urls: []
+max_connect_errors: 2
+tunnels:
+  - listen: 127.0.0.1:5432
+    remote: db.internal:5432
+  - listen: 127.0.0.1:2222
+    remote: 10.0.0.2:22
+    action: DIRECT
+servers: []
+rules: []
+"#;
+        let conf: Config = serde_yaml::from_str(data).unwrap();
+        assert_eq!(
+            conf.tunnels,
+            vec![
+                TunnelConfig {
+                    listen: "127.0.0.1:5432".parse().unwrap(),
+                    remote: Address::DomainNameAddress("db.internal".to_string(), 5432),
+                    action: Action::Proxy,
+                },
+                TunnelConfig {
+                    listen: "127.0.0.1:2222".parse().unwrap(),
+                    remote: "10.0.0.2:22".parse().unwrap(),
+                    action: Action::Direct,
+                },
+            ]
+        );
+        let data = data.replace("DIRECT", "SSH");
+        assert!(serde_yaml::from_str::<Config>(&data).is_err());
+        assert_eq!(conf.last_fake_ip(), Ipv4Addr::new(11, 0, 255, 254));
+    }
+
+    #[test]
+    fn test_bypass_lan() {
+        let data = r#"
+dns_start_ip: 10.0.0.10
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 10.0.0.1
+tun_cidr: 10.0.0.0/16
+dns_listen: 0.0.0.0:53
+ping_urls: []
+max_connect_errors: 2
+servers: []
+rules: []
+"#;
+        let mut conf: Config = serde_yaml::from_str(data).unwrap();
+        assert!(conf.bypass_lan);
+        for ip in [
+            "192.168.1.1",
+            "10.1.0.1",
+            "127.0.0.1",
+            "169.254.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(conf.is_bypassed_lan(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "10.0.0.10",
+            "8.8.8.8",
+            "::ffff:8.8.8.8",
+            "100.64.0.1",
+            "2001:db8::1",
+        ] {
+            assert!(!conf.is_bypassed_lan(ip.parse().unwrap()), "{ip}");
+        }
+        conf.bypass_lan = false;
+        assert!(!conf.is_bypassed_lan("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_dns_access() {
+        let data = r#"
+dns_start_ip: 10.0.0.10
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 10.0.0.1
+tun_cidr: 10.0.0.0/16
+dns_listen: 0.0.0.0:53
+dns_access:
+  allow:
+    - 192.168.1.0/24
+  deny:
+    - 192.168.1.100/32
+ping_urls: []
+max_connect_errors: 2
+servers: []
+rules: []
+"#;
+        let mut conf: Config = serde_yaml::from_str(data).unwrap();
+        for ip in [
+            "192.168.1.20",
+            "::ffff:192.168.1.20",
+            "127.0.0.1",
+            "10.0.0.1",
+            "::1",
+        ] {
+            assert!(conf.is_dns_client_allowed(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["192.168.1.100", "192.168.2.1", "fd00::1"] {
+            assert!(!conf.is_dns_client_allowed(ip.parse().unwrap()), "{ip}");
+        }
+        conf.dns_access.allow.clear();
+        assert!(conf.is_dns_client_allowed("192.168.2.1".parse().unwrap()));
+        assert!(conf.is_dns_client_allowed("fd00::1".parse().unwrap()));
+        assert!(!conf.is_dns_client_allowed("192.168.1.100".parse().unwrap()));
+
+        let data = data.replace("192.168.1.0/24", "192.168.1.0");
+        assert!(serde_yaml::from_str::<Config>(&data).is_err());
+    }
+
+    #[test]
+    fn test_profile() -> std::io::Result<()> {
+        let data = r#"
+dns_start_ip: 11.0.0.10
+dns_servers:
+  - 223.5.5.5:53
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+ping_urls: []
+max_connect_errors: 2
+servers:
+  - name: home-server
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+rules:
+  - 'MATCH,PROXY'
+profiles:
+  travel:
+    servers:
+      - name: travel-server
+        addr: 127.0.0.2:1080
+        protocol: Socks5
+    rules:
+      - 'DOMAIN-SUFFIX,example.com,PROXY'
+      - 'MATCH,DIRECT'
+    dns_servers:
+      - 1.1.1.1:53
+"#;
+        let mut conf: Config = serde_yaml::from_str(data).unwrap();
+        assert_eq!(conf.profile_names(), vec!["travel"]);
+        assert!(conf.apply_profile("work").is_err());
+        assert_eq!(conf.servers[0].name(), "home-server");
+        assert_eq!(
+            conf.rules.action_for_domain(Some("google.com"), None),
+            Some(rule::Action::Proxy)
+        );
+
+        conf.apply_profile("travel")?;
+        assert_eq!(conf.profile.as_deref(), Some("travel"));
+        assert_eq!(conf.servers[0].name(), "travel-server");
+        assert!(matches!(
+            conf.dns_servers.as_slice(),
+            [DnsServerAddr::UdpSocketAddr(addr)] if addr.to_string() == "1.1.1.1:53"
+        ));
+        assert_eq!(
+            conf.rules.action_for_domain(Some("google.com"), None),
+            Some(rule::Action::Direct)
+        );
+        assert_eq!(
+            conf.rules.action_for_domain(Some("www.example.com"), None),
+            Some(rule::Action::Proxy)
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,106 @@
+//! Upgrade config written for older versions of seeker to the current schema.
+//!
+//! The schema version is stored in the `version` key, configs without it are version 0.
+use serde_yaml::{Mapping, Value};
+use std::io::{self, ErrorKind, Read};
+
+const VERSION_KEY: &str = "version";
+
+/// Current version of the config schema.
+pub const CONFIG_VERSION: u64 = 1;
+
+/// Migrations indexed by the version they upgrade from.
+const MIGRATIONS: [fn(&mut Mapping, &mut Vec<String>); CONFIG_VERSION as usize] = [migrate_v0];
+
+fn migrate_v0(config: &mut Mapping, warnings: &mut Vec<String>) {
+    rename(config, "direct_connect_timeout", "probe_timeout", warnings);
+    rename(config, "dns_server", "dns_listen", warnings);
+    remove(
+        config,
+        "verbose",
+        "use `--trace` or `RUST_LOG` to control logging",
+        warnings,
+    );
+}
+
+fn rename(config: &mut Mapping, from: &str, to: &str, warnings: &mut Vec<String>) {
+    let Some(value) = config.remove(from) else {
+        return;
+    };
+    if config.contains_key(to) {
+        warnings.push(format!("`{from}` is ignored because `{to}` is set"));
+    } else {
+        warnings.push(format!("`{from}` is renamed to `{to}`"));
+        let _ = config.insert(to.into(), value);
+    }
+}
+
+fn remove(config: &mut Mapping, key: &str, hint: &str, warnings: &mut Vec<String>) {
+    if config.remove(key).is_some() {
+        warnings.push(format!("`{key}` is removed, {hint}"));
+    }
+}
+
+/// Upgrade `value` to [`CONFIG_VERSION`] and return warnings about renamed and removed keys.
+pub(crate) fn migrate(value: &mut Value) -> io::Result<Vec<String>> {
+    let Some(config) = value.as_mapping_mut() else {
+        return Err(invalid_data("config must be a mapping"));
+    };
+    let version = match config.get(VERSION_KEY) {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| invalid_data(format!("invalid version: {v:?}")))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(invalid_data(format!(
+            "config version {version} is newer than supported version {CONFIG_VERSION}"
+        )));
+    }
+    let mut warnings = vec![];
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(config, &mut warnings);
+    }
+    let _ = config.insert(VERSION_KEY.into(), CONFIG_VERSION.into());
+    Ok(warnings)
+}
+
+/// Upgrade the config from `reader` to the current schema, returning the upgraded yaml and
+/// warnings about renamed and removed keys. Comments are not preserved.
+pub fn migrate_config<R: Read>(reader: R) -> io::Result<(String, Vec<String>)> {
+    let mut value: Value = serde_yaml::from_reader(reader).map_err(invalid_data)?;
+    let warnings = migrate(&mut value)?;
+    let yaml = serde_yaml::to_string(&value).map_err(invalid_data)?;
+    Ok((yaml, warnings))
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_v0() {
+        let mut value: Value = serde_yaml::from_str(
+            "verbose: true\ndirect_connect_timeout: 100ms\ndns_server: 0.0.0.0:53\n",
+        )
+        .unwrap();
+        let warnings = migrate(&mut value).unwrap();
+        let expected: Value =
+            serde_yaml::from_str("probe_timeout: 100ms\ndns_listen: 0.0.0.0:53\nversion: 1\n")
+                .unwrap();
+        assert_eq!(value, expected);
+        assert_eq!(warnings.len(), 3);
+
+        assert!(migrate(&mut value).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let mut value: Value = serde_yaml::from_str("version: 100").unwrap();
+        assert!(migrate(&mut value).is_err());
+    }
+}
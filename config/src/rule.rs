@@ -1,12 +1,17 @@
+use crate::duration::parse_duration;
 use crate::parse_cidr;
 use maxminddb::geoip2::Country;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+use std::collections::HashMap;
 use std::fmt::{self, Formatter};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tcp_connection::SocketMark;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Rule {
@@ -15,7 +20,52 @@ pub enum Rule {
     DomainKeyword(String, Action),
     IpCidr(Ipv4Cidr, Action),
     GeoIp(String, Action),
+    /// Connections from sockets in the cgroup v2 at the path or below it, linux only.
+    Cgroup(String, Action),
+    /// Connections from source addresses in the network, eg. a container subnet.
+    SrcIpCidr(Ipv4Cidr, Action),
+    /// Connections forwarded from the subnet of an interface, eg. `docker0`. A trailing `*`
+    /// matches interface names by prefix, eg. `br-*`.
+    SrcInterface(String, Action),
     Match(Action),
+    /// A rule with options after the action, the marks set on the sockets of the direct
+    /// connections it matches, eg. `DOMAIN-SUFFIX,zoom.us,DIRECT,dscp=EF`, how they are
+    /// logged, eg. `DOMAIN-SUFFIX,apple.com,DIRECT,log=off`, and the `dns_groups` resolving the
+    /// domains it matches, eg. `DOMAIN-SUFFIX,google.com,PROXY,dns=clean`.
+    Marked(Box<Rule>, RuleOptions, Option<String>),
+}
+
+/// The options after the action of a rule.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct RuleOptions {
+    pub mark: SocketMark,
+    pub log: ConnectionLog,
+    /// Timeout of each attempt to connect, from `timeout=`, eg. for known slow destinations.
+    pub connect_timeout: Option<Duration>,
+}
+
+/// What the connections matched by a rule record to the store.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ConnectionLog {
+    /// A row per connection, updated with its traffic.
+    #[default]
+    Verbose,
+    /// A row per connection, with its traffic written once when it's closed.
+    Basic,
+    /// Nothing, eg. for noisy connections to telemetry hosts.
+    Off,
+}
+
+/// Where a connection comes from, matched by the `CGROUP`, `SRC-IP-CIDR` and `SRC-INTERFACE`
+/// rules.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionSource<'a> {
+    pub ip: Option<Ipv4Addr>,
+    /// The interface whose subnet the connection is forwarded from, None for connections of
+    /// this machine.
+    pub interface: Option<&'a str>,
+    /// Cgroup v2 path of the socket, for connections of this machine.
+    pub cgroup: Option<&'a str>,
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug, Hash, PartialOrd, Ord, Default)]
@@ -29,17 +79,25 @@ pub enum Action {
 
 #[derive(Debug, Clone)]
 pub struct ProxyRules {
-    rules: Arc<Vec<Rule>>,
+    // Shared by the clones, so rules replaced by `Self::replace` apply wherever they are used.
+    rules: Arc<RwLock<Arc<Vec<Rule>>>>,
     geo_ip_path: Option<PathBuf>,
     geo_ip_db: Arc<Mutex<Option<maxminddb::Reader<Vec<u8>>>>>,
+    // Domain each fake IP is allocated to, with the index and the action of the domain or IP
+    // rule it matched.
+    fake_ip_actions: Arc<RwLock<HashMap<Ipv4Addr, (String, Option<(usize, Action, RuleOptions)>)>>>,
+    // Everything goes direct while paused, shared by the clones like the rules.
+    paused: Arc<AtomicBool>,
 }
 
 impl ProxyRules {
     pub fn new(rules: Vec<Rule>) -> Self {
         Self {
-            rules: Arc::new(rules),
+            rules: Arc::new(RwLock::new(Arc::new(rules))),
             geo_ip_db: Arc::new(Mutex::new(None)),
             geo_ip_path: None,
+            fake_ip_actions: Default::default(),
+            paused: Default::default(),
         }
     }
 
@@ -69,45 +127,228 @@ impl ProxyRules {
     }
 
     pub fn action_for_domain(&self, domain: Option<&str>, ip: Option<IpAddr>) -> Option<Action> {
-        let ip = ip.and_then(|ip| match ip {
+        self.marked_action_for_domain(domain, ip)
+            .map(|(action, _)| action)
+    }
+
+    /// Same as [`Self::action_for_domain`], with the options of the matched rule.
+    pub fn marked_action_for_domain(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Option<(Action, RuleOptions)> {
+        self.find_for_domain(domain, ip, |_, rule| (rule.action(), rule.options()))
+    }
+
+    /// Name of the `dns_groups` resolving `domain`, of the first rule matching it. None if the
+    /// rule has no `dns=` option, the domain is resolved by `dns_servers` then.
+    pub fn dns_group_for_domain(&self, domain: &str) -> Option<String> {
+        self.find_for_domain(Some(domain), None, |_, rule| {
+            rule.dns_group().map(str::to_string)
+        })
+        .flatten()
+    }
+
+    /// Names of the `dns_groups` the rules refer to.
+    pub fn dns_groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = self
+            .rules
+            .read()
+            .iter()
+            .filter_map(|rule| rule.dns_group().map(str::to_string))
+            .collect();
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+
+    /// The first rule matching `domain` or `ip`, eg. to tell users which rule blocked a site.
+    pub fn rule_for_domain(&self, domain: Option<&str>, ip: Option<IpAddr>) -> Option<Rule> {
+        self.find_for_domain(domain, ip, |_, rule| rule.clone())
+    }
+
+    /// `f` of the index and the first rule matching `domain` or `ip`.
+    fn find_for_domain<T>(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        f: impl FnOnce(usize, &Rule) -> T,
+    ) -> Option<T> {
+        // IPv4-mapped IPv6 addresses are matched as the IPv4 ones.
+        let ip = ip.and_then(|ip| match ip.to_canonical() {
             IpAddr::V4(ip) => Some(ip),
             _ => None,
         });
-        let matched_rule = self.rules.iter().find(|rule| match (rule, domain, ip) {
-            (Rule::Domain(d, _), Some(domain), _) if d == domain => true,
-            (Rule::DomainSuffix(d, _), Some(domain), _) if domain.ends_with(d) => true,
-            (Rule::DomainKeyword(d, _), Some(domain), _) if domain.contains(d) => true,
-            (Rule::IpCidr(cidr, _), _, Some(ip)) => {
-                let ip: Ipv4Address = ip.into();
-                if cidr.contains_addr(&ip) {
-                    return true;
+        let rules = self.rules.read();
+        let index = rules
+            .iter()
+            .position(|rule| match (rule.unmarked(), domain, ip) {
+                (Rule::Domain(d, _), Some(domain), _) if d == domain => true,
+                (Rule::DomainSuffix(d, _), Some(domain), _) if domain.ends_with(d) => true,
+                (Rule::DomainKeyword(d, _), Some(domain), _) if domain.contains(d) => true,
+                (Rule::IpCidr(cidr, _), _, Some(ip)) => {
+                    let ip: Ipv4Address = ip.into();
+                    if cidr.contains_addr(&ip) {
+                        return true;
+                    }
+                    false
                 }
-                false
-            }
-            (Rule::GeoIp(name, _), _, Some(ip))
-                if self.did_geo_ip_matches_name(ip.into(), name) =>
-            {
-                true
+                (Rule::GeoIp(name, _), _, Some(ip))
+                    if self.did_geo_ip_matches_name(ip.into(), name) =>
+                {
+                    true
+                }
+                (Rule::Match(_), _, _) => true,
+                _ => false,
+            });
+        let matched_rule = index.map(|i| &rules[i]);
+        tracing::info!("matched rule: {:?}, {:?}, {:?}", matched_rule, domain, ip);
+        index.map(|i| f(i, &rules[i]))
+    }
+
+    /// Action of the first `CGROUP`, `SRC-IP-CIDR` or `SRC-INTERFACE` rule matching where the
+    /// connection comes from, the other rules aside.
+    pub fn action_for_source(&self, source: &ConnectionSource) -> Option<Action> {
+        self.marked_action_for_source(source)
+            .map(|(action, _)| action)
+    }
+
+    /// Same as [`Self::action_for_source`], with the options of the matched rule.
+    pub fn marked_action_for_source(
+        &self,
+        source: &ConnectionSource,
+    ) -> Option<(Action, RuleOptions)> {
+        let rules = self.rules.read();
+        let matched_rule = rules.iter().find(|rule| rule.matches_source(source));
+        matched_rule.map(|rule| (rule.action(), rule.options()))
+    }
+
+    /// The action of the first rule matching a connection from `source` to `domain` or `ip`, with
+    /// the options of the rule and whether it matched the source. The domain and IP rules of a
+    /// fake `ip` are matched like [`Self::marked_action_for_fake_ip`], cached by the IP.
+    pub fn marked_action_for_connection(
+        &self,
+        source: &ConnectionSource,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        fake_ip: bool,
+    ) -> Option<(Action, RuleOptions, bool)> {
+        let matched = match (domain, ip) {
+            (Some(domain), Some(IpAddr::V4(ip))) if fake_ip => self.fake_ip_match(domain, ip),
+            _ => self.find_for_domain(domain, ip, |i, rule| (i, rule.action(), rule.options())),
+        };
+        let rules = self.rules.read();
+        let end = matched.map_or(rules.len(), |(i, ..)| i.min(rules.len()));
+        if let Some(rule) = rules[..end].iter().find(|rule| rule.matches_source(source)) {
+            return Some((rule.action(), rule.options(), true));
+        }
+        matched.map(|(_, action, options)| (action, options, false))
+    }
+
+    pub fn has_cgroup_rules(&self) -> bool {
+        self.rules
+            .read()
+            .iter()
+            .any(|rule| matches!(rule.unmarked(), Rule::Cgroup(..)))
+    }
+
+    /// Whether any rule matches where connections come from.
+    pub fn has_source_rules(&self) -> bool {
+        self.rules.read().iter().any(Rule::is_source_rule)
+    }
+
+    /// Same as [`Self::action_for_domain`] for `domain` resolved to the fake IP `ip`. The result
+    /// is cached by IP, so later connections to the domain don't match the rules again.
+    pub fn action_for_fake_ip(&self, domain: &str, ip: Ipv4Addr) -> Option<Action> {
+        self.marked_action_for_fake_ip(domain, ip)
+            .map(|(action, _)| action)
+    }
+
+    /// Same as [`Self::action_for_fake_ip`], with the options of the matched rule.
+    pub fn marked_action_for_fake_ip(
+        &self,
+        domain: &str,
+        ip: Ipv4Addr,
+    ) -> Option<(Action, RuleOptions)> {
+        self.fake_ip_match(domain, ip)
+            .map(|(_, action, options)| (action, options))
+    }
+
+    fn fake_ip_match(&self, domain: &str, ip: Ipv4Addr) -> Option<(usize, Action, RuleOptions)> {
+        if let Some((cached_domain, matched)) = self.fake_ip_actions.read().get(&ip) {
+            if cached_domain == domain {
+                return *matched;
             }
-            (Rule::Match(_), _, _) => true,
-            _ => false,
+        }
+        let matched = self.find_for_domain(Some(domain), Some(ip.into()), |i, rule| {
+            (i, rule.action(), rule.options())
         });
-        tracing::info!("matched rule: {:?}, {:?}, {:?}", matched_rule, domain, ip);
-        matched_rule.map(|rule| match rule {
-            Rule::Match(action) => *action,
-            Rule::Domain(_, action) => *action,
-            Rule::DomainSuffix(_, action) => *action,
-            Rule::DomainKeyword(_, action) => *action,
-            Rule::IpCidr(_, action) => *action,
-            Rule::GeoIp(_, action) => *action,
-        })
+        self.fake_ip_actions
+            .write()
+            .insert(ip, (domain.to_string(), matched));
+        matched
     }
 
+    /// Whether connections to the fake IP `ip` are rejected by the rule cached for it wherever
+    /// they come from, without matching any rule. False until a connection to it has been
+    /// matched, or if a source rule comes before the rule.
+    pub fn is_fake_ip_rejected(&self, ip: Ipv4Addr) -> bool {
+        let Some((_, Some((index, Action::Reject, _)))) =
+            self.fake_ip_actions.read().get(&ip).cloned()
+        else {
+            return false;
+        };
+        let rules = self.rules.read();
+        !rules[..index.min(rules.len())]
+            .iter()
+            .any(Rule::is_source_rule)
+    }
+
+    /// The fake IP `ip` is allocated to `domain`, the action cached for another domain it was
+    /// allocated to before is forgotten.
+    pub fn assign_fake_ip(&self, ip: Ipv4Addr, domain: &str) {
+        let stale =
+            matches!(self.fake_ip_actions.read().get(&ip), Some((cached, _)) if cached != domain);
+        if stale {
+            let _ = self.fake_ip_actions.write().remove(&ip);
+        }
+    }
+
+    /// Insert `rules` before the others. Unlike [`Self::replace`], the clones made earlier keep
+    /// their rules.
     pub fn prepend_rules(&mut self, rules: Vec<Rule>) {
-        let rules_mut = Arc::make_mut(&mut self.rules);
+        self.fake_ip_actions = Default::default();
+        let mut rules_mut = Vec::clone(&self.rules.read());
         for rule in rules {
             rules_mut.insert(0, rule);
         }
+        self.rules = Arc::new(RwLock::new(Arc::new(rules_mut)));
+    }
+
+    /// Replace the rules with those of `other`, eg. loaded again from the config file, for this
+    /// and all its clones. The actions cached by fake IP are forgotten, connections already
+    /// relayed keep their actions.
+    pub fn replace(&self, other: &ProxyRules) {
+        let rules = other.rules.read().clone();
+        *self.rules.write() = rules;
+        self.fake_ip_actions.write().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// While paused, the proxy sends connections direct without matching them against the rules.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
     }
 
     pub fn default_action(&self) -> Action {
@@ -116,8 +357,9 @@ impl ProxyRules {
 
     pub fn additional_cidrs(&self) -> Vec<Ipv4Cidr> {
         self.rules
+            .read()
             .iter()
-            .filter_map(|rule| match rule {
+            .filter_map(|rule| match rule.unmarked() {
                 Rule::IpCidr(cidr, Action::Probe | Action::Proxy) => Some(*cidr),
                 _ => None,
             })
@@ -125,10 +367,82 @@ impl ProxyRules {
     }
 
     pub(crate) fn set_geo_ip_path(&mut self, path: Option<PathBuf>) {
+        self.fake_ip_actions = Default::default();
         self.geo_ip_path = path;
     }
 }
 
+impl Rule {
+    fn is_source_rule(&self) -> bool {
+        matches!(
+            self.unmarked(),
+            Rule::Cgroup(..) | Rule::SrcIpCidr(..) | Rule::SrcInterface(..)
+        )
+    }
+
+    /// Whether it's a source rule matching `source`.
+    fn matches_source(&self, source: &ConnectionSource) -> bool {
+        match self.unmarked() {
+            Rule::Cgroup(cgroup, _) => source
+                .cgroup
+                .map_or(false, |path| is_cgroup_within(path, cgroup)),
+            Rule::SrcIpCidr(cidr, _) => {
+                source.ip.map_or(false, |ip| cidr.contains_addr(&ip.into()))
+            }
+            Rule::SrcInterface(name, _) => {
+                source
+                    .interface
+                    .map_or(false, |interface| match name.strip_suffix('*') {
+                        Some(prefix) => interface.starts_with(prefix),
+                        None => interface == name,
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// The rule without its options.
+    fn unmarked(&self) -> &Rule {
+        match self {
+            Rule::Marked(rule, ..) => rule.unmarked(),
+            rule => rule,
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        match self.unmarked() {
+            Rule::Match(action)
+            | Rule::Domain(_, action)
+            | Rule::DomainSuffix(_, action)
+            | Rule::DomainKeyword(_, action)
+            | Rule::IpCidr(_, action)
+            | Rule::GeoIp(_, action)
+            | Rule::Cgroup(_, action)
+            | Rule::SrcIpCidr(_, action)
+            | Rule::SrcInterface(_, action) => *action,
+            Rule::Marked(..) => unreachable!(),
+        }
+    }
+
+    pub fn mark(&self) -> SocketMark {
+        self.options().mark
+    }
+
+    pub fn options(&self) -> RuleOptions {
+        match self {
+            Rule::Marked(_, options, _) => *options,
+            _ => RuleOptions::default(),
+        }
+    }
+
+    pub fn dns_group(&self) -> Option<&str> {
+        match self {
+            Rule::Marked(_, _, dns) => dns.as_deref(),
+            _ => None,
+        }
+    }
+}
+
 impl FromStr for Action {
     type Err = ();
 
@@ -138,7 +452,30 @@ impl FromStr for Action {
             "DIRECT" => Action::Direct,
             "PROXY" => Action::Proxy,
             "PROBE" => Action::Probe,
-            _ => panic!("Invalid action: {}", s),
+            _ => return Err(()),
+        })
+    }
+}
+
+impl FromStr for ConnectionLog {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "verbose" => ConnectionLog::Verbose,
+            "basic" => ConnectionLog::Basic,
+            "off" => ConnectionLog::Off,
+            _ => return Err(format!("invalid log {s}")),
+        })
+    }
+}
+
+impl fmt::Display for ConnectionLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionLog::Verbose => "verbose",
+            ConnectionLog::Basic => "basic",
+            ConnectionLog::Off => "off",
         })
     }
 }
@@ -149,31 +486,163 @@ impl fmt::Display for Action {
     }
 }
 
+/// The rule as it's written in config.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let action = self.action().to_string().to_uppercase();
+        match self {
+            Rule::Domain(domain, _) => write!(f, "DOMAIN,{domain},{action}"),
+            Rule::DomainSuffix(suffix, _) => write!(f, "DOMAIN-SUFFIX,{suffix},{action}"),
+            Rule::DomainKeyword(keyword, _) => write!(f, "DOMAIN-KEYWORD,{keyword},{action}"),
+            Rule::IpCidr(cidr, _) => write!(f, "IP-CIDR,{cidr},{action}"),
+            Rule::GeoIp(name, _) => write!(f, "GEOIP,{name},{action}"),
+            Rule::Cgroup(cgroup, _) => write!(f, "CGROUP,/{cgroup},{action}"),
+            Rule::SrcIpCidr(cidr, _) => write!(f, "SRC-IP-CIDR,{cidr},{action}"),
+            Rule::SrcInterface(name, _) => write!(f, "SRC-INTERFACE,{name},{action}"),
+            Rule::Match(_) => write!(f, "MATCH,{action}"),
+            Rule::Marked(rule, options, dns) => {
+                write!(f, "{rule}")?;
+                if let Some(fwmark) = options.mark.fwmark {
+                    write!(f, ",mark={fwmark:#x}")?;
+                }
+                if let Some(dscp) = options.mark.dscp {
+                    write!(f, ",dscp={dscp}")?;
+                }
+                if options.log != ConnectionLog::Verbose {
+                    write!(f, ",log={}", options.log)?;
+                }
+                if let Some(timeout) = options.connect_timeout {
+                    write!(f, ",timeout={}ms", timeout.as_millis())?;
+                }
+                if let Some(dns) = dns {
+                    write!(f, ",dns={dns}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 impl FromStr for Rule {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let segments = s.splitn(3, ',').collect::<Vec<_>>();
-        let (rule, criteria, action) = match segments.len() {
-            2 => (segments[0], "", segments[1]),
-            3 => (segments[0], segments[1], segments[2]),
-            _ => unreachable!("{}", s),
+        let mut segments = s.split(',');
+        let rule = segments.next().unwrap_or_default();
+        let criteria = match rule {
+            "MATCH" => "",
+            _ => segments.next().unwrap_or_default(),
+        };
+        let Some(action) = segments.next() else {
+            return Err(format!("invalid rule: {s}"));
         };
+        let action =
+            Action::from_str(action).map_err(|_| format!("invalid action in rule: {s}"))?;
+        let (options, dns) = parse_options(segments).map_err(|e| format!("{e} in rule: {s}"))?;
+        // Proxied connections share the sockets to the servers, only direct ones can be marked.
+        if !options.mark.is_empty() && !matches!(action, Action::Direct | Action::Probe) {
+            return Err(format!("only DIRECT and PROBE rules can mark sockets: {s}"));
+        }
+        if options.connect_timeout.is_some() && action == Action::Reject {
+            return Err(format!(
+                "REJECT rules don't connect, they can't set timeout: {s}"
+            ));
+        }
+        // Only domains are resolved, rules of ips and sources never match a query.
+        if dns.is_some()
+            && !matches!(
+                rule,
+                "DOMAIN" | "DOMAIN-SUFFIX" | "DOMAIN-KEYWORD" | "MATCH"
+            )
+        {
+            return Err(format!("only domain and MATCH rules can set dns: {s}"));
+        }
 
-        Ok(match rule {
-            "DOMAIN" => Rule::Domain(criteria.to_string(), Action::from_str(action).unwrap()),
-            "DOMAIN-SUFFIX" => {
-                Rule::DomainSuffix(criteria.to_string(), Action::from_str(action).unwrap())
+        let rule = match rule {
+            "DOMAIN" => Rule::Domain(criteria.to_string(), action),
+            "DOMAIN-SUFFIX" => Rule::DomainSuffix(criteria.to_string(), action),
+            "DOMAIN-KEYWORD" => Rule::DomainKeyword(criteria.to_string(), action),
+            "IP-CIDR" => Rule::IpCidr(parse_cidr(criteria)?, action),
+            "GEOIP" => Rule::GeoIp(criteria.to_string(), action),
+            "SRC-IP-CIDR" => Rule::SrcIpCidr(parse_cidr(criteria)?, action),
+            "SRC-INTERFACE" => Rule::SrcInterface(criteria.to_string(), action),
+            "CGROUP" => Rule::Cgroup(criteria.trim_matches('/').to_string(), action),
+            "MATCH" => Rule::Match(action),
+            _ => return Err(format!("invalid rule: {s}")),
+        };
+        Ok(if options == RuleOptions::default() && dns.is_none() {
+            rule
+        } else {
+            Rule::Marked(Box::new(rule), options, dns)
+        })
+    }
+}
+
+/// The `mark=<fwmark>`, `dscp=<code point>`, `log=verbose|basic|off` and `timeout=<10s|10ms>`
+/// options of a rule, with the dns group of `dns=<name>`.
+fn parse_options<'a>(
+    options: impl Iterator<Item = &'a str>,
+) -> Result<(RuleOptions, Option<String>), String> {
+    let mut parsed = RuleOptions::default();
+    let mut dns = None;
+    let mark = &mut parsed.mark;
+    for option in options {
+        match option.split_once('=') {
+            Some(("mark", value)) => {
+                let fwmark = match value.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => value.parse(),
+                };
+                mark.fwmark = Some(fwmark.map_err(|_| format!("invalid mark {value}"))?);
             }
-            "DOMAIN-KEYWORD" => {
-                Rule::DomainKeyword(criteria.to_string(), Action::from_str(action).unwrap())
+            Some(("dscp", value)) => {
+                mark.dscp = Some(parse_dscp(value).ok_or_else(|| format!("invalid dscp {value}"))?);
             }
-            "IP-CIDR" => Rule::IpCidr(parse_cidr(criteria)?, Action::from_str(action).unwrap()),
-            "GEOIP" => Rule::GeoIp(criteria.to_string(), Action::from_str(action).unwrap()),
-            "MATCH" => Rule::Match(Action::from_str(action).unwrap()),
-            _ => unreachable!(),
-        })
+            Some(("log", value)) => {
+                parsed.log = ConnectionLog::from_str(value)?;
+            }
+            Some(("timeout", value)) => {
+                let timeout = parse_duration(value)?;
+                if timeout.is_zero() {
+                    return Err(format!("invalid timeout {value}"));
+                }
+                parsed.connect_timeout = Some(timeout);
+            }
+            Some(("dns", value)) if !value.is_empty() => {
+                dns = Some(value.to_string());
+            }
+            _ => return Err(format!("invalid option {option}")),
+        }
     }
+    Ok((parsed, dns))
+}
+
+/// A DSCP code point by its name, `EF`, `AF11` to `AF43`, `CS0` to `CS7` or `LE`, or by its value.
+fn parse_dscp(value: &str) -> Option<u8> {
+    let value = value.to_ascii_uppercase();
+    let digit = |c: Option<char>, max: u32| c?.to_digit(10).filter(|d| *d <= max);
+    let dscp = match value.as_str() {
+        "EF" => 46,
+        "LE" => 1,
+        _ if value.starts_with("CS") && value.len() == 3 => {
+            8 * digit(value.chars().nth(2), 7)? as u8
+        }
+        _ if value.starts_with("AF") && value.len() == 4 => {
+            let class = digit(value.chars().nth(2), 4).filter(|d| *d >= 1)?;
+            let drop = digit(value.chars().nth(3), 3).filter(|d| *d >= 1)?;
+            (8 * class + 2 * drop) as u8
+        }
+        _ => value.parse().ok()?,
+    };
+    (dscp < 64).then_some(dscp)
+}
+
+/// Whether cgroup `path` is `ancestor` or below it, the root cgroup is the empty path.
+fn is_cgroup_within(path: &str, ancestor: &str) -> bool {
+    ancestor.is_empty()
+        || path
+            .strip_prefix(ancestor)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
 }
 
 fn did_geo_ip_matches_name(reader: &maxminddb::Reader<Vec<u8>>, ip: IpAddr, name: &str) -> bool {
@@ -200,3 +669,404 @@ fn did_geo_ip_matches_name(reader: &maxminddb::Reader<Vec<u8>>, ip: IpAddr, name
 //         ));
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn rules(count: usize) -> ProxyRules {
+        let mut rules: Vec<Rule> = (0..count)
+            .map(|i| Rule::DomainSuffix(format!("domain{i}.com"), Action::Proxy))
+            .collect();
+        rules.push(Rule::IpCidr(
+            parse_cidr("11.0.0.0/16").unwrap(),
+            Action::Reject,
+        ));
+        rules.push(Rule::Match(Action::Direct));
+        ProxyRules::new(rules)
+    }
+
+    #[test]
+    fn test_action_for_fake_ip() {
+        let mut rules = rules(10);
+        let ip = "11.0.0.10".parse().unwrap();
+        assert_eq!(
+            rules.action_for_fake_ip("domain3.com", ip),
+            Some(Action::Proxy)
+        );
+        assert_eq!(
+            rules.action_for_fake_ip("domain3.com", ip),
+            Some(Action::Proxy)
+        );
+        // The same IP allocated to another domain.
+        assert_eq!(
+            rules.action_for_fake_ip("example.com", ip),
+            Some(Action::Reject)
+        );
+
+        rules.prepend_rules(vec![Rule::Domain("example.com".to_string(), Action::Probe)]);
+        assert_eq!(
+            rules.action_for_fake_ip("example.com", ip),
+            Some(Action::Probe)
+        );
+    }
+
+    #[test]
+    fn test_replace() {
+        let rules = rules(10);
+        let clone = rules.clone();
+        let ip = "11.0.0.10".parse().unwrap();
+        assert_eq!(
+            clone.action_for_fake_ip("domain3.com", ip),
+            Some(Action::Proxy)
+        );
+        rules.replace(&ProxyRules::new(vec![Rule::Match(Action::Direct)]));
+        assert_eq!(
+            clone.action_for_fake_ip("domain3.com", ip),
+            Some(Action::Direct)
+        );
+        assert!(clone.additional_cidrs().is_empty());
+        rules.set_paused(true);
+        assert!(clone.is_paused());
+    }
+
+    #[test]
+    fn test_action_for_source() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("CGROUP,/user.slice/user-1000.slice/app-firefox.scope,PROXY").unwrap(),
+            Rule::from_str("CGROUP,system.slice,DIRECT").unwrap(),
+            Rule::from_str("SRC-IP-CIDR,172.17.0.2/32,REJECT").unwrap(),
+            Rule::from_str("SRC-INTERFACE,docker0,PROXY").unwrap(),
+            Rule::from_str("SRC-INTERFACE,br-*,DIRECT").unwrap(),
+            Rule::Match(Action::Proxy),
+        ]);
+        assert!(rules.has_cgroup_rules());
+        assert!(rules.has_source_rules());
+        let cgroup = |path| {
+            rules.action_for_source(&ConnectionSource {
+                cgroup: Some(path),
+                ..Default::default()
+            })
+        };
+        let firefox = "user.slice/user-1000.slice/app-firefox.scope";
+        assert_eq!(cgroup(firefox), Some(Action::Proxy));
+        assert_eq!(cgroup(&format!("{firefox}/tab")), Some(Action::Proxy));
+        assert_eq!(cgroup("system.slice/docker.service"), Some(Action::Direct));
+        assert_eq!(cgroup("system.slice2"), None);
+        assert_eq!(cgroup("user.slice"), None);
+
+        let forwarded = |ip: &str, interface| {
+            rules.action_for_source(&ConnectionSource {
+                ip: Some(ip.parse().unwrap()),
+                interface: Some(interface),
+                cgroup: None,
+            })
+        };
+        assert_eq!(forwarded("172.17.0.2", "docker0"), Some(Action::Reject));
+        assert_eq!(forwarded("172.17.0.3", "docker0"), Some(Action::Proxy));
+        assert_eq!(forwarded("172.18.0.2", "br-3f2a"), Some(Action::Direct));
+        assert_eq!(forwarded("192.168.1.2", "eth0"), None);
+
+        // Not matched by domains.
+        assert_eq!(
+            rules.action_for_domain(Some("example.com"), None),
+            Some(Action::Proxy)
+        );
+        assert!(!ProxyRules::new(vec![Rule::Match(Action::Proxy)]).has_source_rules());
+    }
+
+    #[test]
+    fn test_action_for_connection() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN,blocked.com,REJECT").unwrap(),
+            Rule::from_str("SRC-INTERFACE,docker0,PROXY").unwrap(),
+            Rule::from_str("DOMAIN,direct.com,DIRECT").unwrap(),
+            Rule::Match(Action::Probe),
+        ]);
+        let docker = ConnectionSource {
+            interface: Some("docker0"),
+            ..Default::default()
+        };
+        let ip = "11.0.0.10".parse().unwrap();
+        let action = |source: &ConnectionSource, domain| {
+            rules
+                .marked_action_for_connection(source, Some(domain), Some(ip), true)
+                .map(|(action, _, source)| (action, source))
+        };
+        // Matched in order, the earlier domain rule goes first.
+        assert_eq!(
+            action(&docker, "blocked.com"),
+            Some((Action::Reject, false))
+        );
+        rules.assign_fake_ip(ip, "direct.com");
+        assert_eq!(action(&docker, "direct.com"), Some((Action::Proxy, true)));
+        assert_eq!(
+            action(&ConnectionSource::default(), "direct.com"),
+            Some((Action::Direct, false))
+        );
+
+        // Reallocated to another domain.
+        rules.assign_fake_ip(ip, "blocked.com");
+        assert!(rules.fake_ip_actions.read().get(&ip).is_none());
+        assert_eq!(
+            action(&ConnectionSource::default(), "blocked.com"),
+            Some((Action::Reject, false))
+        );
+        rules.assign_fake_ip(ip, "blocked.com");
+        assert!(rules.fake_ip_actions.read().get(&ip).is_some());
+        assert!(rules.is_fake_ip_rejected(ip));
+        assert!(!rules.is_fake_ip_rejected("11.0.0.11".parse().unwrap()));
+
+        // The SYN of a domain rejected after a source rule isn't reset, the rule may not apply
+        // to where it comes from.
+        let ip = "11.0.0.12".parse().unwrap();
+        rules.replace(&ProxyRules::new(vec![
+            Rule::from_str("DOMAIN,other.com,REJECT").unwrap(),
+            Rule::from_str("SRC-INTERFACE,docker0,PROXY").unwrap(),
+        ]));
+        let _ = rules.action_for_fake_ip("other.com", ip);
+        assert!(rules.is_fake_ip_rejected(ip));
+        rules.replace(&ProxyRules::new(vec![
+            Rule::from_str("SRC-INTERFACE,docker0,PROXY").unwrap(),
+            Rule::from_str("DOMAIN,other.com,REJECT").unwrap(),
+        ]));
+        let _ = rules.action_for_fake_ip("other.com", ip);
+        assert!(!rules.is_fake_ip_rejected(ip));
+    }
+
+    #[test]
+    fn test_action_for_mapped_ip() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("IP-CIDR,10.0.0.0/8,PROXY").unwrap(),
+            Rule::Match(Action::Direct),
+        ]);
+        let action = |ip: &str| rules.action_for_domain(None, Some(ip.parse().unwrap()));
+        assert_eq!(action("10.1.1.1"), Some(Action::Proxy));
+        assert_eq!(action("::ffff:10.1.1.1"), Some(Action::Proxy));
+        assert_eq!(action("2001:db8::a01:101"), Some(Action::Direct));
+    }
+
+    #[test]
+    fn test_display_rule() {
+        for rule in [
+            "DOMAIN-SUFFIX,ad.com,REJECT",
+            "IP-CIDR,10.0.0.0/8,PROXY",
+            "CGROUP,/user.slice,DIRECT",
+            "MATCH,DIRECT",
+            "DOMAIN,zoom.us,DIRECT,mark=0x10,dscp=46",
+            "DOMAIN-SUFFIX,apple.com,PROXY,log=off",
+            "DOMAIN-SUFFIX,google.com,PROXY,log=basic,dns=clean",
+        ] {
+            assert_eq!(Rule::from_str(rule).unwrap().to_string(), rule);
+        }
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,ad.com,REJECT").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ]);
+        assert_eq!(
+            rules
+                .rule_for_domain(Some("www.ad.com"), None)
+                .map(|rule| rule.to_string()),
+            Some("DOMAIN-SUFFIX,ad.com,REJECT".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_rule() {
+        for rule in [
+            "MATCH",
+            "DOMAIN,example.com,DIRECTLY",
+            "DOMAINS,example.com,DIRECT",
+            "IP-CIDR,10.0.0.0,DIRECT",
+            "IP-CIDR,10.0.0.256/8,DIRECT",
+            "IP-CIDR,10.0.0.0/33,DIRECT",
+        ] {
+            assert!(Rule::from_str(rule).is_err(), "{rule}");
+        }
+        assert!(Rule::from_str("IP-CIDR,10.0.0.0/8,DIRECT").is_ok());
+    }
+
+    #[test]
+    fn test_parse_marked_rule() {
+        let dscp = |dscp| SocketMark {
+            fwmark: None,
+            dscp: Some(dscp),
+        };
+        let marked = |mark| RuleOptions {
+            mark,
+            ..Default::default()
+        };
+        let rule = Rule::from_str("DOMAIN-SUFFIX,zoom.us,DIRECT,dscp=EF").unwrap();
+        assert_eq!(
+            rule,
+            Rule::Marked(
+                Box::new(Rule::DomainSuffix("zoom.us".to_string(), Action::Direct)),
+                marked(dscp(46)),
+                None
+            )
+        );
+        assert_eq!(
+            Rule::from_str("MATCH,PROBE,mark=0x10,dscp=af41")
+                .unwrap()
+                .mark(),
+            SocketMark {
+                fwmark: Some(16),
+                dscp: Some(34),
+            }
+        );
+        assert_eq!(
+            Rule::from_str("MATCH,DIRECT,dscp=CS1").unwrap().mark(),
+            dscp(8)
+        );
+        assert_eq!(
+            Rule::from_str("MATCH,DIRECT,dscp=10").unwrap().mark(),
+            dscp(10)
+        );
+        for rule in [
+            "MATCH,DIRECT,dscp=64",
+            "MATCH,DIRECT,dscp=AF44",
+            "MATCH,DIRECT,dscp=CS8",
+            "MATCH,DIRECT,mark=-1",
+            "MATCH,DIRECT,tos=1",
+            "MATCH,PROXY,dscp=EF",
+            "MATCH,REJECT,mark=1",
+        ] {
+            assert!(Rule::from_str(rule).is_err(), "{rule}");
+        }
+
+        let rules = ProxyRules::new(vec![
+            rule,
+            Rule::from_str("SRC-IP-CIDR,172.17.0.0/16,DIRECT,mark=1").unwrap(),
+            Rule::Match(Action::Direct),
+        ]);
+        assert_eq!(
+            rules.marked_action_for_domain(Some("www.zoom.us"), None),
+            Some((Action::Direct, marked(dscp(46))))
+        );
+        assert_eq!(
+            rules.marked_action_for_fake_ip("example.com", "11.0.0.1".parse().unwrap()),
+            Some((Action::Direct, RuleOptions::default()))
+        );
+        let source = ConnectionSource {
+            ip: Some("172.17.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rules
+                .marked_action_for_source(&source)
+                .unwrap()
+                .1
+                .mark
+                .fwmark,
+            Some(1)
+        );
+        assert!(rules.has_source_rules());
+    }
+
+    #[test]
+    fn test_parse_logged_rule() {
+        let rule = Rule::from_str("DOMAIN-SUFFIX,telemetry.com,PROXY,log=off").unwrap();
+        assert_eq!(rule.action(), Action::Proxy);
+        assert_eq!(rule.options().log, ConnectionLog::Off);
+        assert!(rule.mark().is_empty());
+        let rule = Rule::from_str("MATCH,DIRECT,dscp=EF,log=basic").unwrap();
+        assert_eq!(rule.options().log, ConnectionLog::Basic);
+        assert_eq!(rule.mark().dscp, Some(46));
+        // The default isn't wrapped in options.
+        assert_eq!(
+            Rule::from_str("MATCH,REJECT,log=verbose").unwrap(),
+            Rule::Match(Action::Reject)
+        );
+        assert!(Rule::from_str("MATCH,DIRECT,log=all").is_err());
+
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-KEYWORD,telemetry,PROXY,log=off").unwrap(),
+            Rule::Match(Action::Direct),
+        ]);
+        let ip = "11.0.0.2".parse().unwrap();
+        for _ in 0..2 {
+            let (action, options) = rules
+                .marked_action_for_fake_ip("telemetry.example.com", ip)
+                .unwrap();
+            assert_eq!(action, Action::Proxy);
+            assert_eq!(options.log, ConnectionLog::Off);
+        }
+    }
+
+    #[test]
+    fn test_parse_timeout_rule() {
+        let rule = Rule::from_str("DOMAIN-SUFFIX,slow.example.com,PROXY,timeout=20s").unwrap();
+        assert_eq!(
+            rule.options().connect_timeout,
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            rule.to_string(),
+            "DOMAIN-SUFFIX,slow.example.com,PROXY,timeout=20000ms"
+        );
+        assert_eq!(Rule::from_str(&rule.to_string()).unwrap(), rule);
+        for rule in [
+            "MATCH,DIRECT,timeout=0s",
+            "MATCH,DIRECT,timeout=1m",
+            "MATCH,DIRECT,timeout=s",
+            "MATCH,REJECT,timeout=5s",
+        ] {
+            assert!(Rule::from_str(rule).is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_dns_group_rule() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,google.com,PROXY,dns=clean").unwrap(),
+            Rule::from_str("DOMAIN-KEYWORD,google,DIRECT").unwrap(),
+            Rule::from_str("MATCH,DIRECT,dns=fast").unwrap(),
+        ]);
+        assert_eq!(
+            rules.dns_group_for_domain("www.google.com"),
+            Some("clean".to_string())
+        );
+        assert_eq!(rules.dns_group_for_domain("google.cn"), None);
+        assert_eq!(
+            rules.dns_group_for_domain("example.com"),
+            Some("fast".to_string())
+        );
+        assert_eq!(rules.dns_groups(), ["clean", "fast"]);
+        assert_eq!(
+            rules.action_for_domain(Some("www.google.com"), None),
+            Some(Action::Proxy)
+        );
+        for rule in [
+            "IP-CIDR,10.0.0.0/8,DIRECT,dns=fast",
+            "SRC-IP-CIDR,172.17.0.0/16,DIRECT,dns=fast",
+            "MATCH,DIRECT,dns=",
+        ] {
+            assert!(Rule::from_str(rule).is_err(), "{rule}");
+        }
+    }
+
+    /// Run with `cargo test -p config --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_action_for_fake_ip_50k_rules() {
+        let rules = rules(50_000);
+        let ip = "11.0.0.10".parse().unwrap();
+        let rounds = 100;
+
+        let start = Instant::now();
+        for _ in 0..rounds {
+            rules.action_for_domain(Some("example.com"), Some(IpAddr::V4(ip)));
+        }
+        let uncached = start.elapsed() / rounds;
+
+        rules.action_for_fake_ip("example.com", ip);
+        let start = Instant::now();
+        for _ in 0..rounds {
+            rules.action_for_fake_ip("example.com", ip);
+        }
+        let cached = start.elapsed() / rounds;
+        println!("match 50k rules: {uncached:?}, cached by fake ip: {cached:?}");
+        assert!(cached < uncached);
+    }
+}
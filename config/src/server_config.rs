@@ -11,6 +11,11 @@ use tcp_connection::ObfsMode;
 use tracing::error;
 use url::Url;
 
+/// Prefix of encrypted secrets in config, eg. `password: enc:BASE64`.
+pub const ENCRYPTED_SECRET_PREFIX: &str = "enc:";
+/// Prefix of plain text secrets starting with `enc:`, eg. `password: enc::secret` is `enc:secret`.
+pub const ESCAPED_SECRET_PREFIX: &str = "enc::";
+
 /// Server address
 #[derive(Clone, Debug, Deserialize)]
 #[serde(untagged)]
@@ -43,6 +48,31 @@ pub struct ServerConfig {
     #[serde(with = "cipher_type")]
     method: Option<CipherType>,
     obfs: Option<Obfs>,
+    /// Send a PROXY protocol v2 header with the address of the client first, for servers behind
+    /// a relay that wants it. Shadowsocks servers without obfs only.
+    #[serde(default)]
+    proxy_protocol: bool,
+    /// TLS server name sent to an `Https` server instead of the domain of `addr`, eg. a domain
+    /// of the CDN in front of it for domain fronting. The certificate is checked against it.
+    #[serde(default)]
+    sni: Option<String>,
+    /// Host header of the CONNECT requests to an `Https` server instead of the target, eg. the
+    /// domain the CDN routes to the server.
+    #[serde(default)]
+    host_header: Option<String>,
+    /// Offer the cipher suites of the TLS handshake with an `Https` server in the order of a
+    /// browser. Only the order changes, the handshake still looks like rustls otherwise.
+    #[serde(default)]
+    tls_cipher_order: Option<TlsCipherOrder>,
+}
+
+/// Browser whose order of TLS cipher suites the handshake with an `Https` server follows.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsCipherOrder {
+    Chrome,
+    Firefox,
+    Safari,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
@@ -73,7 +103,11 @@ mod cipher_type {
         let s: Option<String> = Option::deserialize(deserializer)?;
         match s {
             None => Ok(None),
-            Some(s) => Ok(Some(CipherType::from_str(&s).map_err(Error::custom)?)),
+            Some(s) => Ok(Some(CipherType::from_str(&s).map_err(|_| {
+                Error::custom(format!(
+                    "unsupported method `{s}`, eg. chacha20-ietf-poly1305 or aes-256-gcm"
+                ))
+            })?)),
         }
     }
 }
@@ -112,6 +146,10 @@ impl ServerConfig {
             password,
             method,
             obfs,
+            proxy_protocol: false,
+            sni: None,
+            host_header: None,
+            tls_cipher_order: None,
         }
     }
 
@@ -152,6 +190,53 @@ impl ServerConfig {
         self.obfs.as_ref()
     }
 
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    pub fn host_header(&self) -> Option<&str> {
+        self.host_header.as_deref()
+    }
+
+    pub fn tls_cipher_order(&self) -> Option<TlsCipherOrder> {
+        self.tls_cipher_order
+    }
+
+    /// Whether username or password is encrypted.
+    pub fn has_encrypted_secrets(&self) -> bool {
+        [&self.username, &self.password]
+            .into_iter()
+            .flatten()
+            .any(|s| {
+                s.starts_with(ENCRYPTED_SECRET_PREFIX) && !s.starts_with(ESCAPED_SECRET_PREFIX)
+            })
+    }
+
+    /// Replace encrypted username and password with the plain text returned by `decrypt`, and
+    /// unescape the ones starting with `enc::`.
+    ///
+    /// `decrypt` receives the value without the `enc:` prefix.
+    pub fn decrypt_secrets<E>(
+        &mut self,
+        decrypt: &impl Fn(&str) -> Result<String, E>,
+    ) -> Result<(), E> {
+        for secret in [&mut self.username, &mut self.password]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(plain) = secret.strip_prefix(ESCAPED_SECRET_PREFIX) {
+                *secret = format!("{ENCRYPTED_SECRET_PREFIX}{plain}");
+            } else if let Some(encrypted) = secret.strip_prefix(ENCRYPTED_SECRET_PREFIX) {
+                *secret = decrypt(encrypted)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn from_url(encoded: &str) -> Result<ServerConfig, UrlParseError> {
         let parsed = Url::parse(encoded).map_err(UrlParseError::from)?;
 
@@ -375,4 +460,38 @@ mod tests {
         assert_eq!(server_config.method(), Some(CipherType::Aes256Gcm));
         Ok(())
     }
+
+    #[test]
+    fn test_decrypt_secrets() {
+        let mut server_config = ServerConfig::new(
+            "proxy".to_string(),
+            Address::from_str("127.0.0.1:1080").unwrap(),
+            ServerProtocol::Https,
+            Some("user".to_string()),
+            Some("enc:c2VjcmV0".to_string()),
+            None,
+            None,
+        );
+        assert!(server_config.has_encrypted_secrets());
+        server_config
+            .decrypt_secrets(&|s: &str| Ok::<_, ()>(format!("decrypted-{s}")))
+            .unwrap();
+        assert!(!server_config.has_encrypted_secrets());
+        assert_eq!(server_config.username(), Some("user"));
+        assert_eq!(server_config.password(), Some("decrypted-c2VjcmV0"));
+
+        let mut server_config = ServerConfig::new(
+            "proxy".to_string(),
+            Address::from_str("127.0.0.1:1080").unwrap(),
+            ServerProtocol::Https,
+            Some("enc::user".to_string()),
+            Some("plain".to_string()),
+            None,
+            None,
+        );
+        assert!(!server_config.has_encrypted_secrets());
+        server_config.decrypt_secrets(&|_: &str| Err(())).unwrap();
+        assert_eq!(server_config.username(), Some("enc:user"));
+        assert_eq!(server_config.password(), Some("plain"));
+    }
 }
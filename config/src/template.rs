@@ -0,0 +1,144 @@
+//! Variable substitution in config values.
+//!
+//! ```yaml
+//! variables:
+//!   region: hk
+//!   port: 1080
+//! servers:
+//!   - name: "{{ region }}-server"
+//!     addr: "{{ region }}.example.com:{{ port }}"
+//!     password: "{{ env.SS_PASSWORD }}"
+//! ```
+//!
+//! `{{ name }}` is replaced with the variable from `variables` and `{{ env.NAME }}` with the
+//! environment variable `NAME`. A value consisting of a single `{{ name }}` keeps the type of the
+//! variable, so numbers and lists can be substituted too. Mapping keys are not substituted.
+use serde_yaml::{Mapping, Value};
+use std::io::{self, ErrorKind};
+
+const VARIABLES_KEY: &str = "variables";
+const ENV_PREFIX: &str = "env.";
+
+/// Remove `variables` from `value` and substitute them in all the other values.
+pub(crate) fn render(mut value: Value) -> io::Result<Value> {
+    let variables = match value.as_mapping_mut() {
+        Some(mapping) => match mapping.remove(VARIABLES_KEY) {
+            Some(Value::Mapping(variables)) => variables,
+            Some(Value::Null) | None => Mapping::new(),
+            Some(v) => return Err(invalid_data(format!("invalid variables: {v:?}"))),
+        },
+        None => Mapping::new(),
+    };
+    substitute(value, &variables)
+}
+
+fn substitute(value: Value, variables: &Mapping) -> io::Result<Value> {
+    Ok(match value {
+        Value::String(s) => substitute_str(s, variables)?,
+        Value::Sequence(seq) => Value::Sequence(
+            seq.into_iter()
+                .map(|v| substitute(v, variables))
+                .collect::<io::Result<_>>()?,
+        ),
+        Value::Mapping(mapping) => Value::Mapping(
+            mapping
+                .into_iter()
+                .map(|(k, v)| Ok((k, substitute(v, variables)?)))
+                .collect::<io::Result<_>>()?,
+        ),
+        v => v,
+    })
+}
+
+fn substitute_str(s: String, variables: &Mapping) -> io::Result<Value> {
+    if !s.contains("{{") {
+        return Ok(Value::String(s));
+    }
+    let mut rendered = String::with_capacity(s.len());
+    let mut rest = s.as_str();
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            return Err(invalid_data(format!("unclosed `{{{{` in `{s}`")));
+        };
+        let name = rest[start + 2..start + len].trim();
+        let value = lookup(name, variables)?;
+        // Keep the type of the variable if the whole string is the placeholder.
+        if rest.len() == s.len() && start == 0 && len + 2 == s.len() {
+            return Ok(value);
+        }
+        rendered.push_str(&rest[..start]);
+        match value {
+            Value::String(v) => rendered.push_str(&v),
+            Value::Number(v) => rendered.push_str(&v.to_string()),
+            Value::Bool(v) => rendered.push_str(&v.to_string()),
+            v => {
+                return Err(invalid_data(format!(
+                    "variable `{name}` can not be used in `{s}`: {v:?}"
+                )))
+            }
+        }
+        rest = &rest[start + len + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(Value::String(rendered))
+}
+
+fn lookup(name: &str, variables: &Mapping) -> io::Result<Value> {
+    if let Some(env) = name.strip_prefix(ENV_PREFIX) {
+        return std::env::var(env)
+            .map(Value::String)
+            .map_err(|e| invalid_data(format!("environment variable `{env}`: {e}")));
+    }
+    variables
+        .get(name)
+        .cloned()
+        .ok_or_else(|| invalid_data(format!("undefined variable `{name}`")))
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        std::env::set_var("SEEKER_TEST_PASSWORD", "secret");
+        let value: Value = serde_yaml::from_str(
+            r#"
+variables:
+  region: hk
+  port: 1080
+  dns:
+    - 1.1.1.1:53
+dns_servers: "{{ dns }}"
+servers:
+  - name: "{{region}}-server"
+    addr: "{{ region }}.example.com:{{ port }}"
+    password: "{{ env.SEEKER_TEST_PASSWORD }}"
+    port: "{{ port }}"
+"#,
+        )
+        .unwrap();
+        let expected: Value = serde_yaml::from_str(
+            r#"
+dns_servers:
+  - 1.1.1.1:53
+servers:
+  - name: hk-server
+    addr: hk.example.com:1080
+    password: secret
+    port: 1080
+"#,
+        )
+        .unwrap();
+        assert_eq!(render(value).unwrap(), expected);
+
+        let undefined: Value = serde_yaml::from_str("name: '{{ missing }}'").unwrap();
+        assert!(render(undefined).is_err());
+        let unclosed: Value = serde_yaml::from_str("name: '{{ region'").unwrap();
+        assert!(render(unclosed).is_err());
+    }
+}
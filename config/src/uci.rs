@@ -0,0 +1,244 @@
+//! Basic settings from an OpenWrt UCI file, so seeker can be configured like other router
+//! packages.
+//!
+//! ```yaml
+//! uci: /etc/config/seeker
+//! ```
+//!
+//! The first `seeker` section of the file overrides the yaml config:
+//!
+//! ```text
+//! config seeker 'main'
+//!     option tun_name 'utun4'
+//!     option gateway_mode '1'
+//!     list dns_server '223.5.5.5:53'
+//!     list server 'ss://aes-256-gcm:password@example.com:8388#hk'
+//!     list rule 'DOMAIN-SUFFIX,cn,DIRECT'
+//! ```
+//!
+//! Lists replace the yaml `dns_servers`, `servers` and `rules` instead of being merged.
+use crate::ServerConfig;
+use serde_yaml::{Mapping, Value};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+const UCI_KEY: &str = "uci";
+const SECTION_TYPE: &str = "seeker";
+
+const STRING_OPTIONS: &[&str] = &[
+    "tun_name",
+    "tun_ip",
+    "tun_cidr",
+    "dns_listen",
+    "dns_start_ip",
+];
+const BOOL_OPTIONS: &[&str] = &["gateway_mode", "redir_mode", "tun_bypass_direct"];
+/// UCI list names and the config keys they set.
+const LISTS: &[(&str, &str)] = &[
+    ("dns_server", "dns_servers"),
+    ("server", "servers"),
+    ("rule", "rules"),
+];
+
+/// Remove `uci` from `value` and apply the settings of the UCI file it points to, relative to
+/// `base_dir`.
+pub(crate) fn apply(value: &mut Value, base_dir: &Path) -> io::Result<()> {
+    let Some(mapping) = value.as_mapping_mut() else {
+        return Ok(());
+    };
+    let path = match mapping.remove(UCI_KEY) {
+        None | Some(Value::Null) => return Ok(()),
+        Some(Value::String(path)) => base_dir.join(path),
+        Some(v) => return Err(invalid_data(format!("invalid uci: {v:?}"))),
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("read {}: {e}", path.display())))?;
+    for (key, value) in parse(&text)? {
+        mapping.insert(key, value);
+    }
+    Ok(())
+}
+
+/// Config keys and values set by the first `seeker` section of `text`.
+fn parse(text: &str) -> io::Result<Mapping> {
+    let mut settings = Mapping::new();
+    let mut lists: Vec<(&str, Vec<Value>)> = vec![];
+    let mut in_section = false;
+    let mut seen_section = false;
+    for (n, line) in text.lines().enumerate() {
+        let words = split_words(line).map_err(|e| invalid_data(format!("line {}: {e}", n + 1)))?;
+        match words.as_slice() {
+            [] => {}
+            [config, ty, ..] if config == "config" => {
+                in_section = ty == SECTION_TYPE && !seen_section;
+                seen_section |= in_section;
+            }
+            _ if !in_section => {}
+            [option, name, value] if option == "option" => {
+                let value = if STRING_OPTIONS.contains(&name.as_str()) {
+                    Value::String(value.clone())
+                } else if BOOL_OPTIONS.contains(&name.as_str()) {
+                    Value::Bool(parse_bool(value).ok_or_else(|| {
+                        invalid_data(format!("line {}: invalid boolean {value}", n + 1))
+                    })?)
+                } else {
+                    return Err(invalid_data(format!(
+                        "line {}: unsupported option {name}",
+                        n + 1
+                    )));
+                };
+                settings.insert(Value::String(name.clone()), value);
+            }
+            [list, name, value] if list == "list" => {
+                let Some((_, key)) = LISTS.iter().find(|(list, _)| list == name) else {
+                    return Err(invalid_data(format!(
+                        "line {}: unsupported list {name}",
+                        n + 1
+                    )));
+                };
+                let value = if *key == "servers" {
+                    server_value(value).map_err(|e| invalid_data(format!("line {}: {e}", n + 1)))?
+                } else {
+                    Value::String(value.clone())
+                };
+                match lists.iter_mut().find(|(k, _)| k == key) {
+                    Some((_, values)) => values.push(value),
+                    None => lists.push((key, vec![value])),
+                }
+            }
+            _ => return Err(invalid_data(format!("line {}: invalid line", n + 1))),
+        }
+    }
+    for (key, values) in lists {
+        settings.insert(Value::String(key.to_string()), Value::Sequence(values));
+    }
+    Ok(settings)
+}
+
+/// Split a line into words, unquoting `'` and `"` quoted ones. Comments are dropped.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => word.push(ch),
+                        None => return Err("unterminated quote".to_string()),
+                    }
+                }
+                words.push(word);
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() {
+                        break;
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+                words.push(word);
+            }
+        }
+    }
+    Ok(words)
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "1" | "on" | "true" | "yes" | "enabled" => Some(true),
+        "0" | "off" | "false" | "no" | "disabled" => Some(false),
+        _ => None,
+    }
+}
+
+/// The yaml of a server given by URI, eg. `ss://method:password@host:port#name`.
+fn server_value(uri: &str) -> Result<Value, String> {
+    let server = ServerConfig::from_url(uri).map_err(|e| format!("invalid server {uri}: {e:?}"))?;
+    let mut mapping = Mapping::new();
+    let mut insert = |key: &str, value: String| {
+        mapping.insert(Value::String(key.to_string()), Value::String(value));
+    };
+    insert("name", server.name().to_string());
+    insert("addr", server.addr().to_string());
+    insert("protocol", format!("{:?}", server.protocol()));
+    if let Some(username) = server.username() {
+        insert("username", username.to_string());
+    }
+    if let Some(password) = server.password() {
+        insert("password", password.to_string());
+    }
+    if let Some(method) = server.method() {
+        insert("method", method.to_string());
+    }
+    if let Some(obfs) = server.obfs() {
+        let mut obfs_mapping = Mapping::new();
+        obfs_mapping.insert(
+            Value::String("mode".to_string()),
+            Value::String(format!("{:?}", obfs.mode)),
+        );
+        obfs_mapping.insert(
+            Value::String("host".to_string()),
+            Value::String(obfs.host.clone()),
+        );
+        mapping.insert(
+            Value::String("obfs".to_string()),
+            Value::Mapping(obfs_mapping),
+        );
+    }
+    Ok(Value::Mapping(mapping))
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let settings = parse(
+            r#"
+# seeker settings
+config seeker 'main'
+	option tun_name 'utun9'
+	option gateway_mode '1'
+	list dns_server '223.5.5.5:53'
+	list dns_server "114.114.114.114:53"
+	list rule 'DOMAIN-SUFFIX,cn,DIRECT'
+	list server 'ss://aes-256-gcm:password@example.com:8388#hk'
+
+config seeker 'other'
+	option tun_name 'utun10'
+"#,
+        )
+        .unwrap();
+        let get = |key: &str| settings.get(&Value::String(key.to_string())).unwrap();
+        assert_eq!(get("tun_name"), &Value::String("utun9".to_string()));
+        assert_eq!(get("gateway_mode"), &Value::Bool(true));
+        assert_eq!(get("dns_servers").as_sequence().unwrap().len(), 2);
+        assert_eq!(get("rules").as_sequence().unwrap().len(), 1);
+        let server = &get("servers").as_sequence().unwrap()[0];
+        assert_eq!(server["name"], Value::String("hk".to_string()));
+        assert_eq!(server["protocol"], Value::String("Shadowsocks".to_string()));
+
+        assert!(parse("config seeker\n\toption unknown '1'").is_err());
+        assert!(parse("config seeker\n\toption gateway_mode 'maybe'").is_err());
+        assert!(parse("config seeker\n\toption tun_name 'utun4").is_err());
+        // Other sections are ignored.
+        assert!(parse("config other\n\toption unknown '1'")
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,620 @@
+//! Checks run when loading config, so mistakes are reported before touching the system.
+use crate::rule::Action;
+use crate::{
+    Address, Config, DnsRecordConfig, DnsRecordData, DnsServerAddr, HookEvent, ServerProtocol,
+};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Max length of interface names on linux, excluding the trailing nul.
+const MAX_TUN_NAME_LEN: usize = 15;
+
+impl Config {
+    /// Validate listen addresses and TUN settings.
+    pub fn validate(&self) -> io::Result<()> {
+        let Ok(dns_listen) = self.dns_listen.parse::<SocketAddr>() else {
+            return Err(invalid_config(format!(
+                "invalid dns_listen `{}`, expect ip:port such as 127.0.0.1:53, \
+                 or 0.0.0.0:53 to serve LAN clients",
+                self.dns_listen
+            )));
+        };
+        if self.gateway_mode && dns_listen.ip().is_loopback() {
+            return Err(invalid_config(format!(
+                "dns_listen {dns_listen} is not accessible from LAN in gateway_mode, \
+                 listen on 0.0.0.0:{} instead",
+                dns_listen.port()
+            )));
+        }
+        self.validate_tun_name()?;
+        if self.worker_threads == Some(0) {
+            return Err(invalid_config("worker_threads must be at least 1"));
+        }
+        if self.tun_queues == Some(0) {
+            return Err(invalid_config("tun_queues must be at least 1"));
+        }
+        if self.flow_control.tun_recv_buffer == Some(0)
+            || self.flow_control.notsent_lowat == Some(0)
+        {
+            return Err(invalid_config(
+                "flow_control.tun_recv_buffer and flow_control.notsent_lowat must be positive",
+            ));
+        }
+        if self.admission.connections_per_second == Some(0) || self.admission.burst == Some(0) {
+            return Err(invalid_config(
+                "admission.connections_per_second and admission.burst must be positive",
+            ));
+        }
+        if self.dns_rate_limit.queries_per_second == Some(0) || self.dns_rate_limit.burst == Some(0)
+        {
+            return Err(invalid_config(
+                "dns_rate_limit.queries_per_second and dns_rate_limit.burst must be positive",
+            ));
+        }
+        if let Some(run_as) = &self.run_as {
+            if run_as.user.is_empty() {
+                return Err(invalid_config("run_as.user must not be empty"));
+            }
+            if self.redir_mode {
+                return Err(invalid_config(
+                    "run_as is not supported in redir_mode, iptables rules need root to clean up",
+                ));
+            }
+        }
+        if !cfg!(target_os = "linux") && self.sandbox {
+            return Err(invalid_config("sandbox is only supported on linux"));
+        }
+        if !cfg!(target_os = "linux") && self.rules.has_cgroup_rules() {
+            return Err(invalid_config("CGROUP rules are only supported on linux"));
+        }
+        if self.redir_mode && self.rules.has_cgroup_rules() {
+            return Err(invalid_config(
+                "CGROUP rules are not supported in redir_mode, the source of connections is lost",
+            ));
+        }
+        if self.tun_cidr.prefix_len() > 30 {
+            return Err(invalid_config(format!(
+                "tun_cidr {} is too small, use a prefix length of at most 30",
+                self.tun_cidr
+            )));
+        }
+        if !self.tun_cidr.contains_addr(&self.tun_ip.into()) {
+            return Err(invalid_config(format!(
+                "tun_ip {} is not in tun_cidr {}",
+                self.tun_ip, self.tun_cidr
+            )));
+        }
+        if let Some(prefix) = self.nat64_prefix {
+            if prefix.octets()[12..] != [0; 4] {
+                return Err(invalid_config(format!(
+                    "nat64_prefix {prefix} must be a /96 prefix such as 64:ff9b::"
+                )));
+            }
+        }
+        for server in &self.dns_hijack.servers {
+            let upstream = self.dns_servers.iter().any(|dns| match dns {
+                DnsServerAddr::UdpSocketAddr(addr) => addr.ip() == IpAddr::V4(*server),
+                DnsServerAddr::TcpSocketAddr(url) => url.host_str() == Some(&server.to_string()),
+            });
+            if upstream {
+                return Err(invalid_config(format!(
+                    "dns_hijack server {server} is in dns_servers, queries of seeker itself \
+                     would be hijacked"
+                )));
+            }
+        }
+        for name in self.rules.dns_groups() {
+            if !self.dns_groups.contains_key(&name) {
+                return Err(invalid_config(format!(
+                    "rules use dns group `{name}`, which isn't in dns_groups"
+                )));
+            }
+        }
+        for (name, group) in &self.dns_groups {
+            if group.servers.is_empty() {
+                return Err(invalid_config(format!("dns group `{name}` has no servers")));
+            }
+            if group.proxy && group.tcp_servers().len() != group.servers.len() {
+                return Err(invalid_config(format!(
+                    "dns group `{name}` is queried through the proxy over tcp, its servers must \
+                     be like tcp://8.8.8.8:53"
+                )));
+            }
+        }
+        for name in &self.dns_steering.groups {
+            if !self.dns_groups.contains_key(name) {
+                return Err(invalid_config(format!(
+                    "dns_steering uses dns group `{name}`, which isn't in dns_groups"
+                )));
+            }
+        }
+        for hook in &self.hooks {
+            if hook.command.is_empty() && hook.url.is_none() {
+                return Err(invalid_config(format!(
+                    "{} hook has neither a command nor a url",
+                    hook.event
+                )));
+            }
+            if hook.event == HookEvent::Usage && hook.bytes.unwrap_or_default() == 0 {
+                return Err(invalid_config("usage hook needs positive bytes"));
+            }
+            if hook.event != HookEvent::Usage && hook.bytes.is_some() {
+                return Err(invalid_config(format!(
+                    "{} hook: bytes are for usage hooks only",
+                    hook.event
+                )));
+            }
+            if hook.event != HookEvent::Connection && !hook.domains.is_empty() {
+                return Err(invalid_config(format!(
+                    "{} hook: domains are for connection hooks only",
+                    hook.event
+                )));
+            }
+        }
+        for (i, tunnel) in self.tunnels.iter().enumerate() {
+            if !matches!(tunnel.action, Action::Direct | Action::Proxy) {
+                return Err(invalid_config(format!(
+                    "tunnel {} can't {}, use DIRECT or PROXY",
+                    tunnel.listen,
+                    tunnel.action.to_string().to_uppercase()
+                )));
+            }
+            if self.tunnels[..i].iter().any(|t| t.listen == tunnel.listen) {
+                return Err(invalid_config(format!(
+                    "tunnels listen on {} more than once",
+                    tunnel.listen
+                )));
+            }
+        }
+        for server in self.servers.iter() {
+            if server.proxy_protocol()
+                && (server.protocol() != ServerProtocol::Shadowsocks || server.obfs().is_some())
+            {
+                return Err(invalid_config(format!(
+                    "server {} can't send the PROXY protocol header, only shadowsocks servers \
+                     without obfs can",
+                    server.name()
+                )));
+            }
+            if (server.sni().is_some()
+                || server.host_header().is_some()
+                || server.tls_cipher_order().is_some())
+                && server.protocol() != ServerProtocol::Https
+            {
+                return Err(invalid_config(format!(
+                    "server {} can't set sni, host_header or tls_cipher_order, only https servers \
+                     can",
+                    server.name()
+                )));
+            }
+        }
+        for chaos in &self.chaos {
+            if !(0.0..=1.0).contains(&chaos.loss) {
+                return Err(invalid_config(format!(
+                    "chaos loss {} must be from 0 to 1",
+                    chaos.loss
+                )));
+            }
+            if chaos.jitter > chaos.latency {
+                return Err(invalid_config(
+                    "chaos jitter must not be larger than latency",
+                ));
+            }
+            if matches!(chaos.action, Some(Action::Reject | Action::Probe)) {
+                return Err(invalid_config(
+                    "chaos action must be DIRECT or PROXY, PROBE connections end up as one of them",
+                ));
+            }
+        }
+        for record in &self.dns_records {
+            if record.name.trim_end_matches('.').is_empty() {
+                return Err(invalid_config("dns_records name must not be empty"));
+            }
+            let same_name = |other: &DnsRecordConfig| {
+                other
+                    .name
+                    .trim_end_matches('.')
+                    .eq_ignore_ascii_case(record.name.trim_end_matches('.'))
+            };
+            if matches!(record.data, DnsRecordData::Cname { .. })
+                && self.dns_records.iter().filter(|r| same_name(r)).count() > 1
+            {
+                return Err(invalid_config(format!(
+                    "dns_records {} has a CNAME and other records, a CNAME must be the only record \
+                     of its name",
+                    record.name
+                )));
+            }
+        }
+        let udp_buffer_size = self.udp_inbound().buffer_size;
+        let min_udp_buffer_size = self.tun_mtu.unwrap_or(1500) as usize;
+        if udp_buffer_size < min_udp_buffer_size {
+            return Err(invalid_config(format!(
+                "udp buffer size {udp_buffer_size} is smaller than the MTU \
+                 {min_udp_buffer_size}, datagrams would be truncated"
+            )));
+        }
+        self.validate_fake_ip_range()
+    }
+
+    /// Fake IPs are allocated from `dns_start_ip` to the end of `tun_cidr`, and all traffic to
+    /// `tun_cidr` goes into the TUN device, so upstream addresses in it would loop forever.
+    fn validate_fake_ip_range(&self) -> io::Result<()> {
+        let in_tun_cidr = |ip: IpAddr| match ip {
+            IpAddr::V4(ip) => self.tun_cidr.contains_addr(&ip.into()),
+            IpAddr::V6(_) => false,
+        };
+        let mask = u32::MAX
+            .checked_shl(32 - self.tun_cidr.prefix_len() as u32)
+            .unwrap_or(0);
+        let network = u32::from(Ipv4Addr::from(self.tun_cidr.address().0)) & mask;
+        let broadcast = network | !mask;
+        let dns_start_ip = u32::from(self.dns_start_ip);
+        if !in_tun_cidr(self.dns_start_ip.into())
+            || dns_start_ip == network
+            || dns_start_ip == broadcast
+        {
+            return Err(invalid_config(format!(
+                "dns_start_ip {} must be a host address in tun_cidr {}",
+                self.dns_start_ip, self.tun_cidr
+            )));
+        }
+        if dns_start_ip <= u32::from(self.tun_ip) {
+            return Err(invalid_config(format!(
+                "dns_start_ip {} must be greater than tun_ip {}, \
+                 otherwise fake IPs would conflict with tun_ip",
+                self.dns_start_ip, self.tun_ip
+            )));
+        }
+        for server in self.servers.iter() {
+            if let Address::SocketAddress(addr) = server.addr() {
+                if in_tun_cidr(addr.ip()) {
+                    return Err(invalid_config(format!(
+                        "server {} {addr} is in tun_cidr {}, which would cause a routing loop, \
+                         change tun_cidr to an unused subnet",
+                        server.name(),
+                        self.tun_cidr
+                    )));
+                }
+            }
+        }
+        for dns in &self.dns_servers {
+            if let DnsServerAddr::UdpSocketAddr(addr) = dns {
+                if in_tun_cidr(addr.ip()) {
+                    return Err(invalid_config(format!(
+                        "dns server {addr} is in tun_cidr {}, which would cause a routing loop, \
+                         change tun_cidr to an unused subnet",
+                        self.tun_cidr
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_tun_name(&self) -> io::Result<()> {
+        let name = self.tun_name.as_str();
+        if name.is_empty() {
+            return Err(invalid_config("tun_name can not be empty"));
+        }
+        if name.len() > MAX_TUN_NAME_LEN {
+            return Err(invalid_config(format!(
+                "tun_name `{name}` is too long, at most {MAX_TUN_NAME_LEN} characters"
+            )));
+        }
+        if cfg!(target_os = "macos") {
+            let valid = name
+                .strip_prefix("utun")
+                .map_or(false, |idx| idx.chars().all(|c| c.is_ascii_digit()));
+            if !valid {
+                return Err(invalid_config(format!(
+                    "tun_name `{name}` is invalid, it must be utun followed by a number, eg. utun4"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_config(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::ProxyRules;
+    use crate::{
+        ChaosConfig, DnsGroupConfig, HookConfig, RunAsConfig, ServerConfig, TlsCipherOrder,
+        TunnelConfig,
+    };
+    use std::time::Duration;
+
+    fn config() -> Config {
+        serde_yaml::from_str(
+            r#"
+dns_start_ip: 11.0.0.10
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+ping_urls: []
+max_connect_errors: 2
+servers: []
+rules: []
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let conf = config();
+        assert!(conf.validate().is_ok());
+
+        let mut conf = config();
+        conf.dns_listen = "0.0.0.0".to_string();
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.dns_listen = "127.0.0.1:53".to_string();
+        conf.gateway_mode = true;
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.tun_name = "utun12345678901234".to_string();
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.tun_ip = "10.0.0.1".parse().unwrap();
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.nat64_prefix = Some("64:ff9b::".parse().unwrap());
+        assert!(conf.validate().is_ok());
+        conf.nat64_prefix = Some("64:ff9b::1".parse().unwrap());
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.udp_buffer_size = 1024;
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.admission.connections_per_second = Some(0);
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.dns_rate_limit.burst = Some(0);
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.run_as = Some(RunAsConfig {
+            user: "nobody".to_string(),
+            group: None,
+        });
+        assert!(conf.validate().is_ok());
+        conf.redir_mode = true;
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.dns_hijack.servers = vec!["8.8.8.8".parse().unwrap()];
+        assert!(conf.validate().is_ok());
+        conf.dns_servers = vec![DnsServerAddr::UdpSocketAddr("8.8.8.8:53".parse().unwrap())];
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.rules = ProxyRules::new(vec!["MATCH,DIRECT,dns=clean".parse().unwrap()]);
+        assert!(conf.validate().is_err());
+        let tcp = |url: &str| DnsServerAddr::TcpSocketAddr(url.parse().unwrap());
+        let mut group = DnsGroupConfig {
+            servers: vec![
+                tcp("tcp://8.8.8.8:53"),
+                tcp("tcp://[2001:4860:4860::8888]:53"),
+            ],
+            proxy: true,
+        };
+        conf.dns_groups.insert("clean".to_string(), group.clone());
+        assert!(conf.validate().is_ok());
+        group.servers.push(tcp("tcp://dns.google:53"));
+        conf.dns_groups.insert("clean".to_string(), group.clone());
+        assert!(conf.validate().is_err());
+        group.proxy = false;
+        conf.dns_groups.insert("clean".to_string(), group);
+        assert!(conf.validate().is_ok());
+        conf.dns_steering.groups = vec!["clean".to_string(), "fast".to_string()];
+        assert!(conf.validate().is_err());
+        conf.dns_steering.groups.pop();
+        assert!(conf.validate().is_ok());
+
+        let mut conf = config();
+        let mut hook = HookConfig {
+            event: HookEvent::Usage,
+            domains: vec![],
+            bytes: Some(100 << 30),
+            command: vec![],
+            url: Some("http://127.0.0.1:8123/api/webhook/seeker".to_string()),
+            body: None,
+        };
+        conf.hooks = vec![hook.clone()];
+        assert!(conf.validate().is_ok());
+        hook.url = None;
+        conf.hooks = vec![hook.clone()];
+        assert!(conf.validate().is_err());
+        hook.command = vec!["notify-send".to_string(), "{server}".to_string()];
+        hook.event = HookEvent::ServerDown;
+        conf.hooks = vec![hook.clone()];
+        assert!(conf.validate().is_err());
+        hook.bytes = None;
+        conf.hooks = vec![hook.clone()];
+        assert!(conf.validate().is_ok());
+        hook.domains = vec!["example.com".to_string()];
+        conf.hooks = vec![hook.clone()];
+        assert!(conf.validate().is_err());
+        hook.event = HookEvent::Connection;
+        conf.hooks = vec![hook];
+        assert!(conf.validate().is_ok());
+
+        let mut conf = config();
+        let tunnel = TunnelConfig {
+            listen: "127.0.0.1:5432".parse().unwrap(),
+            remote: "db.internal:5432".parse().unwrap(),
+            action: Action::Proxy,
+        };
+        conf.tunnels = vec![tunnel.clone()];
+        assert!(conf.validate().is_ok());
+        conf.tunnels.push(tunnel.clone());
+        assert!(conf.validate().is_err());
+        conf.tunnels = vec![TunnelConfig {
+            action: Action::Reject,
+            ..tunnel
+        }];
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.chaos = vec![ChaosConfig {
+            latency: Duration::from_millis(100),
+            jitter: Duration::from_millis(20),
+            loss: 0.1,
+            ..Default::default()
+        }];
+        assert!(conf.validate().is_ok());
+        conf.chaos[0].loss = 10.0;
+        assert!(conf.validate().is_err());
+        conf.chaos[0].loss = 0.0;
+        conf.chaos[0].jitter = Duration::from_secs(1);
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.dns_records = serde_yaml::from_str(
+            r#"
+- name: nas.home
+  type: A
+  value: 192.168.1.10
+- name: www.home
+  type: CNAME
+  value: nas.home
+- name: _smb._tcp.home
+  type: SRV
+  port: 445
+  target: nas.home
+  ttl: 10s
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.dns_records[2].data,
+            DnsRecordData::Srv {
+                priority: 0,
+                weight: 0,
+                port: 445,
+                target: "nas.home".to_string()
+            }
+        );
+        assert_eq!(conf.dns_records[0].ttl, Duration::from_secs(60));
+        assert!(conf.validate().is_ok());
+        conf.dns_records.push(DnsRecordConfig {
+            name: "WWW.home.".to_string(),
+            ..conf.dns_records[0].clone()
+        });
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.servers = serde_yaml::from_str(
+            r#"
+- name: ss
+  addr: 1.2.3.4:8388
+  protocol: Shadowsocks
+  method: chacha20-ietf-poly1305
+  password: secret
+  proxy_protocol: true
+"#,
+        )
+        .unwrap();
+        assert!(conf.validate().is_ok());
+        let mut servers: Vec<ServerConfig> = serde_yaml::from_str(
+            r#"
+- name: socks
+  addr: 1.2.3.4:1080
+  protocol: Socks5
+  proxy_protocol: true
+"#,
+        )
+        .unwrap();
+        conf.servers.append(&mut servers);
+        assert!(conf.validate().is_err());
+
+        let mut conf = config();
+        conf.servers = serde_yaml::from_str(
+            r#"
+- name: fronted
+  addr: 1.2.3.4:443
+  protocol: Https
+  sni: cdn.example.com
+  host_header: proxy.example.com
+  tls_cipher_order: firefox
+"#,
+        )
+        .unwrap();
+        assert_eq!(conf.servers[0].sni(), Some("cdn.example.com"));
+        assert_eq!(conf.servers[0].host_header(), Some("proxy.example.com"));
+        assert_eq!(
+            conf.servers[0].tls_cipher_order(),
+            Some(TlsCipherOrder::Firefox)
+        );
+        assert!(conf.validate().is_ok());
+        conf.servers = serde_yaml::from_str(
+            r#"
+- name: http
+  addr: 1.2.3.4:8080
+  protocol: Http
+  host_header: proxy.example.com
+"#,
+        )
+        .unwrap();
+        assert!(conf.validate().is_err());
+        conf.servers = serde_yaml::from_str(
+            r#"
+- name: socks
+  addr: 1.2.3.4:1080
+  protocol: Socks5
+  tls_cipher_order: chrome
+"#,
+        )
+        .unwrap();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_fake_ip_range() {
+        for dns_start_ip in ["11.0.0.1", "11.0.255.255", "12.0.0.10"] {
+            let mut conf = config();
+            conf.dns_start_ip = dns_start_ip.parse().unwrap();
+            assert!(conf.validate().is_err(), "{dns_start_ip}");
+        }
+
+        let mut conf = config();
+        conf.dns_servers = vec![DnsServerAddr::UdpSocketAddr("11.0.1.1:53".parse().unwrap())];
+        assert!(conf.validate().is_err());
+
+        let conf: Config = serde_yaml::from_str(
+            r#"
+dns_start_ip: 11.0.0.10
+tun_bypass_direct: false
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+ping_urls: []
+max_connect_errors: 2
+servers:
+  - name: loop
+    addr: 11.0.2.2:1080
+    protocol: Socks5
+rules: []
+"#,
+        )
+        .unwrap();
+        assert!(conf.validate().is_err());
+    }
+}
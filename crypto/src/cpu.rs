@@ -0,0 +1,37 @@
+//! CPU features used by the accelerated cipher implementations.
+//!
+//! `ring` and `libsodium` pick their AES-NI / NEON code paths at runtime, this is only used to
+//! report what the current machine offers.
+
+/// Names of the detected crypto related CPU features.
+pub fn accelerated_features() -> Vec<&'static str> {
+    detected()
+        .into_iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detected() -> Vec<(&'static str, bool)> {
+    vec![
+        ("aes-ni", std::is_x86_feature_detected!("aes")),
+        ("pclmulqdq", std::is_x86_feature_detected!("pclmulqdq")),
+        ("ssse3", std::is_x86_feature_detected!("ssse3")),
+        ("avx2", std::is_x86_feature_detected!("avx2")),
+    ]
+}
+
+#[cfg(target_arch = "aarch64")]
+fn detected() -> Vec<(&'static str, bool)> {
+    vec![
+        ("neon", std::arch::is_aarch64_feature_detected!("neon")),
+        ("aes", std::arch::is_aarch64_feature_detected!("aes")),
+        ("pmull", std::arch::is_aarch64_feature_detected!("pmull")),
+    ]
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn detected() -> Vec<(&'static str, bool)> {
+    vec![]
+}
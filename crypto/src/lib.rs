@@ -13,6 +13,7 @@ use ::openssl::symm;
 
 pub mod aead;
 pub mod cipher;
+pub mod cpu;
 pub mod digest;
 pub mod dummy;
 #[cfg(feature = "openssl")]
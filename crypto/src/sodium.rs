@@ -164,6 +164,11 @@ impl SodiumAeadCipher {
     pub fn new(t: CipherType, key: &[u8], salt: &[u8]) -> SodiumAeadCipher {
         // TODO: Check if salt is duplicated
 
+        // Let libsodium select the fastest implementations for this CPU
+        SODIUM_INIT_FLAG.call_once(|| unsafe {
+            assert_eq!(sodium_init(), 0);
+        });
+
         let nonce_size = t.iv_size();
         let mut nonce = BytesMut::with_capacity(nonce_size);
         unsafe {
@@ -0,0 +1,59 @@
+//! Benchmarks of answering queries with fake IPs, run with `cargo bench -p dnsserver`.
+#[path = "../../benches/harness.rs"]
+mod harness;
+
+use async_std::task::block_on;
+use async_std_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use config::rule::{Action, ProxyRules, Rule};
+use dnsserver::resolver::RuleBasedDnsResolver;
+use harness::bench;
+use hermesdns::{DnsResolver, QueryType};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::Duration;
+use store::Store;
+
+fn main() {
+    Store::setup_global_for_test();
+    let resolver = block_on(async {
+        // Proxied domains are answered with fake IPs, the upstream is never queried.
+        let name_servers =
+            NameServerConfigGroup::from_ips_clear(&["127.0.0.1".parse().unwrap()], 53, false);
+        let upstream = async_std_resolver::resolver(
+            ResolverConfig::from_parts(None, Vec::new(), name_servers),
+            ResolverOpts::default(),
+        )
+        .await
+        .expect("failed to create resolver");
+        let rules: Vec<Rule> = (0..1000)
+            .map(|i| Rule::DomainSuffix(format!("domain{i}.com"), Action::Proxy))
+            .chain([Rule::Match(Action::Proxy)])
+            .collect();
+        RuleBasedDnsResolver::new(
+            false,
+            true,
+            vec![],
+            &[],
+            ProxyRules::new(rules),
+            upstream,
+            HashMap::new(),
+            None,
+        )
+        .await
+    });
+
+    for (name, new_hosts) in [
+        ("resolve new domain", true),
+        ("resolve known domain", false),
+    ] {
+        bench(name, 10_000, Duration::from_millis(2), |i| {
+            let domain = if new_hosts {
+                format!("host{i}.example.com")
+            } else {
+                "host0.example.com".to_string()
+            };
+            let packet = DnsResolver::resolve(&resolver, &domain, QueryType::A, true);
+            black_box(block_on(packet).unwrap());
+        });
+    }
+}
@@ -1,19 +1,41 @@
 pub mod resolver;
+pub mod steering;
+pub mod upstream;
 
 use async_std_resolver::AsyncStdResolver;
 use config::rule::ProxyRules;
+use config::DnsRecordConfig;
 use hermesdns::DnsUdpServer;
 use resolver::RuleBasedDnsResolver;
+use std::collections::HashMap;
+use steering::DnsSteering;
+use upstream::DnsGroup;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn create_dns_server(
     listen: String,
     bypass_direct: bool,
+    passthrough_unknown: bool,
+    real_ip_domains: Vec<String>,
+    local_records: &[DnsRecordConfig],
     rules: ProxyRules,
     async_resolver: AsyncStdResolver,
-) -> (DnsUdpServer, RuleBasedDnsResolver) {
-    let resolver = RuleBasedDnsResolver::new(bypass_direct, rules, async_resolver).await;
-    let server = DnsUdpServer::new(listen, Box::new(resolver.clone())).await;
-    (server, resolver)
+    groups: HashMap<String, DnsGroup>,
+    steering: Option<DnsSteering>,
+) -> std::io::Result<(DnsUdpServer, RuleBasedDnsResolver)> {
+    let resolver = RuleBasedDnsResolver::new(
+        bypass_direct,
+        passthrough_unknown,
+        real_ip_domains,
+        local_records,
+        rules,
+        async_resolver,
+        groups,
+        steering,
+    )
+    .await;
+    let server = DnsUdpServer::new(listen, Box::new(resolver.clone())).await?;
+    Ok((server, resolver))
 }
 
 #[cfg(test)]
@@ -58,10 +80,16 @@ pub(crate) mod tests {
             let (server, resolver) = create_dns_server(
                 format!("0.0.0.0:{LOCAL_UDP_PORT}"),
                 false,
+                true,
+                vec![],
+                &[],
                 ProxyRules::new(vec![]),
                 resolver,
+                HashMap::new(),
+                None,
             )
-            .await;
+            .await
+            .unwrap();
             task::spawn(server.run_server());
             task::sleep(Duration::from_secs(3)).await;
             let client = DnsNetworkClient::new(0, Duration::from_secs(50)).await;
@@ -71,11 +99,11 @@ pub(crate) mod tests {
             assert!(baidu_ip.is_some());
 
             assert_eq!(
-                resolver.lookup_host(&baidu_ip.unwrap()),
+                resolver.lookup_host(&baidu_ip.unwrap()).unwrap(),
                 Some("baidu.com".to_string())
             );
             assert_eq!(
-                resolver.lookup_host(&ali_ip.unwrap()),
+                resolver.lookup_host(&ali_ip.unwrap()).unwrap(),
                 Some("google.com".to_string())
             )
         });
@@ -1,14 +1,21 @@
+use crate::steering::DnsSteering;
+use crate::upstream::DnsGroup;
 use async_std_resolver::AsyncStdResolver;
 use async_trait::async_trait;
 use config::rule::{Action, ProxyRules};
+use config::{DnsRecordConfig, DnsRecordData};
+use futures_util::future::join_all;
 use hermesdns::{DnsPacket, DnsRecord, DnsResolver, Hosts, QueryType, TransientTtl};
 use std::any::Any;
+use std::collections::HashMap;
 use std::io;
 use std::io::Result;
+use std::net::Ipv4Addr;
 use std::sync::Arc;
 use store::Store;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use trust_dns_proto::rr::{RData, RecordType};
+use trust_dns_proto::serialize::binary::BinEncoder;
 
 /// A Forwarding DNS Resolver
 ///
@@ -19,37 +26,140 @@ pub struct RuleBasedDnsResolver {
 }
 
 struct Inner {
+    /// Records of `dns_records` by their lowercase names without the trailing dot.
+    local_records: HashMap<String, Vec<DnsRecord>>,
     hosts: Hosts,
     rules: ProxyRules,
     bypass_direct: bool,
+    passthrough_unknown: bool,
+    /// Always resolved to their real IPs, with their subdomains.
+    real_ip_domains: Vec<String>,
     resolver: AsyncStdResolver,
+    /// The `dns_groups` by name, resolving domains of rules with `dns=<name>` instead of
+    /// `resolver`.
+    groups: HashMap<String, DnsGroup>,
+    /// Resolves the direct domains without a dns group when `bypass_direct` is true.
+    steering: Option<DnsSteering>,
 }
 
 impl RuleBasedDnsResolver {
-    pub async fn new(bypass_direct: bool, rules: ProxyRules, resolver: AsyncStdResolver) -> Self {
+    /// `passthrough_unknown` keeps answers of record types without a `DnsRecord` variant, with
+    /// their data as it is, instead of dropping them. `real_ip_domains` and their subdomains, eg.
+    /// NTP servers, get their real IPs whatever the rules are. `local_records` are answered before
+    /// anything else. Real resolutions of domains of rules with `dns=<name>` go to that one of
+    /// `groups`. Direct domains resolved to their real IPs without a group go through `steering`
+    /// if set.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        bypass_direct: bool,
+        passthrough_unknown: bool,
+        real_ip_domains: Vec<String>,
+        local_records: &[DnsRecordConfig],
+        rules: ProxyRules,
+        resolver: AsyncStdResolver,
+        groups: HashMap<String, DnsGroup>,
+        steering: Option<DnsSteering>,
+    ) -> Self {
         RuleBasedDnsResolver {
             inner: Arc::new(Inner {
-                hosts: Hosts::load().expect("load /etc/hosts"),
+                local_records: local_zone(local_records),
+                hosts: Hosts::load().unwrap_or_else(|e| {
+                    warn!(%e, "load /etc/hosts error, ignore it");
+                    Hosts::default()
+                }),
                 rules,
                 bypass_direct,
+                passthrough_unknown,
+                real_ip_domains,
                 resolver,
+                groups,
+                steering,
             }),
         }
     }
 
-    pub fn lookup_host(&self, addr: &str) -> Option<String> {
+    /// The domain `addr` is the fake ip of, None if it isn't one.
+    pub fn lookup_host(&self, addr: &str) -> Result<Option<String>> {
+        let ip: Ipv4Addr = addr.parse().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid ip {addr}"))
+        })?;
         let host = Store::global()
-            .get_host_by_ipv4(addr.parse().expect("invalid addr"))
-            .expect("get host");
+            .get_host_by_ipv4(ip)
+            .map_err(|e| store_error(format!("lookup host of {ip}: {e}")))?
+            // The row before the first fake ip has no host.
+            .filter(|host| !host.is_empty());
         debug!("lookup host: {:?}, addr: {:?}", host, addr);
-        host
+        Ok(host)
+    }
+
+    fn is_real_ip_domain(&self, domain: &str) -> bool {
+        self.inner.real_ip_domains.iter().any(|d| {
+            domain == d
+                || domain
+                    .strip_suffix(d.as_str())
+                    .map_or(false, |sub| sub.ends_with('.'))
+        })
     }
 
     async fn resolve_real(&self, domain: &str, qtype: QueryType) -> Result<DnsPacket> {
-        let mut packet = DnsPacket::new();
-        let lookup = self
+        let group = self
             .inner
-            .resolver
+            .rules
+            .dns_group_for_domain(domain)
+            .and_then(|name| self.inner.groups.get(&name));
+        self.query(group, domain, qtype).await
+    }
+
+    /// Resolve a direct domain to its real IPs, by all the upstreams of `steering` if it's set
+    /// and the rules don't pick a dns group.
+    async fn resolve_direct(&self, domain: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let steering = match &self.inner.steering {
+            Some(steering) if self.inner.rules.dns_group_for_domain(domain).is_none() => steering,
+            _ => return self.resolve_real(domain, qtype).await,
+        };
+        let queries = std::iter::once(None)
+            .chain(steering.groups().iter().map(Some))
+            .map(|group| self.query(group, domain, qtype));
+        let mut answers = Vec::new();
+        let mut last_error = None;
+        for ret in join_all(queries).await {
+            match ret {
+                Ok(packet) => answers.push(packet),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match steering.choose(answers).await {
+            Some(packet) => Ok(packet),
+            None => Err(last_error
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no dns answer"))),
+        }
+    }
+
+    /// Query `domain` on `group`, the default resolver if None.
+    async fn query(
+        &self,
+        group: Option<&DnsGroup>,
+        domain: &str,
+        qtype: QueryType,
+    ) -> Result<DnsPacket> {
+        let resolver = match group {
+            Some(DnsGroup::Upstream(upstream)) => {
+                let mut packet = upstream.query(domain, qtype).await.map_err(|e| {
+                    error!(%e, domain, "lookup host through dns group error");
+                    e
+                })?;
+                if !self.inner.passthrough_unknown {
+                    packet
+                        .answers
+                        .retain(|record| !matches!(record, DnsRecord::UNKNOWN { .. }));
+                }
+                return Ok(packet);
+            }
+            Some(DnsGroup::Resolver(resolver)) => resolver,
+            None => &self.inner.resolver,
+        };
+        let mut packet = DnsPacket::new();
+        let lookup = resolver
             .lookup(domain, RecordType::from(qtype.to_num()))
             .await
             .map_err(|e| {
@@ -112,8 +222,21 @@ impl RuleBasedDnsResolver {
                     host: srv.target().to_string(),
                     ttl: TransientTtl(record.ttl()),
                 },
-                other => {
-                    tracing::error!("unsupported record type: {:?}", other);
+                Some(other) if self.inner.passthrough_unknown => {
+                    let mut data = Vec::new();
+                    if let Err(e) = other.emit(&mut BinEncoder::new(&mut data)) {
+                        error!(%e, "encode record error: {:?}", other);
+                        continue;
+                    }
+                    DnsRecord::UNKNOWN {
+                        domain: domain.to_string(),
+                        qtype: record.record_type().into(),
+                        data,
+                        ttl: TransientTtl(record.ttl()),
+                    }
+                }
+                Some(other) => {
+                    debug!("drop record of unsupported type: {:?}", other);
                     continue;
                 }
             };
@@ -123,7 +246,32 @@ impl RuleBasedDnsResolver {
         Ok(packet)
     }
 
+    /// The answer of `dns_records` if `domain` has records there. CNAME targets and SRV hosts are
+    /// resolved by the server with further queries.
+    fn resolve_local(&self, domain: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let records = self.inner.local_records.get(&normalize_name(domain))?;
+        let mut packet = DnsPacket::new();
+        packet.answers = records
+            .iter()
+            .filter(|record| {
+                record.get_querytype() == qtype || matches!(record, DnsRecord::CNAME { .. })
+            })
+            .map(|record| with_domain(record, domain))
+            .collect();
+        debug!(
+            domain,
+            ?qtype,
+            answers = packet.answers.len(),
+            "local record"
+        );
+        Some(packet)
+    }
+
     async fn resolve(&self, domain: &str, qtype: QueryType) -> Result<DnsPacket> {
+        if let Some(packet) = self.resolve_local(domain, qtype) {
+            return Ok(packet);
+        }
+
         // We only support A record for now, for other records, we just forward them to upstream.
         if !matches!(qtype, QueryType::A | QueryType::AAAA) {
             return self.resolve_real(domain, qtype).await;
@@ -145,12 +293,16 @@ impl RuleBasedDnsResolver {
             return Ok(packet);
         }
 
+        if self.is_real_ip_domain(domain) {
+            return self.resolve_real(domain, qtype).await;
+        }
+
         // direct traffic bypass tun.
         let bypass_direct = self.inner.bypass_direct;
         match self.inner.rules.action_for_domain(Some(domain), None) {
             // Return real ip when `bypass_direct` is true.
             Some(Action::Direct) if bypass_direct => {
-                return self.resolve_real(domain, qtype).await;
+                return self.resolve_direct(domain, qtype).await;
             }
             // Do not return dns records when action is reject.
             Some(Action::Reject) => return Ok(packet),
@@ -159,7 +311,8 @@ impl RuleBasedDnsResolver {
 
         let ip = Store::global()
             .get_ipv4_by_host(domain)
-            .expect("get domain");
+            .map_err(|e| store_error(format!("allocate fake ip for {domain}: {e}")))?;
+        self.inner.rules.assign_fake_ip(ip, domain);
         packet.answers.push(DnsRecord::A {
             domain: domain.to_string(),
             addr: ip,
@@ -169,6 +322,77 @@ impl RuleBasedDnsResolver {
     }
 }
 
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn local_zone(records: &[DnsRecordConfig]) -> HashMap<String, Vec<DnsRecord>> {
+    let mut zone: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+    for record in records {
+        let domain = normalize_name(&record.name);
+        let ttl = TransientTtl(record.ttl.as_secs() as u32);
+        let record = match &record.data {
+            DnsRecordData::A { value } => DnsRecord::A {
+                domain: domain.clone(),
+                addr: *value,
+                ttl,
+            },
+            DnsRecordData::Aaaa { value } => DnsRecord::AAAA {
+                domain: domain.clone(),
+                addr: *value,
+                ttl,
+            },
+            DnsRecordData::Cname { value } => DnsRecord::CNAME {
+                domain: domain.clone(),
+                host: value.trim_end_matches('.').to_string(),
+                ttl,
+            },
+            DnsRecordData::Txt { value } => DnsRecord::TXT {
+                domain: domain.clone(),
+                data: value.clone(),
+                ttl,
+            },
+            DnsRecordData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => DnsRecord::SRV {
+                domain: domain.clone(),
+                priority: *priority,
+                weight: *weight,
+                port: *port,
+                host: target.trim_end_matches('.').to_string(),
+                ttl,
+            },
+        };
+        zone.entry(domain).or_default().push(record);
+    }
+    zone
+}
+
+/// `record` answering a query of `domain`, which may differ from the configured name in case.
+fn with_domain(record: &DnsRecord, domain: &str) -> DnsRecord {
+    let mut record = record.clone();
+    match &mut record {
+        DnsRecord::A { domain: name, .. }
+        | DnsRecord::AAAA { domain: name, .. }
+        | DnsRecord::CNAME { domain: name, .. }
+        | DnsRecord::TXT { domain: name, .. }
+        | DnsRecord::SRV { domain: name, .. } => *name = domain.to_string(),
+        _ => {}
+    }
+    record
+}
+
+/// Log and record a failure of the fake ip store, the query or connection fails instead of the
+/// whole proxy.
+fn store_error(message: String) -> io::Error {
+    error!("{}", message);
+    let _ = Store::global().new_event(Store::EVENT_DNS_ERROR, &message);
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
 #[async_trait]
 impl DnsResolver for RuleBasedDnsResolver {
     async fn resolve(&self, domain: &str, qtype: QueryType, _recursive: bool) -> Result<DnsPacket> {
@@ -184,7 +408,9 @@ impl DnsResolver for RuleBasedDnsResolver {
 mod tests {
     use super::*;
     use crate::tests::new_resolver;
+    use crate::upstream::DnsUpstream;
     use async_std::task;
+    use std::time::Duration;
 
     #[test]
     fn test_inner_resolve_ip_and_lookup_host() {
@@ -193,8 +419,13 @@ mod tests {
         task::block_on(async {
             let resolver = RuleBasedDnsResolver::new(
                 true,
+                true,
+                vec![],
+                &[],
                 ProxyRules::new(vec![]),
                 new_resolver(dns, 53).await,
+                HashMap::new(),
+                None,
             )
             .await;
             let baidu_ip = resolver
@@ -204,7 +435,7 @@ mod tests {
                 .get_random_a();
             assert!(baidu_ip.is_some());
             assert_eq!(
-                resolver.lookup_host(&baidu_ip.unwrap()),
+                resolver.lookup_host(&baidu_ip.unwrap()).unwrap(),
                 Some("baidu.com".to_string())
             );
             assert!(resolver
@@ -213,7 +444,158 @@ mod tests {
                 .unwrap()
                 .get_txt()
                 .is_some());
-            assert_eq!(resolver.lookup_host("10.1.0.1"), None);
+            assert_eq!(resolver.lookup_host("10.1.0.1").unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_lookup_host_malformed() {
+        store::Store::setup_global_for_test();
+        task::block_on(async {
+            let resolver = RuleBasedDnsResolver::new(
+                true,
+                true,
+                vec![],
+                &[],
+                ProxyRules::new(vec![]),
+                new_resolver("127.0.0.1".to_string(), 53).await,
+                HashMap::new(),
+                None,
+            )
+            .await;
+            assert_eq!(
+                resolver.lookup_host("not an ip").unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+            assert!(resolver.lookup_host("::1").is_err());
+        });
+    }
+
+    #[test]
+    fn test_real_ip_domains() {
+        store::Store::setup_global_for_test();
+        task::block_on(async {
+            let resolver = RuleBasedDnsResolver::new(
+                false,
+                true,
+                vec!["pool.ntp.org".to_string()],
+                &[],
+                ProxyRules::new(vec![]),
+                new_resolver("127.0.0.1".to_string(), 53).await,
+                HashMap::new(),
+                None,
+            )
+            .await;
+            assert!(resolver.is_real_ip_domain("pool.ntp.org"));
+            assert!(resolver.is_real_ip_domain("0.openwrt.pool.ntp.org"));
+            assert!(!resolver.is_real_ip_domain("evilpool.ntp.org"));
+            assert!(!resolver.is_real_ip_domain("ntp.org"));
+        });
+    }
+
+    #[test]
+    fn test_local_records() {
+        store::Store::setup_global_for_test();
+        let record = |name: &str, data| DnsRecordConfig {
+            name: name.to_string(),
+            data,
+            ttl: Duration::from_secs(60),
+        };
+        let records = [
+            record(
+                "nas.home",
+                DnsRecordData::A {
+                    value: "192.168.1.10".parse().unwrap(),
+                },
+            ),
+            record(
+                "nas.home",
+                DnsRecordData::Txt {
+                    value: "hello".to_string(),
+                },
+            ),
+            record(
+                "www.home.",
+                DnsRecordData::Cname {
+                    value: "nas.home".to_string(),
+                },
+            ),
+        ];
+        task::block_on(async {
+            let resolver = RuleBasedDnsResolver::new(
+                false,
+                true,
+                vec![],
+                &records,
+                ProxyRules::new(vec![]),
+                new_resolver("127.0.0.1".to_string(), 53).await,
+                HashMap::new(),
+                None,
+            )
+            .await;
+            let packet = resolver.resolve("NAS.home", QueryType::A).await.unwrap();
+            assert_eq!(packet.get_random_a(), Some("192.168.1.10".to_string()));
+            assert_eq!(packet.answers[0].get_domain(), Some("NAS.home".to_string()));
+            let packet = resolver.resolve("nas.home", QueryType::TXT).await.unwrap();
+            assert_eq!(packet.get_txt(), Some("hello".to_string()));
+            // Authoritative for the name, no AAAA record means an empty answer.
+            let packet = resolver.resolve("nas.home", QueryType::AAAA).await.unwrap();
+            assert!(packet.answers.is_empty());
+            let packet = resolver.resolve("www.home", QueryType::A).await.unwrap();
+            assert_eq!(packet.get_unresolved_cnames().len(), 1);
+        });
+    }
+
+    struct FixedUpstream;
+
+    #[async_trait]
+    impl DnsUpstream for FixedUpstream {
+        async fn query(&self, domain: &str, _qtype: QueryType) -> Result<DnsPacket> {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::TXT {
+                domain: domain.to_string(),
+                data: "clean".to_string(),
+                ttl: TransientTtl(60),
+            });
+            packet.answers.push(DnsRecord::UNKNOWN {
+                domain: domain.to_string(),
+                qtype: 99,
+                data: vec![],
+                ttl: TransientTtl(60),
+            });
+            Ok(packet)
+        }
+    }
+
+    #[test]
+    fn test_dns_groups() {
+        store::Store::setup_global_for_test();
+        task::block_on(async {
+            let rules = ProxyRules::new(vec!["DOMAIN-SUFFIX,google.com,PROXY,dns=clean"
+                .parse()
+                .unwrap()]);
+            let groups = HashMap::from([(
+                "clean".to_string(),
+                DnsGroup::Upstream(Arc::new(FixedUpstream)),
+            )]);
+            let resolver = RuleBasedDnsResolver::new(
+                false,
+                false,
+                vec![],
+                &[],
+                rules,
+                new_resolver("127.0.0.1".to_string(), 53).await,
+                groups,
+                None,
+            )
+            .await;
+            let packet = resolver
+                .resolve("www.google.com", QueryType::TXT)
+                .await
+                .unwrap();
+            assert_eq!(packet.get_txt(), Some("clean".to_string()));
+            // Dropped as `passthrough_unknown` is off.
+            assert_eq!(packet.answers.len(), 1);
         });
     }
 }
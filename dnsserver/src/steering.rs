@@ -0,0 +1,187 @@
+//! `dns_steering`, the direct domains are resolved by several upstreams at once and answered by
+//! the one whose ips connect fastest, so CDNs geolocating the resolvers badly still serve from
+//! nearby. Latencies are measured with tcp connects and reused for the whole subnet.
+use crate::upstream::DnsGroup;
+use async_std::io::timeout;
+use async_std::net::TcpStream;
+use futures_util::future::join_all;
+use hermesdns::{DnsPacket, DnsRecord};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Subnets whose latencies are remembered, the expired ones are dropped beyond it.
+const MAX_SUBNETS: usize = 4096;
+
+pub struct DnsSteering {
+    groups: Vec<DnsGroup>,
+    port: u16,
+    timeout: Duration,
+    cache_ttl: Duration,
+    /// Connect latency of each subnet and when it was measured, None if it didn't connect.
+    latencies: Mutex<HashMap<IpAddr, (Option<Duration>, Instant)>>,
+}
+
+impl DnsSteering {
+    /// `groups` are queried besides the default resolver, ips are connected on `port` within
+    /// `timeout` and the latencies reused for `cache_ttl`.
+    pub fn new(groups: Vec<DnsGroup>, port: u16, timeout: Duration, cache_ttl: Duration) -> Self {
+        DnsSteering {
+            groups,
+            port,
+            timeout,
+            cache_ttl,
+            latencies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn groups(&self) -> &[DnsGroup] {
+        &self.groups
+    }
+
+    /// The one of `answers` with the ip connecting fastest, the first one if none connects. None
+    /// if `answers` is empty.
+    pub(crate) async fn choose(&self, mut answers: Vec<DnsPacket>) -> Option<DnsPacket> {
+        let ips: Vec<Vec<IpAddr>> = answers.iter().map(answered_ips).collect();
+        let mut subnets: Vec<IpAddr> = ips.iter().flatten().copied().collect();
+        subnets.sort();
+        subnets.dedup_by_key(|ip| subnet(*ip));
+        let latencies: HashMap<IpAddr, Option<Duration>> =
+            join_all(subnets.into_iter().map(|ip| async move {
+                let latency = self.latency(ip).await;
+                (subnet(ip), latency)
+            }))
+            .await
+            .into_iter()
+            .collect();
+
+        let fastest = ips
+            .iter()
+            .enumerate()
+            .filter_map(|(i, ips)| {
+                let latency = ips
+                    .iter()
+                    .filter_map(|ip| latencies.get(&subnet(*ip)).copied().flatten())
+                    .min()?;
+                Some((latency, i))
+            })
+            .min();
+        debug!(?fastest, answers = answers.len(), "steer dns answer");
+        let i = fastest.map_or(0, |(_, i)| i);
+        if i < answers.len() {
+            Some(answers.swap_remove(i))
+        } else {
+            None
+        }
+    }
+
+    /// Connect latency of the subnet of `ip`, measured again when the last one expired.
+    async fn latency(&self, ip: IpAddr) -> Option<Duration> {
+        let key = subnet(ip);
+        if let Some((latency, time)) = self.latencies.lock().get(&key) {
+            if time.elapsed() < self.cache_ttl {
+                return *latency;
+            }
+        }
+        let start = Instant::now();
+        let latency = timeout(
+            self.timeout,
+            TcpStream::connect(SocketAddr::new(ip, self.port)),
+        )
+        .await
+        .ok()
+        .map(|_| start.elapsed());
+        debug!(%ip, ?latency, "measure latency of subnet");
+
+        let mut latencies = self.latencies.lock();
+        if latencies.len() >= MAX_SUBNETS {
+            latencies.retain(|_, (_, time)| time.elapsed() < self.cache_ttl);
+        }
+        let _ = latencies.insert(key, (latency, Instant::now()));
+        latency
+    }
+}
+
+fn answered_ips(packet: &DnsPacket) -> Vec<IpAddr> {
+    packet
+        .answers
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The /24 of IPv4 and /64 of IPv6 addresses, usually served from the same place.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u128::MAX >> 64))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task;
+    use hermesdns::TransientTtl;
+
+    fn answer(ip: &str) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "cdn.example.com".to_string(),
+            addr: ip.parse().unwrap(),
+            ttl: TransientTtl(60),
+        });
+        packet
+    }
+
+    #[test]
+    fn test_subnet() {
+        assert_eq!(
+            subnet("1.2.3.4".parse().unwrap()),
+            "1.2.3.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            subnet("2001:db8:1:2:3::4".parse().unwrap()),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_choose() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let steering = DnsSteering::new(
+                vec![],
+                port,
+                Duration::from_secs(1),
+                Duration::from_secs(60),
+            );
+
+            // Nothing listens on 127.0.1.1, only the second answer connects.
+            let chosen = steering
+                .choose(vec![answer("127.0.1.1"), answer("127.0.0.1")])
+                .await
+                .unwrap();
+            assert_eq!(chosen.get_random_a(), Some("127.0.0.1".to_string()));
+            assert_eq!(steering.latencies.lock().len(), 2);
+
+            // The first one is kept when none connects.
+            let chosen = steering
+                .choose(vec![answer("127.0.2.1"), answer("127.0.1.2")])
+                .await
+                .unwrap();
+            assert_eq!(chosen.get_random_a(), Some("127.0.2.1".to_string()));
+
+            assert!(steering.choose(vec![]).await.is_none());
+            drop(listener);
+        });
+    }
+}
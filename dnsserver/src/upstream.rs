@@ -0,0 +1,107 @@
+//! The named upstreams of `dns_groups`, resolving the domains of the rules with `dns=<name>`
+//! instead of `dns_servers`.
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std_resolver::AsyncStdResolver;
+use async_trait::async_trait;
+use hermesdns::{DnsPacket, DnsQuestion, QueryType, VectorPacketBuffer};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+/// An upstream not reachable by the resolver, eg. one queried through the proxy.
+#[async_trait]
+pub trait DnsUpstream: Send + Sync {
+    async fn query(&self, domain: &str, qtype: QueryType) -> Result<DnsPacket>;
+}
+
+/// A dns group, queried by a resolver of its servers or by an upstream of its own.
+#[derive(Clone)]
+pub enum DnsGroup {
+    Resolver(AsyncStdResolver),
+    Upstream(Arc<dyn DnsUpstream>),
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Query `domain` on a tcp connection to a DNS server, the messages are prefixed by their
+/// lengths, RFC 1035 section 4.2.2.
+pub async fn query_tcp<S: Read + Write + Unpin>(
+    stream: &mut S,
+    domain: &str,
+    qtype: QueryType,
+) -> Result<DnsPacket> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    packet.header.recursion_desired = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(domain.to_string(), qtype));
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer, u16::MAX as usize)?;
+    let mut request = (buffer.buffer.len() as u16).to_be_bytes().to_vec();
+    request.extend_from_slice(&buffer.buffer);
+    stream.write_all(&request).await?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response).await?;
+    let mut buffer = VectorPacketBuffer {
+        buffer: response,
+        ..Default::default()
+    };
+    let answer = DnsPacket::from_buffer(&mut buffer)?;
+    if answer.header.id != packet.header.id {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("dns answer of another query for {domain}"),
+        ));
+    }
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+    use hermesdns::{DnsRecord, TransientTtl};
+
+    #[test]
+    fn test_query_tcp() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            task::spawn(async move {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut len = [0; 2];
+                conn.read_exact(&mut len).await.unwrap();
+                let mut request = vec![0; u16::from_be_bytes(len) as usize];
+                conn.read_exact(&mut request).await.unwrap();
+                let mut buffer = VectorPacketBuffer {
+                    buffer: request,
+                    ..Default::default()
+                };
+                let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+                packet.header.response = true;
+                packet.answers.push(DnsRecord::A {
+                    domain: packet.questions[0].name.clone(),
+                    addr: "1.2.3.4".parse().unwrap(),
+                    ttl: TransientTtl(60),
+                });
+                let mut buffer = VectorPacketBuffer::new();
+                packet.write(&mut buffer, u16::MAX as usize).unwrap();
+                conn.write_all(&(buffer.buffer.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                conn.write_all(&buffer.buffer).await.unwrap();
+            });
+
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let answer = query_tcp(&mut conn, "example.com", QueryType::A)
+                .await
+                .unwrap();
+            assert_eq!(answer.get_random_a(), Some("1.2.3.4".to_string()));
+        });
+    }
+}
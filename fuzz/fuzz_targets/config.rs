@@ -0,0 +1,19 @@
+//! The config and rule parsers, fed config files and the rules of remote configs.
+#![no_main]
+use config::rule::Rule;
+use config::Config;
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    for line in text.lines() {
+        let _ = Rule::from_str(line);
+    }
+    // `Config::from_reader` also sets up the store and fetches remote configs.
+    if let Ok(conf) = serde_yaml::from_str::<Config>(text) {
+        let _ = conf.validate();
+    }
+});
@@ -0,0 +1,15 @@
+//! The DNS packet parser, fed the queries of the LAN clients and the answers of the upstreams.
+#![no_main]
+use hermesdns::{BytePacketBuffer, DnsPacket, VectorPacketBuffer};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = BytePacketBuffer::new();
+    let len = data.len().min(buffer.buf.len());
+    buffer.buf[..len].copy_from_slice(&data[..len]);
+    let Ok(mut packet) = DnsPacket::from_buffer(&mut buffer) else {
+        return;
+    };
+    // Parsed packets are written back when answering or caching them.
+    let _ = packet.write(&mut VectorPacketBuffer::new(), 512);
+});
@@ -0,0 +1,24 @@
+//! The decoder of Shadowsocks AEAD streams, fed whatever the server sends back.
+#![no_main]
+use async_std::io::Cursor;
+use async_std::prelude::*;
+use async_std::task::block_on;
+use crypto::CipherType;
+use libfuzzer_sys::fuzz_target;
+use ssclient::AeadDecryptedReader;
+
+const METHOD: CipherType = CipherType::ChaCha20IetfPoly1305;
+/// Password the seeds are encrypted with.
+const PASSWORD: &str = "seeker-fuzz";
+
+fuzz_target!(|data: &[u8]| {
+    // The salt comes first on the wire, then the chunks.
+    if data.len() < METHOD.salt_size() {
+        return;
+    }
+    let (salt, chunks) = data.split_at(METHOD.salt_size());
+    let key = METHOD.bytes_to_key(PASSWORD.as_bytes());
+    let mut reader = AeadDecryptedReader::new(Cursor::new(chunks.to_vec()), METHOD, &key, salt);
+    let mut buf = vec![];
+    let _ = block_on(reader.read_to_end(&mut buf));
+});
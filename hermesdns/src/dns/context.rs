@@ -1,6 +1,10 @@
 //! The `ServerContext in this thread holds the common state across the server
 
 use crate::dns::resolve::DnsResolver;
+use std::net::IpAddr;
+
+/// Whether a query from the client at the ip is answered, eg. by its rate of queries.
+pub type QueryFilter = Box<dyn Fn(IpAddr) -> bool + Send + Sync>;
 
 pub enum ResolveStrategy {
     Recursive,
@@ -11,6 +15,8 @@ pub struct ServerContext {
     pub listen: String,
     pub resolver: Box<dyn DnsResolver + Send + Sync>,
     pub allow_recursive: bool,
+    /// Queries of the clients it rejects are dropped without an answer.
+    pub query_filter: Option<QueryFilter>,
 }
 
 impl ServerContext {
@@ -22,8 +28,16 @@ impl ServerContext {
             listen,
             resolver,
             allow_recursive: true,
+            query_filter: None,
         }
     }
+
+    /// Whether the query from `client` is answered, see `query_filter`.
+    pub fn admits(&self, client: IpAddr) -> bool {
+        self.query_filter
+            .as_ref()
+            .map_or(true, |filter| filter(client))
+    }
 }
 
 #[cfg(test)]
@@ -100,10 +100,11 @@ impl Hash for TransientTtl {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub enum DnsRecord {
+    /// A record of a type not parsed, eg. CAA, TLSA or HTTPS, `data` is its RDATA as on the wire.
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: TransientTtl,
     }, // 0
     A {
@@ -318,12 +319,14 @@ impl DnsRecord {
                 })
             }
             QueryType::UNKNOWN(_) => {
+                let cur_pos = buffer.pos();
+                let data = buffer.get_range(cur_pos, data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl: TransientTtl(ttl),
                 })
             }
@@ -493,8 +496,20 @@ impl DnsRecord {
                 }
             }
             DnsRecord::OPT { .. } => {}
-            DnsRecord::UNKNOWN { .. } => {
-                tracing::warn!("Skipping record: {:?}", self);
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl: TransientTtl(ttl),
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
@@ -1109,4 +1124,27 @@ mod tests {
         assert_eq!(packet.answers[2], parsed_packet.answers[2]);
         assert_eq!(packet.answers[3], parsed_packet.answers[3]);
     }
+
+    #[test]
+    fn test_unknown_record_passthrough() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        // CAA 0 issue "letsencrypt.org"
+        let mut caa = vec![0, 5];
+        caa.extend_from_slice(b"issue");
+        caa.extend_from_slice(b"letsencrypt.org");
+        packet.answers.push(DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 257,
+            data: caa,
+            ttl: TransientTtl(300),
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+        buffer.seek(0).unwrap();
+
+        let parsed_packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(packet.answers, parsed_packet.answers);
+    }
 }
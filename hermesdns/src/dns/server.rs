@@ -1,11 +1,12 @@
 //! UDP and TCP server implementations for DNS
 
 use crate::dns::buffer::{BytePacketBuffer, PacketBuffer, VectorPacketBuffer};
-use crate::dns::context::ServerContext;
+use crate::dns::context::{QueryFilter, ServerContext};
 use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode};
 use crate::dns::resolve::DnsResolver;
 use async_std::net::UdpSocket;
 use async_std::task::spawn;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use tracing::Instrument;
 
@@ -141,6 +142,36 @@ pub async fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) ->
     packet
 }
 
+/// Answer the query packet `query` received other than by the server, eg. DNS traffic to other
+/// servers intercepted by seeker. Answers over TCP aren't limited to the UDP packet size. None if
+/// the query can't be parsed.
+pub async fn answer_query(
+    context: Arc<ServerContext>,
+    query: &[u8],
+    over_tcp: bool,
+) -> Option<Vec<u8>> {
+    let mut req_buffer = BytePacketBuffer::new();
+    if query.len() > req_buffer.buf.len() {
+        return None;
+    }
+    req_buffer.buf[..query.len()].copy_from_slice(query);
+    let request = DnsPacket::from_buffer(&mut req_buffer).ok()?;
+
+    let size_limit = match request.resources.first() {
+        _ if over_tcp => u16::MAX as usize,
+        Some(DnsRecord::OPT { packet_len, .. }) if request.resources.len() == 1 => {
+            *packet_len as usize
+        }
+        _ => 512,
+    };
+    let mut res_buffer = VectorPacketBuffer::new();
+    let mut packet = execute_query(context, &request).await;
+    packet.write(&mut res_buffer, size_limit).ok()?;
+    let len = res_buffer.pos();
+    res_buffer.buffer.truncate(len);
+    Some(res_buffer.buffer)
+}
+
 /// The UDP server
 ///
 /// Accepts DNS queries through UDP, and uses the `ServerContext` to determine
@@ -148,25 +179,46 @@ pub async fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) ->
 /// a new thread is spawned to service the request asynchronously.
 pub struct DnsUdpServer {
     context: Arc<ServerContext>,
+    socket: Arc<UdpSocket>,
 }
 
 impl DnsUdpServer {
-    pub async fn new(listen: String, resolver: Box<dyn DnsResolver + Send + Sync>) -> DnsUdpServer {
+    /// Create the server and bind the socket to `listen`.
+    pub async fn new(
+        listen: String,
+        resolver: Box<dyn DnsResolver + Send + Sync>,
+    ) -> std::io::Result<DnsUdpServer> {
+        let socket = UdpSocket::bind(&listen).await.map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("bind dns server to {listen} error: {e}, is the port already in use?"),
+            )
+        })?;
         let context = Arc::new(ServerContext::new(listen, resolver).await);
-        DnsUdpServer { context }
+        Ok(DnsUdpServer {
+            context,
+            socket: Arc::new(socket),
+        })
     }
 
     pub fn context(&self) -> Arc<ServerContext> {
         self.context.clone()
     }
 
+    /// Drop the queries of the clients `filter` rejects, before the server runs or its context
+    /// is shared.
+    pub fn set_query_filter(&mut self, filter: QueryFilter) {
+        Arc::get_mut(&mut self.context)
+            .expect("context is shared")
+            .query_filter = Some(filter);
+    }
+
     /// Launch the server
     ///
     /// This method takes ownership of the server, preventing the method from
     /// being called multiple times.
     pub async fn run_server(self) {
-        // Bind the socket
-        let socket = Arc::new(UdpSocket::bind(&self.context.listen).await.unwrap());
+        let socket = self.socket;
 
         loop {
             // Read a query packet
@@ -179,10 +231,14 @@ impl DnsUdpServer {
                 }
             };
 
+            if !self.context.admits(src.ip()) {
+                continue;
+            }
+
             let context = self.context.clone();
             let socket_clone = socket.clone();
             spawn(async move {
-                async move {
+                let query = async move {
                     // Parse it
                     let request = return_or_report!(
                         DnsPacket::from_buffer(&mut req_buffer),
@@ -200,7 +256,10 @@ impl DnsUdpServer {
 
                     // Create a response buffer, and ask the context for an appropriate
                     // resolver
-                    let mut res_buffer = VectorPacketBuffer::new();
+                    let mut res_buffer = VectorPacketBuffer {
+                        buffer: buffer_pool::take_vec(size_limit),
+                        ..Default::default()
+                    };
 
                     let mut packet = execute_query(context, &request).await;
                     let _ = packet.write(&mut res_buffer, size_limit);
@@ -215,9 +274,16 @@ impl DnsUdpServer {
                         socket_clone.send_to(data, src).await,
                         "Failed to send response packet"
                     );
+                    buffer_pool::recycle_vec(res_buffer.buffer);
+                }
+                .instrument(tracing::trace_span!("udp_server"));
+                // A panic answering one query must not take down the server.
+                if futures_util::FutureExt::catch_unwind(AssertUnwindSafe(query))
+                    .await
+                    .is_err()
+                {
+                    tracing::error!(%src, "dns query panicked");
                 }
-                .instrument(tracing::trace_span!("udp_server"))
-                .await
             });
         }
     }
@@ -405,4 +471,45 @@ mod tests {
             };
         });
     }
+
+    #[test]
+    fn test_answer_query() {
+        block_on(async {
+            let context = create_test_context(
+                Box::new(|qname, _, _, _| {
+                    let mut packet = DnsPacket::new();
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        addr: Ipv4Addr::new(10, 0, 0, 2),
+                        ttl: TransientTtl(3),
+                    });
+                    Ok(packet)
+                }),
+                ResolveStrategy::Forward {
+                    host: "127.0.0.1".to_string(),
+                    port: 53,
+                },
+            )
+            .await;
+
+            let mut query = build_query("google.com", QueryType::A);
+            query.header.id = 42;
+            let mut buffer = VectorPacketBuffer::new();
+            query.write(&mut buffer, 512).unwrap();
+
+            let answer = answer_query(context.clone(), &buffer.buffer, false)
+                .await
+                .unwrap();
+            let mut answer_buffer = VectorPacketBuffer {
+                buffer: answer,
+                ..Default::default()
+            };
+            let answer = DnsPacket::from_buffer(&mut answer_buffer).unwrap();
+            assert_eq!(answer.header.id, 42);
+            assert_eq!(answer.get_random_a(), Some("10.0.0.2".to_string()));
+
+            // Larger than a query can be.
+            assert!(answer_query(context, &[0; 1024], true).await.is_none());
+        });
+    }
 }
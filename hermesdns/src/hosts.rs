@@ -8,7 +8,7 @@ use std::io::Read;
 
 const HOSTS_PATH: &str = "/etc/hosts";
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Default, PartialEq, Eq)]
 pub struct Hosts {
     map: HashMap<String, Ipv4Addr>,
 }
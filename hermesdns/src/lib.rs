@@ -3,9 +3,10 @@
 mod dns;
 mod hosts;
 
+pub use dns::buffer::{BytePacketBuffer, PacketBuffer, VectorPacketBuffer};
 pub use dns::client::{DnsClient, DnsNetworkClient};
-pub use dns::context::{ResolveStrategy, ServerContext};
-pub use dns::protocol::{DnsPacket, DnsRecord, QueryType, TransientTtl};
+pub use dns::context::{QueryFilter, ResolveStrategy, ServerContext};
+pub use dns::protocol::{DnsPacket, DnsQuestion, DnsRecord, QueryType, TransientTtl};
 pub use dns::resolve::{DnsResolver, ForwardingDnsResolver, RecursiveDnsResolver};
-pub use dns::server::DnsUdpServer;
+pub use dns::server::{answer_query, DnsUdpServer};
 pub use hosts::{Hosts, LoadHostError};
@@ -0,0 +1,93 @@
+//! TLS connectors of `Https` proxies offering the cipher suites in a [`TlsCipherOrder`]. Only
+//! the order changes, the rest of the ClientHello is still the one of rustls.
+use async_tls::TlsConnector;
+use config::TlsCipherOrder;
+use rustls::ciphersuite::{
+    TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256,
+    TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256, TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+};
+use rustls::{ClientConfig, SupportedCipherSuite};
+use std::sync::Arc;
+
+pub(crate) fn connector(order: Option<TlsCipherOrder>) -> TlsConnector {
+    let Some(order) = order else {
+        return TlsConnector::default();
+    };
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config.ciphersuites = cipher_suites(order).to_vec();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Cipher suites in the order the browser offers them, without the ones rustls doesn't support.
+fn cipher_suites(order: TlsCipherOrder) -> [&'static SupportedCipherSuite; 9] {
+    match order {
+        TlsCipherOrder::Chrome => [
+            &TLS13_AES_128_GCM_SHA256,
+            &TLS13_AES_256_GCM_SHA384,
+            &TLS13_CHACHA20_POLY1305_SHA256,
+            &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ],
+        TlsCipherOrder::Firefox => [
+            &TLS13_AES_128_GCM_SHA256,
+            &TLS13_CHACHA20_POLY1305_SHA256,
+            &TLS13_AES_256_GCM_SHA384,
+            &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        ],
+        TlsCipherOrder::Safari => [
+            &TLS13_AES_128_GCM_SHA256,
+            &TLS13_AES_256_GCM_SHA384,
+            &TLS13_CHACHA20_POLY1305_SHA256,
+            &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cipher_suites() {
+        for order in [
+            TlsCipherOrder::Chrome,
+            TlsCipherOrder::Firefox,
+            TlsCipherOrder::Safari,
+        ] {
+            let suites = cipher_suites(order);
+            // Every suite rustls supports is offered once.
+            assert_eq!(suites.len(), rustls::ALL_CIPHERSUITES.len());
+            for suite in rustls::ALL_CIPHERSUITES {
+                assert_eq!(
+                    suites.iter().filter(|s| s.suite == suite.suite).count(),
+                    1,
+                    "{order:?} {:?}",
+                    suite.suite
+                );
+            }
+        }
+        assert_eq!(
+            cipher_suites(TlsCipherOrder::Firefox)[1].suite,
+            TLS13_CHACHA20_POLY1305_SHA256.suite
+        );
+    }
+}
@@ -17,7 +17,7 @@ impl HttpProxyTcpStream {
         username: Option<&str>,
         password: Option<&str>,
     ) -> Result<Self> {
-        let mut conn = TcpStream::connect(proxy_server).await?;
+        let mut conn = tcp_connection::connect(proxy_server).await?;
         let authorization = match (username, password) {
             (Some(username), Some(password)) => base64::encode(format!("{username}:{password}")),
             _ => "".to_string(),
@@ -1,9 +1,9 @@
+use crate::cipher_order;
 use async_std::io::prelude::{Read, ReadExt, Write, WriteExt};
 use async_std::net::{SocketAddr, TcpStream};
 use async_std::task::{Context, Poll};
 use async_tls::client::TlsStream;
-use async_tls::TlsConnector;
-use config::Address;
+use config::{Address, TlsCipherOrder};
 use parking_lot::Mutex;
 use std::io::Error;
 use std::io::{ErrorKind, Result};
@@ -16,15 +16,20 @@ pub struct HttpsProxyTcpStream {
 }
 
 impl HttpsProxyTcpStream {
+    /// `proxy_server_domain` is sent as the TLS server name and the certificate is checked
+    /// against it, `host` is sent as the Host header instead of `addr` if set, eg. for domain
+    /// fronting. The cipher suites are offered in `cipher_order` if set.
     pub async fn connect(
         proxy_server: SocketAddr,
         proxy_server_domain: &str,
+        host: Option<&str>,
+        cipher_order: Option<TlsCipherOrder>,
         addr: Address,
         username: Option<&str>,
         password: Option<&str>,
     ) -> Result<Self> {
-        let connector = TlsConnector::default();
-        let stream = TcpStream::connect(proxy_server).await?;
+        let connector = cipher_order::connector(cipher_order);
+        let stream = tcp_connection::connect(proxy_server).await?;
         let mut conn = connector.connect(proxy_server_domain, stream).await?;
         let authorization = match (username, password) {
             (Some(username), Some(password)) => base64::encode(format!("{username}:{password}")),
@@ -34,7 +39,10 @@ impl HttpsProxyTcpStream {
         if !authorization.is_empty() {
             req_buf.push(format!("Proxy-Authorization: basic {authorization}"));
         }
-        req_buf.push(format!("Host: {addr}"));
+        match host {
+            Some(host) => req_buf.push(format!("Host: {host}")),
+            None => req_buf.push(format!("Host: {addr}")),
+        }
         req_buf.push("\r\n".to_string());
         let req: String = req_buf.join("\r\n");
         conn.write_all(req.as_bytes()).await?;
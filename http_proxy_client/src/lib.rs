@@ -1,3 +1,4 @@
+mod cipher_order;
 mod http;
 mod https;
 
@@ -0,0 +1,131 @@
+//! Admission control of new connections.
+//!
+//! Accepting a connection spawns a task and allocates its relay buffers, under a connection
+//! storm that evicts everything else. A token bucket bounds the rate, connections over it are
+//! closed right away and the overload is recorded as an event in the store.
+use config::AdmissionConfig;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use store::Store;
+use tracing::{error, warn};
+
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            state: Mutex::new((f64::from(burst), now)),
+        }
+    }
+
+    pub(crate) fn try_acquire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        let (tokens, last) = &mut *state;
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.burst);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub(crate) struct Admission {
+    bucket: Option<TokenBucket>,
+    overloaded: AtomicBool,
+    rejected: AtomicU64,
+}
+
+impl Admission {
+    pub(crate) fn new(config: &AdmissionConfig) -> Self {
+        let bucket = config
+            .connections_per_second
+            .map(|rate| TokenBucket::new(rate, config.burst.unwrap_or(rate), Instant::now()));
+        Admission {
+            bucket,
+            overloaded: AtomicBool::new(false),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a new connection may be set up now.
+    pub(crate) fn admit(&self) -> bool {
+        let Some(bucket) = &self.bucket else {
+            return true;
+        };
+        if bucket.try_acquire(Instant::now()) {
+            if self.overloaded.swap(false, Ordering::Relaxed) {
+                let rejected = self.rejected.swap(0, Ordering::Relaxed);
+                record_overload(&format!(
+                    "overload ended, {rejected} new connections were rejected"
+                ));
+            }
+            true
+        } else {
+            let _ = self.rejected.fetch_add(1, Ordering::Relaxed);
+            if !self.overloaded.swap(true, Ordering::Relaxed) {
+                warn!("too many new connections, rejecting until the rate drops");
+                record_overload("too many new connections, rejecting until the rate drops");
+            }
+            false
+        }
+    }
+}
+
+fn record_overload(message: &str) {
+    if let Err(e) = Store::global().new_event(Store::EVENT_OVERLOAD, message) {
+        error!(?e, "record overload event error");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(10, 2, start);
+        assert!(bucket.try_acquire(start));
+        assert!(bucket.try_acquire(start));
+        assert!(!bucket.try_acquire(start));
+        assert!(!bucket.try_acquire(start + Duration::from_millis(50)));
+        assert!(bucket.try_acquire(start + Duration::from_millis(100)));
+        // Idle time doesn't accumulate more than `burst` tokens.
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.try_acquire(later));
+        assert!(bucket.try_acquire(later));
+        assert!(!bucket.try_acquire(later));
+    }
+
+    #[test]
+    fn test_admission_records_overload() {
+        Store::setup_global_for_test();
+        let admission = Admission::new(&AdmissionConfig {
+            connections_per_second: Some(1),
+            burst: Some(1),
+        });
+        assert!(admission.admit());
+        assert!(!admission.admit());
+        assert!(!admission.admit());
+        assert_eq!(admission.rejected.load(Ordering::Relaxed), 2);
+        assert!(Store::global()
+            .list_events()
+            .unwrap()
+            .iter()
+            .any(|e| e.kind == Store::EVENT_OVERLOAD));
+
+        assert!(Admission::new(&AdmissionConfig::default()).admit());
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use crypto::CipherType;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// AEAD ciphers, the ones shadowsocks servers should be using.
+const CIPHERS: &[&str] = &[
+    "aes-128-gcm",
+    "aes-256-gcm",
+    "chacha20-ietf-poly1305",
+    "xchacha20-ietf-poly1305",
+];
+
+/// Largest AEAD chunk the shadowsocks protocol allows.
+const CHUNK_SIZE: usize = 0x3FFF;
+const CHUNKS_PER_ROUND: usize = 64;
+
+/// Measure the encryption and decryption throughput of each AEAD cipher on this machine for
+/// about `duration`, and write the results to `output`.
+pub(crate) fn run(duration: Duration, mut output: impl Write) -> Result<()> {
+    let features = crypto::cpu::accelerated_features();
+    if features.is_empty() {
+        writeln!(output, "CPU acceleration: none detected")?;
+    } else {
+        writeln!(output, "CPU acceleration: {}", features.join(", "))?;
+    }
+
+    let mut fastest: Option<(CipherType, f64)> = None;
+    for name in CIPHERS {
+        // Not compiled in
+        let Ok(cipher) = name.parse::<CipherType>() else {
+            continue;
+        };
+        let (encrypt, decrypt) = bench(cipher, duration);
+        writeln!(
+            output,
+            "{name:<24}encrypt {encrypt:>9.1} MB/s  decrypt {decrypt:>9.1} MB/s"
+        )?;
+        let throughput = encrypt.min(decrypt);
+        if !matches!(fastest, Some((_, f)) if f >= throughput) {
+            fastest = Some((cipher, throughput));
+        }
+    }
+    if let Some((cipher, _)) = fastest {
+        writeln!(output, "Fastest: {cipher}")?;
+    }
+    Ok(())
+}
+
+/// Returns the encryption and decryption throughput of `cipher` in MB/s.
+fn bench(cipher: CipherType, duration: Duration) -> (f64, f64) {
+    let key = cipher.bytes_to_key(b"seeker-bench-ciphers");
+    let salt = cipher.gen_salt();
+    let mut encryptor = crypto::new_aead_encryptor(cipher, &key, &salt);
+    let mut decryptor = crypto::new_aead_decryptor(cipher, &key, &salt);
+    let plain = vec![0x5a; CHUNK_SIZE];
+    let mut sealed = vec![vec![0; CHUNK_SIZE + cipher.tag_size()]; CHUNKS_PER_ROUND];
+    let mut opened = vec![0; CHUNK_SIZE];
+
+    let mut encrypt_time = Duration::ZERO;
+    let mut decrypt_time = Duration::ZERO;
+    let mut bytes = 0;
+    while encrypt_time + decrypt_time < duration {
+        let start = Instant::now();
+        for chunk in &mut sealed {
+            encryptor.encrypt(&plain, chunk);
+        }
+        let encrypted = Instant::now();
+        for chunk in &sealed {
+            decryptor
+                .decrypt(chunk, &mut opened)
+                .expect("decrypt chunk encrypted with the same key");
+        }
+        encrypt_time += encrypted - start;
+        decrypt_time += encrypted.elapsed();
+        bytes += CHUNK_SIZE * CHUNKS_PER_ROUND;
+    }
+    let mb_per_sec = |time: Duration| bytes as f64 / 1e6 / time.as_secs_f64().max(f64::EPSILON);
+    (mb_per_sec(encrypt_time), mb_per_sec(decrypt_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let mut output = vec![];
+        run(Duration::from_millis(1), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("CPU acceleration: "));
+        assert!(output.contains("aes-128-gcm"));
+        assert!(output.contains("chacha20-ietf-poly1305"));
+        assert!(output.lines().last().unwrap().starts_with("Fastest: "));
+    }
+}
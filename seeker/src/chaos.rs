@@ -0,0 +1,294 @@
+//! Degraded networks on purpose, the latency, jitter and loss of the matching `chaos` entry are
+//! added to relayed connections, eg. to see how apps behave over a slow server or to make a
+//! server fail over in tests.
+use crate::proxy_connection::ProxyConnection;
+use async_io::Timer;
+use async_std::io::{Read, Write};
+use async_std::task::ready;
+use config::rule::Action;
+use config::{Address, ChaosConfig, Config};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{IoSlice, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Minimum retransmission timeout of linux, what a lost TCP segment costs at least.
+const RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+const READ_SIZE: usize = 16 * 1024;
+/// Bytes read ahead while waiting for the delay of the data before them.
+const MAX_QUEUED: usize = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Fault {
+    latency: Duration,
+    jitter: Duration,
+    loss: f64,
+}
+
+impl Fault {
+    fn new(config: &ChaosConfig) -> Self {
+        Fault {
+            latency: config.latency,
+            jitter: config.jitter.min(config.latency),
+            loss: config.loss,
+        }
+    }
+
+    /// The latency with a random jitter.
+    pub(crate) fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+        let jitter = rand::thread_rng().gen_range(0..=2 * self.jitter.as_micros() as u64);
+        self.latency - self.jitter + Duration::from_micros(jitter)
+    }
+
+    /// Whether a datagram is lost.
+    pub(crate) fn drops(&self) -> bool {
+        self.loss > 0.0 && rand::thread_rng().gen_bool(self.loss.min(1.0))
+    }
+}
+
+/// The fault of the first `chaos` entry matching `conn`, None for the usual connections.
+pub(crate) fn fault_for(config: &Config, conn: &dyn ProxyConnection) -> Option<Fault> {
+    let server = conn.config().map(|server| server.name());
+    let domain = match conn.remote_addr() {
+        Some(Address::DomainNameAddress(domain, _)) => Some(domain.as_str()),
+        _ => None,
+    };
+    config
+        .chaos
+        .iter()
+        .find(|chaos| is_match(chaos, conn.action(), server, domain))
+        .map(Fault::new)
+}
+
+fn is_match(
+    chaos: &ChaosConfig,
+    action: Action,
+    server: Option<&str>,
+    domain: Option<&str>,
+) -> bool {
+    if chaos.action.map_or(false, |a| a != action) {
+        return false;
+    }
+    if chaos.server.is_some() && chaos.server.as_deref() != server {
+        return false;
+    }
+    match (&chaos.domain, domain) {
+        (None, _) => true,
+        (Some(suffix), Some(domain)) => {
+            domain == suffix
+                || domain
+                    .strip_suffix(suffix.as_str())
+                    .map_or(false, |parent| parent.ends_with('.'))
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// A stream whose data is read only once the delay of the fault passed, in the order it came.
+/// Writes go through as they are, wrap both sides of a relay to delay both directions.
+pub(crate) struct ChaosStream<S> {
+    inner: S,
+    fault: Fault,
+    /// Chunks read and when they can be handed out, an empty one is the EOF.
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    queued: usize,
+    eof: bool,
+    last_ready_at: Instant,
+    timer: Option<Timer>,
+}
+
+impl<S> ChaosStream<S> {
+    pub(crate) fn new(inner: S, fault: Fault) -> Self {
+        ChaosStream {
+            inner,
+            fault,
+            queue: VecDeque::new(),
+            queued: 0,
+            eof: false,
+            last_ready_at: Instant::now(),
+            timer: None,
+        }
+    }
+
+    /// When data read now is handed out, never before the data read earlier.
+    fn ready_at(&mut self) -> Instant {
+        let mut delay = self.fault.delay();
+        if self.fault.drops() {
+            delay += RETRANSMIT_DELAY;
+        }
+        self.last_ready_at = self.last_ready_at.max(Instant::now() + delay);
+        self.last_ready_at
+    }
+}
+
+/// Clones share the stream but not the data read ahead, the relay reads from one of them only.
+impl<S: Clone> Clone for ChaosStream<S> {
+    fn clone(&self) -> Self {
+        ChaosStream::new(self.inner.clone(), self.fault)
+    }
+}
+
+impl<S: Read + Unpin> Read for ChaosStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let this = &mut *self;
+        // Take in what's readable now, its delay starts when it's read.
+        while !this.eof && this.queued < MAX_QUEUED {
+            let mut chunk = vec![0; READ_SIZE];
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(size)) => {
+                    chunk.truncate(size);
+                    this.eof = size == 0;
+                    this.queued += size;
+                    let ready_at = this.ready_at();
+                    this.queue.push_back((ready_at, chunk));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            let Some((ready_at, data)) = this.queue.front_mut() else {
+                return Poll::Pending;
+            };
+            if *ready_at > Instant::now() {
+                let timer = this.timer.get_or_insert_with(|| Timer::at(*ready_at));
+                timer.set_at(*ready_at);
+                ready!(Pin::new(timer).poll(cx));
+                continue;
+            }
+            this.timer = None;
+            // The EOF is kept, later reads get it too.
+            if data.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let size = data.len().min(buf.len());
+            buf[..size].copy_from_slice(&data[..size]);
+            data.drain(..size);
+            if data.is_empty() {
+                this.queue.pop_front();
+            }
+            this.queued -= size;
+            return Poll::Ready(Ok(size));
+        }
+    }
+}
+
+impl<S: Write + Unpin> Write for ChaosStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{Cursor, ReadExt};
+    use async_std::task::block_on;
+
+    fn with_latency(latency_ms: u64) -> ChaosConfig {
+        ChaosConfig {
+            latency: Duration::from_millis(latency_ms),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let chaos = ChaosConfig {
+            action: Some(Action::Proxy),
+            domain: Some("example.com".to_string()),
+            ..with_latency(10)
+        };
+        assert!(is_match(
+            &chaos,
+            Action::Proxy,
+            Some("ss"),
+            Some("example.com")
+        ));
+        assert!(is_match(
+            &chaos,
+            Action::Proxy,
+            None,
+            Some("www.example.com")
+        ));
+        assert!(!is_match(
+            &chaos,
+            Action::Proxy,
+            None,
+            Some("badexample.com")
+        ));
+        assert!(!is_match(&chaos, Action::Direct, None, Some("example.com")));
+        assert!(!is_match(&chaos, Action::Proxy, None, None));
+
+        let chaos = ChaosConfig {
+            server: Some("ss".to_string()),
+            ..with_latency(10)
+        };
+        assert!(is_match(&chaos, Action::Proxy, Some("ss"), None));
+        assert!(!is_match(&chaos, Action::Proxy, Some("other"), None));
+        assert!(!is_match(&chaos, Action::Direct, None, None));
+    }
+
+    #[test]
+    fn test_delay() {
+        let fault = Fault::new(&ChaosConfig {
+            jitter: Duration::from_millis(20),
+            ..with_latency(100)
+        });
+        for _ in 0..100 {
+            let delay = fault.delay();
+            assert!(delay >= Duration::from_millis(80) && delay <= Duration::from_millis(120));
+        }
+        assert!(!fault.drops());
+        assert!(Fault::new(&ChaosConfig {
+            loss: 1.0,
+            ..with_latency(0)
+        })
+        .drops());
+    }
+
+    #[test]
+    fn test_chaos_stream() {
+        block_on(async {
+            let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+            let fault = Fault::new(&with_latency(50));
+            let mut stream = ChaosStream::new(Cursor::new(data.clone()), fault);
+            let start = Instant::now();
+            let mut read = vec![];
+            stream.read_to_end(&mut read).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(50));
+            assert_eq!(read, data);
+            assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0);
+        });
+    }
+}
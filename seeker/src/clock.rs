@@ -0,0 +1,177 @@
+//! Checks of the system clock at startup and periodically. AEAD ciphers and TLS handshakes fail
+//! confusingly when it's wrong, eg. on routers without an RTC booting in 1970, so a wrong clock
+//! is reported as what it is.
+use crate::dns_client::DnsClient;
+use async_std::future::pending;
+use async_std::io::timeout;
+use async_std::net::UdpSocket;
+use async_std::task;
+use config::{Address, NtpConfig};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use store::Store;
+use tracing::{debug, info, warn};
+
+const NTP_PORT: u16 = 123;
+/// Seconds from 1900, where NTP timestamps start, to 1970.
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+/// 2022-10-01, a clock before it is wrong whatever the NTP servers say.
+const MIN_UNIX_TIME: f64 = 1_664_582_400.0;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Check the clock now and every `check_interval`.
+pub(crate) async fn watch(config: NtpConfig, dns_client: DnsClient) {
+    loop {
+        check(&config, &dns_client).await;
+        if config.check_interval.is_zero() {
+            return pending().await;
+        }
+        task::sleep(config.check_interval).await;
+    }
+}
+
+/// Compare the clock with the first NTP server answering.
+async fn check(config: &NtpConfig, dns_client: &DnsClient) {
+    let now = unix_now();
+    if now < MIN_UNIX_TIME {
+        report(format!(
+            "system clock is {now:.0}s since 1970, which is surely wrong, \
+             encrypted connections will fail until it's synced"
+        ));
+    }
+    for server in &config.servers {
+        let addr = match server.parse::<IpAddr>() {
+            Ok(ip) => Address::SocketAddress((ip, NTP_PORT).into()),
+            Err(_) => Address::DomainNameAddress(server.clone(), NTP_PORT),
+        };
+        let ret = match dns_client.lookup_address(&addr).await {
+            Ok(addr) => query_offset(addr, QUERY_TIMEOUT).await,
+            Err(e) => Err(e),
+        };
+        match ret {
+            Ok(offset) if offset.abs() > config.max_offset.as_secs_f64() => {
+                report(format!(
+                    "system clock is {offset:+.1}s off from {server}, sync it with NTP, \
+                     encrypted connections may fail"
+                ));
+                return;
+            }
+            Ok(offset) => {
+                debug!(server, offset, "clock checked");
+                return;
+            }
+            Err(e) => debug!(server, ?e, "query ntp server error"),
+        }
+    }
+    info!("no ntp server answered, clock not checked");
+}
+
+fn report(message: String) {
+    warn!("{}", message);
+    let _ = Store::global().new_event(Store::EVENT_CLOCK, &message);
+}
+
+/// Seconds of the clock ahead of `server`, negative if it's behind, by SNTP (RFC 4330).
+async fn query_offset(server: SocketAddr, timeout_duration: Duration) -> io::Result<f64> {
+    let socket = UdpSocket::bind(match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })
+    .await?;
+    let mut request = [0u8; 48];
+    // No leap second warning, version 3, client mode.
+    request[0] = 0x1b;
+    let sent = ntp_now();
+    request[40..48].copy_from_slice(&sent.to_be_bytes());
+    socket.send_to(&request, server).await?;
+
+    let mut response = [0u8; 48];
+    let (size, _) = timeout(timeout_duration, socket.recv_from(&mut response)).await?;
+    let received = ntp_now();
+    let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, format!("{server}: {msg}"));
+    if size < response.len() || response[0] & 0x7 != 4 {
+        return Err(invalid("not an ntp server response"));
+    }
+    // Stratum 0 is a kiss-o'-death packet, eg. the client is rate limited.
+    if response[1] == 0 {
+        return Err(invalid("ntp server refused"));
+    }
+    if response[24..32] != sent.to_be_bytes() {
+        return Err(invalid("response to another request"));
+    }
+
+    let t1 = ntp_seconds(sent);
+    let t2 = ntp_seconds(read_timestamp(&response[32..40]));
+    let t3 = ntp_seconds(read_timestamp(&response[40..48]));
+    let t4 = ntp_seconds(received);
+    // The server is ahead by `((t2 - t1) + (t3 - t4)) / 2`.
+    Ok(((t1 - t2) + (t4 - t3)) / 2.0)
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// The clock as an NTP timestamp, seconds since 1900 in the high 32 bits and the fraction in the
+/// low 32 bits.
+fn ntp_now() -> u64 {
+    ntp_timestamp(unix_now() + NTP_UNIX_OFFSET)
+}
+
+fn ntp_timestamp(seconds: f64) -> u64 {
+    ((seconds.trunc() as u64) << 32) | (seconds.fract() * (1u64 << 32) as f64) as u64
+}
+
+fn ntp_seconds(timestamp: u64) -> f64 {
+    (timestamp >> 32) as f64 + (timestamp & 0xffff_ffff) as f64 / (1u64 << 32) as f64
+}
+
+fn read_timestamp(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(data);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    /// An NTP server whose clock is ahead by `offset` seconds.
+    async fn serve_ntp(socket: UdpSocket, offset: f64) {
+        let mut request = [0u8; 48];
+        while let Ok((_, client)) = socket.recv_from(&mut request).await {
+            let now = ntp_timestamp(ntp_seconds(ntp_now()) + offset);
+            let mut response = [0u8; 48];
+            // Version 3, server mode, stratum 2.
+            response[0] = 0x1c;
+            response[1] = 2;
+            response[24..32].copy_from_slice(&request[40..48]);
+            response[32..40].copy_from_slice(&now.to_be_bytes());
+            response[40..48].copy_from_slice(&now.to_be_bytes());
+            let _ = socket.send_to(&response, client).await;
+        }
+    }
+
+    #[test]
+    fn test_query_offset() {
+        block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            task::spawn(serve_ntp(socket, 100.0));
+
+            let offset = query_offset(addr, QUERY_TIMEOUT).await.unwrap();
+            assert!((offset + 100.0).abs() < 1.0, "offset {offset}");
+        });
+    }
+
+    #[test]
+    fn test_ntp_timestamp() {
+        let now = ntp_now();
+        assert!((ntp_seconds(now) - NTP_UNIX_OFFSET - unix_now()).abs() < 1.0);
+        assert!(unix_now() > MIN_UNIX_TIME);
+    }
+}
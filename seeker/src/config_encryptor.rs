@@ -1,5 +1,6 @@
 use anyhow::Context;
 use bytes::BytesMut;
+use config::ENCRYPTED_SECRET_PREFIX;
 use crypto::CipherType;
 use ssclient::{decrypt_payload, encrypt_payload};
 use std::io::Read;
@@ -46,9 +47,30 @@ pub fn encrypt_config<R: Read>(
     Ok(content)
 }
 
+/// Encrypt a single secret value, eg. a server password, to `enc:BASE64`.
+pub fn encrypt_secret(
+    secret: &str,
+    cipher_type: CipherType,
+    encrypt_key: &str,
+) -> anyhow::Result<String> {
+    let content = encrypt_config(secret.as_bytes(), cipher_type, encrypt_key)?;
+    Ok(format!("{ENCRYPTED_SECRET_PREFIX}{content}"))
+}
+
+/// Decrypt a secret value produced by [`encrypt_secret`], without the `enc:` prefix.
+pub fn decrypt_secret(
+    encrypted: &str,
+    cipher_type: CipherType,
+    decrypt_key: &str,
+) -> anyhow::Result<String> {
+    let plain = decrypt_config(encrypted.as_bytes(), cipher_type, decrypt_key)?;
+    String::from_utf8(plain).context("secret is not valid utf8")
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::config_encryptor::{decrypt_config, encrypt_config};
+    use crate::config_encryptor::{decrypt_config, decrypt_secret, encrypt_config, encrypt_secret};
+    use config::ENCRYPTED_SECRET_PREFIX;
     use crypto::CipherType;
 
     #[test]
@@ -58,4 +80,12 @@ mod tests {
         let d = decrypt_config(e.as_bytes(), CipherType::ChaCha20Ietf, "test").unwrap();
         assert_eq!(content, String::from_utf8(d).unwrap());
     }
+
+    #[test]
+    fn test_secret() {
+        let e = encrypt_secret("password", CipherType::ChaCha20Ietf, "test").unwrap();
+        let encrypted = e.strip_prefix(ENCRYPTED_SECRET_PREFIX).unwrap();
+        let d = decrypt_secret(encrypted, CipherType::ChaCha20Ietf, "test").unwrap();
+        assert_eq!(d, "password");
+    }
 }
@@ -0,0 +1,185 @@
+use crate::dns_client::DnsClient;
+use async_std::io::timeout;
+use config::{ConnectionPoolConfig, ServerConfig, ServerProtocol};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tcp_connection::TcpConnection;
+
+/// Connect to a shadowsocks server, with obfs if configured.
+pub(crate) async fn connect_server(
+    config: &ServerConfig,
+    addr: SocketAddr,
+) -> Result<TcpConnection> {
+    match config.obfs() {
+        Some(obfs) => TcpConnection::connect_obfs(addr, obfs.host.clone(), obfs.mode).await,
+        None => TcpConnection::connect_tcp(addr).await,
+    }
+}
+
+/// Idle connections to the selected shadowsocks server, established ahead of time.
+///
+/// Connections to a previously selected server are dropped when the selected server changes.
+pub(crate) struct ConnectionPool {
+    config: ConnectionPoolConfig,
+    inner: Mutex<PoolInner>,
+    refilling: AtomicBool,
+}
+
+#[derive(Default)]
+struct PoolInner {
+    server: Option<ServerConfig>,
+    idle: VecDeque<(TcpConnection, Instant)>,
+}
+
+impl PoolInner {
+    /// Drop expired connections and connections to other servers.
+    fn retain(&mut self, server: &ServerConfig, idle_timeout: Duration) {
+        if self.server.as_ref() != Some(server) {
+            self.server = Some(server.clone());
+            self.idle.clear();
+        }
+        self.idle
+            .retain(|(_, created)| created.elapsed() < idle_timeout);
+    }
+}
+
+/// Clears the refilling flag even if the refill is cancelled.
+struct RefillGuard<'a>(&'a AtomicBool);
+
+impl Drop for RefillGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(config: ConnectionPoolConfig) -> Self {
+        ConnectionPool {
+            config,
+            inner: Mutex::new(PoolInner::default()),
+            refilling: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.config.size > 0
+    }
+
+    /// Drop all idle connections.
+    pub(crate) fn clear(&self) {
+        self.inner.lock().idle.clear();
+    }
+
+    /// Take an idle connection to `server`, the oldest one first.
+    pub(crate) fn take(&self, server: &ServerConfig) -> Option<TcpConnection> {
+        let mut inner = self.inner.lock();
+        inner.retain(server, self.config.idle_timeout);
+        inner.idle.pop_front().map(|(conn, _)| conn)
+    }
+
+    /// Connect to `server` until there are `size` idle connections. Only one refill runs at a
+    /// time, concurrent calls return immediately. Only shadowsocks servers are pooled, other
+    /// protocols need the target address for the handshake.
+    pub(crate) async fn refill(
+        &self,
+        server: &ServerConfig,
+        dns_client: &DnsClient,
+        connect_timeout: Duration,
+    ) -> Result<()> {
+        if !self.is_enabled()
+            || server.protocol() != ServerProtocol::Shadowsocks
+            || self.refilling.swap(true, Ordering::AcqRel)
+        {
+            return Ok(());
+        }
+        let _guard = RefillGuard(&self.refilling);
+        self.do_refill(server, dns_client, connect_timeout).await
+    }
+
+    async fn do_refill(
+        &self,
+        server: &ServerConfig,
+        dns_client: &DnsClient,
+        connect_timeout: Duration,
+    ) -> Result<()> {
+        let missing = {
+            let mut inner = self.inner.lock();
+            inner.retain(server, self.config.idle_timeout);
+            self.config.size.saturating_sub(inner.idle.len())
+        };
+        for _ in 0..missing {
+            let addr = dns_client.lookup_server(server.addr()).await?;
+            let conn = timeout(connect_timeout, connect_server(server, addr)).await?;
+            let mut inner = self.inner.lock();
+            if inner.server.as_ref() != Some(server) {
+                break;
+            }
+            inner.idle.push_back((conn, Instant::now()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::prelude::*;
+    use async_std::task::spawn;
+    use config::{Address, DnsServerAddr};
+
+    fn server(name: &str, addr: SocketAddr) -> ServerConfig {
+        ServerConfig::new(
+            name.to_string(),
+            Address::SocketAddress(addr),
+            ServerProtocol::Shadowsocks,
+            None,
+            Some("password".to_string()),
+            None,
+            None,
+        )
+    }
+
+    #[async_std::test]
+    async fn test_connection_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            let mut conns = vec![];
+            while let Some(Ok(conn)) = listener.incoming().next().await {
+                conns.push(conn);
+            }
+        });
+        // Servers are ip addresses, so nothing is resolved.
+        let dns_client = DnsClient::new(
+            &[DnsServerAddr::UdpSocketAddr(
+                "127.0.0.1:53".parse().unwrap(),
+            )],
+            Duration::from_secs(1),
+        )
+        .await;
+        let pool = ConnectionPool::new(ConnectionPoolConfig {
+            size: 2,
+            idle_timeout: Duration::from_secs(10),
+        });
+        let server1 = server("ss1", addr);
+        let connect_timeout = Duration::from_secs(1);
+        pool.refill(&server1, &dns_client, connect_timeout)
+            .await
+            .unwrap();
+        assert!(pool.take(&server1).is_some());
+        assert!(pool.take(&server1).is_some());
+        assert!(pool.take(&server1).is_none());
+
+        pool.refill(&server1, &dns_client, connect_timeout)
+            .await
+            .unwrap();
+        // Switching servers drops the idle connections.
+        assert!(pool.take(&server("ss2", addr)).is_none());
+        assert!(pool.take(&server1).is_none());
+    }
+}
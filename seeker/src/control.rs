@@ -0,0 +1,449 @@
+//! Control socket for `seeker ctl`. A unix domain socket only the user running seeker can
+//! connect to, so it needs no other authentication.
+//!
+//! Each frame is a big endian `u32` length followed by the payload. A request is one frame with
+//! the command and its arguments on separate lines, the response is one frame with a status
+//! byte, `0` for success, followed by the output.
+//!
+//! Actions changing what the proxy does are recorded in the audit log of the store with where
+//! they came from, the uid and pid of the `seeker ctl` process or `api` for embedding programs.
+use crate::network_monitor::NetworkReset;
+use crate::runtime::Mode;
+use crate::server_chooser::ServerChooser;
+use anyhow::{bail, Context};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::prelude::*;
+use async_std::task::{sleep, spawn};
+use config::rule::{Action, ProxyRules};
+use config::Config;
+use std::fmt::Write as _;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use store::Store;
+use tracing::{info, warn};
+
+/// Requests larger than this are rejected, they are a few words.
+const MAX_REQUEST_LEN: usize = 4096;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+/// Entries `seeker ctl audit` prints.
+const AUDIT_LIMIT: usize = 100;
+/// Wait after failing to accept a connection.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+/// Source of the actions of programs embedding seeker, see `ProxyRuntime`.
+pub(crate) const SOURCE_API: &str = "api";
+
+/// What the control commands act on.
+pub struct Controller {
+    pub(crate) server_chooser: Arc<ServerChooser>,
+    pub(crate) network_reset: NetworkReset,
+    pub(crate) rules: ProxyRules,
+    pub(crate) mode: Mode,
+    /// Config file `seeker ctl reload` reads the rules from, None if seeker wasn't started with
+    /// one, eg. with `--config-url`.
+    pub(crate) config_file: Option<ConfigFile>,
+}
+
+/// Config file and profile seeker was started with.
+#[derive(Debug, Clone)]
+pub(crate) struct ConfigFile {
+    pub(crate) path: String,
+    pub(crate) profile: Option<String>,
+}
+
+impl Controller {
+    /// Read the rules from `path` with `profile` on `seeker ctl reload`.
+    pub fn with_config_file(mut self, path: impl Into<String>, profile: Option<String>) -> Self {
+        self.config_file = Some(ConfigFile {
+            path: path.into(),
+            profile,
+        });
+        self
+    }
+
+    /// Send new connections direct without tearing down the TUN device, eg. from a tray menu.
+    /// Connections through the servers and the UDP sessions are closed, so apps reconnect
+    /// directly.
+    pub(crate) fn pause(&self, source: &str) {
+        if self.rules.is_paused() {
+            return;
+        }
+        audit(source, "pause", "");
+        self.rules.set_paused(true);
+        let count = self.server_chooser.kill_connections(Action::Proxy);
+        info!(count, "Paused, close proxied connections");
+        self.network_reset.close_udp_sessions();
+    }
+
+    /// Match new connections against the rules again. Direct connections made while paused are
+    /// kept until they close.
+    pub(crate) fn resume(&self, source: &str) {
+        if !self.rules.is_paused() {
+            return;
+        }
+        audit(source, "resume", "");
+        self.rules.set_paused(false);
+        info!("Resumed");
+        self.network_reset.close_udp_sessions();
+    }
+
+    pub(crate) fn reset_network(&self, source: &str) {
+        audit(source, "reset", "");
+        self.network_reset.reset();
+    }
+
+    pub(crate) fn reload_rules(&self, rules: &ProxyRules, source: &str) {
+        audit(source, "reload_rules", &format!("{} rules", rules.len()));
+        self.rules.replace(rules);
+    }
+
+    /// Read the config file again and replace the rules with its rules, the other settings are
+    /// left as they are until seeker restarts. Returns the number of rules.
+    pub(crate) fn reload(&self, source: &str) -> std::result::Result<usize, String> {
+        let Some(file) = &self.config_file else {
+            return Err("seeker wasn't started with a config file, nothing to reload".to_string());
+        };
+        let config = Config::from_config_file_with_profile(&file.path, file.profile.as_deref())
+            .map_err(|e| format!("load config {} error: {e}", file.path))?;
+        self.reload_rules(&config.rules, source);
+        Ok(config.rules.len())
+    }
+
+    fn execute(&self, request: &str, source: &str) -> std::result::Result<String, String> {
+        let mut args = request.lines();
+        let mut out = String::new();
+        match (args.next().unwrap_or_default(), args.next()) {
+            ("connections", None) => {
+                self.server_chooser.for_each_live_connection(|conn| {
+                    let mut remote = conn
+                        .remote_addr()
+                        .map(|addr| addr.to_string())
+                        .unwrap_or_default();
+                    if let Some(host) = conn.sniffed_host() {
+                        let _ = write!(remote, " ({host})");
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{}\t{}\t{}\t{}\tsent: {}\trecv: {}\t{}s",
+                        conn.id(),
+                        conn.network(),
+                        conn.action(),
+                        remote,
+                        conn.sent_bytes(),
+                        conn.recv_bytes(),
+                        conn.duration().as_secs()
+                    );
+                });
+            }
+            ("servers", None) => {
+                let (selected, candidates) = self.server_chooser.servers();
+                for server in candidates {
+                    let mark = if server == selected { "*" } else { " " };
+                    let _ = writeln!(out, "{mark} {}\t{}", server.name(), server.addr());
+                }
+            }
+            ("switch", Some(name)) => {
+                if !self.server_chooser.select_server(name) {
+                    return Err(format!("server {name} not found"));
+                }
+                audit(source, "switch", name);
+            }
+            ("kill", Some(id)) => {
+                let id = id
+                    .parse()
+                    .map_err(|_| format!("invalid connection id {id}"))?;
+                if !self.server_chooser.kill_connection(id) {
+                    return Err(format!("connection {id} not found"));
+                }
+                audit(source, "kill", &id.to_string());
+            }
+            ("reset", None) => self.reset_network(source),
+            ("status", None) => {
+                let (selected, _) = self.server_chooser.servers();
+                let _ = writeln!(out, "mode: {}", self.mode);
+                let _ = writeln!(out, "paused: {}", self.rules.is_paused());
+                let _ = writeln!(out, "server: {}", selected.name());
+                if let Ok(fake_ips) = Store::global().fake_ip_stats() {
+                    let _ = writeln!(
+                        out,
+                        "fake ips: {}/{} ({:.1}%), {} allocated since start",
+                        fake_ips.used,
+                        fake_ips.size,
+                        fake_ips.utilization() * 100.0,
+                        fake_ips.allocated
+                    );
+                }
+            }
+            ("reload", None) => {
+                let count = self.reload(source)?;
+                let _ = writeln!(out, "reloaded {count} rules");
+            }
+            ("pause", None) => self.pause(source),
+            ("resume", None) => self.resume(source),
+            ("usage", None) => {
+                let usage = Store::global()
+                    .monthly_usage(store::now())
+                    .map_err(|e| format!("read usage error: {e}"))?;
+                let elapsed = (usage.time - usage.start) as f64 / (usage.end - usage.start) as f64;
+                let _ = writeln!(
+                    out,
+                    "month: {}, {:.0}% elapsed",
+                    usage.month,
+                    elapsed * 100.0
+                );
+                for server in &usage.servers {
+                    let _ = writeln!(
+                        out,
+                        "{}\tsent: {}\trecv: {}\ttotal: {}\tprojected: {}",
+                        server.server,
+                        format_bytes(server.sent_bytes),
+                        format_bytes(server.recv_bytes),
+                        format_bytes(server.total_bytes()),
+                        format_bytes(usage.projected(server.total_bytes()))
+                    );
+                }
+            }
+            ("audit", None) => {
+                let entries = Store::global()
+                    .list_audit_log(AUDIT_LIMIT)
+                    .map_err(|e| format!("read audit log error: {e}"))?;
+                for entry in entries {
+                    let _ = writeln!(
+                        out,
+                        "{}\t{}\t{}\t{}",
+                        entry.time, entry.source, entry.action, entry.detail
+                    );
+                }
+            }
+            (command, _) => return Err(format!("invalid command `{command}`")),
+        }
+        Ok(out)
+    }
+}
+
+/// `bytes` in the largest binary unit keeping it at least 1, eg. `1.5 GiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Record a control action in the audit log, failing to doesn't fail the action.
+fn audit(source: &str, action: &str, detail: &str) {
+    info!(source, action, detail, "Control action");
+    if let Err(e) = Store::global().new_audit_entry(source, action, detail) {
+        warn!(%e, action, "Record audit log error");
+    }
+}
+
+/// Where requests on the control connection `stream` come from, the process on the other end.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_source(stream: &impl AsRawFd) -> String {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    match getsockopt(stream.as_raw_fd(), PeerCredentials) {
+        Ok(cred) => format!("ctl uid={} pid={}", cred.uid(), cred.pid()),
+        Err(_) => "ctl".to_string(),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_source(_stream: &impl AsRawFd) -> String {
+    "ctl".to_string()
+}
+
+/// Serve control requests on the unix socket passed by systemd socket activation, or else on
+/// `path`, until the listener is closed. A socket left by a previous run is replaced.
+pub async fn serve(path: &str, controller: Controller) -> Result<()> {
+    let (listener, _socket_file) = match activated_listener() {
+        Some(listener) => {
+            info!("Control socket passed by systemd");
+            (listener, None)
+        }
+        None => {
+            let listener = bind(path)?;
+            info!(path, "Control socket listening");
+            (listener, Some(SocketFile(path)))
+        }
+    };
+    let controller = Arc::new(controller);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Eg. out of file descriptors, back off instead of spinning.
+                warn!(?e, "Accept control connection error");
+                sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let controller = controller.clone();
+        spawn(async move {
+            if let Err(e) = handle(stream, &controller).await {
+                warn!(?e, "Control connection error");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Bind `path` created with mode 0600, other users can't connect even right after it's bound.
+fn bind(path: &str) -> Result<UnixListener> {
+    let _ = std::fs::remove_file(path);
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = std::os::unix::net::UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    Ok(listener?.into())
+}
+
+/// The first unix stream socket passed by systemd socket activation, its permissions are set
+/// by the socket unit.
+fn activated_listener() -> Option<UnixListener> {
+    use nix::sys::socket::{getsockopt, sockopt, SockType};
+    sysconfig::listen_fds().into_iter().find_map(|fd| {
+        let is_stream = getsockopt(fd, sockopt::SockType).ok() == Some(SockType::Stream);
+        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        if is_stream && listener.local_addr().is_ok() {
+            Some(listener.into())
+        } else {
+            // Not ours, keep it open.
+            let _ = listener.into_raw_fd();
+            None
+        }
+    })
+}
+
+/// Removes the socket file when serving stops.
+struct SocketFile<'a>(&'a str);
+
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.0);
+    }
+}
+
+async fn handle(mut stream: UnixStream, controller: &Controller) -> Result<()> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_REQUEST_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "request too large"));
+    }
+    let mut request = vec![0; len];
+    stream.read_exact(&mut request).await?;
+    let source = peer_source(&stream);
+    let (status, output) = match std::str::from_utf8(&request) {
+        Ok(request) => match controller.execute(request, &source) {
+            Ok(output) => (STATUS_OK, output),
+            Err(e) => (STATUS_ERROR, e),
+        },
+        Err(_) => (STATUS_ERROR, "request is not utf8".to_string()),
+    };
+    stream.write_all(&encode(status, output.as_bytes())).await?;
+    Ok(())
+}
+
+fn encode(status: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.extend(((payload.len() + 1) as u32).to_be_bytes());
+    frame.push(status);
+    frame.extend(payload);
+    frame
+}
+
+/// Send `args` to the seeker listening on `path` and print its output.
+pub fn request(path: &str, args: &[&str], mut output: impl Write) -> anyhow::Result<()> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)
+        .with_context(|| format!("Connect to control socket {path} error, is seeker running?"))?;
+    let request = args.join("\n");
+    stream.write_all(&(request.len() as u32).to_be_bytes())?;
+    stream.write_all(request.as_bytes())?;
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    match response.split_first() {
+        Some((&STATUS_OK, out)) => Ok(output.write_all(out)?),
+        Some((_, e)) => bail!("{}", String::from_utf8_lossy(e)),
+        None => bail!("Empty response"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let path = std::env::temp_dir().join(format!("seeker-ctl-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            for reply in [
+                encode(STATUS_OK, b"1\ttcp\n"),
+                encode(STATUS_ERROR, b"not found"),
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut request = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut request).unwrap();
+                assert_eq!(request, b"kill\n1");
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        let path = path.to_str().unwrap();
+        let mut out = vec![];
+        request(path, &["kill", "1"], &mut out).unwrap();
+        assert_eq!(out, b"1\ttcp\n");
+        let e = request(path, &["kill", "1"], &mut out).unwrap_err();
+        assert_eq!(e.to_string(), "not found");
+        server.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bind() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("seeker-bind-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _listener = bind(path).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_peer_source() {
+        let (conn, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert_eq!(
+            peer_source(&conn),
+            format!(
+                "ctl uid={} pid={}",
+                unsafe { libc::getuid() },
+                std::process::id()
+            )
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Running in the background with `--daemon`, and the PID file keeping a single instance from
+//! fighting over the TUN device and system DNS.
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+
+/// The PID file, locked while seeker runs and removed on exit.
+pub(crate) struct PidFile {
+    path: PathBuf,
+    _file: File,
+}
+
+impl PidFile {
+    pub(crate) fn acquire(path: &str) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Open pid file {path} error"))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            bail!(
+                "Another seeker (pid {}) is running, it holds {path}",
+                pid.trim()
+            );
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(PidFile {
+            path: path.into(),
+            _file: file,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Held by the daemon until it's started, the foreground process exits when it's told so.
+pub(crate) struct Daemon {
+    pipe: File,
+}
+
+impl Daemon {
+    /// Let the foreground process exit successfully.
+    pub(crate) fn ready(mut self) {
+        let _ = self.pipe.write_all(&[0]);
+    }
+
+    /// Let the foreground process exit with `status`, the daemon is about to exit too.
+    pub(crate) fn fail(mut self, status: u8) {
+        let _ = self.pipe.write_all(&[status]);
+    }
+}
+
+/// Detach from the terminal with a double fork and `setsid`. Must be called before any thread is
+/// spawned. The foreground process waits until [`Daemon::ready`] is called, or exits with an
+/// error if the daemon exits first, with the status passed to [`Daemon::fail`]. stdout goes to
+/// /dev/null and stderr to `log_path`, so errors during startup end up in the log.
+pub(crate) fn daemonize(log_path: &str) -> Result<Daemon> {
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .with_context(|| format!("Open log file {log_path} error"))?;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Create pipe error");
+    }
+    // Commands run by the daemon must not keep the pipe open.
+    for fd in fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error()).context("Fork error"),
+        0 => {}
+        _ => {
+            drop(writer);
+            let mut status = [1];
+            let status = match reader.read(&mut status) {
+                Ok(1) => status[0],
+                _ => 1,
+            };
+            if status == 0 {
+                std::process::exit(0);
+            }
+            eprintln!("seeker exited during startup, see {log_path}");
+            std::process::exit(status.into());
+        }
+    }
+    drop(reader);
+    if unsafe { libc::setsid() } == -1 {
+        return Err(std::io::Error::last_os_error()).context("Setsid error");
+    }
+    // The session leader exits, so the daemon can never acquire a controlling terminal.
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error()).context("Fork error"),
+        0 => {}
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    Ok(Daemon { pipe: writer })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("seeker-test-{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+        let pid_file = PidFile::acquire(path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        let err = PidFile::acquire(path).err().unwrap();
+        assert!(err.to_string().contains("is running"));
+
+        drop(pid_file);
+        assert!(std::fs::metadata(path).is_err());
+        drop(PidFile::acquire(path).unwrap());
+    }
+}
@@ -0,0 +1,266 @@
+//! Routing decisions recorded to a file with `--record-decisions`, one line per connection, so a
+//! trace of real traffic can be replayed against another config with `seeker replay` to see which
+//! connections it routes differently before deploying it.
+use config::rule::{Action, ConnectionSource, RuleOptions};
+use config::Config;
+use parking_lot::{const_mutex, Mutex};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, LineWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+static RECORDER: Mutex<Option<LineWriter<File>>> = const_mutex(None);
+
+/// What a connection is routed by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    /// The host of the connection, None for connections made to an ip.
+    pub domain: Option<String>,
+    /// The destination, a fake ip for hosts resolved by seeker's DNS.
+    pub dest: SocketAddr,
+    pub src: SocketAddr,
+    /// The uid of `--uid`, None if every user is proxied.
+    pub uid: Option<u32>,
+    /// The socket belongs to a user other than `uid`.
+    pub other_user: bool,
+    /// The socket is on this machine, not forwarded from a container or another machine.
+    pub local: bool,
+    pub interface: Option<String>,
+    pub cgroup: Option<String>,
+}
+
+/// A route and the action it was decided, before probing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub route: Route,
+    pub action: Action,
+}
+
+/// Start appending the decisions to `path`.
+pub fn record_decisions(path: &str) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *RECORDER.lock() = Some(LineWriter::new(file));
+    Ok(())
+}
+
+pub(crate) fn is_recording() -> bool {
+    RECORDER.lock().is_some()
+}
+
+/// Append a decision if `--record-decisions` is set, recording stops at the first write error.
+pub(crate) fn record(route: &Route, action: Action) {
+    let mut recorder = RECORDER.lock();
+    let Some(writer) = recorder.as_mut() else {
+        return;
+    };
+    let decision = Decision {
+        route: route.clone(),
+        action,
+    };
+    if let Err(e) = writeln!(writer, "{decision}") {
+        warn!(?e, "record decision error, recording stopped");
+        *recorder = None;
+    }
+}
+
+/// The action and the rule options `config` routes `route` with, probing aside.
+pub fn decide(config: &Config, route: &Route) -> (Action, RuleOptions) {
+    // IPv4-mapped addresses are routed as the IPv4 ones.
+    let ip = route.dest.ip().to_canonical();
+    if route.domain.is_none() && config.is_bypassed_lan(ip) {
+        return (Action::Direct, RuleOptions::default());
+    }
+    if route.other_user {
+        return (Action::Direct, RuleOptions::default());
+    }
+    let source = ConnectionSource {
+        ip: match route.src.ip().to_canonical() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        },
+        interface: route.interface.as_deref(),
+        cgroup: route.cgroup.as_deref(),
+    };
+    let fake_ip = matches!(ip, IpAddr::V4(ip) if config.tun_cidr.contains_addr(&ip.into()));
+    let matched = config.rules.marked_action_for_connection(
+        &source,
+        route.domain.as_deref(),
+        Some(ip),
+        fake_ip,
+    );
+    match matched {
+        Some((action, options, true)) => (action, options),
+        _ if route.uid.is_some() && !route.local => {
+            // The uid of sockets in containers, other network namespaces or on other machines
+            // isn't known, they are only proxied by the source rules.
+            debug!(
+                src = %route.src,
+                interface = ?route.interface,
+                "connection not from this machine, uid unknown"
+            );
+            (Action::Direct, RuleOptions::default())
+        }
+        Some((action, options, false)) => (action, options),
+        None => (config.rules.default_action(), RuleOptions::default()),
+    }
+}
+
+/// Tab separated: domain, destination, source, uid (`!` before it for sockets of other users),
+/// `local` or `forwarded`, interface, cgroup and the action, `-` for the missing ones.
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let route = &self.route;
+        let uid = match route.uid {
+            Some(uid) if route.other_user => format!("!{uid}"),
+            Some(uid) => uid.to_string(),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{}\t{}\t{}\t{uid}\t{}\t{}\t{}\t{}",
+            route.domain.as_deref().unwrap_or("-"),
+            route.dest,
+            route.src,
+            if route.local { "local" } else { "forwarded" },
+            route.interface.as_deref().unwrap_or("-"),
+            route.cgroup.as_deref().unwrap_or("-"),
+            self.action.to_string().to_uppercase(),
+        )
+    }
+}
+
+impl FromStr for Decision {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |field: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid {field} in decision: {s}"),
+            )
+        };
+        let fields: Vec<&str> = s.split('\t').collect();
+        let [domain, dest, src, uid, origin, interface, cgroup, action] = fields[..] else {
+            return Err(invalid("number of fields"));
+        };
+        let optional = |field: &str| (field != "-").then(|| field.to_string());
+        let (uid, other_user) = match uid {
+            "-" => (None, false),
+            uid => {
+                let (uid, other_user) = match uid.strip_prefix('!') {
+                    Some(uid) => (uid, true),
+                    None => (uid, false),
+                };
+                (Some(uid.parse().map_err(|_| invalid("uid"))?), other_user)
+            }
+        };
+        let local = match origin {
+            "local" => true,
+            "forwarded" => false,
+            _ => return Err(invalid("origin")),
+        };
+        Ok(Decision {
+            route: Route {
+                domain: optional(domain),
+                dest: dest.parse().map_err(|_| invalid("destination"))?,
+                src: src.parse().map_err(|_| invalid("source"))?,
+                uid,
+                other_user,
+                local,
+                interface: optional(interface),
+                cgroup: optional(cgroup),
+            },
+            action: Action::from_str(action).map_err(|_| invalid("action"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(domain: Option<&str>, dest: &str) -> Route {
+        Route {
+            domain: domain.map(str::to_string),
+            dest: dest.parse().unwrap(),
+            src: "10.0.0.2:50000".parse().unwrap(),
+            uid: None,
+            other_user: false,
+            local: true,
+            interface: None,
+            cgroup: None,
+        }
+    }
+
+    #[test]
+    fn test_decision_line() {
+        let decision = Decision {
+            route: Route {
+                uid: Some(1000),
+                other_user: true,
+                local: false,
+                interface: Some("docker0".to_string()),
+                ..route(Some("www.google.com"), "10.0.0.5:443")
+            },
+            action: Action::Proxy,
+        };
+        let line = decision.to_string();
+        assert_eq!(
+            line,
+            "www.google.com\t10.0.0.5:443\t10.0.0.2:50000\t!1000\tforwarded\tdocker0\t-\tPROXY"
+        );
+        assert_eq!(line.parse::<Decision>().unwrap(), decision);
+
+        let decision = Decision {
+            route: route(None, "1.1.1.1:53"),
+            action: Action::Direct,
+        };
+        assert_eq!(decision.to_string().parse::<Decision>().unwrap(), decision);
+        assert!("www.google.com\t10.0.0.5:443".parse::<Decision>().is_err());
+    }
+
+    #[test]
+    fn test_decide() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+dns_start_ip: 10.0.0.1
+dns_servers:
+  - 223.5.5.5:53
+tun_name: utun-test
+tun_ip: 10.0.0.1
+tun_cidr: 10.0.0.0/16
+dns_listen: 127.0.0.1:53
+servers:
+  - name: server1
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+rules:
+  - 'DOMAIN-SUFFIX,google.com,PROXY'
+  - 'IP-CIDR,8.8.8.0/24,PROXY'
+  - 'IP-CIDR,192.168.0.0/16,PROXY'
+  - 'MATCH,DIRECT'
+"#,
+        )
+        .unwrap();
+        let action = |route: &Route| decide(&config, route).0;
+        assert_eq!(
+            action(&route(Some("www.google.com"), "10.0.0.5:443")),
+            Action::Proxy
+        );
+        assert_eq!(action(&route(None, "8.8.8.8:53")), Action::Proxy);
+        // LAN destinations are bypassed before the IP-CIDR rule.
+        assert_eq!(action(&route(None, "192.168.1.1:80")), Action::Direct);
+        assert_eq!(
+            action(&route(Some("example.com"), "10.0.0.6:443")),
+            Action::Direct
+        );
+        let other_user = Route {
+            uid: Some(1000),
+            other_user: true,
+            ..route(Some("www.google.com"), "10.0.0.5:443")
+        };
+        assert_eq!(action(&other_user), Action::Direct);
+    }
+}
@@ -0,0 +1,268 @@
+//! Checks run before touching the system, so the usual reasons seeker can't start are reported
+//! with what to do about them instead of a bare OS error, and with an exit status scripts and
+//! service managers can tell apart.
+use config::Config;
+use seeker_core::REDIR_LISTEN_PORT;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+
+/// Why seeker can't start, the exit statuses are from sysexits.h. Other errors exit with 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Failure {
+    /// The config can't be loaded or is invalid.
+    Config,
+    /// Not allowed to create the TUN device, change the routes or bind a port.
+    Permission,
+    /// A port seeker listens on is used by another program.
+    PortInUse,
+    /// The system has no TUN driver.
+    NoTun,
+}
+
+impl Failure {
+    pub(crate) fn exit_code(self) -> u8 {
+        match self {
+            Failure::Config => 78,
+            Failure::Permission => 77,
+            Failure::PortInUse => 69,
+            Failure::NoTun => 72,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StartupError {
+    pub(crate) failure: Failure,
+    message: String,
+    hint: String,
+}
+
+impl StartupError {
+    fn new(failure: Failure, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        StartupError {
+            failure,
+            message: message.into(),
+            hint: hint.into(),
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n  hint: {}", self.message, self.hint)
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+/// Errors of loading the config caused by its content, other errors, eg. fetching a remote
+/// config, are returned as they are.
+pub(crate) fn config_error(e: anyhow::Error) -> anyhow::Error {
+    let invalid = e.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .map_or(false, |e| e.kind() == ErrorKind::InvalidData)
+    });
+    if !invalid {
+        return e;
+    }
+    StartupError::new(
+        Failure::Config,
+        format!("{e:#}"),
+        "fix the config, `seeker --dry-run -c <config>` checks it without starting, and \
+         `seeker migrate-config` upgrades configs of older versions",
+    )
+    .into()
+}
+
+/// Check the privileges, the TUN driver and the ports seeker needs.
+pub(crate) fn check(config: &Config) -> Result<(), StartupError> {
+    check_privileges(config)?;
+    if !config.redir_mode {
+        check_tun_driver()?;
+    }
+    if let Ok(dns_listen) = config.dns_listen.parse::<SocketAddr>() {
+        // Addresses of the TUN device can't be bound before it's created.
+        let on_tun = match dns_listen {
+            SocketAddr::V4(addr) => config.tun_cidr.contains_addr(&(*addr.ip()).into()),
+            SocketAddr::V6(_) => false,
+        };
+        if !on_tun {
+            check_port(dns_listen, Protocol::Udp, "dns_listen")?;
+        }
+    }
+    for (i, tunnel) in config.tunnels.iter().enumerate() {
+        check_port(
+            tunnel.listen,
+            Protocol::Tcp,
+            &format!("tunnels[{i}].listen"),
+        )?;
+    }
+    let relay_addr = (Ipv4Addr::UNSPECIFIED, REDIR_LISTEN_PORT).into();
+    check_port(relay_addr, Protocol::Tcp, "the relay server")?;
+    if !config.redir_mode {
+        check_port(relay_addr, Protocol::Udp, "the relay server")?;
+    }
+    Ok(())
+}
+
+fn check_privileges(config: &Config) -> Result<(), StartupError> {
+    if has_net_admin() {
+        return Ok(());
+    }
+    let message = if config.redir_mode {
+        "seeker needs root to set up the iptables rules"
+    } else {
+        "seeker needs root to create the TUN device and change the routes"
+    };
+    Err(StartupError::new(
+        Failure::Permission,
+        message,
+        privileges_hint(),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn has_net_admin() -> bool {
+    const CAP_NET_ADMIN: u32 = 12;
+    let euid = unsafe { libc::geteuid() };
+    euid == 0 || has_capability(CAP_NET_ADMIN)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_net_admin() -> bool {
+    let euid = unsafe { libc::geteuid() };
+    euid == 0
+}
+
+/// Whether the effective capabilities of the process include `cap`.
+#[cfg(target_os = "linux")]
+fn has_capability(cap: u32) -> bool {
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return false;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .map_or(false, |caps| caps & (1 << cap) != 0)
+}
+
+fn privileges_hint() -> &'static str {
+    if cfg!(target_os = "linux") {
+        "run it with sudo, or grant the capabilities with \
+         `sudo setcap cap_net_admin,cap_net_bind_service+ep $(which seeker)`"
+    } else {
+        "run it with sudo, or install it as a service with `sudo seeker service install`"
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_tun_driver() -> Result<(), StartupError> {
+    if std::path::Path::new("/dev/net/tun").exists() {
+        return Ok(());
+    }
+    Err(StartupError::new(
+        Failure::NoTun,
+        "/dev/net/tun not found",
+        "load the driver with `sudo modprobe tun`, in containers pass `--device /dev/net/tun`, \
+         or set `redir_mode: true` to use iptables instead",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_tun_driver() -> Result<(), StartupError> {
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Bind `addr` and release it right away, seeker binds it again when it starts.
+fn check_port(addr: SocketAddr, protocol: Protocol, what: &str) -> Result<(), StartupError> {
+    let (ret, name) = match protocol {
+        Protocol::Tcp => (TcpListener::bind(addr).map(drop), "tcp"),
+        Protocol::Udp => (UdpSocket::bind(addr).map(drop), "udp"),
+    };
+    let Err(e) = ret else {
+        return Ok(());
+    };
+    let message = format!("{what} can't listen on {name} {addr}: {e}");
+    Err(match e.kind() {
+        ErrorKind::AddrInUse => StartupError::new(
+            Failure::PortInUse,
+            message,
+            port_in_use_hint(addr.port(), what),
+        ),
+        ErrorKind::PermissionDenied => StartupError::new(
+            Failure::Permission,
+            message,
+            "ports below 1024 need root or the CAP_NET_BIND_SERVICE capability",
+        ),
+        ErrorKind::AddrNotAvailable => StartupError::new(
+            Failure::Config,
+            message,
+            format!("the address is not one of this machine, change `{what}`"),
+        ),
+        _ => StartupError::new(
+            Failure::Config,
+            message,
+            "check the listen addresses in the config",
+        ),
+    })
+}
+
+fn port_in_use_hint(port: u16, what: &str) -> String {
+    if port == REDIR_LISTEN_PORT {
+        return format!(
+            "another seeker may be running, check with `seeker ctl status`, \
+             or see which program uses port {port} with `lsof -i :{port}`"
+        );
+    }
+    let mut hint = format!(
+        "stop the program using port {port}, see it with `lsof -i :{port}`, \
+         or listen on another address with `{what}`"
+    );
+    if port == 53 && cfg!(target_os = "linux") {
+        hint.push_str(
+            ". systemd-resolved keeps port 53 unless `DNSStubListener=no` is set in \
+             /etc/systemd/resolved.conf",
+        );
+    }
+    hint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_in_use() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let e = check_port(addr, Protocol::Udp, "dns_listen").unwrap_err();
+        assert_eq!(e.failure, Failure::PortInUse);
+        assert!(e.to_string().contains("hint: stop the program"));
+
+        drop(socket);
+        assert!(check_port(addr, Protocol::Udp, "dns_listen").is_ok());
+    }
+
+    #[test]
+    fn test_config_error() {
+        let e = anyhow::Error::from(io::Error::new(ErrorKind::InvalidData, "bad rule"))
+            .context("Load config from path error");
+        let e = config_error(e);
+        assert_eq!(
+            e.downcast_ref::<StartupError>().unwrap().failure,
+            Failure::Config
+        );
+
+        let e = config_error(anyhow::anyhow!("fetch remote config error"));
+        assert!(e.downcast_ref::<StartupError>().is_none());
+    }
+}
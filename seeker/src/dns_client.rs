@@ -1,18 +1,37 @@
+use async_std::future::timeout;
 use async_std_resolver::config::{
     NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
 };
 use async_std_resolver::{resolver, AsyncStdResolver};
 use config::{Address, DnsServerAddr};
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
 use std::net::IpAddr;
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for AAAA records once A records are resolved, see RFC 8305.
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
 
 #[derive(Clone)]
 pub struct DnsClient {
     resolver: AsyncStdResolver,
+    /// Resolved proxy server domains and when they expire.
+    servers: Arc<Mutex<HashMap<String, (IpAddr, Instant)>>>,
+    /// Network whose addresses are routed into the TUN device, servers must not resolve into it.
+    excluded_network: Option<(Ipv4Addr, u8)>,
+    /// /96 prefix the IPv4 addresses of direct connections are also tried through.
+    nat64_prefix: Option<Ipv6Addr>,
+    /// Called with each IP a server domain is resolved to, shared by all clones.
+    on_server_resolved: Arc<Mutex<Option<ServerResolved>>>,
 }
 
+type ServerResolved = Arc<dyn Fn(IpAddr) + Send + Sync>;
+
 impl DnsClient {
     pub async fn new(dns_servers: &[DnsServerAddr], timeout: Duration) -> Self {
         let mut name_servers = NameServerConfigGroup::with_capacity(dns_servers.len());
@@ -62,7 +81,42 @@ impl DnsClient {
         .await
         .expect("failed to create resolver");
 
-        DnsClient { resolver }
+        DnsClient {
+            resolver,
+            servers: Default::default(),
+            excluded_network: None,
+            nat64_prefix: None,
+            on_server_resolved: Default::default(),
+        }
+    }
+
+    /// Call `f` with the IP of each server domain resolved from now on, before it's connected,
+    /// eg. to let it through the kill switch.
+    pub fn set_on_server_resolved(&self, f: impl Fn(IpAddr) + Send + Sync + 'static) {
+        *self.on_server_resolved.lock() = Some(Arc::new(f));
+    }
+
+    /// Reject server addresses in `network/prefix_len`, connecting to them would loop through
+    /// the TUN device.
+    pub fn exclude_network(mut self, network: Ipv4Addr, prefix_len: u8) -> Self {
+        self.excluded_network = Some((network, prefix_len));
+        self
+    }
+
+    /// Also try IPv4 addresses through the NAT64 gateway of `prefix`, for IPv6-only networks.
+    pub fn nat64(mut self, prefix: Ipv6Addr) -> Self {
+        self.nat64_prefix = Some(prefix);
+        self
+    }
+
+    fn is_excluded(&self, ip: IpAddr) -> bool {
+        match (ip, self.excluded_network) {
+            (IpAddr::V4(ip), Some((network, prefix_len))) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(network) & mask
+            }
+            _ => false,
+        }
     }
 
     pub fn resolver(&self) -> AsyncStdResolver {
@@ -80,6 +134,130 @@ impl DnsClient {
             .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{domain} not resolved")))
     }
 
+    /// Resolve both A and AAAA records, ordered for Happy Eyeballs.
+    pub async fn lookup_all(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        let v4 = self.resolver.ipv4_lookup(domain);
+        let v6 = self.resolver.ipv6_lookup(domain);
+        pin_mut!(v4, v6);
+        let (v4, v6) = match select(v4, v6).await {
+            Either::Left((v4, v6)) => (v4.ok(), timeout(RESOLUTION_DELAY, v6).await.ok()),
+            Either::Right((v6, v4)) => (v4.await.ok(), Some(v6)),
+        };
+        let v4 = v4.into_iter().flatten().map(IpAddr::V4);
+        let v6 = v6
+            .and_then(|v6| v6.ok())
+            .into_iter()
+            .flatten()
+            .map(IpAddr::V6);
+        let ips = crate::happy_eyeballs::interleave(v6.chain(v4).collect());
+        if ips.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{domain} not resolved"),
+            ));
+        }
+        Ok(ips)
+    }
+
+    /// Resolve all addresses of `addr` to connect to directly, see [`Self::lookup_all`]. An
+    /// IPv4-mapped address is connected as the IPv4 one.
+    pub async fn lookup_addresses(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        let addrs = match addr {
+            Address::SocketAddress(a) => vec![unmapped(*a)],
+            Address::DomainNameAddress(domain, port) => self
+                .lookup_all(domain)
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, *port))
+                .collect(),
+        };
+        Ok(match self.nat64_prefix {
+            Some(prefix) => synthesize_nat64(prefix, addrs),
+            None => addrs,
+        })
+    }
+
+    /// Resolve the address of a proxy server. The IP is cached until its TTL expires or
+    /// [`Self::forget_server`] is called after failing to connect.
+    pub async fn lookup_server(&self, addr: &Address) -> Result<SocketAddr> {
+        let (domain, port) = match addr {
+            Address::SocketAddress(a) => return Ok(*a),
+            Address::DomainNameAddress(domain, port) => (domain, *port),
+        };
+        if let Some((ip, valid_until)) = self.servers.lock().get(domain) {
+            if *valid_until > Instant::now() {
+                return Ok(SocketAddr::new(*ip, port));
+            }
+        }
+        let response = self
+            .resolver
+            .lookup_ip(domain.as_str())
+            .await
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("{domain} not resolved")))?;
+        let ip = response
+            .iter()
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{domain} not resolved")))?;
+        if self.is_excluded(ip) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "server {domain} resolves to {ip} in tun_cidr, which would cause a routing \
+                     loop, check dns_servers"
+                ),
+            ));
+        }
+        self.servers
+            .lock()
+            .insert(domain.clone(), (ip, response.valid_until()));
+        let on_server_resolved = self.on_server_resolved.lock().clone();
+        if let Some(f) = on_server_resolved {
+            f(ip);
+        }
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    /// Drop the cached IP of a server, it is resolved again on the next connection.
+    pub fn forget_server(&self, addr: &Address) {
+        if let Address::DomainNameAddress(domain, _) = addr {
+            self.servers.lock().remove(domain);
+        }
+    }
+
+    /// Resolve all addresses of `addr`, also returning when the answer expires. An IP address
+    /// never expires.
+    pub async fn lookup_addresses_with_expiry(
+        &self,
+        addr: &Address,
+    ) -> Result<(Vec<SocketAddr>, Option<Instant>)> {
+        let (domain, port) = match addr {
+            Address::SocketAddress(a) => return Ok((vec![*a], None)),
+            Address::DomainNameAddress(domain, port) => (domain, *port),
+        };
+        let response = self
+            .resolver
+            .lookup_ip(domain.as_str())
+            .await
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("{domain} not resolved")))?;
+        let addrs: Vec<_> = response
+            .iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        if addrs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{domain} not resolved"),
+            ));
+        }
+        Ok((addrs, Some(response.valid_until())))
+    }
+
+    /// Drop all cached records, eg. after the network changed and the answers may differ.
+    pub fn clear_cache(&self) {
+        self.resolver.clear_cache();
+        self.servers.lock().clear();
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn lookup_address(&self, addr: &Address) -> Result<SocketAddr> {
         match addr {
@@ -91,3 +269,108 @@ impl DnsClient {
         }
     }
 }
+
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+/// Each IPv4 address followed by its address in the NAT64 `prefix` as DNS64 would, only when
+/// there is no IPv6 address. The IPv4 one fails at once on IPv6-only networks, so the other is
+/// tried next without waiting.
+fn synthesize_nat64(prefix: Ipv6Addr, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    if addrs.iter().any(SocketAddr::is_ipv6) {
+        return addrs;
+    }
+    addrs
+        .into_iter()
+        .flat_map(|addr| {
+            let synthesized = match addr.ip() {
+                IpAddr::V4(ip) => {
+                    let mut octets = prefix.octets();
+                    octets[12..].copy_from_slice(&ip.octets());
+                    Some(SocketAddr::new(Ipv6Addr::from(octets).into(), addr.port()))
+                }
+                IpAddr::V6(_) => None,
+            };
+            std::iter::once(addr).chain(synthesized)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_lookup_server() {
+        let dns_client = DnsClient::new(
+            &[DnsServerAddr::UdpSocketAddr(
+                "127.0.0.1:53".parse().unwrap(),
+            )],
+            Duration::from_secs(1),
+        )
+        .await
+        .exclude_network(Ipv4Addr::new(11, 0, 0, 0), 16);
+        assert!(dns_client.is_excluded("11.0.3.1".parse().unwrap()));
+        assert!(!dns_client.is_excluded("11.1.0.1".parse().unwrap()));
+
+        dns_client.servers.lock().insert(
+            "ss.example.com".to_string(),
+            (
+                "1.2.3.4".parse().unwrap(),
+                Instant::now() + Duration::from_secs(60),
+            ),
+        );
+        let addr = Address::DomainNameAddress("ss.example.com".to_string(), 8388);
+        assert_eq!(
+            dns_client.lookup_server(&addr).await.unwrap(),
+            "1.2.3.4:8388".parse().unwrap()
+        );
+        dns_client.forget_server(&addr);
+        assert!(dns_client.servers.lock().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_lookup_addresses_nat64() {
+        let dns_client = DnsClient::new(
+            &[DnsServerAddr::UdpSocketAddr(
+                "127.0.0.1:53".parse().unwrap(),
+            )],
+            Duration::from_secs(1),
+        )
+        .await;
+        let addr = |s: &str| Address::SocketAddress(s.parse().unwrap());
+        assert_eq!(
+            dns_client
+                .lookup_addresses(&addr("[::ffff:1.2.3.4]:443"))
+                .await
+                .unwrap(),
+            vec!["1.2.3.4:443".parse().unwrap()]
+        );
+
+        let dns_client = dns_client.nat64("64:ff9b::".parse().unwrap());
+        assert_eq!(
+            dns_client
+                .lookup_addresses(&addr("[::ffff:1.2.3.4]:443"))
+                .await
+                .unwrap(),
+            vec![
+                "1.2.3.4:443".parse().unwrap(),
+                "[64:ff9b::102:304]:443".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            dns_client
+                .lookup_addresses(&addr("[2001:db8::1]:443"))
+                .await
+                .unwrap(),
+            vec!["[2001:db8::1]:443".parse().unwrap()]
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! DNS queries through the TUN device to other servers, eg. apps hard coding 8.8.8.8, are
+//! answered by the embedded resolver instead of being relayed, so they get fake ips and follow
+//! the rules like the queries to `dns_listen`.
+use crate::udp_batch::BatchSocket;
+use async_std::io::{timeout, ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use config::{Config, InboundConfig};
+use hermesdns::{answer_query, ServerContext};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::debug;
+
+const DNS_PORT: u16 = 53;
+
+/// Whether the connection or the datagrams to `dest` are answered by the embedded resolver.
+pub(crate) fn is_hijacked(config: &Config, dest: SocketAddr) -> bool {
+    config.dns_hijack.enabled && dest.port() == DNS_PORT
+}
+
+/// Answer the queries of a UDP session from `client`, the answers are sent through `tun_socket`
+/// to `tun_addr`, the address of the session.
+pub(crate) async fn answer_udp(
+    context: Arc<ServerContext>,
+    tun_socket: Arc<BatchSocket>,
+    tun_addr: SocketAddr,
+    client: IpAddr,
+    queries: Vec<Vec<u8>>,
+    inbound: InboundConfig,
+) -> io::Result<()> {
+    let mut answers = Vec::with_capacity(queries.len());
+    for query in &queries {
+        if !context.admits(client) {
+            continue;
+        }
+        match answer_query(context.clone(), query, false).await {
+            Some(answer) => answers.push(answer),
+            None => debug!(?tun_addr, "invalid hijacked dns query"),
+        }
+    }
+    let packets: Vec<(&[u8], SocketAddr)> = answers
+        .iter()
+        .map(|answer| (answer.as_slice(), tun_addr))
+        .collect();
+    timeout(inbound.write_timeout, tun_socket.send_batch(&packets)).await?;
+    Ok(())
+}
+
+/// Answer the queries of a DNS over TCP connection from `client`, each prefixed with its length,
+/// until the client closes it.
+pub(crate) async fn serve_tcp(
+    context: Arc<ServerContext>,
+    mut conn: TcpStream,
+    client: IpAddr,
+    inbound: InboundConfig,
+) -> io::Result<()> {
+    loop {
+        let mut len = [0; 2];
+        match timeout(inbound.read_timeout, conn.read_exact(&mut len)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut query = vec![0; u16::from_be_bytes(len) as usize];
+        timeout(inbound.read_timeout, conn.read_exact(&mut query)).await?;
+        if !context.admits(client) {
+            continue;
+        }
+        let Some(answer) = answer_query(context.clone(), &query, true).await else {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid dns query"));
+        };
+        let mut reply = Vec::with_capacity(2 + answer.len());
+        reply.extend_from_slice(&(answer.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&answer);
+        timeout(inbound.write_timeout, conn.write_all(&reply)).await?;
+    }
+}
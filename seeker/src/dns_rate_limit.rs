@@ -0,0 +1,97 @@
+//! Rate limit of the DNS queries of each client ip, configured by `dns_rate_limit`. Every client
+//! gets a token bucket, queries over it are dropped, logged when a client starts and stops being
+//! limited, so a misbehaving device on the LAN can't flood the resolver and the upstreams.
+use crate::admission::TokenBucket;
+use config::DnsRateLimitConfig;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Clients with no query for this long are forgotten once there are many.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_CLIENTS: usize = 4096;
+
+struct Client {
+    bucket: TokenBucket,
+    last_query: Instant,
+    /// Queries dropped since the client was limited.
+    dropped: u64,
+}
+
+pub(crate) struct DnsRateLimit {
+    rate: u32,
+    burst: u32,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+impl DnsRateLimit {
+    /// None if queries aren't limited.
+    pub(crate) fn new(config: &DnsRateLimitConfig) -> Option<Self> {
+        let rate = config.queries_per_second?;
+        Some(DnsRateLimit {
+            rate,
+            burst: config.burst.unwrap_or(rate),
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether a query from `client` is answered now.
+    pub(crate) fn admit(&self, client: IpAddr) -> bool {
+        self.admit_at(client, Instant::now())
+    }
+
+    fn admit_at(&self, client: IpAddr, now: Instant) -> bool {
+        let mut clients = self.clients.lock();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            clients.retain(|_, c| now.saturating_duration_since(c.last_query) < IDLE_TIMEOUT);
+        }
+        let state = clients.entry(client).or_insert_with(|| Client {
+            bucket: TokenBucket::new(self.rate, self.burst, now),
+            last_query: now,
+            dropped: 0,
+        });
+        state.last_query = now;
+        if state.bucket.try_acquire(now) {
+            if state.dropped > 0 {
+                info!(%client, dropped = state.dropped, "dns queries of client no longer limited");
+                state.dropped = 0;
+            }
+            true
+        } else {
+            if state.dropped == 0 {
+                warn!(%client, "too many dns queries from client, dropping until the rate drops");
+            }
+            state.dropped += 1;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_rate_limit() {
+        assert!(DnsRateLimit::new(&DnsRateLimitConfig::default()).is_none());
+        let limit = DnsRateLimit::new(&DnsRateLimitConfig {
+            queries_per_second: Some(10),
+            burst: Some(2),
+        })
+        .unwrap();
+        let start = Instant::now();
+        let device: IpAddr = "192.168.1.20".parse().unwrap();
+        assert!(limit.admit_at(device, start));
+        assert!(limit.admit_at(device, start));
+        assert!(!limit.admit_at(device, start));
+        assert!(!limit.admit_at(device, start));
+        assert_eq!(limit.clients.lock()[&device].dropped, 2);
+        // Other clients have their own buckets.
+        assert!(limit.admit_at("192.168.1.21".parse().unwrap(), start));
+
+        assert!(limit.admit_at(device, start + Duration::from_millis(100)));
+        assert_eq!(limit.clients.lock()[&device].dropped, 0);
+    }
+}
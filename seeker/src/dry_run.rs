@@ -0,0 +1,91 @@
+use anyhow::Result;
+use config::rule::{Action, ProxyRules};
+use config::{Config, ServerConfig};
+use std::io::{BufRead, Write};
+use std::net::IpAddr;
+use store::Store;
+
+/// Read hostnames or IPs line by line from `input` and write the action and server each of them
+/// would take to `output`, without creating the TUN device or changing system DNS.
+///
+/// Fake IPs in `tun_cidr` are mapped back to their hosts with the store.
+pub(crate) fn run(config: &Config, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let query = line.trim();
+        if query.is_empty() || query.starts_with('#') {
+            continue;
+        }
+        let host = match query.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) if config.tun_cidr.contains_addr(&ip.into()) => {
+                Store::global().get_host_by_ipv4(ip)?
+            }
+            _ => None,
+        };
+        let decision = match &host {
+            Some(host) => decide(&config.rules, &config.servers, host),
+            None => decide(&config.rules, &config.servers, query),
+        };
+        match host {
+            Some(host) => writeln!(output, "{query} ({host})\t{decision}")?,
+            None => writeln!(output, "{query}\t{decision}")?,
+        }
+    }
+    Ok(())
+}
+
+fn decide(rules: &ProxyRules, servers: &[ServerConfig], query: &str) -> String {
+    let action = match query.parse::<IpAddr>() {
+        Ok(ip) => rules.action_for_domain(None, Some(ip)),
+        Err(_) => rules.action_for_domain(Some(query), None),
+    }
+    .unwrap_or_else(|| rules.default_action());
+    // Servers are not pinged in dry run, so the first server is the one selected.
+    let server = servers.first().map(ServerConfig::name).unwrap_or("-");
+    match action {
+        Action::Proxy => format!("{action}\t{server}"),
+        Action::Probe => format!("{action}\tDirect or {server} if direct connection times out"),
+        Action::Direct | Action::Reject => format!("{action}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::rule::Rule;
+    use config::{Address, ServerProtocol};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_decide() {
+        let rules = ProxyRules::new(
+            [
+                "DOMAIN-SUFFIX,google.com,PROXY",
+                "DOMAIN,ad.com,REJECT",
+                "DOMAIN-KEYWORD,probe,PROBE",
+                "IP-CIDR,10.0.0.0/8,PROXY",
+                "MATCH,DIRECT",
+            ]
+            .into_iter()
+            .map(|r| Rule::from_str(r).unwrap())
+            .collect(),
+        );
+        let servers = vec![ServerConfig::new(
+            "server1".to_string(),
+            Address::from_str("127.0.0.1:1080").unwrap(),
+            ServerProtocol::Socks5,
+            None,
+            None,
+            None,
+            None,
+        )];
+        assert_eq!(decide(&rules, &servers, "www.google.com"), "Proxy\tserver1");
+        assert_eq!(decide(&rules, &servers, "ad.com"), "Reject");
+        assert_eq!(
+            decide(&rules, &servers, "probe.com"),
+            "Probe\tDirect or server1 if direct connection times out"
+        );
+        assert_eq!(decide(&rules, &servers, "10.1.1.1"), "Proxy\tserver1");
+        assert_eq!(decide(&rules, &servers, "example.com"), "Direct");
+    }
+}
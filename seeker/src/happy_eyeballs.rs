@@ -0,0 +1,172 @@
+//! Happy Eyeballs (RFC 8305) for direct connections.
+//!
+//! Connection attempts to the resolved addresses are started one after another, each
+//! `ATTEMPT_DELAY` after the previous one or as soon as it fails. The first established
+//! connection wins and the others are dropped, so a dead address doesn't stall the connection
+//! for a full connect timeout.
+//!
+//! The socket of the first attempt can be prepared while the domain is still being resolved, for
+//! the family the addresses of the last connection started with. IPv6 comes first wherever it's
+//! available, so the guess is rarely wrong and costs an unused socket when it is.
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use futures_util::stream::FuturesUnordered;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tcp_connection::{PreparedSocket, SocketMark};
+
+/// Delay between connection attempts, the recommended value of RFC 8305.
+pub(crate) const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Whether the addresses of the last connection started with an IPv6 one.
+static FIRST_IS_V6: AtomicBool = AtomicBool::new(false);
+
+/// A socket for the first attempt of the next [`connect`], of the family it likely starts with.
+pub(crate) fn prepare() -> Option<PreparedSocket> {
+    PreparedSocket::new(FIRST_IS_V6.load(Ordering::Relaxed)).ok()
+}
+
+/// Order the addresses alternating between address families, starting with the family of the
+/// first address.
+pub(crate) fn interleave(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_v6 = first.is_ipv6();
+    let (mut first_family, mut second_family): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|ip| ip.is_ipv6() == first_is_v6);
+    let mut ret = Vec::with_capacity(first_family.len() + second_family.len());
+    first_family.reverse();
+    second_family.reverse();
+    loop {
+        match (first_family.pop(), second_family.pop()) {
+            (None, None) => break,
+            (a, b) => ret.extend(a.into_iter().chain(b)),
+        }
+    }
+    ret
+}
+
+/// Connect to the first address that accepts the connection, starting a new attempt every
+/// `attempt_delay`. `prepared` is used for the first attempt if it's of the same family.
+pub(crate) async fn connect(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+    mut prepared: Option<PreparedSocket>,
+    mark: SocketMark,
+) -> Result<TcpStream> {
+    if let Some(first) = addrs.first() {
+        FIRST_IS_V6.store(first.is_ipv6(), Ordering::Relaxed);
+    }
+    let mut addrs = addrs.iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            let socket = prepared
+                .take()
+                .filter(|socket| socket.is_ipv6() == addr.is_ipv6());
+            attempts.push(attempt(*addr, socket, mark));
+        }
+        // Wait for the next attempt to finish, or until it's time to start another one.
+        let ret = if !addrs.as_slice().is_empty() {
+            timeout(attempt_delay, attempts.next()).await.ok()
+        } else {
+            Some(attempts.next().await)
+        };
+        match ret {
+            Some(Some(Ok(stream))) => return Ok(stream),
+            Some(Some(Err(e))) => last_err = Some(e),
+            // All attempts failed.
+            Some(None) => {
+                return Err(last_err
+                    .unwrap_or_else(|| Error::new(ErrorKind::NotFound, "no address to connect")))
+            }
+            None => {}
+        }
+    }
+}
+
+async fn attempt(
+    addr: SocketAddr,
+    socket: Option<PreparedSocket>,
+    mark: SocketMark,
+) -> Result<TcpStream> {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => PreparedSocket::new(addr.is_ipv6())?,
+    };
+    socket.set_mark(mark)?;
+    socket.connect(addr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use std::time::Instant;
+
+    #[test]
+    fn test_interleave() {
+        let v4 = |n: u8| IpAddr::from([10, 0, 0, n]);
+        let v6 = |n: u16| IpAddr::from([0, 0, 0, 0, 0, 0, 0, n]);
+        assert_eq!(
+            interleave(vec![v6(1), v6(2), v6(3), v4(1), v4(2)]),
+            vec![v6(1), v4(1), v6(2), v4(2), v6(3)]
+        );
+        assert_eq!(
+            interleave(vec![v4(1), v4(2), v6(1)]),
+            vec![v4(1), v6(1), v4(2)]
+        );
+        assert_eq!(interleave(vec![]), Vec::<IpAddr>::new());
+    }
+
+    #[async_std::test]
+    async fn test_connect_skips_dead_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Nothing listens on the first address, it either refuses the connection or times out.
+        let addrs = ["10.255.255.1:9".parse().unwrap(), addr];
+        let start = Instant::now();
+        let stream = connect(&addrs, ATTEMPT_DELAY, None, SocketMark::default())
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        assert!(connect(&[], ATTEMPT_DELAY, None, SocketMark::default())
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_connect_prepared() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let prepared = PreparedSocket::new(false).unwrap();
+        let stream = connect(
+            &[addr],
+            ATTEMPT_DELAY,
+            Some(prepared),
+            SocketMark::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        // A socket of the other family is not used.
+        let prepared = PreparedSocket::new(true).ok();
+        let mark = SocketMark {
+            fwmark: None,
+            dscp: Some(46),
+        };
+        let stream = connect(&[addr], ATTEMPT_DELAY, prepared, mark)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+    }
+}
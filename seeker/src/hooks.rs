@@ -0,0 +1,415 @@
+//! `hooks`, external commands and webhooks run on connections to some domains, servers going
+//! down or up and monthly traffic thresholds, eg. for alerting and home automation. They run
+//! on the blocking pool, a slow one doesn't hold up the relay.
+use async_std::task::spawn_blocking;
+use config::{HookConfig, HookEvent};
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use store::{MonthlyUsage, Store};
+use tracing::{debug, info, warn};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Runs of a hook at once, more events are dropped, eg. of a `connection` hook for all domains.
+const MAX_RUNNING: usize = 4;
+
+/// What a hook is run for, filled into its templates.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Event {
+    pub server: Option<String>,
+    pub domain: Option<String>,
+    pub bytes: Option<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<HookConfig>,
+    /// The runs of each hook not finished yet.
+    running: Vec<Arc<AtomicUsize>>,
+    /// Servers that didn't answer the last ping.
+    down: Mutex<HashSet<String>>,
+    /// The month of the last usage and the usage hooks run in it, by index and server.
+    usage_run: Mutex<(String, HashSet<(usize, String)>)>,
+}
+
+impl Hooks {
+    pub(crate) fn new(hooks: Vec<HookConfig>) -> Self {
+        Hooks {
+            running: hooks.iter().map(|_| Default::default()).collect(),
+            hooks,
+            ..Default::default()
+        }
+    }
+
+    /// Run the hook at index `i` for `event`, unless it's running `MAX_RUNNING` times already.
+    fn run(&self, i: usize, event: Event) {
+        let hook = &self.hooks[i];
+        let Some(running) = Running::start(&self.running[i]) else {
+            debug!(event = %hook.event, ?event, "hook is running too many times, skip it");
+            return;
+        };
+        run(hook, event, running);
+    }
+
+    fn has(&self, event: HookEvent) -> bool {
+        self.hooks.iter().any(|hook| hook.event == event)
+    }
+
+    /// A connection to `domain` was made through `server`, None for direct connections.
+    pub(crate) fn on_connection(&self, domain: &str, server: Option<&str>) {
+        let domain = domain.trim_end_matches('.');
+        for (i, hook) in self.hooks.iter().enumerate() {
+            if hook.event == HookEvent::Connection && matches_domains(&hook.domains, domain) {
+                self.run(
+                    i,
+                    Event {
+                        server: server.map(ToString::to_string),
+                        domain: Some(domain.to_string()),
+                        bytes: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// `server` answered the ping if `up`, the hooks run when it changes. Servers are up until
+    /// the first ping they don't answer.
+    pub(crate) fn on_ping(&self, server: &str, up: bool) {
+        let Some(event) = self.server_change(server, up) else {
+            return;
+        };
+        for (i, _) in self
+            .hooks
+            .iter()
+            .enumerate()
+            .filter(|(_, hook)| hook.event == event)
+        {
+            self.run(
+                i,
+                Event {
+                    server: Some(server.to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    fn server_change(&self, server: &str, up: bool) -> Option<HookEvent> {
+        let mut down = self.down.lock();
+        if up && down.remove(server) {
+            Some(HookEvent::ServerUp)
+        } else if !up && down.insert(server.to_string()) {
+            Some(HookEvent::ServerDown)
+        } else {
+            None
+        }
+    }
+
+    /// Run the usage hooks whose `bytes` the servers reached this month.
+    pub(crate) fn check_usage(&self) {
+        if !self.has(HookEvent::Usage) {
+            return;
+        }
+        let usage = match Store::global().monthly_usage(store::now()) {
+            Ok(usage) => usage,
+            Err(e) => {
+                warn!(?e, "read usage for hooks error");
+                return;
+            }
+        };
+        for (i, event) in self.usage_reached(&usage) {
+            self.run(i, event);
+        }
+    }
+
+    /// Usage hooks reached by `usage` and not run yet this month, by index.
+    fn usage_reached(&self, usage: &MonthlyUsage) -> Vec<(usize, Event)> {
+        let mut usage_run = self.usage_run.lock();
+        if usage_run.0 != usage.month {
+            *usage_run = (usage.month.clone(), HashSet::new());
+        }
+        let mut reached = Vec::new();
+        for (i, hook) in self.hooks.iter().enumerate() {
+            let Some(bytes) = hook.bytes.filter(|_| hook.event == HookEvent::Usage) else {
+                continue;
+            };
+            for server in &usage.servers {
+                if server.total_bytes() >= bytes && usage_run.1.insert((i, server.server.clone())) {
+                    reached.push((
+                        i,
+                        Event {
+                            server: Some(server.server.clone()),
+                            domain: None,
+                            bytes: Some(server.total_bytes()),
+                        },
+                    ));
+                }
+            }
+        }
+        reached
+    }
+}
+
+/// All domains if `domains` is empty.
+fn matches_domains(domains: &[String], domain: &str) -> bool {
+    domains.is_empty()
+        || domains.iter().any(|d| {
+            domain.eq_ignore_ascii_case(d)
+                || domain.len().checked_sub(d.len() + 1).map_or(false, |i| {
+                    domain.as_bytes()[i] == b'.' && domain[i + 1..].eq_ignore_ascii_case(d)
+                })
+        })
+}
+
+/// A run of a hook counted in its running count until dropped.
+struct Running(Arc<AtomicUsize>);
+
+impl Running {
+    fn start(count: &Arc<AtomicUsize>) -> Option<Self> {
+        count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_RUNNING).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Running(count.clone()))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn run(hook: &HookConfig, event: Event, running: Running) {
+    info!(event = %hook.event, ?event, "Run hook");
+    let hook = hook.clone();
+    let _ = spawn_blocking(move || {
+        let _running = running;
+        if let Some((program, args)) = hook.command.split_first() {
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| render(arg, hook.event, &event, str::to_string))
+                .collect();
+            match Command::new(program).args(&args).status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!(event = %hook.event, program, %status, "hook command failed"),
+                Err(e) => warn!(event = %hook.event, program, %e, "run hook command error"),
+            }
+        }
+        if let Some(url) = &hook.url {
+            let body = match &hook.body {
+                Some(body) => render(body, hook.event, &event, json_escape),
+                None => to_json(hook.event, &event),
+            };
+            if let Err(e) = ureq::post(url)
+                .timeout(WEBHOOK_TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(&body)
+            {
+                warn!(event = %hook.event, url, %e, "post webhook error");
+            }
+        }
+    });
+}
+
+/// `template` with `{event}`, `{server}`, `{domain}` and `{bytes}` replaced by the values passed
+/// through `escape`, by empty strings if the event doesn't have them. Placeholders in the values
+/// are kept as they are.
+fn render(
+    template: &str,
+    kind: HookEvent,
+    event: &Event,
+    escape: impl Fn(&str) -> String,
+) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let value = match &rest[1..end] {
+                "event" => kind.to_string(),
+                "server" => event.server.clone().unwrap_or_default(),
+                "domain" => event.domain.clone().unwrap_or_default(),
+                "bytes" => event.bytes.map(|b| b.to_string()).unwrap_or_default(),
+                _ => return None,
+            };
+            Some((value, end + 1))
+        });
+        match value {
+            Some((value, len)) => {
+                rendered.push_str(&escape(&value));
+                rest = &rest[len..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Like `{"event":"server_down","server":"hk","time":1700000000}`, without the fields the event
+/// doesn't have.
+fn to_json(kind: HookEvent, event: &Event) -> String {
+    let mut json = format!("{{\"event\":\"{kind}\"");
+    if let Some(server) = &event.server {
+        let _ = write!(json, ",\"server\":{}", json_string(server));
+    }
+    if let Some(domain) = &event.domain {
+        let _ = write!(json, ",\"domain\":{}", json_string(domain));
+    }
+    if let Some(bytes) = event.bytes {
+        let _ = write!(json, ",\"bytes\":{bytes}");
+    }
+    let _ = write!(json, ",\"time\":{}}}", store::now());
+    json
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", json_escape(s))
+}
+
+/// `s` escaped to go between the quotes of a JSON string.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::ServerUsage;
+
+    fn hook(event: HookEvent, bytes: Option<u64>) -> HookConfig {
+        HookConfig {
+            event,
+            domains: vec![],
+            bytes,
+            command: vec!["true".to_string()],
+            url: None,
+            body: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let event = Event {
+            server: Some("hk".to_string()),
+            bytes: Some(42),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(
+                "{event}: {server} {domain}{bytes}",
+                HookEvent::Usage,
+                &event,
+                str::to_string
+            ),
+            "usage: hk 42"
+        );
+        let event = Event {
+            server: Some("{domain}".to_string()),
+            domain: Some("a\"b.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(
+                r#"{"s": "{server}", "d": "{domain}", "x": "{x}{"}"#,
+                HookEvent::Connection,
+                &event,
+                json_escape
+            ),
+            r#"{"s": "{domain}", "d": "a\"b.com", "x": "{x}{"}"#
+        );
+        let json = to_json(
+            HookEvent::Connection,
+            &Event {
+                domain: Some("a\"b.com".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(json.starts_with(r#"{"event":"connection","domain":"a\"b.com","time":"#));
+        assert_eq!(json_string("a\nb"), r#""a\u000ab""#);
+    }
+
+    #[test]
+    fn test_running() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let runs: Vec<_> = (0..MAX_RUNNING)
+            .map(|_| Running::start(&count).unwrap())
+            .collect();
+        assert!(Running::start(&count).is_none());
+        drop(runs);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert!(Running::start(&count).is_some());
+    }
+
+    #[test]
+    fn test_matches_domains() {
+        let domains = vec!["example.com".to_string()];
+        assert!(matches_domains(&domains, "example.com"));
+        assert!(matches_domains(&domains, "www.Example.com"));
+        assert!(!matches_domains(&domains, "badexample.com"));
+        assert!(!matches_domains(&domains, "example.org"));
+        assert!(matches_domains(&[], "example.org"));
+    }
+
+    #[test]
+    fn test_server_change() {
+        let hooks = Hooks::new(vec![]);
+        assert_eq!(hooks.server_change("hk", true), None);
+        assert_eq!(
+            hooks.server_change("hk", false),
+            Some(HookEvent::ServerDown)
+        );
+        assert_eq!(hooks.server_change("hk", false), None);
+        assert_eq!(hooks.server_change("hk", true), Some(HookEvent::ServerUp));
+    }
+
+    #[test]
+    fn test_usage_reached() {
+        let hooks = Hooks::new(vec![
+            hook(HookEvent::ServerDown, None),
+            hook(HookEvent::Usage, Some(100)),
+        ]);
+        let mut usage = MonthlyUsage {
+            month: "2024-03".to_string(),
+            start: 0,
+            end: 31,
+            time: 10,
+            servers: vec![ServerUsage {
+                server: "hk".to_string(),
+                sent_bytes: 10,
+                recv_bytes: 50,
+            }],
+        };
+        assert!(hooks.usage_reached(&usage).is_empty());
+        usage.servers[0].recv_bytes = 90;
+        let reached = hooks.usage_reached(&usage);
+        assert_eq!(reached.len(), 1);
+        assert_eq!(reached[0].0, 1);
+        assert_eq!(reached[0].1.bytes, Some(100));
+        // Once a month.
+        assert!(hooks.usage_reached(&usage).is_empty());
+        usage.month = "2024-04".to_string();
+        assert_eq!(hooks.usage_reached(&usage).len(), 1);
+    }
+}
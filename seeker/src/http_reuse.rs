@@ -0,0 +1,427 @@
+//! Direct plain HTTP connections reused after the application closes them, configured by
+//! `http_reuse`. Clients opening a connection per request leave the upstream one open with
+//! HTTP/1.1 keep-alive, the next connection to the same host with the same action and socket
+//! mark takes it instead of connecting.
+//!
+//! A connection is only kept when every request on it was answered with a response of known
+//! length and neither side asked to close it, so the next request starts on a clean connection.
+use crate::proxy_connection::ProxyConnection;
+use crate::proxy_tcp_stream::ProxyTcpStream;
+use async_std::io::{timeout, Read, ReadExt, Write};
+use async_std::task::{ready, spawn, JoinHandle};
+use config::rule::Action;
+use config::{Address, HttpReuseConfig};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::io::{IoSlice, Result};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tcp_connection::SocketMark;
+
+const HTTP_PORT: u16 = 80;
+/// Longer request or response heads aren't parsed, the connection isn't reused.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// The requests and responses seen on a connection.
+#[derive(Default)]
+struct HttpExchange {
+    requests: Message,
+    responses: Message,
+    /// Whether each request waiting for its response is a HEAD one, its response has no body.
+    pending: VecDeque<bool>,
+    exchanges: usize,
+    broken: bool,
+}
+
+/// A message being received, its head first, then `body_left` bytes of body.
+#[derive(Default)]
+struct Message {
+    head: Vec<u8>,
+    body_left: u64,
+}
+
+impl Message {
+    fn is_idle(&self) -> bool {
+        self.head.is_empty() && self.body_left == 0
+    }
+
+    /// Consume `data`, `on_head` returns the body length of each complete head, or None if the
+    /// message can't be followed. Returns false once one can't.
+    fn feed(&mut self, mut data: &[u8], mut on_head: impl FnMut(&[u8]) -> Option<u64>) -> bool {
+        while !data.is_empty() {
+            if self.body_left > 0 {
+                let n = self.body_left.min(data.len() as u64) as usize;
+                self.body_left -= n as u64;
+                data = &data[n..];
+                continue;
+            }
+            let received = self.head.len();
+            self.head.extend_from_slice(data);
+            // The end of the head may be split between two reads.
+            let start = received.saturating_sub(3);
+            let Some(end) = self.head[start..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|i| start + i + 4)
+            else {
+                return self.head.len() <= MAX_HEAD_SIZE;
+            };
+            let Some(body_len) = on_head(&self.head[..end]) else {
+                return false;
+            };
+            data = &data[end - received..];
+            self.head.clear();
+            self.body_left = body_len;
+        }
+        true
+    }
+}
+
+impl HttpExchange {
+    fn on_request(&mut self, data: &[u8]) {
+        if self.broken {
+            return;
+        }
+        let pending = &mut self.pending;
+        let ok = self.requests.feed(data, |head| {
+            let (method, content_length) = parse_head(head, true)?;
+            pending.push_back(method == "HEAD");
+            Some(content_length.unwrap_or(0))
+        });
+        self.broken = !ok;
+    }
+
+    /// `data` is empty once the server closed the connection.
+    fn on_response(&mut self, data: &[u8]) {
+        if self.broken {
+            return;
+        }
+        if data.is_empty() {
+            self.broken = true;
+            return;
+        }
+        let pending = &mut self.pending;
+        let exchanges = &mut self.exchanges;
+        let ok = self.responses.feed(data, |head| {
+            let head_request = pending.pop_front()?;
+            let (status, content_length) = parse_head(head, false)?;
+            let status: u16 = status.parse().ok()?;
+            *exchanges += 1;
+            match status {
+                // Interim responses come before the final one of the same request.
+                100..=199 => None,
+                204 | 304 => Some(0),
+                _ if head_request => Some(0),
+                // Without a length the body ends when the server closes the connection.
+                _ => content_length,
+            }
+        });
+        self.broken = !ok;
+    }
+
+    /// Every request was answered completely and the connection can carry the next one.
+    fn is_reusable(&self) -> bool {
+        !self.broken
+            && self.exchanges > 0
+            && self.pending.is_empty()
+            && self.requests.is_idle()
+            && self.responses.is_idle()
+    }
+}
+
+/// Parse a request head for its method or a response head for its status code, with the
+/// content length. None if it isn't HTTP/1.1 or asks to close or switch protocols.
+fn parse_head(head: &[u8], request: bool) -> Option<(&str, Option<u64>)> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.trim_start_matches("\r\n").split("\r\n");
+    let mut start_line = lines.next()?.split(' ');
+    let (first, second) = (start_line.next()?, start_line.next()?);
+    let (version, key) = if request {
+        (start_line.next()?, first)
+    } else {
+        (first, second)
+    };
+    if version != "HTTP/1.1" || key == "CONNECT" {
+        return None;
+    }
+    let mut content_length = None;
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let name = name.trim();
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            let len: u64 = value.parse().ok()?;
+            if content_length.map_or(false, |l| l != len) {
+                return None;
+            }
+            content_length = Some(len);
+        } else if name.eq_ignore_ascii_case("connection") {
+            let closing = value.split(',').any(|token| {
+                let token = token.trim();
+                token.eq_ignore_ascii_case("close") || token.eq_ignore_ascii_case("upgrade")
+            });
+            if closing {
+                return None;
+            }
+        } else if ["transfer-encoding", "upgrade", "expect"]
+            .iter()
+            .any(|n| name.eq_ignore_ascii_case(n))
+        {
+            return None;
+        }
+    }
+    Some((key, content_length))
+}
+
+struct ReuseState {
+    exchange: HttpExchange,
+    parked: bool,
+}
+
+/// The remote side of a relayed plain HTTP connection. When the application closes its side
+/// after a complete exchange, the connection is parked instead: the close isn't passed on and
+/// reads return EOF, so the relay ends with the connection still open.
+#[derive(Clone)]
+pub(crate) struct ReusableStream {
+    inner: ProxyTcpStream,
+    state: Arc<Mutex<ReuseState>>,
+}
+
+impl ReusableStream {
+    pub(crate) fn new(inner: ProxyTcpStream) -> Self {
+        ReusableStream {
+            inner,
+            state: Arc::new(Mutex::new(ReuseState {
+                exchange: HttpExchange::default(),
+                parked: false,
+            })),
+        }
+    }
+
+    /// Whether the relay ended with the connection reusable.
+    pub(crate) fn is_parked(&self) -> bool {
+        self.state.lock().parked
+    }
+}
+
+impl Read for ReusableStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        if self.state.lock().parked {
+            return Poll::Ready(Ok(0));
+        }
+        let size = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.state.lock().exchange.on_response(&buf[..size]);
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl Write for ReusableStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let size = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.state.lock().exchange.on_request(&buf[..size]);
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        let size = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, bufs))?;
+        let mut state = self.state.lock();
+        let mut left = size;
+        for buf in bufs {
+            let n = left.min(buf.len());
+            state.exchange.on_request(&buf[..n]);
+            left -= n;
+            if left == 0 {
+                break;
+            }
+        }
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        {
+            let mut state = self.state.lock();
+            if state.exchange.is_reusable() {
+                state.parked = true;
+                return Poll::Ready(Ok(()));
+            }
+        }
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+struct IdleConnection {
+    id: u64,
+    conn: ProxyTcpStream,
+    /// Waits on the connection while it's idle, finishes once the server closes it or after
+    /// `idle_timeout`.
+    watcher: JoinHandle<()>,
+}
+
+/// Connections are only reused for connections routed the same way, eg. a connection opened
+/// with a mark for a routing table isn't taken by one without.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct IdleKey {
+    pub host: Address,
+    pub action: Action,
+    pub mark: SocketMark,
+}
+
+type IdleMap = Arc<Mutex<HashMap<IdleKey, Vec<IdleConnection>>>>;
+
+/// The parked connections of each host, at most `size` for each and `max_total` in all.
+pub(crate) struct IdleHttpConnections {
+    config: HttpReuseConfig,
+    idle: IdleMap,
+    next_id: AtomicU64,
+}
+
+impl IdleHttpConnections {
+    pub(crate) fn new(config: HttpReuseConfig) -> Self {
+        IdleHttpConnections {
+            config,
+            idle: Default::default(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether direct connections to `host` are relayed as reusable ones.
+    pub(crate) fn is_reusable(&self, host: &Address) -> bool {
+        self.config.size > 0 && host.port() == HTTP_PORT
+    }
+
+    /// Take the connection parked last for `key`, it's the least likely to be closed by the
+    /// server soon.
+    pub(crate) async fn take(&self, key: &IdleKey) -> Option<ProxyTcpStream> {
+        loop {
+            let idle = {
+                let mut map = self.idle.lock();
+                let conns = map.get_mut(key)?;
+                let idle = conns.pop();
+                if conns.is_empty() {
+                    map.remove(key);
+                }
+                idle?
+            };
+            // The watcher finished, the connection was closed meanwhile.
+            if idle.watcher.cancel().await.is_none() {
+                return Some(idle.conn);
+            }
+            idle.conn.shutdown();
+        }
+    }
+
+    /// Park `conn` for the next connection with `key`, it's closed if there are enough already
+    /// for the host or in all.
+    pub(crate) fn put(&self, key: IdleKey, conn: ProxyTcpStream) {
+        let mut map = self.idle.lock();
+        let total: usize = map.values().map(Vec::len).sum();
+        if total >= self.config.max_total {
+            conn.shutdown();
+            return;
+        }
+        let conns = map.entry(key.clone()).or_default();
+        if conns.len() >= self.config.size {
+            conn.shutdown();
+            return;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let idle = self.idle.clone();
+        let mut reader = conn.clone();
+        let idle_timeout = self.config.idle_timeout;
+        let watcher = spawn(async move {
+            // Anything but the timeout, data, EOF or an error, means the server is done with it.
+            let mut buf = [0; 1];
+            let _ = timeout(idle_timeout, reader.read(&mut buf)).await;
+            let mut map = idle.lock();
+            if let Some(conns) = map.get_mut(&key) {
+                conns.retain(|idle| idle.id != id);
+                if conns.is_empty() {
+                    map.remove(&key);
+                }
+            }
+            reader.shutdown();
+        });
+        conns.push(IdleConnection { id, conn, watcher });
+    }
+
+    /// Close all parked connections.
+    pub(crate) fn clear(&self) {
+        for (_, conns) in self.idle.lock().drain() {
+            conns.iter().for_each(|idle| idle.conn.shutdown());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_exchange() {
+        let mut exchange = HttpExchange::default();
+        exchange.on_request(b"GET / HTTP/1.1\r\nHost: a.com\r\n");
+        exchange.on_request(b"\r\nPOST /form HTTP/1.1\r\nContent-Length: 3\r\n\r\nab");
+        assert!(!exchange.is_reusable());
+        exchange.on_request(b"c");
+        exchange.on_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel");
+        assert!(!exchange.is_reusable());
+        exchange.on_response(b"loHTTP/1.1 204 No Content\r");
+        exchange.on_response(b"\n\r\n");
+        assert!(exchange.is_reusable());
+
+        let exchange_of = |request: &[u8], response: &[u8]| {
+            let mut exchange = HttpExchange::default();
+            exchange.on_request(request);
+            exchange.on_response(response);
+            exchange.is_reusable()
+        };
+        assert!(exchange_of(
+            b"HEAD / HTTP/1.1\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n"
+        ));
+        assert!(!exchange_of(
+            b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        ));
+        assert!(!exchange_of(
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(!exchange_of(
+            b"GET / HTTP/1.0\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        ));
+        // A body without a length, or a response nobody asked for.
+        assert!(!exchange_of(
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n\r\n"
+        ));
+        assert!(!exchange_of(
+            b"",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        ));
+
+        let mut exchange = HttpExchange::default();
+        exchange.on_request(b"GET / HTTP/1.1\r\n\r\n");
+        exchange.on_response(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        exchange.on_response(b"");
+        assert!(!exchange.is_reusable());
+    }
+}
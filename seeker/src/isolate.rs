@@ -0,0 +1,67 @@
+//! Panics of the tasks relaying one connection are caught, so a bug hit by one connection only
+//! closes it instead of unwinding into the executor and the tasks of the other connections.
+use futures_util::FutureExt;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Once;
+use tracing::error;
+
+/// Run `fut`, None if it panicked. The panic itself is logged with its backtrace by the hook of
+/// [`install_panic_hook`].
+pub(crate) async fn isolate<F: Future>(task: &str, fut: F) -> Option<F::Output> {
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(output) => Some(output),
+        Err(panic) => {
+            error!(
+                task,
+                panic = panic_message(&*panic),
+                "task panicked, closed"
+            );
+            None
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown"
+    }
+}
+
+/// Log panics as error events with their backtraces, which are only known where they happen.
+/// The previous hook still runs, eg. printing the panic to stderr.
+pub fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::force_capture();
+            error!(%info, %backtrace, "panic");
+            previous(info);
+        }));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_isolate() {
+        block_on(async {
+            assert_eq!(isolate("ok", async { 1 }).await, Some(1));
+            let task = spawn(isolate("panic", async {
+                let ip: Option<u32> = None;
+                ip.expect("parse ip")
+            }));
+            assert_eq!(task.await, None);
+        });
+    }
+}
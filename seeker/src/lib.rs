@@ -0,0 +1,57 @@
+//! The proxy engine of seeker, for programs embedding it, eg. a GUI frontend or a test harness,
+//! see [`ProxyRuntime`]. The `seeker` binary adds the command line, the routes of the TUN device
+//! and the system DNS on top of it.
+#![type_length_limit = "2374570"]
+#[macro_use]
+mod macros;
+mod admission;
+mod chaos;
+mod clock;
+mod connection_pool;
+mod control;
+mod decision_log;
+mod dns_client;
+mod dns_hijack;
+mod dns_rate_limit;
+mod happy_eyeballs;
+mod hooks;
+mod http_reuse;
+mod isolate;
+mod network_monitor;
+mod probe_connectivity;
+mod proxied_dns;
+mod proxy_client;
+mod proxy_connection;
+mod proxy_fallback;
+mod proxy_protocol;
+mod proxy_tcp_stream;
+mod proxy_udp_socket;
+mod quic;
+mod reject_page;
+mod relay;
+mod relay_tcp_stream;
+mod relay_udp_socket;
+mod reset_retry;
+mod runtime;
+mod server_chooser;
+mod sniff;
+#[cfg(target_os = "linux")]
+mod splice;
+#[cfg(test)]
+mod test_harness;
+mod traffic;
+mod tunnel;
+mod udp_batch;
+mod udp_nat;
+mod usage;
+
+pub use control::{request as control_request, serve as serve_control, Controller};
+pub use decision_log::{decide, record_decisions, Decision, Route};
+pub use isolate::install_panic_hook;
+pub use network_monitor::{watch as watch_network, NetworkReset};
+pub use proxy_client::ProxyClient;
+pub use runtime::{ConnectionStats, Handle, Mode, ProxyRuntime, Stats};
+
+/// Port of the relay servers, the TUN device and the iptables rules of redir mode forward
+/// connections to it.
+pub const REDIR_LISTEN_PORT: u16 = 1300;
@@ -1,35 +1,40 @@
 #![type_length_limit = "2374570"]
-#[macro_use]
-mod macros;
+mod bench_ciphers;
 mod config_encryptor;
-mod dns_client;
+mod daemon;
+mod diagnostics;
+mod dry_run;
+mod init_config;
 mod logger;
-mod probe_connectivity;
-mod proxy_client;
-mod proxy_connection;
-mod proxy_tcp_stream;
-mod proxy_udp_socket;
-mod relay_tcp_stream;
-mod relay_udp_socket;
-mod server_chooser;
-mod traffic;
-
-use clap::Parser;
-use std::time::Duration;
+mod remote_config;
+mod replay;
+mod service;
 
+use clap::{Parser, Subcommand};
+
+use crate::diagnostics::StartupError;
 use crate::logger::setup_logger;
-use crate::proxy_client::ProxyClient;
+use crate::remote_config::SignatureOptions;
 use anyhow::{bail, Context};
 use async_std::prelude::FutureExt;
 use async_std::task::block_on;
 use config::Config;
 use crypto::CipherType;
+use seeker_core::{
+    control_request, install_panic_hook, serve_control, watch_network, ProxyClient,
+    REDIR_LISTEN_PORT,
+};
 use std::fs::File;
-use sysconfig::{set_rlimit_no_file, DNSSetup, IpForward, IptablesSetup};
+use std::net::Ipv4Addr;
+#[cfg(target_os = "linux")]
+use std::net::ToSocketAddrs;
+use store::Store;
+use sysconfig::{
+    sd_notify, set_rlimit_no_file, watchdog_interval, DNSSetup, IpForward, IptablesSetup,
+    RouteSetup,
+};
 use tracing::Instrument;
 
-const REDIR_LISTEN_PORT: u16 = 1300;
-
 /// CLI program for a proxy
 #[derive(Parser, Debug)]
 #[clap(
@@ -50,6 +55,22 @@ struct SeekerArgs {
     #[clap(long, value_name = "KEY")]
     key: Option<String>,
 
+    /// Read key for encryption/decryption from file
+    #[clap(long, value_name = "KEY_FILE", conflicts_with = "key")]
+    key_file: Option<String>,
+
+    /// Base64 encoded ed25519 public key to verify the signature of the remote config
+    #[clap(long, value_name = "PUBLIC_KEY")]
+    config_pubkey: Option<String>,
+
+    /// URL to the signature of the remote config. Defaults to `<CONFIG_URL>.sig`
+    #[clap(long, value_name = "SIGNATURE_URL")]
+    config_signature_url: Option<String>,
+
+    /// Name of the profile in config to use
+    #[clap(short = 'p', long, value_name = "NAME")]
+    profile: Option<String>,
+
     /// User id to proxy
     #[clap(short = 'u', long, value_name = "UID")]
     user_id: Option<u32>,
@@ -58,10 +79,53 @@ struct SeekerArgs {
     #[clap(long)]
     encrypt: bool,
 
+    /// Encrypt a secret (eg. server password) for use in config and output to terminal
+    #[clap(long, value_name = "SECRET")]
+    encrypt_secret: Option<String>,
+
+    /// Delete the recorded connections whose host or sniffed host matches the glob, eg.
+    /// `*.example.com`, from the store and exit. Works while seeker is running
+    #[clap(long, value_name = "HOST_GLOB")]
+    purge_connections: Option<String>,
+
+    /// Read hostnames or IPs from stdin and print the action each would take, without
+    /// creating the TUN device or changing system DNS
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Append the routing decision of every connection to a file, to replay them against another
+    /// config with `seeker replay`
+    #[clap(long, value_name = "FILE")]
+    record_decisions: Option<String>,
+
+    /// Don't point the system resolver at seeker's DNS server, eg. when the router or the
+    /// clients are configured to use it by hand
+    #[clap(long)]
+    no_dns_takeover: bool,
+
     /// Log file
     #[clap(short = 'l', long, value_name = "PATH")]
     log: Option<String>,
 
+    /// Run in the background. Requires `--log`, startup errors are written there
+    #[clap(long, requires = "log")]
+    daemon: bool,
+
+    /// Unix socket for `seeker ctl`, only accessible to the user running seeker. Defaults to
+    /// `seeker.sock`, or `seeker-<NAME>.sock` with `--instance`
+    #[clap(long, value_name = "PATH")]
+    control_socket: Option<String>,
+
+    /// PID file, locked while running so only one seeker sets up the TUN device and system DNS.
+    /// Defaults to `seeker.pid`, or `seeker-<NAME>.pid` with `--instance`
+    #[clap(long, value_name = "PATH")]
+    pid_file: Option<String>,
+
+    /// Name of this seeker, to run several on one host, eg. one per network namespace. The
+    /// store, routes, pid file and control socket in the current directory get the name as suffix
+    #[clap(long, value_name = "NAME", global = true)]
+    instance: Option<String>,
+
     /// Write a trace log
     #[clap(short = 't', long)]
     trace: bool,
@@ -69,16 +133,213 @@ struct SeekerArgs {
     /// Show connection stats
     #[clap(short = 's', long)]
     stats: bool,
+
+    #[clap(subcommand)]
+    command: Option<SeekerCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum SeekerCommand {
+    /// Convert a Clash config to seeker `servers`, `rules` and `dns_servers`
+    ConvertConfig {
+        /// Clash config file
+        #[clap(value_name = "FILE")]
+        input: String,
+
+        /// Output file. Print to terminal if not set
+        #[clap(short, long, value_name = "PATH")]
+        output: Option<String>,
+    },
+    /// Generate an initial config interactively. Answers can also be given with flags
+    Init {
+        /// Server URI, eg. ss://method:password@host:port, socks5://host:port or http://host:port
+        #[clap(long, value_name = "URI")]
+        server: Option<String>,
+
+        /// DNS upstream, eg. 223.5.5.5:53
+        #[clap(long, value_name = "DNS")]
+        dns: Option<String>,
+
+        /// Action for domains not matched by any rule: PROXY, DIRECT or PROBE
+        #[clap(long, value_name = "ACTION")]
+        default_action: Option<String>,
+
+        /// Path of the generated config
+        #[clap(short, long, value_name = "PATH", default_value = "config.yml")]
+        output: String,
+
+        /// Overwrite the config if it exists
+        #[clap(long)]
+        force: bool,
+
+        /// Also write a systemd unit running seeker with the generated config
+        #[clap(long, value_name = "PATH")]
+        systemd_unit: Option<String>,
+    },
+    /// Upgrade a config written for an older version of seeker to the current schema
+    MigrateConfig {
+        /// Config file
+        #[clap(value_name = "FILE")]
+        input: String,
+
+        /// Output file. Print to terminal if not set
+        #[clap(short, long, value_name = "PATH")]
+        output: Option<String>,
+    },
+    /// Control the running seeker through its control socket
+    Ctl {
+        /// Control socket of the running seeker. Defaults to the one of `--instance`
+        #[clap(long, value_name = "PATH")]
+        socket: Option<String>,
+
+        #[clap(subcommand)]
+        command: CtlCommand,
+    },
+    /// Run seeker at boot as a launchd daemon on macOS
+    Service {
+        /// Label of the launchd daemon
+        #[clap(long, value_name = "LABEL", default_value = service::DEFAULT_LABEL)]
+        label: String,
+
+        #[clap(subcommand)]
+        command: ServiceCommand,
+    },
+    /// Route the decisions recorded with `--record-decisions` with another config and print the
+    /// ones that change
+    Replay {
+        /// Decisions recorded with `--record-decisions`
+        #[clap(value_name = "FILE")]
+        trace: String,
+
+        /// Config to route them with
+        #[clap(short, long, value_name = "FILE")]
+        config: String,
+
+        /// Name of the profile in config to use
+        #[clap(short = 'p', long, value_name = "NAME")]
+        profile: Option<String>,
+    },
+    /// Measure the throughput of the AEAD ciphers on this machine
+    BenchCiphers {
+        /// Seconds to spend on each cipher
+        #[clap(long, value_name = "SECONDS", default_value = "1")]
+        duration: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Write the launchd plist and start seeker, it also runs at boot
+    Install {
+        /// Config file
+        #[clap(short, long, value_name = "PATH")]
+        config: String,
+
+        /// Log file
+        #[clap(
+            short,
+            long,
+            value_name = "PATH",
+            default_value = "/var/log/seeker.log"
+        )]
+        log: String,
+    },
+    /// Stop seeker and remove the launchd plist
+    Uninstall,
+    /// Start the installed seeker
+    Start,
+    /// Stop the installed seeker until it's started again or the next boot
+    Stop,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// List live connections: id, network, action, remote address, traffic and duration
+    Connections,
+    /// List the servers that answered the last ping, the selected one is marked with `*`
+    Servers,
+    /// Select a server by name
+    Switch {
+        #[clap(value_name = "NAME")]
+        name: String,
+    },
+    /// Shut down a connection by id
+    Kill {
+        #[clap(value_name = "ID")]
+        id: u64,
+    },
+    /// Forget server health, resolved server IPs and connections, as after a network change
+    Reset,
+    /// Show the mode, whether seeker is paused and the selected server
+    Status,
+    /// Send all new connections direct until resumed, the TUN device stays up
+    Pause,
+    /// Match connections against the rules again
+    Resume,
+    /// Read the rules from the config file again, connections already relayed keep their actions
+    Reload,
+    /// List the last control actions: time, source, action and detail
+    Audit,
+    /// Show the month to date traffic through each server and its projection for the month
+    Usage,
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() {
+    if let Err(e) = run() {
+        // Printed like errors returned from main, failed startup checks have their own status.
+        eprintln!("Error: {e:?}");
+        let status = e
+            .downcast_ref::<StartupError>()
+            .map_or(1, |e| e.failure.exit_code());
+        std::process::exit(status.into());
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     let args = SeekerArgs::parse();
+    if let Some(instance) = &args.instance {
+        config::set_instance(instance)?;
+    }
+
+    if let Some(command) = &args.command {
+        return run_command(command);
+    }
+
+    if let Some(host_glob) = &args.purge_connections {
+        let store = Store::open_existing(config::instance_path(config::DEFAULT_STORE_PATH))
+            .context("Open store error")?;
+        let deleted = store
+            .delete_connections_matching(host_glob)
+            .context("Delete connections error")?;
+        println!("Deleted {deleted} connections matching `{host_glob}`");
+        return Ok(());
+    }
 
     let path = args.config.as_ref().map(String::as_ref);
-    let key = args.key.as_ref().map(String::as_ref);
+    let key = match &args.key_file {
+        Some(key_file) => Some(
+            std::fs::read_to_string(key_file)
+                .context("Read key file error")?
+                .trim()
+                .to_string(),
+        ),
+        None => args.key.clone(),
+    };
+    let key = key.as_deref();
     let to_encrypt = args.encrypt;
     let to_trace = args.trace;
 
+    if let Some(secret) = &args.encrypt_secret {
+        let Some(key) = key else {
+            bail!("key or key_file must be provided");
+        };
+        println!(
+            "{}",
+            config_encryptor::encrypt_secret(secret, CipherType::ChaCha20Ietf, key)?
+        );
+        return Ok(());
+    }
+
     if to_encrypt {
         println!(
             "Encrypted content is as below:\n\n\n{}\n\n",
@@ -88,9 +349,66 @@ fn main() -> anyhow::Result<()> {
     }
     let config_url = args.config_url;
 
-    let dns_setup = DNSSetup::new("127.0.0.1".to_string());
+    let signature = args
+        .config_pubkey
+        .as_deref()
+        .map(|public_key| SignatureOptions {
+            public_key,
+            signature_url: args.config_signature_url.as_deref(),
+        });
+
+    if args.dry_run {
+        let config = load_config(
+            path,
+            config_url.as_deref(),
+            signature.as_ref(),
+            args.profile.as_deref(),
+            vec![],
+            key,
+        )
+        .map_err(diagnostics::config_error)?;
+        let stdin = std::io::stdin();
+        return dry_run::run(&config, stdin.lock(), std::io::stdout().lock());
+    }
+
+    let mut daemon = match (&args.log, args.daemon) {
+        (Some(log), true) => Some(daemon::daemonize(log)?),
+        _ => None,
+    };
+    let pid_file = args
+        .pid_file
+        .unwrap_or_else(|| config::instance_path(config::DEFAULT_PID_PATH));
+    let _pid_file = daemon::PidFile::acquire(&pid_file)?;
+    let control_socket = args
+        .control_socket
+        .unwrap_or_else(|| config::instance_path(config::DEFAULT_CONTROL_SOCKET_PATH));
+
+    let mut dns_setup = DNSSetup::new(
+        "127.0.0.1".to_string(),
+        config::instance_path(config::DEFAULT_DNS_BACKUP_PATH),
+    );
+    let dns_takeover = !args.no_dns_takeover;
 
-    let config = load_config(path, config_url.as_deref(), dns_setup.original_dns(), key)?;
+    let config = load_config(
+        path,
+        config_url.as_deref(),
+        signature.as_ref(),
+        args.profile.as_deref(),
+        dns_setup.original_dns(),
+        key,
+    )
+    .map_err(|e| startup_failed(&mut daemon, diagnostics::config_error(e)))?;
+    diagnostics::check(&config).map_err(|e| startup_failed(&mut daemon, e.into()))?;
+    if !config.redir_mode {
+        check_route_conflicts(&config)?;
+    }
+    tcp_connection::set_tcp_options(config.tcp_options());
+    // Read by async-std when the executor starts, which is the first spawn below.
+    if let Some(threads) = config.worker_threads {
+        if std::env::var_os("ASYNC_STD_THREAD_COUNT").is_none() {
+            std::env::set_var("ASYNC_STD_THREAD_COUNT", threads.to_string());
+        }
+    }
 
     let uid = args.user_id;
     let log_path = args.log;
@@ -98,9 +416,22 @@ fn main() -> anyhow::Result<()> {
 
     eprint!("Starting.");
     let _guard = setup_logger(log_path.as_deref(), to_trace)?;
+    install_panic_hook();
     eprint!(".");
     set_rlimit_no_file(10240)?;
     eprint!(".");
+    if let Some(path) = &args.record_decisions {
+        seeker_core::record_decisions(path).context("Open decision record error")?;
+    }
+    if config.sandbox {
+        // Before the worker threads start, landlock only restricts threads created afterwards.
+        let files: Vec<&str> = log_path
+            .as_deref()
+            .into_iter()
+            .chain([pid_file.as_str(), control_socket.as_str()])
+            .collect();
+        apply_sandbox(&files, dns_takeover && dns_setup.rewrites_resolv_conf())?;
+    }
     let _ip_forward = if config.gateway_mode {
         // In gateway mode, dns server need be accessible from the network.
         Some(IpForward::new())
@@ -116,13 +447,83 @@ fn main() -> anyhow::Result<()> {
     block_on(async {
         let cidr = config.tun_cidr.to_string();
         let redir_mode = config.redir_mode;
+        let tun_name = config.tun_name.clone();
+        let tun_ip = config.tun_ip.to_string();
+        let run_as = config.run_as.clone();
+        #[cfg(target_os = "linux")]
+        let gateway_mode = config.gateway_mode;
+        let hijacked_dns_servers = config
+            .dns_hijack
+            .servers
+            .iter()
+            .filter(|_| config.dns_hijack.enabled)
+            .map(|ip| format!("{ip}/32"));
+        let tun_routes: Vec<_> = std::iter::once(config.tun_cidr)
+            .chain(config.rules.additional_cidrs())
+            .map(|cidr| cidr.to_string())
+            .chain(hijacked_dns_servers)
+            .collect();
+        let _kill_switch = if config.kill_switch.enabled {
+            Some(std::sync::Arc::new(start_kill_switch(&config)?))
+        } else {
+            None
+        };
         let client = ProxyClient::new(config, uid, show_stats)
             .instrument(tracing::trace_span!("ProxyClient.new"))
-            .await;
+            .await
+            .context("Start proxy client error")?;
+        #[cfg(target_os = "linux")]
+        if let Some(kill_switch) = &_kill_switch {
+            // Weak, the table is still removed on exit while tasks hold the dns client.
+            let kill_switch = std::sync::Arc::downgrade(kill_switch);
+            client.on_server_resolved(move |ip| {
+                if let Some(kill_switch) = kill_switch.upgrade() {
+                    if let Err(e) = kill_switch.allow(ip) {
+                        tracing::warn!(?e, %ip, "Allow server through kill switch error");
+                    }
+                }
+            });
+        }
+        let network_reset = client.network_reset();
+        let mut controller = client.controller();
+        if let Some(path) = path {
+            controller = controller.with_config_file(path, args.profile.clone());
+        }
         eprint!(".");
 
-        dns_setup.start();
+        let route_setup = if redir_mode {
+            None
+        } else {
+            let setup = RouteSetup::new(
+                tun_name.clone(),
+                tun_ip,
+                tun_routes,
+                config::instance_path(config::DEFAULT_ROUTES_PATH),
+            );
+            setup.start().context("Setup routes error")?;
+            Some(setup)
+        };
+        #[cfg(target_os = "linux")]
+        let _nat_setup = if gateway_mode {
+            let setup = sysconfig::NatSetup::new(tun_name.clone());
+            setup.start().context("Setup gateway NAT error")?;
+            Some(setup)
+        } else {
+            None
+        };
+        if dns_takeover {
+            dns_setup.start(&tun_name);
+        }
+        if let Some(run_as) = run_as {
+            drop_privileges(&run_as, dns_takeover && dns_setup.rewrites_resolv_conf())?;
+        }
         eprintln!("Started!");
+        if let Some(daemon) = daemon {
+            daemon.ready();
+        }
+        if let Err(e) = sd_notify("READY=1") {
+            tracing::warn!(?e, "Notify systemd error");
+        }
 
         let mut _iptables_setup: Option<IptablesSetup> = None;
         if redir_mode {
@@ -140,40 +541,298 @@ fn main() -> anyhow::Result<()> {
                     .await
                     .expect("Could not receive signal on channel.");
             })
+            .race(ping_watchdog())
+            .race(async {
+                if let Err(e) = serve_control(&control_socket, controller).await {
+                    tracing::error!(?e, "Control socket error");
+                }
+                std::future::pending::<()>().await
+            })
+            .race(async {
+                let ret = watch_network(&tun_name, || {
+                    if let Some(setup) = &route_setup {
+                        if let Err(e) = setup.reapply() {
+                            tracing::warn!(?e, "Reapply routes error");
+                        }
+                    }
+                    network_reset.reset();
+                })
+                .await;
+                if let Err(e) = ret {
+                    tracing::error!(?e, "Watch network changes error");
+                }
+                std::future::pending::<()>().await
+            })
             .await;
-    });
+        let _ = sd_notify("STOPPING=1");
+        // Bytes counted since the last flush of the store thread.
+        if let Err(e) = Store::global().flush_connection_counters() {
+            tracing::error!(?e, "Flush connection counters error");
+        }
+        anyhow::Ok(())
+    })?;
 
     println!("Stop server. Bye bye...");
     Ok(())
 }
 
+/// Pass the status of a failed startup check to the foreground process of `--daemon`.
+fn startup_failed(daemon: &mut Option<daemon::Daemon>, e: anyhow::Error) -> anyhow::Error {
+    if let (Some(startup), Some(daemon)) = (e.downcast_ref::<StartupError>(), daemon.take()) {
+        daemon.fail(startup.failure.exit_code());
+    }
+    e
+}
+
+/// Block traffic leaving through physical interfaces except to the servers and DNS upstreams.
+/// Server domains are resolved now, before the system DNS is taken over, addresses they are
+/// resolved to later are allowed when seeker resolves them.
+#[cfg(target_os = "linux")]
+fn start_kill_switch(config: &Config) -> anyhow::Result<sysconfig::KillSwitch> {
+    let mut allowed = vec![];
+    for server in config.servers.iter() {
+        match server.addr() {
+            config::Address::SocketAddress(addr) => allowed.push(addr.ip()),
+            config::Address::DomainNameAddress(domain, port) => allowed.extend(
+                (domain.as_str(), *port)
+                    .to_socket_addrs()
+                    .with_context(|| format!("Resolve server {domain} error"))?
+                    .map(|addr| addr.ip()),
+            ),
+        }
+    }
+    for dns in &config.dns_servers {
+        match dns {
+            config::DnsServerAddr::UdpSocketAddr(addr) => allowed.push(addr.ip()),
+            config::DnsServerAddr::TcpSocketAddr(url) => allowed.extend(
+                url.socket_addrs(|| Some(53))
+                    .with_context(|| format!("Resolve dns server {url} error"))?
+                    .into_iter()
+                    .map(|addr| addr.ip()),
+            ),
+        }
+    }
+    allowed.sort();
+    allowed.dedup();
+    let kill_switch = sysconfig::KillSwitch::new(
+        config.tun_name.clone(),
+        allowed,
+        config.kill_switch.allow_lan,
+    );
+    kill_switch.start().context("Setup kill switch error")?;
+    Ok(kill_switch)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_kill_switch(_config: &Config) -> anyhow::Result<()> {
+    bail!("kill_switch is only supported on linux")
+}
+
+/// Switch to the `run_as` user, keeping `CAP_NET_ADMIN` for cleaning up the routes on exit, and
+/// `CAP_DAC_OVERRIDE` too if `/etc/resolv.conf` has to be restored.
+#[cfg(target_os = "linux")]
+fn drop_privileges(run_as: &config::RunAsConfig, restores_resolv_conf: bool) -> anyhow::Result<()> {
+    let mut keep = vec![sysconfig::CAP_NET_ADMIN];
+    if restores_resolv_conf {
+        keep.push(sysconfig::CAP_DAC_OVERRIDE);
+    }
+    sysconfig::drop_privileges(&run_as.user, run_as.group.as_deref(), &keep)
+        .with_context(|| format!("Switch to user {} error", run_as.user))?;
+    tracing::info!(user = %run_as.user, "Dropped privileges");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_privileges(
+    _run_as: &config::RunAsConfig,
+    _restores_resolv_conf: bool,
+) -> anyhow::Result<()> {
+    bail!("run_as is only supported on linux")
+}
+
+/// Restrict seeker to the files and syscalls it needs. Files can be read from the system
+/// directories, and written next to `files`, in the current directory holding the store and
+/// `seeker.routes`, and where the commands run on exit keep their locks.
+#[cfg(target_os = "linux")]
+fn apply_sandbox(files: &[&str], rewrites_resolv_conf: bool) -> anyhow::Result<()> {
+    use std::path::Path;
+
+    let read_only = [
+        "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/proc", "/sys",
+    ]
+    .map(Path::new);
+    let mut read_write: Vec<&Path> = ["/dev", "/run", "/tmp", "/proc/sys/net", "."]
+        .into_iter()
+        .map(Path::new)
+        .collect();
+    // `/etc/resolv.conf` is replaced by renaming, which needs the directory.
+    if rewrites_resolv_conf {
+        read_write.push(Path::new("/etc"));
+    }
+    read_write.extend(
+        files
+            .iter()
+            .filter_map(|file| Path::new(file).parent())
+            .filter(|dir| !dir.as_os_str().is_empty()),
+    );
+    if !sysconfig::restrict_filesystem(&read_only, &read_write).context("Landlock error")? {
+        tracing::warn!("Landlock is not supported by the kernel, files are not restricted");
+    }
+    sysconfig::restrict_syscalls().context("Seccomp error")?;
+    tracing::info!("Sandbox applied");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn apply_sandbox(_files: &[&str], _rewrites_resolv_conf: bool) -> anyhow::Result<()> {
+    bail!("sandbox is only supported on linux")
+}
+
+/// Send `WATCHDOG=1` to systemd at half of `WatchdogSec`. It runs on the same executor as the
+/// proxy, so a stuck executor gets the service restarted. Never returns.
+async fn ping_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return futures_util::future::pending().await;
+    };
+    loop {
+        if let Err(e) = sd_notify("WATCHDOG=1") {
+            tracing::warn!(?e, "Ping systemd watchdog error");
+        }
+        async_std::task::sleep(interval / 2).await;
+    }
+}
+
+fn run_command(command: &SeekerCommand) -> anyhow::Result<()> {
+    let (converted, output) = match command {
+        SeekerCommand::Init {
+            server,
+            dns,
+            default_action,
+            output,
+            force,
+            systemd_unit,
+        } => {
+            let options = init_config::InitOptions {
+                server: server.as_deref(),
+                dns: dns.as_deref(),
+                default_action: default_action.as_deref(),
+                output,
+                force: *force,
+                systemd_unit: systemd_unit.as_deref(),
+            };
+            let stdin = std::io::stdin();
+            return init_config::run(&options, stdin.lock(), std::io::stdout().lock());
+        }
+        SeekerCommand::Ctl { socket, command } => {
+            let args = match command {
+                CtlCommand::Connections => vec!["connections".to_string()],
+                CtlCommand::Servers => vec!["servers".to_string()],
+                CtlCommand::Switch { name } => vec!["switch".to_string(), name.clone()],
+                CtlCommand::Kill { id } => vec!["kill".to_string(), id.to_string()],
+                CtlCommand::Reset => vec!["reset".to_string()],
+                CtlCommand::Status => vec!["status".to_string()],
+                CtlCommand::Pause => vec!["pause".to_string()],
+                CtlCommand::Resume => vec!["resume".to_string()],
+                CtlCommand::Reload => vec!["reload".to_string()],
+                CtlCommand::Audit => vec!["audit".to_string()],
+                CtlCommand::Usage => vec!["usage".to_string()],
+            };
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            let socket = socket
+                .clone()
+                .unwrap_or_else(|| config::instance_path(config::DEFAULT_CONTROL_SOCKET_PATH));
+            return control_request(&socket, &args, std::io::stdout().lock());
+        }
+        SeekerCommand::Service { label, command } => {
+            return match command {
+                ServiceCommand::Install { config, log } => service::install(label, config, log),
+                ServiceCommand::Uninstall => service::uninstall(label),
+                ServiceCommand::Start => service::start(label),
+                ServiceCommand::Stop => service::stop(label),
+            };
+        }
+        SeekerCommand::Replay {
+            trace,
+            config,
+            profile,
+        } => {
+            let config = Config::from_config_file_with_profile(config, profile.as_deref())
+                .context("Load config error")?;
+            let file = File::open(trace).context("Open recorded decisions error")?;
+            return replay::run(
+                &config,
+                std::io::BufReader::new(file),
+                std::io::stdout().lock(),
+            );
+        }
+        SeekerCommand::BenchCiphers { duration } => {
+            return bench_ciphers::run(
+                std::time::Duration::from_secs(*duration),
+                std::io::stdout().lock(),
+            );
+        }
+        SeekerCommand::ConvertConfig { input, output } => {
+            let file = File::open(input).context("Open clash config error")?;
+            let converted =
+                config::convert_clash_config(file).context("Convert clash config error")?;
+            (converted, output)
+        }
+        SeekerCommand::MigrateConfig { input, output } => {
+            let file = File::open(input).context("Open config error")?;
+            let (migrated, warnings) =
+                config::migrate_config(file).context("Migrate config error")?;
+            for warning in warnings {
+                eprintln!("{warning}");
+            }
+            (migrated, output)
+        }
+    };
+    match output {
+        Some(output) => std::fs::write(output, converted).context("Write config error")?,
+        None => println!("{converted}"),
+    }
+    Ok(())
+}
+
 fn load_config(
     path: Option<&str>,
     url: Option<&str>,
+    signature: Option<&SignatureOptions>,
+    profile: Option<&str>,
     original_dns: Vec<String>,
     decrypt_key: Option<&str>,
 ) -> anyhow::Result<Config> {
-    let mut c = match (path, url, decrypt_key) {
-        (Some(p), ..) => Config::from_config_file(p).context("Load config from path error")?,
-        (_, Some(url), Some(key)) => {
-            let ret = ureq::get(url).timeout(Duration::from_secs(5)).call();
-            let resp = match ret {
-                Err(e) => {
-                    return Err(anyhow::anyhow!(
-                        "Load config from remote host error: {}",
-                        e.to_string()
-                    ));
+    let mut c = match (path, url) {
+        (Some(p), _) => Config::from_config_file_with_profile(p, profile)
+            .context("Load config from path error")?,
+        (_, Some(url)) => {
+            let data = remote_config::fetch_remote_config(
+                url,
+                signature,
+                config::instance_path(config::DEFAULT_STORE_PATH),
+            )?;
+            let config = match decrypt_key {
+                Some(key) => {
+                    config_encryptor::decrypt_config(data.as_slice(), CipherType::ChaCha20Ietf, key)
+                        .context("Decrypt remote config error")?
                 }
-                Ok(resp) => resp,
+                None => data,
             };
-            let config =
-                config_encryptor::decrypt_config(resp.into_reader(), CipherType::ChaCha20Ietf, key)
-                    .context("Decrypt remote config error")?;
-            Config::from_reader(config.as_slice()).context("Load Config error")?
+            Config::from_reader_with_profile(config.as_slice(), profile)
+                .context("Load Config error")?
         }
         _ => bail!("Parameters error"),
     };
 
+    // Always run, plain text secrets escaped with `enc::` are unescaped here as well.
+    c.decrypt_secrets(|encrypted| {
+        let Some(key) = decrypt_key else {
+            bail!("Config contains encrypted secrets, key or key_file must be provided");
+        };
+        config_encryptor::decrypt_secret(encrypted, CipherType::ChaCha20Ietf, key)
+    })
+    .context("Decrypt config secrets error")?;
+
     // If dns_servers is empty, use original dns servers.
     if c.dns_servers.is_empty() {
         for dns in original_dns {
@@ -185,6 +844,27 @@ fn load_config(
     Ok(c)
 }
 
+/// Fail if `tun_cidr` overlaps with routes of other interfaces, in which case either the fake IPs
+/// or the local network would be unreachable.
+fn check_route_conflicts(config: &Config) -> anyhow::Result<()> {
+    let network = Ipv4Addr::from(config.tun_cidr.address().0);
+    let prefix_len = config.tun_cidr.prefix_len();
+    let routes = sysconfig::list_routes().context("List routes error")?;
+    if let Some(route) = routes.iter().find(|r| {
+        !r.is_default() && r.interface != config.tun_name && r.overlaps(network, prefix_len)
+    }) {
+        bail!(
+            "tun_cidr {} overlaps with route {}/{} of interface {}, \
+             change tun_cidr to an unused subnet",
+            config.tun_cidr,
+            route.destination,
+            route.prefix_len,
+            route.interface
+        );
+    }
+    Ok(())
+}
+
 fn encrypt_config(path: Option<&str>, encrypt_key: Option<&str>) -> anyhow::Result<String> {
     let (Some(path), Some(key)) = (path, encrypt_key) else {
         return Err(anyhow::anyhow!("path and encrypt_key must be provided"));
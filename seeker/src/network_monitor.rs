@@ -0,0 +1,98 @@
+//! Set up again when the machine switches networks or wakes from sleep: routes of the TUN device
+//! may have been flushed, and server health, resolved IPs, established connections and UDP
+//! sessions belong to the previous network. After a sleep the servers have usually dropped the
+//! connections already, closing them is faster than waiting for the TCP timeouts.
+use crate::dns_client::DnsClient;
+use crate::probe_connectivity::ProbeConnectivity;
+use crate::proxy_client::UdpManager;
+use crate::proxy_connection::ProxyConnection;
+use crate::server_chooser::ServerChooser;
+use async_std::channel::{bounded, TrySendError};
+use async_std::prelude::*;
+use async_std::task::sleep;
+use std::future::pending;
+use std::io::Result;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use sysconfig::NetworkMonitor;
+use tracing::{info, warn};
+
+/// Changes come in bursts, eg. the link, then the address, then the routes.
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+const WAKE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// The wall clock running ahead of the sleep by more than this means the machine was suspended.
+const WAKE_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Forgets the state of the proxy learned on the previous network.
+#[derive(Clone)]
+pub struct NetworkReset {
+    pub(crate) server_chooser: Arc<ServerChooser>,
+    pub(crate) connectivity: ProbeConnectivity,
+    pub(crate) dns_client: DnsClient,
+    pub(crate) udp_manager: UdpManager,
+}
+
+impl NetworkReset {
+    pub fn reset(&self) {
+        // Before the servers are pinged again, so they are resolved on the new network.
+        self.dns_client.clear_cache();
+        self.server_chooser.reset();
+        // The fake IPs are kept, apps still have them cached.
+        self.close_udp_sessions();
+        self.connectivity.clear();
+    }
+
+    /// Packets of the sessions go through new sockets instead of the closed ones.
+    pub(crate) fn close_udp_sessions(&self) {
+        let sessions = self.udp_manager.clear();
+        if !sessions.is_empty() {
+            info!(count = sessions.len(), "Close udp sessions");
+        }
+        for (socket, _) in sessions {
+            socket.shutdown();
+        }
+    }
+}
+
+/// Call `on_change` after every network change, ignoring changes of `tun_name`. Never returns
+/// unless the monitor can't be created.
+pub async fn watch(tun_name: &str, mut on_change: impl FnMut()) -> Result<()> {
+    let monitor = NetworkMonitor::new(tun_name)?;
+    let (tx, rx) = bounded(1);
+    std::thread::Builder::new()
+        .name("network-monitor".to_string())
+        .spawn(move || loop {
+            if let Err(e) = monitor.wait() {
+                warn!(?e, "Network monitor error");
+                return;
+            }
+            // A pending notification covers this change too.
+            if let Err(TrySendError::Closed(_)) = tx.try_send(()) {
+                return;
+            }
+        })?;
+    loop {
+        let changed = async {
+            if rx.recv().await.is_err() {
+                pending::<()>().await;
+            }
+        };
+        changed.race(wait_for_wake()).await;
+        sleep(SETTLE_DELAY).await;
+        while rx.try_recv().is_ok() {}
+        info!("Network changed, set up again");
+        on_change();
+    }
+}
+
+async fn wait_for_wake() {
+    loop {
+        let before = SystemTime::now();
+        sleep(WAKE_CHECK_INTERVAL).await;
+        let elapsed = SystemTime::now().duration_since(before).unwrap_or_default();
+        if elapsed > WAKE_CHECK_INTERVAL + WAKE_THRESHOLD {
+            info!(?elapsed, "Woke from sleep");
+            return;
+        }
+    }
+}
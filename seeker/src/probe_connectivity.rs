@@ -5,7 +5,6 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use async_std::net::TcpStream;
 use async_std::prelude::*;
 use config::Address;
 use tracing::instrument;
@@ -24,13 +23,18 @@ impl ProbeConnectivity {
         }
     }
 
+    /// Forget the probe results, eg. after the network changed.
+    pub(crate) fn clear(&self) {
+        self.map.lock().clear();
+    }
+
     #[instrument(skip(sock_addr, timeout))]
     pub(crate) async fn force_probe_connectivity(
         sock_addr: SocketAddr,
         addr: &Address,
         timeout: Duration,
     ) -> bool {
-        let Ok(Ok(tcp_stream)) = TcpStream::connect(sock_addr).timeout(timeout).await else {
+        let Ok(Ok(tcp_stream)) = tcp_connection::connect(sock_addr).timeout(timeout).await else {
             return false;
         };
 
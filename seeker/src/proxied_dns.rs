@@ -0,0 +1,74 @@
+//! The `dns_groups` with `proxy: true`, their servers are queried over tcp through the selected
+//! proxy server so answers can't be tampered with on the way.
+use crate::proxy_connection::ProxyConnection;
+use crate::server_chooser::ServerChooser;
+use async_std::io::timeout;
+use async_trait::async_trait;
+use config::rule::{Action, RuleOptions};
+use config::Address;
+use dnsserver::upstream::{query_tcp, DnsUpstream};
+use hermesdns::{DnsPacket, QueryType};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) struct ProxiedDnsUpstream {
+    servers: Vec<SocketAddr>,
+    server_chooser: Arc<ServerChooser>,
+    timeout: Duration,
+}
+
+impl ProxiedDnsUpstream {
+    /// Each server is given `timeout` to answer, the next one is queried when it doesn't.
+    pub(crate) fn new(
+        servers: Vec<SocketAddr>,
+        server_chooser: Arc<ServerChooser>,
+        timeout: Duration,
+    ) -> Self {
+        ProxiedDnsUpstream {
+            servers,
+            server_chooser,
+            timeout,
+        }
+    }
+
+    async fn query_server(
+        &self,
+        server: SocketAddr,
+        domain: &str,
+        qtype: QueryType,
+    ) -> Result<DnsPacket> {
+        let mut conn = self
+            .server_chooser
+            .candidate_tcp_stream(
+                Address::SocketAddress(server),
+                Action::Proxy,
+                RuleOptions::default(),
+                None,
+                self.timeout,
+                0,
+            )
+            .await?;
+        let ret = timeout(self.timeout, query_tcp(&mut conn, domain, qtype)).await;
+        conn.shutdown();
+        ret
+    }
+}
+
+#[async_trait]
+impl DnsUpstream for ProxiedDnsUpstream {
+    async fn query(&self, domain: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let mut last_error = Error::new(ErrorKind::NotFound, "no dns server");
+        for server in &self.servers {
+            match self.query_server(*server, domain, qtype).await {
+                Ok(packet) => return Ok(packet),
+                Err(e) => {
+                    tracing::warn!(%e, %server, domain, "query through proxy error");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
@@ -1,9 +1,27 @@
+use crate::admission::Admission;
+use crate::chaos;
+use crate::clock;
+use crate::control::Controller;
+use crate::decision_log::{self, Route};
 use crate::dns_client::DnsClient;
+use crate::dns_hijack;
+use crate::dns_rate_limit::DnsRateLimit;
+use crate::hooks::Hooks;
+use crate::isolate::isolate;
+use crate::network_monitor::NetworkReset;
 use crate::probe_connectivity::ProbeConnectivity;
+use crate::proxied_dns::ProxiedDnsUpstream;
+use crate::proxy_connection::ProxyConnection;
 use crate::proxy_udp_socket::ProxyUdpSocket;
+use crate::quic::QuicConnectionIds;
+use crate::reject_page;
 use crate::relay_tcp_stream::relay_tcp_stream;
-use crate::relay_udp_socket::relay_udp_socket;
+use crate::relay_udp_socket::{relay_udp_socket, UdpDest};
+use crate::runtime::Mode;
 use crate::server_chooser::ServerChooser;
+use crate::tunnel;
+use crate::udp_batch::{BatchSocket, UdpBatch};
+use crate::udp_nat::UdpNatTable;
 use crate::REDIR_LISTEN_PORT;
 use async_std::future::pending;
 use async_std::io::timeout;
@@ -11,22 +29,28 @@ use async_std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
 use async_std::task::{spawn, JoinHandle};
 use async_std::{prelude::*, task};
 use async_std_resolver::AsyncStdResolver;
-use config::rule::Action;
-use config::{Address, Config};
+use config::rule::{Action, RuleOptions};
+use config::{Address, Config, InboundConfig};
 use dnsserver::create_dns_server;
 use dnsserver::resolver::RuleBasedDnsResolver;
-use parking_lot::RwLock;
+use dnsserver::steering::DnsSteering;
+use dnsserver::upstream::DnsGroup;
+use futures_util::stream::FuturesUnordered;
+use hermesdns::{QueryFilter, ServerContext};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
 
-use std::net::IpAddr;
-
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
 use std::sync::Arc;
-use tracing::{error, instrument, trace, trace_span};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use sysconfig::SourceOrigin;
+use tracing::{debug, error, info, instrument, trace, trace_span, warn};
 use tracing_futures::Instrument;
-use tun_nat::{run_nat, SessionManager};
+use tun_nat::{run_nat, SessionManager, SynFilter};
 
-pub(crate) type UdpManager = Arc<RwLock<HashMap<u16, (ProxyUdpSocket, SocketAddr, Address)>>>;
+pub(crate) type UdpManager = UdpNatTable<(ProxyUdpSocket, UdpDest)>;
 
 pub struct ProxyClient {
     config: Config,
@@ -35,27 +59,122 @@ pub struct ProxyClient {
     // When in redir mode, session_manager is None
     session_manager: Option<SessionManager>,
     udp_manager: UdpManager,
+    quic_ids: QuicConnectionIds,
     resolver: RuleBasedDnsResolver,
+    /// The embedded DNS server, answering the hijacked DNS traffic too.
+    dns_context: Arc<ServerContext>,
     dns_client: DnsClient,
     server_chooser: Arc<ServerChooser>,
+    admission: Admission,
     nat_join_handle: Option<JoinHandle<()>>,
     dns_server_join_handle: Option<JoinHandle<()>>,
     chooser_join_handle: Option<JoinHandle<()>>,
 }
 
+/// Finishes with the task, never if there's none. The task is cancelled when this is dropped,
+/// eg. when the proxy client stops running.
+struct BackgroundTask(Option<JoinHandle<()>>);
+
+impl Future for BackgroundTask {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.0.as_mut() {
+            Some(handle) => Pin::new(handle).poll(cx),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            spawn(handle.cancel());
+        }
+    }
+}
+
+fn set_recv_buffer_size(listener: &TcpListener, size: usize) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &size as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&size) as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Resolve the servers ahead of the first connection, in the background so a slow dns server
+/// doesn't delay startup. Servers resolved into `tun_cidr` would be connected through the TUN
+/// device itself, they are logged and their connections fail.
+async fn check_server_addresses(config: &Config, dns_client: &DnsClient) {
+    let mut lookups: FuturesUnordered<_> = config
+        .servers
+        .iter()
+        .filter(|server| matches!(server.addr(), Address::DomainNameAddress(..)))
+        .map(|server| async move { (server, dns_client.lookup_server(server.addr()).await) })
+        .collect();
+    while let Some((server, addr)) = lookups.next().await {
+        match addr {
+            // The address is cached for connections to the server.
+            Ok(_) => {}
+            // Resolved into tun_cidr, see `DnsClient::exclude_network`.
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                error!(
+                    server = server.name(),
+                    "{e} or change tun_cidr {}", config.tun_cidr
+                );
+            }
+            // Unresolvable servers are reported by the server chooser later.
+            Err(_) => {}
+        }
+    }
+}
+
 impl ProxyClient {
-    pub async fn new(config: Config, uid: Option<u32>, show_stats: bool) -> Self {
-        let additional_cidrs = config.rules.additional_cidrs();
+    pub async fn new(config: Config, uid: Option<u32>, show_stats: bool) -> Result<Self> {
+        let mut dns_client = DnsClient::new(&config.dns_servers, config.dns_timeout).await;
+        if !config.redir_mode {
+            dns_client = dns_client.exclude_network(
+                Ipv4Addr::from(config.tun_cidr.address().0),
+                config.tun_cidr.prefix_len(),
+            );
+            let (config, dns_client) = (config.clone(), dns_client.clone());
+            spawn(async move { check_server_addresses(&config, &dns_client).await });
+        }
+        if let Some(prefix) = config.nat64_prefix {
+            dns_client = dns_client.nat64(prefix);
+        }
 
         let (session_manager, nat_join_handle) = if !config.redir_mode {
+            // Connections of other users bypass the rules, which user a SYN comes from isn't
+            // known. Where it comes from isn't either, `is_fake_ip_rejected` passes the domains
+            // rejected after source rules.
+            let reject_syn = uid.is_none().then(|| {
+                let config = config.clone();
+                Arc::new(move |dest| is_rejected(&config, dest)) as SynFilter
+            });
             let (session_manager, blocking_join_handle) = run_nat(
                 &config.tun_name,
                 config.tun_ip,
-                config.tun_cidr,
                 REDIR_LISTEN_PORT,
-                &additional_cidrs,
+                config.tun_mtu,
+                config.tcp_mss,
+                config.tun_io_uring,
+                config.tun_queues.unwrap_or(1),
+                reject_syn,
+                config.tun_offload,
             )
-            .expect("run nat");
+            .map_err(|e| Error::new(e.kind(), format!("create tun {}: {e}", config.tun_name)))?;
             let nat_join_handle = task::spawn_blocking(move || match blocking_join_handle.join() {
                 Ok(()) => tracing::info!("nat stopped"),
                 Err(e) => tracing::error!("nat stopped with error: {:?}", e),
@@ -64,23 +183,42 @@ impl ProxyClient {
         } else {
             (None, None)
         };
+        Self::with_sessions(
+            config,
+            uid,
+            show_stats,
+            dns_client,
+            session_manager,
+            nat_join_handle,
+        )
+        .await
+    }
 
-        let dns_client = DnsClient::new(&config.dns_servers, config.dns_timeout).await;
-
-        let (resolver, dns_server_join_handle) =
-            run_dns_resolver(&config, dns_client.resolver()).await;
-
+    /// The client relaying the sessions of `session_manager`, None in redir mode. Sessions are
+    /// created by the NAT of the TUN device, or by the tests simulating it.
+    pub(crate) async fn with_sessions(
+        config: Config,
+        uid: Option<u32>,
+        show_stats: bool,
+        dns_client: DnsClient,
+        session_manager: Option<SessionManager>,
+        nat_join_handle: Option<JoinHandle<()>>,
+    ) -> Result<Self> {
         let ping_urls = config.ping_urls.clone();
-        let chooser = Arc::new(
-            ServerChooser::new(
-                config.servers.clone(),
-                dns_client.clone(),
-                ping_urls,
-                config.ping_timeout,
-                show_stats,
-            )
-            .await,
-        );
+        let chooser = Arc::new(ServerChooser::new(
+            config.servers.clone(),
+            dns_client.clone(),
+            ping_urls,
+            config.ping_timeout,
+            config.tcp_connect_timeout(Action::Proxy, RuleOptions::default()),
+            config.connection_pool,
+            config.http_reuse,
+            Arc::new(Hooks::new(config.hooks.clone())),
+            show_stats,
+        ));
+
+        let (resolver, dns_context, dns_server_join_handle) =
+            run_dns_resolver(&config, dns_client.resolver(), chooser.clone()).await?;
         let chooser_clone = chooser.clone();
         let chooser_join_handle = spawn(async move {
             chooser_clone
@@ -90,10 +228,18 @@ impl ProxyClient {
                 .unwrap()
         });
 
-        Self {
+        let udp_manager = UdpManager::new(config.udp_idle_timeout());
+        if show_stats {
+            spawn(print_udp_nat_stats(udp_manager.clone()));
+        }
+
+        Ok(Self {
             resolver,
+            dns_context,
             connectivity: ProbeConnectivity::new(config.probe_timeout),
-            udp_manager: Arc::new(RwLock::new(HashMap::new())),
+            admission: Admission::new(&config.admission),
+            udp_manager,
+            quic_ids: QuicConnectionIds::default(),
             dns_client,
             config,
             uid,
@@ -102,7 +248,7 @@ impl ProxyClient {
             nat_join_handle,
             dns_server_join_handle: Some(dns_server_join_handle),
             chooser_join_handle: Some(chooser_join_handle),
-        }
+        })
     }
 
     async fn run_tcp_relay_server(&self) -> Result<()> {
@@ -112,6 +258,17 @@ impl ProxyClient {
                 eprintln!("error: bind to {REDIR_LISTEN_PORT}");
                 e
             })?;
+        if let Some(size) = self.config.flow_control.tun_recv_buffer {
+            // Inherited by accepted connections, it bounds the data an application can queue
+            // while the outbound is slower.
+            set_recv_buffer_size(&listener, size)?;
+        }
+        self.relay_tcp_connections(listener).await
+    }
+
+    /// Relay the connections accepted by `listener`, whose source ports are the ports of their
+    /// sessions.
+    pub(crate) async fn relay_tcp_connections(&self, listener: TcpListener) -> Result<()> {
         let mut incoming = listener.incoming();
         while let Some(Ok(conn)) = incoming.next().await {
             let session_manager = self.session_manager.clone();
@@ -126,19 +283,41 @@ impl ProxyClient {
             };
             trace!(peer_addr = ?peer_addr, "new connection");
             let session_port = peer_addr.port();
+            if !self.admission.admit() {
+                trace!(peer_addr = ?peer_addr, "connection rejected, overloaded");
+                if let Some(session_manager) = &session_manager {
+                    session_manager.recycle_port(session_port);
+                }
+                continue;
+            }
 
             let (real_src, real_dest, host) = match (config.redir_mode, &session_manager) {
                 (true, _) => {
                     let Some(original_addr) = get_original_addr_from_socket(&conn) else {
-                        panic!("redir mode is not supported on this platform");
+                        continue;
+                    };
+                    let host = match resolver.lookup_host(&original_addr.ip().to_string()) {
+                        Ok(Some(s)) => Address::DomainNameAddress(s, original_addr.port()),
+                        Ok(None) => Address::SocketAddress(original_addr),
+                        Err(e) => {
+                            error!(?e, ?original_addr, "lookup host error");
+                            continue;
+                        }
                     };
-                    let host = resolver
-                        .lookup_host(&original_addr.ip().to_string())
-                        .map(|s| Address::DomainNameAddress(s, original_addr.port()))
-                        .unwrap_or_else(|| Address::SocketAddress(original_addr));
                     (peer_addr, original_addr, host)
                 }
                 (false, Some(session_manager)) => {
+                    if let Some((real_src, real_dest)) = session_manager.get_by_port(session_port) {
+                        if dns_hijack::is_hijacked(&config, real_dest) {
+                            self.hijack_tcp_dns(
+                                conn,
+                                real_src.ip(),
+                                session_manager.clone(),
+                                session_port,
+                            );
+                            continue;
+                        }
+                    }
                     let Ok(ret) = get_real_src_real_dest_and_host(
                         session_port,
                         session_manager,
@@ -158,22 +337,25 @@ impl ProxyClient {
             tracing::info!("real_src: {real_src:?}, real_desc: {real_dest:?}, host: {host:?}");
 
             spawn(async move {
-                let _ = relay_tcp_stream(
-                    conn,
-                    real_src,
-                    real_dest,
-                    host,
-                    config,
-                    server_chooser,
-                    connectivity,
-                    uid,
-                    || {
-                        if let Some(session_manager) = &session_manager {
-                            session_manager.update_activity_for_port(session_port)
-                        } else {
-                            true
-                        }
-                    },
+                let _ = isolate(
+                    "tcp connection",
+                    relay_tcp_stream(
+                        conn,
+                        real_src,
+                        real_dest,
+                        host,
+                        config,
+                        server_chooser,
+                        connectivity,
+                        uid,
+                        || {
+                            if let Some(session_manager) = &session_manager {
+                                session_manager.update_activity_for_port(session_port)
+                            } else {
+                                true
+                            }
+                        },
+                    ),
                 )
                 .await;
                 if let Some(session_manager) = &session_manager {
@@ -184,10 +366,51 @@ impl ProxyClient {
         Ok::<(), std::io::Error>(())
     }
 
+    /// Call `f` with each IP a server domain is resolved to, see
+    /// [`DnsClient::set_on_server_resolved`].
+    pub fn on_server_resolved(&self, f: impl Fn(IpAddr) + Send + Sync + 'static) {
+        self.dns_client.set_on_server_resolved(f);
+    }
+
+    /// Handle to forget the state learned on the current network once it changes.
+    pub fn network_reset(&self) -> NetworkReset {
+        NetworkReset {
+            server_chooser: self.server_chooser.clone(),
+            connectivity: self.connectivity.clone(),
+            dns_client: self.dns_client.clone(),
+            udp_manager: self.udp_manager.clone(),
+        }
+    }
+
+    /// What `seeker ctl` commands act on.
+    pub fn controller(&self) -> Controller {
+        Controller {
+            server_chooser: self.server_chooser.clone(),
+            network_reset: self.network_reset(),
+            rules: self.config.rules.clone(),
+            mode: if self.config.redir_mode {
+                Mode::Redir
+            } else {
+                Mode::Tun
+            },
+            config_file: None,
+        }
+    }
+
+    /// Relay connections until a relay server or background task stops. The background tasks are
+    /// cancelled when the returned future is dropped, except the threads of the TUN device, which
+    /// run until the process exits.
     pub async fn run(mut self) {
-        let chooser_join_handle = self.chooser_join_handle.take();
-        let dns_server_join_handle = self.dns_server_join_handle.take();
-        let nat_join_handle = self.nat_join_handle.take();
+        if !self.config.chaos.is_empty() {
+            warn!(
+                entries = self.config.chaos.len(),
+                "chaos enabled, matching connections are degraded on purpose"
+            );
+        }
+        let chooser_task = BackgroundTask(self.chooser_join_handle.take());
+        let dns_server_task = BackgroundTask(self.dns_server_join_handle.take());
+        let nat_task = BackgroundTask(self.nat_join_handle.take());
+        let clock_watch = clock::watch(self.config.ntp.clone(), self.dns_client.clone());
         let ret = self
             .run_tcp_relay_server()
             .instrument(tracing::trace_span!("ProxyClient.run_tcp_relay_server"))
@@ -206,41 +429,64 @@ impl ProxyClient {
                 }
             })
             .race(async move {
-                if let Some(chooser_join_handle) = chooser_join_handle {
-                    chooser_join_handle.await;
-                } else {
-                    pending::<()>().await;
-                }
+                chooser_task.await;
                 Ok(())
             })
             .race(async move {
-                if let Some(dns_server_join_handle) = dns_server_join_handle {
-                    dns_server_join_handle.await;
-                } else {
-                    pending::<()>().await;
-                };
+                dns_server_task.await;
                 Ok(())
             })
             .race(async move {
-                if let Some(nat_join_handle) = nat_join_handle {
-                    nat_join_handle.await;
-                } else {
-                    pending::<()>().await;
-                }
+                nat_task.await;
+                Ok(())
+            })
+            .race(async move {
+                clock_watch.await;
                 Ok(())
             })
+            .race(async {
+                let ret = tunnel::serve(self.config.clone(), self.server_chooser.clone()).await;
+                if let Err(e) = &ret {
+                    tracing::error!(?e, "run tunnels error");
+                }
+                ret
+            })
             .await;
         ret.expect("run proxy client");
     }
 
+    /// Answer the DNS over TCP connection of `session_port` from `client` with the embedded
+    /// resolver.
+    fn hijack_tcp_dns(
+        &self,
+        conn: TcpStream,
+        client: IpAddr,
+        session_manager: SessionManager,
+        session_port: u16,
+    ) {
+        let context = self.dns_context.clone();
+        let inbound = self.config.tcp_inbound();
+        spawn(async move {
+            let serve = dns_hijack::serve_tcp(context, conn, client, inbound);
+            let ret = isolate("dns hijack", serve).await;
+            if let Some(Err(e)) = ret {
+                debug!(?e, session_port, "hijacked dns connection error");
+            }
+            session_manager.recycle_port(session_port);
+        });
+    }
+
+    /// The socket of the session of `tun_addr`, a new session is created for `first_packet` if
+    /// there is none.
     async fn get_proxy_udp_socket(
         &self,
-        tun_socket: Arc<UdpSocket>,
+        tun_socket: Arc<BatchSocket>,
         tun_addr: SocketAddr,
-    ) -> Result<(ProxyUdpSocket, SocketAddr, Address)> {
+        first_packet: &[u8],
+    ) -> Result<(ProxyUdpSocket, UdpDest)> {
         let port = tun_addr.port();
-        if let Some(r) = self.udp_manager.read().get(&port) {
-            return Ok(r.clone());
+        if let Some(r) = self.udp_manager.get(port) {
+            return Ok(r);
         }
 
         let Some(session_manager) = self.session_manager.clone() else {
@@ -252,6 +498,7 @@ impl ProxyClient {
         relay_udp_socket(
             tun_socket,
             tun_addr,
+            first_packet,
             session_manager,
             self.resolver.clone(),
             self.dns_client.clone(),
@@ -260,6 +507,7 @@ impl ProxyClient {
             self.connectivity.clone(),
             self.uid,
             self.udp_manager.clone(),
+            self.quic_ids.clone(),
         )
         .await
     }
@@ -269,40 +517,157 @@ impl ProxyClient {
             !self.config.redir_mode,
             "UDP is not supported in redir mode, skipping"
         );
-        let udp_listener = Arc::new(UdpSocket::bind(format!("0.0.0.0:{REDIR_LISTEN_PORT}")).await?);
-        let mut buf = vec![0; 2000];
+        let udp_listener = Arc::new(BatchSocket::new(Arc::new(
+            UdpSocket::bind(format!("0.0.0.0:{REDIR_LISTEN_PORT}")).await?,
+        ))?);
+        self.relay_udp_sessions(udp_listener).await
+    }
+
+    /// Relay the packets received by `udp_listener`, whose source ports are the ports of their
+    /// sessions.
+    pub(crate) async fn relay_udp_sessions(&self, udp_listener: Arc<BatchSocket>) -> Result<()> {
+        let inbound = self.config.udp_inbound();
+        let mut batch = UdpBatch::new(inbound.buffer_size);
         loop {
-            let (size, peer_addr) = udp_listener.recv_from(&mut buf).await.map_err(|e| {
+            udp_listener.recv_batch(&mut batch).await.map_err(|e| {
                 error!(?e, "udp recv error");
                 e
             })?;
-            assert!(size < 2000);
-            let session_port = peer_addr.port();
+            let mut packets = batch.packets().peekable();
+            while let Some((data, peer_addr)) = packets.next() {
+                // Consecutive datagrams of a session are sent in one batch.
+                let mut session_packets = vec![data];
+                while let Some((data, _)) = packets.next_if(|(_, addr)| *addr == peer_addr) {
+                    session_packets.push(data);
+                }
+                isolate(
+                    "udp packets",
+                    self.relay_udp_packets(&udp_listener, peer_addr, &session_packets, &inbound),
+                )
+                .await;
+            }
+        }
+    }
 
-            let tun_socket = udp_listener.clone();
-            let (proxy_udp_socket, real_dest, host) =
-                match self.get_proxy_udp_socket(tun_socket, peer_addr).await {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!(?e, "get proxy udp socket error: {:?}", e);
-                        continue;
+    async fn relay_udp_packets(
+        &self,
+        udp_listener: &Arc<BatchSocket>,
+        peer_addr: SocketAddr,
+        packets: &[&[u8]],
+        inbound: &InboundConfig,
+    ) {
+        let session_port = peer_addr.port();
+        let packets: Vec<&[u8]> = packets
+            .iter()
+            .copied()
+            .filter(|data| {
+                let truncated = data.len() >= inbound.buffer_size;
+                if truncated {
+                    self.udp_manager.record_truncated();
+                    warn!(
+                        size = data.len(),
+                        ?peer_addr,
+                        "udp packet truncated, increase udp_buffer_size"
+                    );
+                }
+                !truncated
+            })
+            .collect();
+        if packets.is_empty() {
+            return;
+        }
+
+        if let Some(session_manager) = &self.session_manager {
+            let hijacked = session_manager
+                .get_by_port(session_port)
+                .filter(|(_, real_dest)| dns_hijack::is_hijacked(&self.config, *real_dest));
+            if let Some((client, _)) = hijacked {
+                // Answering may wait for the upstream, the other sessions must not.
+                let context = self.dns_context.clone();
+                let tun_socket = udp_listener.clone();
+                let queries = packets.iter().map(|data| data.to_vec()).collect();
+                let inbound = *inbound;
+                let session_manager = session_manager.clone();
+                spawn(async move {
+                    let answer = dns_hijack::answer_udp(
+                        context,
+                        tun_socket,
+                        peer_addr,
+                        client.ip(),
+                        queries,
+                        inbound,
+                    );
+                    if let Some(Err(e)) = isolate("dns hijack", answer).await {
+                        debug!(?e, session_port, "answer hijacked dns error");
                     }
-                };
-            let ret = timeout(
-                self.config.write_timeout,
-                proxy_udp_socket.send_to(&buf[..size], real_dest),
-            )
-            .await;
-            if let Err(e) = ret {
-                error!("send udp packet error {}: {:?}", host, e);
-                if let Some(session_manager) = &self.session_manager {
+                    // Kept a while for the answers and the retries of the client.
                     session_manager.recycle_port(session_port);
+                });
+                return;
+            }
+        }
+
+        let (proxy_udp_socket, dest) = match self
+            .get_proxy_udp_socket(udp_listener.clone(), peer_addr, packets[0])
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                error!(?e, "get proxy udp socket error: {:?}", e);
+                return;
+            }
+        };
+        let real_dest = dest.addr(&self.dns_client);
+        if let Some(fault) = chaos::fault_for(&self.config, &proxy_udp_socket) {
+            // Delayed in the background, the packets of the other sessions must not wait.
+            let packets: Vec<Vec<u8>> = packets
+                .into_iter()
+                .filter(|_| !fault.drops())
+                .map(|data| data.to_vec())
+                .collect();
+            let write_timeout = inbound.write_timeout;
+            spawn(async move {
+                task::sleep(fault.delay()).await;
+                let packets: Vec<(&[u8], SocketAddr)> = packets
+                    .iter()
+                    .map(|data| (data.as_slice(), real_dest))
+                    .collect();
+                let ret = timeout(write_timeout, proxy_udp_socket.send_batch(&packets)).await;
+                if let Err(e) = ret {
+                    debug!(?e, host = %dest.host(), "send delayed udp packets error");
                 }
+            });
+            return;
+        }
+        let packets: Vec<(&[u8], SocketAddr)> =
+            packets.into_iter().map(|data| (data, real_dest)).collect();
+        let ret = timeout(inbound.write_timeout, proxy_udp_socket.send_batch(&packets)).await;
+        if let Err(e) = ret {
+            error!("send udp packet error {}: {:?}", dest.host(), e);
+            // The relay task of the session exits once it notices the removal.
+            self.udp_manager.remove_port(session_port);
+            proxy_udp_socket.shutdown();
+            if let Some(session_manager) = &self.session_manager {
+                session_manager.recycle_port(session_port);
             }
         }
     }
 }
 
+async fn print_udp_nat_stats(udp_manager: UdpManager) {
+    loop {
+        task::sleep(Duration::from_secs(10)).await;
+        let stats = udp_manager.stats();
+        info!(
+            size = stats.size,
+            created = stats.created,
+            expired = stats.expired,
+            truncated = stats.truncated,
+            "udp nat table"
+        );
+    }
+}
+
 #[instrument(skip(real_src, real_dest, config, connectivity), ret)]
 pub(crate) async fn get_action_for_addr(
     real_src: SocketAddr,
@@ -311,27 +676,14 @@ pub(crate) async fn get_action_for_addr(
     config: &Config,
     connectivity: &ProbeConnectivity,
     user_id: Option<u32>,
-) -> Result<Action> {
-    let mut pass_proxy = false;
-    let (domain, ip) = match &addr {
-        // 如果是 IP 说明是用户手动改了路由表，必须要走代理。
-        Address::SocketAddress(sock_addr) => (None, Some(sock_addr.ip())),
-        Address::DomainNameAddress(domain, _port) => {
-            (Some(domain.to_string()), Some(real_dest.ip()))
-        }
-    };
-    if let Some(uid) = user_id {
-        if !socket_addr_belong_to_user(real_src, uid)? {
-            pass_proxy = true;
-        }
-    }
-    let mut action = if pass_proxy {
-        Action::Direct
+) -> Result<(Action, RuleOptions)> {
+    let (mut action, options) = if config.rules.is_paused() {
+        (Action::Direct, RuleOptions::default())
     } else {
-        config
-            .rules
-            .action_for_domain(domain.as_deref(), ip)
-            .unwrap_or_else(|| config.rules.default_action())
+        let route = route_of(real_src, real_dest, addr, config, user_id)?;
+        let decided = decision_log::decide(config, &route);
+        decision_log::record(&route, decided.0);
+        decided
     };
 
     if action == Action::Probe {
@@ -342,40 +694,236 @@ pub(crate) async fn get_action_for_addr(
         }
     }
 
-    Ok(action)
+    Ok((action, options))
+}
+
+/// What `config` routes the connection by, the origin is looked up only when it's needed.
+fn route_of(
+    real_src: SocketAddr,
+    real_dest: SocketAddr,
+    addr: &Address,
+    config: &Config,
+    user_id: Option<u32>,
+) -> Result<Route> {
+    let (domain, dest) = match addr {
+        // 如果是 IP 说明是用户手动改了路由表，必须要走代理。
+        Address::SocketAddress(sock_addr) => (None, *sock_addr),
+        Address::DomainNameAddress(domain, _port) => (Some(domain.to_string()), real_dest),
+    };
+    let recording = decision_log::is_recording();
+    if domain.is_none() && !recording && config.is_bypassed_lan(dest.ip()) {
+        return Ok(Route {
+            domain,
+            dest,
+            src: real_src,
+            uid: user_id,
+            other_user: false,
+            local: true,
+            interface: None,
+            cgroup: None,
+        });
+    }
+    let origin = ConnectionOrigin::of(config, real_src, real_dest, user_id.is_some() || recording);
+    let other_user = match user_id {
+        Some(uid) if origin.local => !socket_addr_belong_to_user(real_src, real_dest, uid)?,
+        _ => false,
+    };
+    Ok(Route {
+        domain,
+        dest,
+        src: real_src,
+        uid: user_id,
+        other_user,
+        local: origin.local,
+        interface: origin.interface,
+        cgroup: origin.cgroup,
+    })
+}
+
+/// Where a connection comes from, looked up only when `--uid`, the source rules or the recorded
+/// decisions need it.
+#[derive(Default)]
+struct ConnectionOrigin {
+    /// The socket is on this machine, in the current network namespace.
+    local: bool,
+    /// The interface the connection is forwarded from, eg. the bridge of a container.
+    interface: Option<String>,
+    cgroup: Option<String>,
+}
+
+impl ConnectionOrigin {
+    fn of(config: &Config, real_src: SocketAddr, real_dest: SocketAddr, needed: bool) -> Self {
+        let assumed_local = ConnectionOrigin {
+            local: true,
+            ..Default::default()
+        };
+        if !needed && !config.rules.has_source_rules() {
+            return assumed_local;
+        }
+        let IpAddr::V4(ip) = real_src.ip() else {
+            return assumed_local;
+        };
+        let mut origin = match sysconfig::source_origin(ip) {
+            Ok(SourceOrigin::Local) => assumed_local,
+            Ok(SourceOrigin::Interface(name)) => ConnectionOrigin {
+                interface: Some(name),
+                ..Default::default()
+            },
+            Ok(SourceOrigin::Unknown) => ConnectionOrigin::default(),
+            Err(e) => {
+                warn!(?e, "list interface addresses error");
+                assumed_local
+            }
+        };
+        if origin.local {
+            origin.cgroup = socket_cgroup(config, real_src, real_dest);
+        }
+        origin
+    }
+}
+
+/// Cgroup path of the tcp socket from `real_src` to `real_dest`, if there are `CGROUP` rules or
+/// the decisions are recorded.
+#[cfg(target_os = "linux")]
+fn socket_cgroup(config: &Config, real_src: SocketAddr, real_dest: SocketAddr) -> Option<String> {
+    if !config.rules.has_cgroup_rules() && !decision_log::is_recording() {
+        return None;
+    }
+    match sysconfig::socket_cgroup(real_src, real_dest) {
+        Ok(path) => {
+            trace!(?path, %real_src, "socket cgroup");
+            path
+        }
+        Err(e) => {
+            warn!(?e, %real_src, "find socket cgroup error");
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_cgroup(
+    _config: &Config,
+    _real_src: SocketAddr,
+    _real_dest: SocketAddr,
+) -> Option<String> {
+    None
+}
+
+/// Whether tcp connections to `dest` are rejected by the rules, so the TUN device can reset
+/// them at the SYN instead of completing the handshake with the relay first. Called on the
+/// packet thread, only the actions of fake ips cached by earlier connections are looked up, the
+/// others and those getting the reject page are relayed.
+fn is_rejected(config: &Config, dest: SocketAddrV4) -> bool {
+    if reject_page::is_served(&config.reject_page, dest.port()) {
+        return false;
+    }
+    let dest = *dest.ip();
+    !config.rules.is_paused()
+        && config.tun_cidr.contains_addr(&dest.into())
+        && config.rules.is_fake_ip_rejected(dest)
+}
+
+/// The `dns_groups`, those with `proxy: true` are queried through `server_chooser`.
+async fn dns_groups(
+    config: &Config,
+    server_chooser: Arc<ServerChooser>,
+) -> HashMap<String, DnsGroup> {
+    let mut groups = HashMap::new();
+    for (name, group) in &config.dns_groups {
+        let group = if group.proxy {
+            DnsGroup::Upstream(Arc::new(ProxiedDnsUpstream::new(
+                group.tcp_servers(),
+                server_chooser.clone(),
+                config.dns_timeout,
+            )))
+        } else {
+            DnsGroup::Resolver(
+                DnsClient::new(&group.servers, config.dns_timeout)
+                    .await
+                    .resolver(),
+            )
+        };
+        groups.insert(name.clone(), group);
+    }
+    groups
+}
+
+/// The `dns_steering` of the direct domains, None while it has no groups.
+fn dns_steering(config: &Config, groups: &HashMap<String, DnsGroup>) -> Option<DnsSteering> {
+    let steering = &config.dns_steering;
+    if steering.groups.is_empty() {
+        return None;
+    }
+    let groups = steering
+        .groups
+        .iter()
+        .filter_map(|name| groups.get(name).cloned())
+        .collect();
+    Some(DnsSteering::new(
+        groups,
+        steering.port,
+        steering.timeout,
+        steering.cache_ttl,
+    ))
+}
+
+/// Drops the DNS queries of the clients `dns_access` rejects and of those over `dns_rate_limit`.
+fn dns_query_filter(config: &Config) -> QueryFilter {
+    let config = config.clone();
+    let limit = DnsRateLimit::new(&config.dns_rate_limit);
+    Box::new(move |client| {
+        if !config.is_dns_client_allowed(client) {
+            trace!(%client, "dns query of client not in dns_access");
+            return false;
+        }
+        limit.as_ref().map_or(true, |limit| limit.admit(client))
+    })
 }
 
 async fn run_dns_resolver(
     config: &Config,
     resolver: AsyncStdResolver,
-) -> (RuleBasedDnsResolver, JoinHandle<()>) {
-    let (dns_server, resolver) = create_dns_server(
+    server_chooser: Arc<ServerChooser>,
+) -> Result<(RuleBasedDnsResolver, Arc<ServerContext>, JoinHandle<()>)> {
+    let groups = dns_groups(config, server_chooser).await;
+    let steering = dns_steering(config, &groups);
+    let (mut dns_server, resolver) = create_dns_server(
         config.dns_listen.clone(),
         config.tun_bypass_direct,
+        config.dns_passthrough_unknown,
+        config.ntp.servers.clone(),
+        &config.dns_records,
         config.rules.clone(),
         resolver,
+        groups,
+        steering,
     )
-    .await;
+    .await?;
+    dns_server.set_query_filter(dns_query_filter(config));
+    let context = dns_server.context();
     let handle = spawn(async {
         dns_server
             .run_server()
             .instrument(trace_span!("Dns_server.run_server"))
             .await
     });
-    (resolver, handle)
+    Ok((resolver, context, handle))
 }
 
-#[cfg(target_arch = "x86_64")]
-fn socket_addr_belong_to_user(addr: SocketAddr, uid: u32) -> Result<bool> {
-    use sysconfig::SocketInfo;
-    let user_socks: HashMap<i32, Vec<SocketInfo>> = sysconfig::list_user_proc_socks(uid)?;
-    Ok(user_socks
-        .values()
-        .any(|sockets| sockets.iter().any(|s| s.local == addr)))
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn socket_addr_belong_to_user(addr: SocketAddr, remote: SocketAddr, uid: u32) -> Result<bool> {
+    // Finding the process takes a scan of /proc, only do it when it's logged.
+    if tracing::enabled!(tracing::Level::TRACE) {
+        if let Some(owner) = sysconfig::find_user_socket_owner(uid, addr)? {
+            trace!(pid = owner.pid, process = %owner.name, %addr, "socket owner");
+        }
+    }
+    sysconfig::socket_belongs_to_user(uid, addr, remote)
 }
 
-#[cfg(not(target_arch = "x86_64"))]
-fn socket_addr_belong_to_user(_addr: SocketAddr, _uid: u32) -> Result<bool> {
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn socket_addr_belong_to_user(_addr: SocketAddr, _remote: SocketAddr, _uid: u32) -> Result<bool> {
     Ok(true)
 }
 
@@ -403,7 +951,7 @@ pub(crate) async fn get_real_src_real_dest_and_host(
     let is_tun_ip = config.tun_cidr.contains_addr(&ipv4.into());
     let ip = real_dest.ip().to_string();
     let host_optional = resolver
-        .lookup_host(&ip)
+        .lookup_host(&ip)?
         .map(|s| Address::DomainNameAddress(s, real_dest.port()));
 
     let host = match (host_optional, is_tun_ip) {
@@ -446,11 +994,17 @@ pub(crate) async fn get_real_src_real_dest_and_host(
 fn get_original_addr_from_socket(conn: &TcpStream) -> Option<SocketAddr> {
     // When in redir mode, we get the original destination from the socket option.
 
-    use std::net::Ipv4Addr;
     use std::os::fd::AsRawFd;
-    let original_dst =
-        nix::sys::socket::getsockopt(conn.as_raw_fd(), nix::sys::socket::sockopt::OriginalDst)
-            .expect("get original dst");
+    let original_dst = match nix::sys::socket::getsockopt(
+        conn.as_raw_fd(),
+        nix::sys::socket::sockopt::OriginalDst,
+    ) {
+        Ok(dst) => dst,
+        Err(e) => {
+            error!(?e, "get original dst error");
+            return None;
+        }
+    };
     // convert sockaddr_in to SocketAddress
     // sin_addr, sin_port are stored in network edian
     let original_addr = SocketAddr::new(
@@ -1,15 +1,14 @@
-use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::traffic::Traffic;
-use config::{rule::Action, Address, ServerConfig};
+use config::rule::{Action, ConnectionLog};
+use config::{Address, ServerConfig};
 use store::Store;
 
-// id generator for connection
-pub static CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
-
+/// Ids are allocated by the store, so they are unique across restarts too.
 pub fn next_connection_id() -> u64 {
-    CONNECTION_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst) as u64
+    Store::global().next_connection_id()
 }
 
 pub trait ProxyConnection {
@@ -27,6 +26,10 @@ pub trait ProxyConnection {
     fn remote_addr(&self) -> Option<&Address> {
         None
     }
+    /// HTTP Host or TLS SNI found in the bytes sent to a remote ip.
+    fn sniffed_host(&self) -> Option<String> {
+        None
+    }
     fn duration(&self) -> Duration {
         self.connect_time().elapsed()
     }
@@ -38,6 +41,18 @@ pub trait ProxyConnectionEventListener {
     fn on_shutdown(&self, conn: &dyn ProxyConnection);
     fn on_recv_bytes(&self, conn: &dyn ProxyConnection, bytes: usize);
     fn on_send_bytes(&self, conn: &dyn ProxyConnection, bytes: usize);
+    fn on_sniffed_host(&self, conn: &dyn ProxyConnection, host: &str);
+}
+
+/// The listener recording connections to the store as the `log` option of their rule says.
+pub fn store_listener(
+    log: ConnectionLog,
+) -> Option<Arc<dyn ProxyConnectionEventListener + Send + Sync>> {
+    match log {
+        ConnectionLog::Verbose => Some(Arc::new(StoreListener)),
+        ConnectionLog::Basic => Some(Arc::new(BasicStoreListener)),
+        ConnectionLog::Off => None,
+    }
 }
 
 #[derive(Clone)]
@@ -55,7 +70,7 @@ impl ProxyConnectionEventListener for StoreListener {
             .config()
             .map(|config| config.addr().to_string())
             .unwrap_or_default();
-        let ret = store.new_connection(
+        let ret = store.insert_connection(
             conn.id(),
             &host,
             conn.network(),
@@ -89,4 +104,46 @@ impl ProxyConnectionEventListener for StoreListener {
             tracing::error!("Failed to increment sent bytes: {}", e);
         }
     }
+
+    fn on_sniffed_host(&self, conn: &dyn ProxyConnection, host: &str) {
+        let store = Store::global();
+        let ret = store.set_connection_sniffed_host(conn.id(), host);
+        if let Err(e) = ret {
+            tracing::error!("Failed to set sniffed host: {}", e);
+        }
+    }
+}
+
+/// Records the rows of connections like [`StoreListener`], with their traffic written once when
+/// they are shut down instead of on every read and write.
+#[derive(Clone)]
+pub struct BasicStoreListener;
+
+impl ProxyConnectionEventListener for BasicStoreListener {
+    fn on_connect(&self, conn: &dyn ProxyConnection) {
+        StoreListener.on_connect(conn);
+    }
+
+    fn on_shutdown(&self, conn: &dyn ProxyConnection) {
+        let store = Store::global();
+        // Totals, so connections shut down more than once aren't counted twice.
+        let ret = store.update_connection(
+            conn.id(),
+            conn.recv_bytes() as u64,
+            conn.sent_bytes() as u64,
+            None,
+        );
+        if let Err(e) = ret {
+            tracing::error!("Failed to record connection traffic: {}", e);
+        }
+        StoreListener.on_shutdown(conn);
+    }
+
+    fn on_recv_bytes(&self, _conn: &dyn ProxyConnection, _bytes: usize) {}
+
+    fn on_send_bytes(&self, _conn: &dyn ProxyConnection, _bytes: usize) {}
+
+    fn on_sniffed_host(&self, conn: &dyn ProxyConnection, host: &str) {
+        StoreListener.on_sniffed_host(conn, host);
+    }
 }
@@ -0,0 +1,49 @@
+//! Destinations failing to connect through the proxy `proxy_fallback.failures` times in a row
+//! are connected directly until `proxy_fallback.cooldown` is over. The failures are kept in the
+//! store, so the fallback survives restarts and shows up in its events.
+use config::{Address, ProxyFallbackConfig};
+use store::Store;
+use tracing::{error, info};
+
+/// Whether `dest` matched by a `PROXY` rule is connected directly for now.
+pub(crate) fn is_direct(config: &ProxyFallbackConfig, dest: &Address) -> bool {
+    if config.failures == 0 {
+        return false;
+    }
+    match Store::global().is_proxy_fallback(&dest.to_string()) {
+        Ok(direct) => direct,
+        Err(e) => {
+            error!(?e, %dest, "read proxy failures");
+            false
+        }
+    }
+}
+
+/// Count the result of connecting to `dest` through the proxy.
+pub(crate) fn record(config: &ProxyFallbackConfig, dest: &Address, connected: bool) {
+    if config.failures == 0 {
+        return;
+    }
+    let store = Store::global();
+    let dest = dest.to_string();
+    let ret = if connected {
+        store.record_proxy_success(&dest)
+    } else {
+        store
+            .record_proxy_failure(&dest, config.failures, config.cooldown)
+            .and_then(|started| {
+                if started {
+                    let message = format!(
+                        "{dest} failed {} times through the proxy, connecting directly for {:?}",
+                        config.failures, config.cooldown
+                    );
+                    info!("{}", message);
+                    store.new_event(Store::EVENT_PROXY_FALLBACK, &message)?;
+                }
+                Ok(())
+            })
+    };
+    if let Err(e) = ret {
+        error!(?e, %dest, "record proxy failure");
+    }
+}
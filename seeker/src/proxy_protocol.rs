@@ -0,0 +1,71 @@
+//! The PROXY protocol v2 header sent first on connections to servers with `proxy_protocol`, so
+//! the server knows the client and the destination the connection was made for.
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY: u8 = 0x21;
+/// For connections made by seeker itself, eg. pings, the server uses the connection's own address.
+const LOCAL: u8 = 0x20;
+const TCP4: u8 = 0x11;
+const TCP6: u8 = 0x21;
+
+/// The header for a connection from `origin.0` to `origin.1`, a LOCAL one if it's None.
+pub(crate) fn header(origin: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    let Some((src, dest)) = origin else {
+        header.extend_from_slice(&[LOCAL, 0, 0, 0]);
+        return header;
+    };
+    // Both addresses are sent as IPv6 ones when only one of them is.
+    let (family, addresses) = match (src.ip(), dest.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dest_ip)) => {
+            (TCP4, [src_ip.octets(), dest_ip.octets()].concat())
+        }
+        (src_ip, dest_ip) => (TCP6, [ipv6_octets(src_ip), ipv6_octets(dest_ip)].concat()),
+    };
+    header.extend_from_slice(&[PROXY, family]);
+    let len = addresses.len() as u16 + 4;
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dest.port().to_be_bytes());
+    header
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let v4 = header(Some((
+            "192.168.1.2:50000".parse().unwrap(),
+            "11.0.0.5:443".parse().unwrap(),
+        )));
+        assert_eq!(&v4[..12], SIGNATURE);
+        assert_eq!(
+            &v4[12..],
+            &[0x21, 0x11, 0, 12, 192, 168, 1, 2, 11, 0, 0, 5, 0xc3, 0x50, 0x01, 0xbb]
+        );
+
+        let mixed = header(Some((
+            "[::1]:50000".parse().unwrap(),
+            "1.2.3.4:443".parse().unwrap(),
+        )));
+        assert_eq!(&mixed[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(mixed.len(), 16 + 36);
+        assert_eq!(
+            &mixed[32..48],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4]
+        );
+
+        assert_eq!(&header(None)[12..], &[0x20, 0, 0, 0]);
+    }
+}
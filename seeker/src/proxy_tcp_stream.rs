@@ -1,24 +1,28 @@
-use async_std::io::{Read, Write};
+use async_std::io::{Read, Write, WriteExt};
 use async_std::net::TcpStream;
-use config::rule::Action;
+use config::rule::{Action, RuleOptions};
 use config::{Address, ServerConfig, ServerProtocol};
 use http_proxy_client::{HttpProxyTcpStream, HttpsProxyTcpStream};
 use socks5_client::Socks5TcpStream;
 use ssclient::SSTcpStream;
 use std::io::Result;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Instant;
 
-use tcp_connection::TcpConnection;
-
+use crate::connection_pool::{connect_server, ConnectionPool};
 use crate::dns_client::DnsClient;
+use crate::happy_eyeballs;
 use crate::proxy_connection::{
-    next_connection_id, ProxyConnection, ProxyConnectionEventListener, StoreListener,
+    next_connection_id, store_listener, ProxyConnection, ProxyConnectionEventListener,
 };
+use crate::proxy_protocol;
+use crate::sniff::Sniffer;
 use crate::traffic::Traffic;
 use async_std::task::ready;
-use std::io::{Error, ErrorKind};
+use parking_lot::Mutex;
+use std::io::{Error, ErrorKind, IoSlice};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
@@ -41,25 +45,35 @@ pub struct ProxyTcpStream {
     traffic: Traffic,
     connect_time: Instant,
     event_listener: Option<Arc<dyn ProxyConnectionEventListener + Send + Sync>>,
+    /// Looks for the host in the bytes sent, only for connections to ips.
+    sniffer: Option<Arc<Mutex<Sniffer>>>,
 }
 
 impl ProxyTcpStream {
-    #[tracing::instrument(skip(config, dns_client))]
+    /// Connect to `remote_addr` through the server of `config`, or directly if it's `None`, with
+    /// the mark of `options` set on the socket, recorded as its `log` option says. Shadowsocks
+    /// connections are taken from `pool` when there are idle ones. `origin` is the client and the
+    /// destination it connected to, sent in the PROXY protocol header to servers with
+    /// `proxy_protocol`.
+    #[tracing::instrument(skip(config, dns_client, pool))]
     pub async fn connect(
         remote_addr: Address,
         config: Option<&ServerConfig>,
         dns_client: DnsClient,
+        pool: Option<&ConnectionPool>,
+        options: RuleOptions,
+        origin: Option<(SocketAddr, SocketAddr)>,
     ) -> Result<ProxyTcpStream> {
         let remote_addr_clone = remote_addr.clone();
         let stream = if let Some(config) = config {
-            let proxy_socket_addr = dns_client.lookup_address(config.addr()).await?;
+            let proxy_socket_addr = dns_client.lookup_server(config.addr()).await?;
             match config.protocol() {
                 ServerProtocol::Https => {
-                    let proxy_hostname = match config.addr().hostname() {
+                    let proxy_hostname = match config.sni().or(config.addr().hostname()) {
                         None => {
                             return Err(Error::new(
                                 ErrorKind::InvalidData,
-                                "proxy domain must not be empty for https protocol.",
+                                "proxy domain or sni must not be empty for https protocol.",
                             ))
                         }
                         Some(s) => s,
@@ -68,6 +82,8 @@ impl ProxyTcpStream {
                         HttpsProxyTcpStream::connect(
                             proxy_socket_addr,
                             proxy_hostname,
+                            config.host_header(),
+                            config.tls_cipher_order(),
                             remote_addr,
                             config.username(),
                             config.password(),
@@ -97,25 +113,42 @@ impl ProxyTcpStream {
                             ))
                         }
                     };
-                    let stream = if let Some(obfs) = config.obfs() {
-                        TcpConnection::connect_obfs(proxy_socket_addr, obfs.host.clone(), obfs.mode)
-                            .await?
-                    } else {
-                        TcpConnection::connect_tcp(proxy_socket_addr).await?
+                    let mut stream = match pool.and_then(|pool| pool.take(config)) {
+                        Some(stream) => stream,
+                        None => connect_server(config, proxy_socket_addr).await?,
                     };
+                    if config.proxy_protocol() {
+                        stream.write_all(&proxy_protocol::header(origin)).await?;
+                    }
                     ProxyTcpStreamInner::Shadowsocks(
                         SSTcpStream::connect(stream, remote_addr, method, key).await?,
                     )
                 }
             }
         } else {
-            let socket_addr = dns_client.lookup_address(&remote_addr).await?;
-            ProxyTcpStreamInner::Direct(TcpStream::connect(socket_addr).await?)
+            // Set up the socket while the domain is being resolved, the first attempt connects
+            // with it as soon as the addresses are known.
+            let prepared = match &remote_addr {
+                Address::DomainNameAddress(..) => happy_eyeballs::prepare(),
+                Address::SocketAddress(_) => None,
+            };
+            let socket_addrs = dns_client.lookup_addresses(&remote_addr).await?;
+            ProxyTcpStreamInner::Direct(
+                happy_eyeballs::connect(
+                    &socket_addrs,
+                    happy_eyeballs::ATTEMPT_DELAY,
+                    prepared,
+                    options.mark,
+                )
+                .await?,
+            )
         };
 
-        let event_listener: Option<Arc<dyn ProxyConnectionEventListener + Send + Sync>> =
-            Some(Arc::new(StoreListener));
-        let l = event_listener.clone();
+        let event_listener = store_listener(options.log);
+        let sniffer = match &remote_addr_clone {
+            Address::SocketAddress(_) => Some(Arc::new(Mutex::new(Sniffer::default()))),
+            Address::DomainNameAddress(..) => None,
+        };
         let conn = ProxyTcpStream {
             id: next_connection_id(),
             inner: stream,
@@ -124,13 +157,64 @@ impl ProxyTcpStream {
             config: config.cloned(),
             traffic: Traffic::default(),
             connect_time: Instant::now(),
-            event_listener: Some(Arc::new(StoreListener)),
+            event_listener: event_listener.clone(),
+            sniffer,
         };
-        if let Some(l) = l {
+        if let Some(l) = event_listener {
             l.on_connect(&conn);
         }
         Ok(conn)
     }
+
+    /// The socket of a direct connection, for relaying without going through `Read` and `Write`.
+    pub(crate) fn direct_stream(&self) -> Option<&TcpStream> {
+        match &self.inner {
+            ProxyTcpStreamInner::Direct(conn) => Some(conn),
+            _ => None,
+        }
+    }
+
+    /// Whether bytes sent are still looked at for the host, they must go through `Write` then.
+    pub(crate) fn is_sniffing(&self) -> bool {
+        self.sniffer.as_ref().map_or(false, |s| !s.lock().is_done())
+    }
+
+    /// Account bytes sent to the socket returned by [`Self::direct_stream`].
+    pub(crate) fn record_sent(&self, size: usize) {
+        self.traffic.send(size);
+        if let Some(l) = &self.event_listener {
+            l.on_send_bytes(self, size);
+        }
+    }
+
+    /// Look for the host in `bufs`, the first `size` bytes of them were sent.
+    fn sniff_sent(&self, bufs: &[&[u8]], mut size: usize) {
+        let Some(sniffer) = &self.sniffer else {
+            return;
+        };
+        let host = {
+            let mut sniffer = sniffer.lock();
+            bufs.iter().find_map(|buf| {
+                let len = buf.len().min(size);
+                size -= len;
+                sniffer.feed(&buf[..len]).map(str::to_string)
+            })
+        };
+        if let Some(host) = host {
+            tracing::debug!(id = self.id, remote = %self.remote_addr, host, "sniffed host");
+            if let Some(l) = &self.event_listener {
+                l.on_sniffed_host(self, &host);
+            }
+        }
+    }
+
+    /// Account bytes received from the socket returned by [`Self::direct_stream`].
+    pub(crate) fn record_received(&self, size: usize) {
+        self.traffic.recv(size);
+        if let Some(l) = &self.event_listener {
+            l.on_recv_bytes(self, size);
+        }
+    }
 }
 
 impl ProxyConnection for ProxyTcpStream {
@@ -161,6 +245,11 @@ impl ProxyConnection for ProxyTcpStream {
         Some(&self.remote_addr)
     }
 
+    fn sniffed_host(&self) -> Option<String> {
+        let sniffer = self.sniffer.as_ref()?.lock();
+        sniffer.host().map(str::to_string)
+    }
+
     fn action(&self) -> config::rule::Action {
         match self.inner {
             ProxyTcpStreamInner::Direct(_) => Action::Direct,
@@ -263,6 +352,45 @@ impl Write for ProxyTcpStream {
         });
         match ret {
             Ok(size) => {
+                self.sniff_sent(&[buf], size);
+                self.traffic.send(size);
+                if let Some(l) = &self.event_listener {
+                    l.on_send_bytes(&*self, size);
+                }
+                Poll::Ready(Ok(size))
+            }
+            err => {
+                self.shutdown();
+                Poll::Ready(err)
+            }
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        let stream = &mut *self;
+        if !stream.is_alive() {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "ProxyTcpStream not alive",
+            )));
+        }
+        let ret = ready!(match &mut stream.inner {
+            ProxyTcpStreamInner::Direct(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStreamInner::Socks5(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStreamInner::Shadowsocks(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStreamInner::HttpProxy(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStreamInner::HttpsProxy(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+        });
+        match ret {
+            Ok(size) => {
+                if self.sniffer.is_some() {
+                    let bufs: Vec<&[u8]> = bufs.iter().map(|buf| &**buf).collect();
+                    self.sniff_sent(&bufs, size);
+                }
                 self.traffic.send(size);
                 if let Some(l) = &self.event_listener {
                     l.on_send_bytes(&*self, size);
@@ -315,7 +443,10 @@ impl Write for ProxyTcpStream {
             ProxyTcpStreamInner::HttpProxy(conn) => Pin::new(conn).poll_close(cx),
             ProxyTcpStreamInner::HttpsProxy(conn) => Pin::new(conn).poll_close(cx),
         });
-        self.shutdown();
+        // Closing only shuts down the write half, the stream is still readable.
+        if ret.is_err() {
+            self.shutdown();
+        }
         Poll::Ready(ret)
     }
 }
@@ -1,12 +1,13 @@
 use crate::dns_client::DnsClient;
 use crate::proxy_connection::{
-    next_connection_id, ProxyConnection, ProxyConnectionEventListener, StoreListener,
+    next_connection_id, store_listener, ProxyConnection, ProxyConnectionEventListener,
 };
 use crate::traffic::Traffic;
+use crate::udp_batch::{BatchSocket, UdpBatch};
 use async_std::net::{SocketAddr, UdpSocket};
-use config::rule::Action;
+use config::rule::{Action, RuleOptions};
 use config::{ServerConfig, ServerProtocol};
-use socks5_client::Socks5UdpSocket;
+use socks5_client::{Socks5UdpAssociations, Socks5UdpSocket};
 use ssclient::SSUdpSocket;
 use std::io;
 use std::io::{Error, ErrorKind};
@@ -16,7 +17,7 @@ use std::time::Instant;
 
 #[derive(Clone)]
 enum ProxyUdpSocketInner {
-    Direct(Arc<UdpSocket>),
+    Direct(Arc<BatchSocket>),
     Socks5(Arc<Socks5UdpSocket>),
     Shadowsocks(Arc<SSUdpSocket>),
 }
@@ -24,6 +25,7 @@ enum ProxyUdpSocketInner {
 #[derive(Clone)]
 pub struct ProxyUdpSocket {
     id: u64,
+    quic: bool,
     inner: ProxyUdpSocketInner,
     alive: Arc<AtomicBool>,
     config: Option<ServerConfig>,
@@ -33,15 +35,29 @@ pub struct ProxyUdpSocket {
 }
 
 impl ProxyUdpSocket {
-    pub async fn new(config: Option<&ServerConfig>, dns_client: DnsClient) -> io::Result<Self> {
+    /// A socket through the server of `config`, or a direct one with the mark of `options`
+    /// set if it's `None`, recorded as its `log` option says. Socks5 sockets join the association
+    /// of `source` in `associations` when it has one, so all its destinations see the same
+    /// address. `quic` sockets carry a QUIC connection, their network is `quic`.
+    pub async fn new(
+        config: Option<&ServerConfig>,
+        dns_client: DnsClient,
+        options: RuleOptions,
+        associations: Option<(&Socks5UdpAssociations, SocketAddr)>,
+        quic: bool,
+    ) -> io::Result<Self> {
         let socket = if let Some(config) = config {
             match config.protocol() {
                 ServerProtocol::Socks5 => {
-                    let server = dns_client.lookup_address(config.addr()).await?;
-                    ProxyUdpSocketInner::Socks5(Arc::new(Socks5UdpSocket::new(server).await?))
+                    let server = dns_client.lookup_server(config.addr()).await?;
+                    let socket = match associations {
+                        Some((associations, source)) => associations.socket(server, source).await?,
+                        None => Socks5UdpSocket::new(server).await?,
+                    };
+                    ProxyUdpSocketInner::Socks5(Arc::new(socket))
                 }
                 ServerProtocol::Shadowsocks => {
-                    let server = dns_client.lookup_address(config.addr()).await?;
+                    let server = dns_client.lookup_server(config.addr()).await?;
                     let (method, key) = match (config.method(), config.key()) {
                         (Some(m), Some(k)) => (m, k),
                         _ => {
@@ -63,10 +79,11 @@ impl ProxyUdpSocket {
                 }
             }
         } else {
-            ProxyUdpSocketInner::Direct(Arc::new(UdpSocket::bind("0.0.0.0:0").await?))
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            options.mark.apply(&socket, false)?;
+            ProxyUdpSocketInner::Direct(Arc::new(BatchSocket::new(Arc::new(socket))?))
         };
-        let listener: Option<Arc<dyn ProxyConnectionEventListener + Send + Sync>> =
-            Some(Arc::new(StoreListener));
+        let listener = store_listener(options.log);
         let socket = ProxyUdpSocket {
             inner: socket,
             alive: Arc::new(AtomicBool::new(true)),
@@ -74,6 +91,7 @@ impl ProxyUdpSocket {
             traffic: Default::default(),
             connect_time: Instant::now(),
             id: next_connection_id(),
+            quic,
             listener: listener.clone(),
         };
         if let Some(listener) = listener {
@@ -90,7 +108,7 @@ impl ProxyUdpSocket {
             ));
         }
         let ret = match &self.inner {
-            ProxyUdpSocketInner::Direct(socket) => socket.send_to(buf, addr).await,
+            ProxyUdpSocketInner::Direct(socket) => socket.socket().send_to(buf, addr).await,
             ProxyUdpSocketInner::Socks5(socket) => socket.send_to(buf, addr).await,
             ProxyUdpSocketInner::Shadowsocks(socket) => socket.send_to(buf, addr).await,
         };
@@ -116,7 +134,7 @@ impl ProxyUdpSocket {
             ));
         }
         let ret = match &self.inner {
-            ProxyUdpSocketInner::Direct(socket) => socket.recv_from(buf).await,
+            ProxyUdpSocketInner::Direct(socket) => socket.socket().recv_from(buf).await,
             ProxyUdpSocketInner::Socks5(socket) => socket.recv_from(buf).await,
             ProxyUdpSocketInner::Shadowsocks(socket) => socket.recv_from(buf).await,
         };
@@ -133,11 +151,74 @@ impl ProxyUdpSocket {
         }
         ret
     }
+
+    /// Send datagrams, with a single syscall per batch for direct sockets.
+    pub async fn send_batch(&self, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let ProxyUdpSocketInner::Direct(socket) = &self.inner else {
+            let mut total = 0;
+            for (data, addr) in packets {
+                total += self.send_to(data, *addr).await?;
+            }
+            return Ok(total);
+        };
+        if !self.is_alive() {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "ProxyUdpSocket not alive",
+            ));
+        }
+        let ret = socket.send_batch(packets).await;
+        match ret {
+            Err(_) => {
+                self.shutdown();
+            }
+            Ok(size) => {
+                self.traffic.send(size);
+                if let Some(listener) = &self.listener {
+                    listener.on_send_bytes(self, size);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Receive datagrams into `batch`, proxied sockets receive one datagram at a time.
+    pub async fn recv_batch(&self, batch: &mut UdpBatch) -> io::Result<usize> {
+        let ProxyUdpSocketInner::Direct(socket) = &self.inner else {
+            let (size, addr) = self.recv_from(batch.first_mut()).await?;
+            batch.set_received(size, addr);
+            return Ok(1);
+        };
+        if !self.is_alive() {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "ProxyUdpSocket not alive",
+            ));
+        }
+        let ret = socket.recv_batch(batch).await;
+        match ret {
+            Err(_) => {
+                self.shutdown();
+            }
+            Ok(_) => {
+                let size = batch.packets().map(|(data, _)| data.len()).sum();
+                self.traffic.recv(size);
+                if let Some(listener) = &self.listener {
+                    listener.on_recv_bytes(self, size);
+                }
+            }
+        }
+        ret
+    }
 }
 
 impl ProxyConnection for ProxyUdpSocket {
     fn network(&self) -> &'static str {
-        "udp"
+        if self.quic {
+            "quic"
+        } else {
+            "udp"
+        }
     }
     fn traffic(&self) -> Traffic {
         self.traffic.clone()
@@ -0,0 +1,174 @@
+//! QUIC flows among the UDP sessions of the TUN device. They are recognized by the destination
+//! port and the shape of the Initial packet, kept longer when idle as QUIC connections live
+//! long with few packets, and followed by connection ID when the client's port changes, eg.
+//! after NAT rebinding.
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const QUIC_PORT: u16 = 443;
+/// Clients pad Initial packets to at least this size, RFC 9000 section 14.1.
+const MIN_INITIAL_SIZE: usize = 1200;
+const VERSION_1: u32 = 1;
+const VERSION_2: u32 = 0x6b33_43cf;
+const MAX_CID_LEN: usize = 20;
+/// Connection IDs kept for each destination, a client rarely has more flows to one server.
+const MAX_IDS_PER_DEST: usize = 16;
+
+/// Whether `packet` to `dest_port` opens a QUIC connection, ie. it's a padded Initial packet of
+/// QUIC v1 or v2.
+pub(crate) fn is_initial(dest_port: u16, packet: &[u8]) -> bool {
+    if dest_port != QUIC_PORT || packet.len() < MIN_INITIAL_SIZE {
+        return false;
+    }
+    let Some(version) = long_header_version(packet) else {
+        return false;
+    };
+    let packet_type = (packet[0] & 0x30) >> 4;
+    match version {
+        VERSION_1 => packet_type == 0,
+        VERSION_2 => packet_type == 1,
+        _ => false,
+    }
+}
+
+/// Version of a long header packet, None for short header and version negotiation ones.
+fn long_header_version(packet: &[u8]) -> Option<u32> {
+    // Header form and fixed bits.
+    if packet.len() < 5 || packet[0] & 0xc0 != 0xc0 {
+        return None;
+    }
+    let version = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+    (version != 0).then_some(version)
+}
+
+/// The source connection ID of a long header packet of the server, the client sends it as the
+/// destination connection ID of its short header packets.
+fn source_cid(packet: &[u8]) -> Option<&[u8]> {
+    long_header_version(packet)?;
+    let dcid_len = *packet.get(5)? as usize;
+    let scid_len_at = 6 + dcid_len;
+    let scid_len = *packet.get(scid_len_at)? as usize;
+    if dcid_len > MAX_CID_LEN || scid_len == 0 || scid_len > MAX_CID_LEN {
+        return None;
+    }
+    packet.get(scid_len_at + 1..scid_len_at + 1 + scid_len)
+}
+
+/// The session carrying a QUIC connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QuicSession {
+    pub port: u16,
+    pub id: u64,
+}
+
+/// Connection IDs the servers chose for the QUIC sessions, by destination.
+#[derive(Clone, Default)]
+pub(crate) struct QuicConnectionIds {
+    ids: Arc<Mutex<HashMap<SocketAddr, Vec<(Vec<u8>, QuicSession)>>>>,
+}
+
+impl QuicConnectionIds {
+    /// Remember the connection IDs found in `packets` the server `dest` sent to `session`.
+    pub(crate) fn learn<'a>(
+        &self,
+        dest: SocketAddr,
+        session: QuicSession,
+        packets: impl Iterator<Item = &'a [u8]>,
+    ) {
+        let mut cids = packets.filter_map(source_cid).peekable();
+        if cids.peek().is_none() {
+            return;
+        }
+        let mut ids = self.ids.lock();
+        let known = ids.entry(dest).or_default();
+        for cid in cids {
+            if known.iter().any(|(known, _)| known.as_slice() == cid) {
+                continue;
+            }
+            if known.len() == MAX_IDS_PER_DEST {
+                known.remove(0);
+            }
+            known.push((cid.to_vec(), session));
+        }
+    }
+
+    /// The session of the QUIC connection a short header `packet` to `dest` belongs to, if it
+    /// was opened from another port.
+    pub(crate) fn find(&self, dest: SocketAddr, packet: &[u8]) -> Option<QuicSession> {
+        if packet.first().map_or(true, |b| b & 0xc0 != 0x40) {
+            return None;
+        }
+        let ids = self.ids.lock();
+        ids.get(&dest)?
+            .iter()
+            .find(|(cid, _)| packet[1..].starts_with(cid))
+            .map(|(_, session)| *session)
+    }
+
+    /// Forget the connection IDs of a session that ended.
+    pub(crate) fn remove(&self, session: QuicSession) {
+        let mut ids = self.ids.lock();
+        ids.retain(|_, known| {
+            known.retain(|(_, s)| *s != session);
+            !known.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_header(first: u8, version: u32, dcid: &[u8], scid: &[u8], size: usize) -> Vec<u8> {
+        let mut packet = vec![first];
+        packet.extend_from_slice(&version.to_be_bytes());
+        packet.push(dcid.len() as u8);
+        packet.extend_from_slice(dcid);
+        packet.push(scid.len() as u8);
+        packet.extend_from_slice(scid);
+        packet.resize(size, 0);
+        packet
+    }
+
+    #[test]
+    fn test_is_initial() {
+        let initial = long_header(0xc3, VERSION_1, &[1; 8], &[], 1200);
+        assert!(is_initial(443, &initial));
+        assert!(!is_initial(53, &initial));
+        assert!(!is_initial(443, &initial[..1000]));
+        // Handshake packet.
+        assert!(!is_initial(
+            443,
+            &long_header(0xe3, VERSION_1, &[1; 8], &[], 1200)
+        ));
+        assert!(is_initial(
+            443,
+            &long_header(0xd3, VERSION_2, &[1; 8], &[], 1200)
+        ));
+        // Version negotiation.
+        assert!(!is_initial(443, &long_header(0xc3, 0, &[1; 8], &[], 1200)));
+        // Short header.
+        assert!(!is_initial(443, &[0x43; 1200]));
+    }
+
+    #[test]
+    fn test_connection_ids() {
+        let ids = QuicConnectionIds::default();
+        let dest = "1.2.3.4:443".parse().unwrap();
+        let session = QuicSession { port: 1, id: 7 };
+        let reply = long_header(0xc0, VERSION_1, &[1; 8], &[9, 8, 7, 6], 100);
+        ids.learn(dest, session, [&reply[..], &[0x40, 0, 0][..]].into_iter());
+
+        let mut short = vec![0x41, 9, 8, 7, 6, 0xff];
+        assert_eq!(ids.find(dest, &short), Some(session));
+        assert_eq!(ids.find("1.2.3.5:443".parse().unwrap(), &short), None);
+        short[2] = 0;
+        assert_eq!(ids.find(dest, &short), None);
+        assert_eq!(ids.find(dest, &reply), None);
+
+        ids.remove(session);
+        assert_eq!(ids.find(dest, &[0x41, 9, 8, 7, 6]), None);
+    }
+}
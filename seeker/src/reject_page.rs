@@ -0,0 +1,104 @@
+//! The page answering plain HTTP requests to sites rejected by the rules with `reject_page`, so a
+//! block shows up as one in the browser instead of as a broken site.
+use async_std::io::{timeout, ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use config::rule::Rule;
+use config::RejectPageConfig;
+use std::io::Result;
+use std::net::Shutdown;
+use std::time::Duration;
+
+const HTTP_PORT: u16 = 80;
+/// How long the request is waited for, the page is sent anyway once it's over.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Blocked</title></head>
+<body>
+<h1>Blocked by seeker</h1>
+<p><b>{host}</b> is blocked by the rule <code>{rule}</code>.</p>
+</body>
+</html>
+"#;
+
+/// Whether rejected connections to `port` get the page instead of being closed.
+pub(crate) fn is_served(config: &RejectPageConfig, port: u16) -> bool {
+    config.enabled && port == HTTP_PORT
+}
+
+/// Answer the request on `conn` with the page and close it.
+pub(crate) async fn serve(
+    mut conn: TcpStream,
+    config: &RejectPageConfig,
+    host: &str,
+    rule: Option<&Rule>,
+) -> Result<()> {
+    // Closing with the request unread would reset the connection before the page is read.
+    let mut request = [0; 4096];
+    let _ = timeout(READ_TIMEOUT, conn.read(&mut request)).await;
+    conn.write_all(&response(config, host, rule)).await?;
+    conn.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+fn response(config: &RejectPageConfig, host: &str, rule: Option<&Rule>) -> Vec<u8> {
+    let rule = rule.map_or_else(|| "-".to_string(), Rule::to_string);
+    let body = config
+        .html
+        .as_deref()
+        .unwrap_or(DEFAULT_HTML)
+        .replace("{host}", &escape_html(host))
+        .replace("{rule}", &escape_html(&rule));
+    let mut response = format!(
+        "HTTP/1.1 403 Forbidden\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    response
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_response() {
+        let config = RejectPageConfig {
+            enabled: true,
+            html: Some("<p>{host} {rule}</p>".to_string()),
+        };
+        assert!(is_served(&config, 80));
+        assert!(!is_served(&config, 443));
+
+        let rule = Rule::from_str("DOMAIN-KEYWORD,<ad>,REJECT").unwrap();
+        let page = String::from_utf8(response(&config, "ad.com", Some(&rule))).unwrap();
+        let body = "<p>ad.com DOMAIN-KEYWORD,&lt;ad&gt;,REJECT</p>";
+        assert!(page.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(page.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(page.ends_with(&format!("\r\n\r\n{body}")));
+
+        let page =
+            String::from_utf8(response(&RejectPageConfig::default(), "10.1.1.1", None)).unwrap();
+        assert!(page.contains("<b>10.1.1.1</b> is blocked by the rule <code>-</code>"));
+    }
+}
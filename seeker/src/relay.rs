@@ -0,0 +1,370 @@
+//! Bidirectional relay between the TUN side connection and the remote connection.
+//!
+//! Each direction owns a ring buffer, so reading from one side continues while the write to the
+//! other side is pending, and both halves of a wrapped buffer are written with a single vectored
+//! write. When one side reaches EOF, the write half of the other side is closed and the opposite
+//! direction keeps running until it finishes too.
+use async_std::io::{Read, Write};
+use async_std::prelude::FutureExt;
+use async_std::task::{ready, sleep};
+use buffer_pool::Buffer;
+use std::future::poll_fn;
+use std::io::{self, ErrorKind, IoSlice};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Max read/write rounds in one poll before yielding to other tasks.
+const MAX_ROUNDS_PER_POLL: usize = 32;
+const NOT_BLOCKED: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RelayOptions {
+    /// Size of the buffer of each direction.
+    pub buffer_size: usize,
+    /// Abort when nothing is read from either side for this long.
+    pub read_timeout: Duration,
+    /// Abort when a write is blocked for this long.
+    pub write_timeout: Duration,
+}
+
+/// Timestamps shared by both directions and the timeout watchdog, in millis since `start`. Also
+/// used by the splice relay, so a connection only times out when both directions are idle.
+pub(crate) struct Activity {
+    start: Instant,
+    last_read: AtomicU64,
+    write_blocked_since: [AtomicU64; 2],
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last_read: AtomicU64::new(0),
+            write_blocked_since: [AtomicU64::new(NOT_BLOCKED), AtomicU64::new(NOT_BLOCKED)],
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    pub(crate) fn on_read(&self) {
+        self.last_read.store(self.now(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_write_blocked(&self, direction: usize) {
+        let _ = self.write_blocked_since[direction].compare_exchange(
+            NOT_BLOCKED,
+            self.now(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub(crate) fn on_write(&self, direction: usize) {
+        self.write_blocked_since[direction].store(NOT_BLOCKED, Ordering::Relaxed);
+    }
+
+    pub(crate) async fn watch(&self, options: &RelayOptions) -> io::Result<(u64, u64)> {
+        let read_timeout = options.read_timeout.as_millis() as u64;
+        let write_timeout = options.write_timeout.as_millis() as u64;
+        loop {
+            let now = self.now();
+            let idle = now.saturating_sub(self.last_read.load(Ordering::Relaxed));
+            if idle >= read_timeout {
+                return Err(io::Error::new(ErrorKind::TimedOut, "read timeout"));
+            }
+            let mut wait = read_timeout - idle;
+            for blocked_since in &self.write_blocked_since {
+                let blocked_since = blocked_since.load(Ordering::Relaxed);
+                if blocked_since == NOT_BLOCKED {
+                    // A write may become blocked while sleeping.
+                    wait = wait.min(write_timeout);
+                    continue;
+                }
+                let blocked = now.saturating_sub(blocked_since);
+                if blocked >= write_timeout {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "write timeout"));
+                }
+                wait = wait.min(write_timeout - blocked);
+            }
+            sleep(Duration::from_millis(wait.max(1))).await;
+        }
+    }
+}
+
+/// Ring buffer copying one direction of the relay.
+struct CopyBuffer {
+    buf: Buffer,
+    // Pending data is `buf[head..head + len]`, wrapping at the end of `buf`.
+    head: usize,
+    len: usize,
+    read_done: bool,
+    amt: u64,
+    direction: usize,
+}
+
+impl CopyBuffer {
+    fn new(buffer_size: usize, direction: usize) -> Self {
+        CopyBuffer {
+            buf: Buffer::new(buffer_size.max(1)),
+            head: 0,
+            len: 0,
+            read_done: false,
+            amt: 0,
+            direction,
+        }
+    }
+
+    fn poll_copy<R: Read + Unpin, W: Write + Unpin>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: &mut R,
+        writer: &mut W,
+        activity: &Activity,
+    ) -> Poll<io::Result<u64>> {
+        let cap = self.buf.len();
+        for _ in 0..MAX_ROUNDS_PER_POLL {
+            let mut progressed = false;
+            if !self.read_done && self.len < cap {
+                let tail = (self.head + self.len) % cap;
+                let end = if tail >= self.head { cap } else { self.head };
+                match Pin::new(&mut *reader).poll_read(cx, &mut self.buf[tail..end]) {
+                    Poll::Ready(Ok(0)) => {
+                        self.read_done = true;
+                        progressed = true;
+                    }
+                    Poll::Ready(Ok(n)) => {
+                        self.len += n;
+                        activity.on_read();
+                        progressed = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            }
+            if self.len > 0 {
+                let first_end = (self.head + self.len).min(cap);
+                let wrapped = self.head + self.len - first_end;
+                let slices = [
+                    IoSlice::new(&self.buf[self.head..first_end]),
+                    IoSlice::new(&self.buf[..wrapped]),
+                ];
+                match Pin::new(&mut *writer).poll_write_vectored(cx, &slices) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                    Poll::Ready(Ok(n)) => {
+                        self.len -= n;
+                        self.head = if self.len == 0 {
+                            0
+                        } else {
+                            (self.head + n) % cap
+                        };
+                        self.amt += n as u64;
+                        activity.on_write(self.direction);
+                        progressed = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => activity.on_write_blocked(self.direction),
+                }
+            }
+            if self.read_done && self.len == 0 {
+                // Propagate EOF, the other direction keeps running until the peer closes too.
+                ready!(Pin::new(&mut *writer).poll_close(cx))?;
+                return Poll::Ready(Ok(self.amt));
+            }
+            if !progressed {
+                return Poll::Pending;
+            }
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Relay data between `a` and `b` until both directions reach EOF, returning the bytes copied
+/// from `a` to `b` and from `b` to `a`.
+///
+/// `on_activity` is called whenever the relay is woken up, returning false aborts the relay.
+pub(crate) async fn relay<A, B>(
+    a: A,
+    b: B,
+    options: RelayOptions,
+    on_activity: impl Fn() -> bool,
+) -> io::Result<(u64, u64)>
+where
+    A: Read + Write + Unpin + Clone,
+    B: Read + Write + Unpin + Clone,
+{
+    let (mut a_reader, mut a_writer) = (a.clone(), a);
+    let (mut b_reader, mut b_writer) = (b.clone(), b);
+    let activity = Activity::new();
+    let mut a_to_b = CopyBuffer::new(options.buffer_size, 0);
+    let mut b_to_a = CopyBuffer::new(options.buffer_size, 1);
+    let mut a_to_b_done = None;
+    let mut b_to_a_done = None;
+
+    let transfer = poll_fn(|cx| {
+        if !on_activity() {
+            return Poll::Ready(Err(ErrorKind::ConnectionAborted.into()));
+        }
+        if a_to_b_done.is_none() {
+            if let Poll::Ready(amt) = a_to_b.poll_copy(cx, &mut a_reader, &mut b_writer, &activity)
+            {
+                a_to_b_done = Some(amt?);
+            }
+        }
+        if b_to_a_done.is_none() {
+            if let Poll::Ready(amt) = b_to_a.poll_copy(cx, &mut b_reader, &mut a_writer, &activity)
+            {
+                b_to_a_done = Some(amt?);
+            }
+        }
+        match (a_to_b_done, b_to_a_done) {
+            (Some(a_to_b), Some(b_to_a)) => Poll::Ready(Ok((a_to_b, b_to_a))),
+            _ => Poll::Pending,
+        }
+    });
+    transfer.race(activity.watch(&options)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::{Shutdown, TcpListener, TcpStream};
+    use async_std::task::{block_on, spawn};
+
+    async fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_relay_half_close() {
+        block_on(async {
+            let (mut a_client, a_server) = stream_pair().await;
+            let (mut b_client, b_server) = stream_pair().await;
+            let options = RelayOptions {
+                // Smaller than the data so the ring buffer wraps.
+                buffer_size: 3,
+                read_timeout: Duration::from_secs(5),
+                write_timeout: Duration::from_secs(5),
+            };
+            let handle = spawn(relay(a_server, b_server, options, || true));
+
+            a_client.write_all(b"hello world").await.unwrap();
+            a_client.shutdown(Shutdown::Write).unwrap();
+            let mut received = vec![];
+            b_client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"hello world");
+
+            // The other direction still works after the half-close.
+            b_client.write_all(b"bye").await.unwrap();
+            b_client.shutdown(Shutdown::Write).unwrap();
+            let mut received = vec![];
+            a_client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"bye");
+
+            assert_eq!(handle.await.unwrap(), (11, 3));
+        });
+    }
+
+    // HTTP/1.0 style: the remote closes after the response, the client sees EOF while its own
+    // write half is still open.
+    #[test]
+    fn test_relay_remote_closes_first() {
+        block_on(async {
+            let (mut a_client, a_server) = stream_pair().await;
+            let (mut b_client, b_server) = stream_pair().await;
+            let options = RelayOptions {
+                buffer_size: 1024,
+                read_timeout: Duration::from_secs(5),
+                write_timeout: Duration::from_secs(5),
+            };
+            let handle = spawn(relay(a_server, b_server, options, || true));
+
+            a_client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+            let mut request = [0; 18];
+            b_client.read_exact(&mut request).await.unwrap();
+            b_client
+                .write_all(b"HTTP/1.0 200 OK\r\n\r\n")
+                .await
+                .unwrap();
+            drop(b_client);
+
+            let mut response = vec![];
+            a_client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"HTTP/1.0 200 OK\r\n\r\n");
+
+            a_client.shutdown(Shutdown::Write).unwrap();
+            assert_eq!(handle.await.unwrap(), (18, 19));
+        });
+    }
+
+    #[test]
+    fn test_relay_read_timeout() {
+        block_on(async {
+            let (_a_client, a_server) = stream_pair().await;
+            let (_b_client, b_server) = stream_pair().await;
+            let options = RelayOptions {
+                buffer_size: 1024,
+                read_timeout: Duration::from_millis(100),
+                write_timeout: Duration::from_millis(100),
+            };
+            let err = relay(a_server, b_server, options, || true)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+        });
+    }
+
+    /// Run with `cargo test -p seeker --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_relay_loopback() {
+        block_on(async {
+            let (mut a_client, a_server) = stream_pair().await;
+            let (mut b_client, b_server) = stream_pair().await;
+            let options = RelayOptions {
+                buffer_size: 16 * 1024,
+                read_timeout: Duration::from_secs(5),
+                write_timeout: Duration::from_secs(5),
+            };
+            let handle = spawn(relay(a_server, b_server, options, || true));
+            let total: usize = 1024 * 1024 * 1024;
+            let start = Instant::now();
+            let writer = spawn(async move {
+                let chunk = vec![0u8; 64 * 1024];
+                for _ in 0..total / chunk.len() {
+                    a_client.write_all(&chunk).await.unwrap();
+                }
+                a_client.shutdown(Shutdown::Write).unwrap();
+                a_client
+            });
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut received = 0;
+            loop {
+                let n = b_client.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                received += n;
+            }
+            let elapsed = start.elapsed();
+            b_client.shutdown(Shutdown::Write).unwrap();
+            drop(writer.await);
+            handle.await.unwrap();
+            assert_eq!(received, total);
+            let throughput = 1024.0 / elapsed.as_secs_f64();
+            println!("relay 1 GiB over loopback: {elapsed:?}, {throughput:.0} MiB/s");
+            // Loose enough for a slow machine, only regressions of an order of magnitude fail.
+            assert!(throughput >= 100.0, "relay is slower than 100 MiB/s");
+        });
+    }
+}
@@ -1,19 +1,23 @@
 use anyhow::Result;
-use async_std::io::{timeout, Read, Write};
 use async_std::net::TcpStream;
-use async_std::prelude::*;
+use config::rule::Action;
 use config::{Address, Config};
+use futures_util::FutureExt;
 
 use std::net::SocketAddr;
 
 use std::sync::Arc;
-use std::time::Duration;
 use tracing::{error, instrument, trace};
 
+use crate::chaos::{self, ChaosStream};
+use crate::http_reuse::{IdleKey, ReusableStream};
 use crate::probe_connectivity::ProbeConnectivity;
 use crate::proxy_client::get_action_for_addr;
 use crate::proxy_connection::ProxyConnection;
-use crate::proxy_tcp_stream::ProxyTcpStream;
+use crate::proxy_fallback;
+use crate::reject_page;
+use crate::relay::{relay, RelayOptions};
+use crate::reset_retry::RetryStream;
 use crate::server_chooser::ServerChooser;
 
 #[allow(clippy::too_many_arguments)]
@@ -29,112 +33,152 @@ pub(crate) async fn relay_tcp_stream(
     user_id: Option<u32>,
     on_update_activity: impl Fn() -> bool,
 ) -> Result<()> {
-    let remote_conn = match choose_proxy_tcp_stream(
-        real_src,
-        real_dest,
-        &host,
-        &config,
-        &server_chooser,
-        &connectivity,
-        user_id,
-    )
-    .await
-    {
-        Ok(remote_conn) => remote_conn,
-        Err(e) => {
-            error!(?host, ?e, "connect remote error");
-            return Err(e);
+    let (action, rule_options) =
+        get_action_for_addr(real_src, real_dest, &host, &config, &connectivity, user_id).await?;
+    trace!(?action, ?rule_options, "selected action");
+    if action == Action::Reject {
+        return reject(conn, &host, &config).await;
+    }
+    let action =
+        if action == Action::Proxy && proxy_fallback::is_direct(&config.proxy_fallback, &host) {
+            trace!(?host, "failing through the proxy, connect directly");
+            Action::Direct
+        } else {
+            action
+        };
+    let idle_http = server_chooser.idle_http_connections();
+    let reusable = action == Action::Direct && idle_http.is_reusable(&host);
+    let idle_key = IdleKey {
+        host: host.clone(),
+        action,
+        mark: rule_options.mark,
+    };
+    let idle_conn = if reusable {
+        idle_http.take(&idle_key).await
+    } else {
+        None
+    };
+    let mut remote_conn = match idle_conn {
+        Some(remote_conn) => {
+            trace!(?host, "reuse idle http connection");
+            remote_conn
+        }
+        None => {
+            let ret = server_chooser
+                .candidate_tcp_stream(
+                    host.clone(),
+                    action,
+                    rule_options,
+                    Some((real_src, real_dest)),
+                    config.tcp_connect_timeout(action, rule_options),
+                    config.max_connect_errors,
+                )
+                .await;
+            if action == Action::Proxy {
+                proxy_fallback::record(&config.proxy_fallback, &host, ret.is_ok());
+            }
+            match ret {
+                Ok(remote_conn) => remote_conn,
+                Err(e) => {
+                    error!(?host, ?e, "connect remote error");
+                    return Err(e.into());
+                }
+            }
         }
     };
 
-    let ret = tunnel_tcp_stream(
-        &host,
-        conn,
-        remote_conn.clone(),
-        config.read_timeout,
-        config.write_timeout,
-        on_update_activity,
-    )
-    .await;
-    if let Err(e) = &ret {
-        tracing::error!(?e, ?host, "tunnel tcp stream");
+    let inbound = config.tcp_inbound();
+    let options = RelayOptions {
+        buffer_size: inbound.buffer_size,
+        read_timeout: inbound.read_timeout,
+        write_timeout: inbound.write_timeout,
+    };
+    let fault = chaos::fault_for(&config, &remote_conn);
+    let reusable = reusable && fault.is_none();
+    #[cfg(target_os = "linux")]
+    let spliced = if config.tcp_splice && fault.is_none() && !reusable {
+        crate::splice::relay(&conn, &remote_conn, options, &on_update_activity).await
+    } else {
+        None
+    };
+    #[cfg(not(target_os = "linux"))]
+    let spliced = None;
+    let mut parked = false;
+    let ret = match (spliced, fault) {
+        (Some(ret), _) => ret,
+        (None, Some(fault)) => {
+            relay(
+                ChaosStream::new(conn, fault),
+                ChaosStream::new(remote_conn.clone(), fault),
+                options,
+                on_update_activity,
+            )
+            .await
+        }
+        (None, None) if action == Action::Proxy && config.retry_on_reset => {
+            let server_chooser = server_chooser.clone();
+            let host = host.clone();
+            let connect_timeout = config.tcp_connect_timeout(action, rule_options);
+            let max_retries = config.max_connect_errors;
+            let stream = RetryStream::new(remote_conn.clone(), move || {
+                async move {
+                    server_chooser
+                        .candidate_tcp_stream(
+                            host,
+                            action,
+                            rule_options,
+                            Some((real_src, real_dest)),
+                            connect_timeout,
+                            max_retries,
+                        )
+                        .await
+                }
+                .boxed()
+            });
+            let ret = relay(conn, stream.clone(), options, on_update_activity).await;
+            remote_conn = stream.inner();
+            ret
+        }
+        (None, None) if reusable => {
+            let stream = ReusableStream::new(remote_conn.clone());
+            let ret = relay(conn, stream.clone(), options, on_update_activity).await;
+            parked = ret.is_ok() && stream.is_parked();
+            ret
+        }
+        (None, None) => relay(conn, remote_conn.clone(), options, on_update_activity).await,
+    };
+    match &ret {
+        Err(e) => tracing::error!(?e, ?host, "tunnel tcp stream"),
+        Ok((sent, received)) => tracing::info!(
+            "tunnel tcp stream: recycle port, host: {host}, sent: {sent}, received: {received}"
+        ),
+    }
+    if parked {
+        idle_http.put(idle_key, remote_conn);
     } else {
-        tracing::info!("tunnel tcp stream: recycle port, host: {host}, error: {ret:?}");
+        remote_conn.shutdown();
     }
-    remote_conn.shutdown();
     Ok(())
 }
 
-#[instrument(skip(
-    original_addr,
-    sock_addr,
-    config,
-    server_chooser,
-    connectivity,
-    user_id
-))]
-async fn choose_proxy_tcp_stream(
-    original_addr: SocketAddr,
-    sock_addr: SocketAddr,
-    remote_addr: &Address,
-    config: &Config,
-    server_chooser: &ServerChooser,
-    connectivity: &ProbeConnectivity,
-    user_id: Option<u32>,
-) -> Result<ProxyTcpStream> {
-    let action = get_action_for_addr(
-        original_addr,
-        sock_addr,
-        remote_addr,
-        config,
-        connectivity,
-        user_id,
-    )
-    .await?;
-    trace!(?action, "selected action");
-    Ok(retry_timeout!(
-        config.connect_timeout,
-        config.max_connect_errors,
-        server_chooser.candidate_tcp_stream(remote_addr.clone(), action)
-    )
-    .await?)
-}
-
-async fn tunnel_tcp_stream<T1: Read + Write + Unpin + Clone, T2: Read + Write + Unpin + Clone>(
-    _host: &Address,
-    mut conn1: T1,
-    mut conn2: T2,
-    read_timeout: Duration,
-    write_timeout: Duration,
-    on_update_activity: impl Fn() -> bool,
-) -> std::io::Result<()> {
-    let mut conn1_clone = conn1.clone();
-    let mut conn2_clone = conn2.clone();
-    let f1 = async {
-        let mut buf = vec![0; 1500];
-        loop {
-            if !on_update_activity() {
-                break Err(std::io::ErrorKind::ConnectionAborted.into());
-            }
-            let size = timeout(read_timeout, conn1.read(&mut buf)).await?;
-            if size == 0 {
-                break Ok(());
-            }
-            timeout(write_timeout, conn2.write_all(&buf[..size])).await?;
-        }
-    };
-    let f2 = async {
-        let mut buf = vec![0; 1500];
-        loop {
-            if !on_update_activity() {
-                break Err(std::io::ErrorKind::ConnectionAborted.into());
-            }
-            let size = timeout(read_timeout, conn2_clone.read(&mut buf)).await?;
-            if size == 0 {
-                break Ok(());
-            }
-            timeout(write_timeout, conn1_clone.write_all(&buf[..size])).await?;
-        }
+/// Rejected connections are closed, plain HTTP ones get the reject page first if it's enabled.
+async fn reject(conn: TcpStream, host: &Address, config: &Config) -> Result<()> {
+    if !reject_page::is_served(&config.reject_page, host.port()) {
+        return Ok(());
+    }
+    let ip = match host {
+        Address::SocketAddress(addr) => Some(addr.ip()),
+        Address::DomainNameAddress(..) => None,
     };
-    f1.race(f2).await
+    // Connections rejected by the source rules have no rule matching the host.
+    let rule = config
+        .rules
+        .rule_for_domain(host.hostname(), ip)
+        .filter(|rule| rule.action() == Action::Reject);
+    let name = host.hostname().map_or_else(
+        || ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        str::to_string,
+    );
+    reject_page::serve(conn, &config.reject_page, &name, rule.as_ref()).await?;
+    Ok(())
 }
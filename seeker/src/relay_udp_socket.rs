@@ -1,24 +1,112 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_std::io::timeout;
-use async_std::net::UdpSocket;
-use async_std::task::spawn;
+use async_std::task::{self, spawn};
 use config::{Address, Config};
 use dnsserver::resolver::RuleBasedDnsResolver;
+use parking_lot::Mutex;
 use tun_nat::SessionManager;
 
+use crate::chaos;
 use crate::dns_client::DnsClient;
+use crate::isolate::isolate;
 use crate::probe_connectivity::ProbeConnectivity;
 use crate::proxy_client::{get_action_for_addr, get_real_src_real_dest_and_host, UdpManager};
 use crate::proxy_connection::ProxyConnection;
 use crate::proxy_udp_socket::ProxyUdpSocket;
+use crate::quic::{self, QuicConnectionIds, QuicSession};
 use crate::server_chooser::ServerChooser;
+use crate::udp_batch::{BatchSocket, UdpBatch};
+
+/// A domain answering with TTL 0 is still resolved at most this often.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where the packets of a UDP session go. A domain is resolved again once its answer expires,
+/// so long-lived sessions, eg. VoIP calls, follow the DNS changes of the remote. The address is
+/// kept while it's still in the answer, round-robin DNS doesn't move the session around.
+///
+/// The direct socket isn't connected, replies from the new address reach the session too.
+#[derive(Clone)]
+pub(crate) struct UdpDest {
+    host: Address,
+    state: Arc<Mutex<DestState>>,
+}
+
+struct DestState {
+    addr: SocketAddr,
+    /// None for an IP address, it never changes.
+    refresh_at: Option<Instant>,
+    refreshing: bool,
+}
+
+impl UdpDest {
+    pub(crate) fn new(host: Address, addr: SocketAddr) -> Self {
+        let refresh_at = matches!(host, Address::DomainNameAddress(..))
+            .then(|| Instant::now() + MIN_REFRESH_INTERVAL);
+        UdpDest {
+            host,
+            state: Arc::new(Mutex::new(DestState {
+                addr,
+                refresh_at,
+                refreshing: false,
+            })),
+        }
+    }
+
+    pub(crate) fn host(&self) -> &Address {
+        &self.host
+    }
+
+    /// The current address. Once it expires the domain is resolved in the background, packets
+    /// meanwhile still go to the old address.
+    pub(crate) fn addr(&self, dns_client: &DnsClient) -> SocketAddr {
+        let mut state = self.state.lock();
+        if !state.refreshing && state.refresh_at.map_or(false, |t| t <= Instant::now()) {
+            state.refreshing = true;
+            let dest = self.clone();
+            let dns_client = dns_client.clone();
+            spawn(async move { dest.refresh(&dns_client).await });
+        }
+        state.addr
+    }
+
+    async fn refresh(&self, dns_client: &DnsClient) {
+        let ret = dns_client.lookup_addresses_with_expiry(&self.host).await;
+        let mut state = self.state.lock();
+        state.refreshing = false;
+        let next = Instant::now() + MIN_REFRESH_INTERVAL;
+        match ret {
+            Ok((addrs, valid_until)) => {
+                if !addrs.contains(&state.addr) {
+                    tracing::info!(
+                        host = %self.host,
+                        old = ?state.addr,
+                        new = ?addrs[0],
+                        "udp destination changed"
+                    );
+                    state.addr = addrs[0];
+                }
+                state.refresh_at = valid_until.map(|t| t.max(next));
+            }
+            Err(e) => {
+                tracing::warn!(
+                    ?e,
+                    host = %self.host,
+                    "resolve udp destination error, keep the old address"
+                );
+                state.refresh_at = Some(next);
+            }
+        }
+    }
+}
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) async fn relay_udp_socket(
-    tun_socket: Arc<UdpSocket>,
+    tun_socket: Arc<BatchSocket>,
     tun_addr: SocketAddr,
+    first_packet: &[u8],
     session_manager: SessionManager,
     resolver: RuleBasedDnsResolver,
     dns_client: DnsClient,
@@ -27,7 +115,8 @@ pub(crate) async fn relay_udp_socket(
     connectivity: ProbeConnectivity,
     user_id: Option<u32>,
     udp_manager: UdpManager,
-) -> std::io::Result<(ProxyUdpSocket, SocketAddr, Address)> {
+    quic_ids: QuicConnectionIds,
+) -> std::io::Result<(ProxyUdpSocket, UdpDest)> {
     let session_port = tun_addr.port();
     let (real_src, real_dest, host) = get_real_src_real_dest_and_host(
         session_port,
@@ -38,6 +127,14 @@ pub(crate) async fn relay_udp_socket(
     )
     .await?;
     tracing::debug!(?real_src, ?real_dest, ?host, "new udp connection");
+    // The old session of a QUIC connection moved from another port, eg. after NAT rebinding,
+    // is left to expire at once, its relay task exits once it notices the removal.
+    let migrated = quic_ids.find(real_dest, first_packet);
+    if let Some(old) = migrated {
+        tracing::debug!(old_port = old.port, session_port, %host, "quic connection migrated");
+        udp_manager.remove(old.port, old.id);
+    }
+    let is_quic = migrated.is_some() || quic::is_initial(real_dest.port(), first_packet);
     let proxy_socket = choose_proxy_udp_socket(
         real_src,
         real_dest,
@@ -46,17 +143,32 @@ pub(crate) async fn relay_udp_socket(
         &server_chooser,
         &connectivity,
         user_id,
+        is_quic,
     )
     .await?;
 
     tracing::debug!("new udp connection successfully, {}", host);
 
+    let inbound = config.udp_inbound();
     let proxy_client_clone = proxy_socket.clone();
     let host_clone = host.clone();
     let udp_manager_clone = udp_manager.clone();
+    let dest = UdpDest::new(host.clone(), real_dest);
+    let ttl = if is_quic {
+        config.quic_timeout
+    } else {
+        udp_manager.ttl()
+    };
+    let session_id =
+        udp_manager.insert_with_ttl(session_port, (proxy_socket.clone(), dest.clone()), ttl);
+    let quic_session = is_quic.then_some(QuicSession {
+        port: session_port,
+        id: session_id,
+    });
+    let fault = chaos::fault_for(&config, &proxy_socket);
     spawn(async move {
-        let _: std::io::Result<()> = async {
-            let mut buf = vec![0; 2000];
+        let _: Option<std::io::Result<()>> = isolate("udp session", async {
+            let mut batch = UdpBatch::new(inbound.buffer_size);
             loop {
                 if !session_manager.update_activity_for_port(session_port) {
                     return Err(std::io::Error::new(
@@ -64,31 +176,62 @@ pub(crate) async fn relay_udp_socket(
                         format!("port recycled, {host_clone}"),
                     ));
                 }
-                let (recv_size, _peer) =
-                    timeout(config.read_timeout, proxy_client_clone.recv_from(&mut buf)).await?;
-                assert!(recv_size < 2000);
-                let send_size = timeout(
-                    config.write_timeout,
-                    tun_socket.send_to(&buf[..recv_size], tun_addr),
-                )
-                .await?;
-                assert_eq!(send_size, recv_size);
+                match timeout(ttl, proxy_client_clone.recv_batch(&mut batch)).await {
+                    Ok(_) => {}
+                    // Packets sent to the remote keep the session alive too.
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                        if udp_manager_clone.expire(session_port, session_id) {
+                            return Err(e);
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+                if !udp_manager_clone.touch(session_port, session_id) {
+                    return Err(std::io::ErrorKind::ConnectionAborted.into());
+                }
+                if let Some(session) = quic_session {
+                    quic_ids.learn(real_dest, session, batch.packets().map(|(data, _)| data));
+                }
+                let packets: Vec<(&[u8], SocketAddr)> = batch
+                    .packets()
+                    .filter(|(data, _)| {
+                        let truncated = data.len() >= batch.buffer_size();
+                        if truncated {
+                            udp_manager_clone.record_truncated();
+                            tracing::warn!(
+                                size = data.len(),
+                                host = %host_clone,
+                                "udp packet truncated, increase udp_buffer_size"
+                            );
+                        }
+                        !truncated
+                    })
+                    .filter(|_| !fault.map_or(false, |fault| fault.drops()))
+                    .map(|(data, _)| (data, tun_addr))
+                    .collect();
+                if let Some(fault) = fault {
+                    task::sleep(fault.delay()).await;
+                }
+                timeout(inbound.write_timeout, tun_socket.send_batch(&packets)).await?;
             }
-        }
+        })
         .await;
-        session_manager.recycle_port(session_port);
-        udp_manager_clone.write().remove(&session_port);
+        if let Some(session) = quic_session {
+            quic_ids.remove(session);
+        }
+        // The session may have been removed and replaced already, keep the new one.
+        udp_manager_clone.remove(session_port, session_id);
+        if !udp_manager_clone.contains(session_port) {
+            session_manager.recycle_port(session_port);
+        }
         proxy_client_clone.shutdown();
     });
 
-    udp_manager.write().insert(
-        session_port,
-        (proxy_socket.clone(), real_dest, host.clone()),
-    );
-
-    Ok((proxy_socket, real_dest, host))
+    Ok((proxy_socket, dest))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn choose_proxy_udp_socket(
     real_src: SocketAddr,
     real_dest: SocketAddr,
@@ -97,8 +240,9 @@ async fn choose_proxy_udp_socket(
     server_chooser: &ServerChooser,
     connectivity: &ProbeConnectivity,
     user_id: Option<u32>,
+    is_quic: bool,
 ) -> std::io::Result<ProxyUdpSocket> {
-    let action = get_action_for_addr(
+    let (action, options) = get_action_for_addr(
         real_src,
         real_dest,
         remote_addr,
@@ -107,11 +251,11 @@ async fn choose_proxy_udp_socket(
         user_id,
     )
     .await?;
-    tracing::debug!(?action, ?remote_addr, "udp action");
+    tracing::debug!(?action, ?options, ?remote_addr, "udp action");
     retry_timeout!(
-        config.connect_timeout,
+        config.udp_connect_timeout(options),
         config.max_connect_errors,
-        server_chooser.candidate_udp_socket(action)
+        server_chooser.candidate_udp_socket(action, options, real_src, is_quic)
     )
     .await
 }
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Context, Result};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use store::Store;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options to verify the signature of the remote config.
+pub(crate) struct SignatureOptions<'a> {
+    /// Base64 encoded ed25519 public key.
+    pub public_key: &'a str,
+    /// Url of the base64 encoded signature. Defaults to `{config_url}.sig`.
+    pub signature_url: Option<&'a str>,
+}
+
+/// Download the config from `url`, verify its signature if `signature` is set and cache it
+/// in the store at `cache_path`.
+///
+/// When the url is unreachable or the signature is invalid, the cached copy is returned instead.
+/// With `signature` set, the signature is cached along with the config and the cached copy is
+/// only returned if it verifies, a copy cached without one is refused.
+pub(crate) fn fetch_remote_config(
+    url: &str,
+    signature: Option<&SignatureOptions>,
+    cache_path: impl AsRef<Path>,
+) -> Result<Vec<u8>> {
+    let cache = match Store::open_config_cache(cache_path) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            eprintln!("Open remote config cache error: {e}");
+            None
+        }
+    };
+
+    let signature_url = signature.map(|signature| {
+        signature
+            .signature_url
+            .map(ToString::to_string)
+            .unwrap_or_else(|| format!("{url}.sig"))
+    });
+    let fetched = download(url).and_then(|data| {
+        let sig = match (signature, &signature_url) {
+            (Some(signature), Some(signature_url)) => {
+                let sig = download(signature_url).context("Load config signature error")?;
+                verify_signature(signature.public_key, &data, &sig)?;
+                Some(sig)
+            }
+            _ => None,
+        };
+        Ok((data, sig))
+    });
+
+    match fetched {
+        Ok((data, sig)) => {
+            if let Some(cache) = &cache {
+                let cached = match (&signature_url, &sig) {
+                    (Some(signature_url), Some(sig)) => cache
+                        .cache_remote_config_data(signature_url, sig)
+                        .and_then(|_| cache.cache_remote_config_data(url, &data)),
+                    _ => cache.cache_remote_config_data(url, &data),
+                };
+                if let Err(e) = cached {
+                    eprintln!("Cache remote config `{url}` error: {e}");
+                }
+            }
+            Ok(data)
+        }
+        Err(e) => {
+            eprintln!("Load config from `{url}` error: {e:#}");
+            let Some(Ok(Some(data))) = cache.as_ref().map(|c| c.get_cached_remote_config_data(url))
+            else {
+                return Err(e.context(format!("No cached config for `{url}`")));
+            };
+            if let (Some(signature), Some(signature_url)) = (signature, &signature_url) {
+                let Some(Ok(Some(sig))) = cache
+                    .as_ref()
+                    .map(|c| c.get_cached_remote_config_data(signature_url))
+                else {
+                    return Err(e.context(format!("No cached config signature for `{url}`")));
+                };
+                verify_signature(signature.public_key, &data, &sig)
+                    .map_err(|_| e.context(format!("Cached config for `{url}` isn't signed")))?;
+            }
+            eprintln!("Use config for `{url}` from cache instead.");
+            Ok(data)
+        }
+    }
+}
+
+fn download(url: &str) -> Result<Vec<u8>> {
+    let resp = ureq::get(url)
+        .timeout(FETCH_TIMEOUT)
+        .call()
+        .map_err(|e| anyhow!("Load config from remote host error: {e}"))?;
+    let mut data = Vec::new();
+    let _size = resp
+        .into_reader()
+        .read_to_end(&mut data)
+        .context("Read http response error")?;
+    Ok(data)
+}
+
+fn verify_signature(public_key: &str, data: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key = base64::decode(public_key.trim()).context("Decode public key error")?;
+    let signature = base64::decode(String::from_utf8_lossy(signature).trim())
+        .context("Decode config signature error")?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(data, &signature)
+        .map_err(|_| anyhow!("Config signature verification failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let key_pair = key_pair();
+        let public_key = base64::encode(key_pair.public_key().as_ref());
+        let data = b"servers: []";
+        let signature = base64::encode(key_pair.sign(data).as_ref());
+
+        assert!(verify_signature(&public_key, data, signature.as_bytes()).is_ok());
+        assert!(verify_signature(&public_key, b"servers: [1]", signature.as_bytes()).is_err());
+        let other_key = base64::encode(self::key_pair().public_key().as_ref());
+        assert!(verify_signature(&other_key, data, signature.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_fallback_to_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("seeker.sqlite");
+        let url = "http://127.0.0.1:1/config.yml";
+        assert!(fetch_remote_config(url, None, &cache_path).is_err());
+
+        Store::open_config_cache(&cache_path)
+            .unwrap()
+            .cache_remote_config_data(url, b"cached")
+            .unwrap();
+        let data = fetch_remote_config(url, None, &cache_path).unwrap();
+        assert_eq!(data, b"cached");
+
+        // The copy cached without a signature isn't trusted once a public key is configured.
+        let key_pair = key_pair();
+        let public_key = base64::encode(key_pair.public_key().as_ref());
+        let signature = SignatureOptions {
+            public_key: &public_key,
+            signature_url: None,
+        };
+        assert!(fetch_remote_config(url, Some(&signature), &cache_path).is_err());
+
+        let cache = Store::open_config_cache(&cache_path).unwrap();
+        let sig_url = format!("{url}.sig");
+        cache
+            .cache_remote_config_data(&sig_url, b"not a signature")
+            .unwrap();
+        assert!(fetch_remote_config(url, Some(&signature), &cache_path).is_err());
+        let sig = base64::encode(key_pair.sign(b"cached").as_ref());
+        cache
+            .cache_remote_config_data(&sig_url, sig.as_bytes())
+            .unwrap();
+        drop(cache);
+        let data = fetch_remote_config(url, Some(&signature), &cache_path).unwrap();
+        assert_eq!(data, b"cached");
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use config::Config;
+use seeker_core::{decide, Decision};
+use std::io::{BufRead, Write};
+
+/// Route the decisions recorded with `--record-decisions` read from `input` with `config` and
+/// write those it changes to `output`, with a summary at the end.
+pub(crate) fn run(config: &Config, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    let mut total = 0;
+    let mut changed = 0;
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let recorded: Decision = line.parse().with_context(|| format!("line {}", i + 1))?;
+        total += 1;
+        let (action, _) = decide(config, &recorded.route);
+        if action == recorded.action {
+            continue;
+        }
+        changed += 1;
+        let route = &recorded.route;
+        match &route.domain {
+            Some(domain) => write!(output, "{domain} ({})", route.dest)?,
+            None => write!(output, "{}", route.dest)?,
+        }
+        writeln!(
+            output,
+            " from {}\t{} -> {action}",
+            route.src, recorded.action
+        )?;
+    }
+    writeln!(output, "{total} decisions, {changed} changed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+dns_start_ip: 10.0.0.1
+dns_servers:
+  - 223.5.5.5:53
+tun_name: utun-test
+tun_ip: 10.0.0.1
+tun_cidr: 10.0.0.0/16
+dns_listen: 127.0.0.1:53
+servers:
+  - name: server1
+    addr: 127.0.0.1:1080
+    protocol: Socks5
+rules:
+  - 'DOMAIN-SUFFIX,google.com,PROXY'
+  - 'MATCH,DIRECT'
+"#,
+        )
+        .unwrap();
+        let trace = "\
+# recorded with the old config
+www.google.com\t10.0.0.5:443\t192.168.1.2:50000\t-\tlocal\t-\t-\tPROXY
+example.com\t10.0.0.6:443\t192.168.1.2:50001\t-\tlocal\t-\t-\tPROXY
+-\t1.1.1.1:53\t192.168.1.2:50002\t-\tlocal\t-\t-\tDIRECT
+";
+        let mut output = vec![];
+        run(&config, trace.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "example.com (10.0.0.6:443) from 192.168.1.2:50001\tProxy -> Direct\n\
+             3 decisions, 1 changed\n"
+        );
+
+        let err = run(&config, "www.google.com\tPROXY\n".as_bytes(), vec![]).unwrap_err();
+        assert!(format!("{err:#}").starts_with("line 1: "));
+    }
+}
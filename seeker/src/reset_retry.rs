@@ -0,0 +1,256 @@
+//! Proxied connections reset before anything was received are retried once on a new connection,
+//! configured by `retry_on_reset`. Servers under transient trouble often reset right after the
+//! handshake, the bytes the application sent so far are sent again on the new connection so it
+//! doesn't notice.
+use crate::proxy_tcp_stream::ProxyTcpStream;
+use async_std::io::{Read, Write, WriteExt};
+use async_std::task::ready;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io::{Error, ErrorKind, IoSlice, Result};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Connections that sent more than this before anything was received aren't retried.
+const MAX_REPLAY_SIZE: usize = 64 * 1024;
+
+type Reconnect = Box<dyn FnOnce() -> BoxFuture<'static, Result<ProxyTcpStream>> + Send>;
+
+enum Retry {
+    /// Nothing was received yet, `sent` is everything written to the connection.
+    Possible { sent: Vec<u8>, reconnect: Reconnect },
+    /// Connecting again and sending the bytes of the first connection.
+    Connecting(BoxFuture<'static, Result<ProxyTcpStream>>),
+    /// Something was received, too much was sent or the connection was retried already.
+    Done,
+}
+
+struct RetryState {
+    inner: ProxyTcpStream,
+    retry: Retry,
+}
+
+impl RetryState {
+    /// Start connecting again if `e` is a reset and the connection can still be retried.
+    fn start_retry(&mut self, e: &Error) -> bool {
+        if !matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::BrokenPipe) {
+            return false;
+        }
+        let Retry::Possible { sent, reconnect } = std::mem::replace(&mut self.retry, Retry::Done)
+        else {
+            return false;
+        };
+        tracing::info!(?e, sent = sent.len(), "connection reset early, retry");
+        let connect = reconnect();
+        self.retry = Retry::Connecting(
+            async move {
+                let mut conn = connect.await?;
+                conn.write_all(&sent).await?;
+                Ok(conn)
+            }
+            .boxed(),
+        );
+        true
+    }
+
+    /// Wait for the retried connection if it's being connected.
+    fn poll_retry(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let Retry::Connecting(connect) = &mut self.retry else {
+            return Poll::Ready(Ok(()));
+        };
+        let ret = ready!(connect.as_mut().poll(cx));
+        self.retry = Retry::Done;
+        self.inner = ret?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn on_sent(&mut self, bufs: &[&[u8]], mut size: usize) {
+        let Retry::Possible { sent, .. } = &mut self.retry else {
+            return;
+        };
+        if sent.len() + size > MAX_REPLAY_SIZE {
+            self.retry = Retry::Done;
+            return;
+        }
+        for buf in bufs {
+            let n = size.min(buf.len());
+            sent.extend_from_slice(&buf[..n]);
+            size -= n;
+            if size == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// The remote side of a proxied connection, connected again by `reconnect` when it's reset
+/// before the first byte is received.
+#[derive(Clone)]
+pub(crate) struct RetryStream {
+    state: Arc<Mutex<RetryState>>,
+}
+
+impl RetryStream {
+    pub(crate) fn new(
+        inner: ProxyTcpStream,
+        reconnect: impl FnOnce() -> BoxFuture<'static, Result<ProxyTcpStream>> + Send + 'static,
+    ) -> Self {
+        RetryStream {
+            state: Arc::new(Mutex::new(RetryState {
+                inner,
+                retry: Retry::Possible {
+                    sent: vec![],
+                    reconnect: Box::new(reconnect),
+                },
+            })),
+        }
+    }
+
+    /// The connection in use, the retried one if the first was reset.
+    pub(crate) fn inner(&self) -> ProxyTcpStream {
+        self.state.lock().inner.clone()
+    }
+}
+
+impl Read for RetryStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let mut state = self.state.lock();
+        loop {
+            ready!(state.poll_retry(cx))?;
+            match ready!(Pin::new(&mut state.inner).poll_read(cx, buf)) {
+                Ok(size) => {
+                    if size > 0 {
+                        state.retry = Retry::Done;
+                    }
+                    return Poll::Ready(Ok(size));
+                }
+                Err(e) if state.start_retry(&e) => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl Write for RetryStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let mut state = self.state.lock();
+        loop {
+            ready!(state.poll_retry(cx))?;
+            match ready!(Pin::new(&mut state.inner).poll_write(cx, buf)) {
+                Ok(size) => {
+                    state.on_sent(&[buf], size);
+                    return Poll::Ready(Ok(size));
+                }
+                Err(e) if state.start_retry(&e) => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        let mut state = self.state.lock();
+        loop {
+            ready!(state.poll_retry(cx))?;
+            match ready!(Pin::new(&mut state.inner).poll_write_vectored(cx, bufs)) {
+                Ok(size) => {
+                    let bufs: Vec<&[u8]> = bufs.iter().map(|buf| &**buf).collect();
+                    state.on_sent(&bufs, size);
+                    return Poll::Ready(Ok(size));
+                }
+                Err(e) if state.start_retry(&e) => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut state = self.state.lock();
+        ready!(state.poll_retry(cx))?;
+        Pin::new(&mut state.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut state = self.state.lock();
+        ready!(state.poll_retry(cx))?;
+        Pin::new(&mut state.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_client::DnsClient;
+    use async_std::io::ReadExt;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::spawn;
+    use config::rule::{ConnectionLog, RuleOptions};
+    use config::Address;
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    async fn connect(addr: SocketAddr) -> Result<ProxyTcpStream> {
+        let dns_client = DnsClient::new(&[], Duration::from_secs(1)).await;
+        let options = RuleOptions {
+            log: ConnectionLog::Off,
+            ..Default::default()
+        };
+        ProxyTcpStream::connect(
+            Address::SocketAddress(addr),
+            None,
+            dns_client,
+            None,
+            options,
+            None,
+        )
+        .await
+    }
+
+    fn reset(conn: TcpStream) {
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        nix::sys::socket::setsockopt(conn.as_raw_fd(), nix::sys::socket::sockopt::Linger, &linger)
+            .unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_retry_on_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = spawn(async move {
+            // The first connection is reset once the request arrived.
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 5];
+            conn.read_exact(&mut buf).await.unwrap();
+            reset(conn);
+            let (mut conn, _) = listener.accept().await.unwrap();
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(b"world").await.unwrap();
+            buf
+        });
+
+        let first = connect(addr).await.unwrap();
+        let mut stream = RetryStream::new(first, move || connect(addr).boxed());
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(&server.await, b"hello");
+
+        // Nothing is retried once the first byte was received.
+        assert!(matches!(stream.state.lock().retry, Retry::Done));
+    }
+}
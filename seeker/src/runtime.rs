@@ -0,0 +1,176 @@
+//! Run the proxy inside another program. [`ProxyRuntime::start`] starts the TUN device, the relay
+//! servers, the DNS server and the health checks of the servers like `seeker` does, but leaves
+//! the routes and the system DNS to the embedding program, eg. with `sysconfig::RouteSetup` and
+//! `sysconfig::DNSSetup`.
+use crate::control::{Controller, SOURCE_API};
+use crate::proxy_client::ProxyClient;
+use crate::proxy_connection::ProxyConnection;
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::prelude::*;
+use async_std::task::{block_on, spawn, JoinHandle};
+use config::rule::{Action, ProxyRules};
+use config::{Address, Config};
+use std::fmt;
+use std::io::Result;
+use std::time::Duration;
+use store::{FakeIpStats, MonthlyUsage, Store};
+
+pub struct ProxyRuntime;
+
+impl ProxyRuntime {
+    /// Start proxying with `config`, returns once the TUN device and the DNS server are up. The
+    /// proxy runs on the async-std executor until [`Handle::shutdown`] or the handle is dropped.
+    pub fn start(config: Config) -> Result<Handle> {
+        crate::install_panic_hook();
+        tcp_connection::set_tcp_options(config.tcp_options());
+        let client = block_on(ProxyClient::new(config, None, false))?;
+        let controller = client.controller();
+        // Never sent to, closed when the handle is dropped.
+        let (stop, stopped) = bounded::<()>(1);
+        let task = spawn(async move {
+            client
+                .run()
+                .race(async move {
+                    let _ = stopped.recv().await;
+                })
+                .await
+        });
+        Ok(Handle {
+            controller,
+            _stop: stop,
+            task,
+        })
+    }
+}
+
+/// A running proxy started by [`ProxyRuntime::start`].
+pub struct Handle {
+    controller: Controller,
+    _stop: Sender<()>,
+    task: JoinHandle<()>,
+}
+
+/// How connections get to the proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Through the TUN device, DNS queries are answered with fake IPs.
+    Tun,
+    /// Redirected by iptables, see `redir_mode`.
+    Redir,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Tun => write!(f, "tun"),
+            Mode::Redir => write!(f, "redir"),
+        }
+    }
+}
+
+/// What [`Handle::stats`] returns.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub mode: Mode,
+    /// Whether everything goes direct, see [`Handle::pause`].
+    pub paused: bool,
+    /// Name of the server new connections go through.
+    pub server: String,
+    /// Names of the servers that answered the last ping.
+    pub available_servers: Vec<String>,
+    pub connections: Vec<ConnectionStats>,
+    pub fake_ips: FakeIpStats,
+}
+
+/// A connection being relayed, as listed by `seeker ctl connections`.
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    pub id: u64,
+    pub network: &'static str,
+    pub action: Action,
+    /// Name of the server the connection goes through, None for direct connections.
+    pub server: Option<String>,
+    pub remote_addr: Option<Address>,
+    /// HTTP Host or TLS SNI sent on a connection to an ip.
+    pub sniffed_host: Option<String>,
+    pub sent_bytes: usize,
+    pub recv_bytes: usize,
+    pub duration: Duration,
+}
+
+impl ConnectionStats {
+    pub(crate) fn of(conn: &dyn ProxyConnection) -> Self {
+        ConnectionStats {
+            id: conn.id(),
+            network: conn.network(),
+            action: conn.action(),
+            server: conn.config().map(|server| server.name().to_string()),
+            remote_addr: conn.remote_addr().cloned(),
+            sniffed_host: conn.sniffed_host(),
+            sent_bytes: conn.sent_bytes(),
+            recv_bytes: conn.recv_bytes(),
+            duration: conn.duration(),
+        }
+    }
+}
+
+impl Handle {
+    /// Replace the rules with `rules`, eg. `Config::from_config_file(path)?.rules`. New
+    /// connections and DNS queries are matched against them, connections already relayed keep
+    /// their actions. The routes of the TUN device aren't changed, so new `IP-CIDR` rules only
+    /// apply to networks already routed to it.
+    pub fn reload_rules(&self, rules: &ProxyRules) {
+        self.controller.reload_rules(rules, SOURCE_API);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let chooser = &self.controller.server_chooser;
+        let (selected, candidates) = chooser.servers();
+        let mut connections = vec![];
+        chooser.for_each_live_connection(|conn| connections.push(ConnectionStats::of(conn)));
+        Stats {
+            mode: self.controller.mode,
+            paused: self.controller.rules.is_paused(),
+            server: selected.name().to_string(),
+            available_servers: candidates.iter().map(|s| s.name().to_string()).collect(),
+            connections,
+            fake_ips: Store::global().fake_ip_stats().unwrap_or_default(),
+        }
+    }
+
+    /// Month to date traffic through each server, like `seeker ctl usage`.
+    pub fn usage(&self) -> Result<MonthlyUsage> {
+        Store::global()
+            .monthly_usage(store::now())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+    }
+
+    /// Receive each connection as it's made, eg. to list the recent connections. New
+    /// connections are dropped while the receiver is full.
+    pub fn connections(&self) -> Receiver<ConnectionStats> {
+        self.controller.server_chooser.subscribe_connections()
+    }
+
+    /// Send new connections direct until [`Self::resume`], without tearing down the TUN device,
+    /// like `seeker ctl pause`. Connections through the servers are closed.
+    pub fn pause(&self) {
+        self.controller.pause(SOURCE_API);
+    }
+
+    pub fn resume(&self) {
+        self.controller.resume(SOURCE_API);
+    }
+
+    /// Forget the servers' health, resolved IPs and connections after a network change, like
+    /// `seeker ctl reset`.
+    pub fn reset_network(&self) {
+        self.controller.reset_network(SOURCE_API);
+    }
+
+    /// Stop the relay servers, the DNS server and the health checks, and wait for them to stop.
+    /// The TUN device stays open until the process exits, connections to it are refused.
+    pub fn shutdown(self) {
+        drop(self._stop);
+        block_on(self.task);
+    }
+}
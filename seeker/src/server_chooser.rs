@@ -1,54 +1,86 @@
+use crate::connection_pool::ConnectionPool;
 use crate::dns_client::DnsClient;
+use crate::hooks::Hooks;
+use crate::http_reuse::IdleHttpConnections;
 use crate::proxy_connection::ProxyConnection;
 use crate::proxy_tcp_stream::ProxyTcpStream;
 use crate::proxy_udp_socket::ProxyUdpSocket;
+use crate::runtime::ConnectionStats;
+use crate::usage::UsageMeter;
 use anyhow::Result;
+use async_std::channel::{bounded, Receiver, Sender, TrySendError};
 use async_std::io::timeout;
 use async_std::prelude::*;
 use async_std::task::{sleep, spawn};
 use async_tls::TlsConnector;
-use config::rule::Action;
-use config::{Address, PingURL, ServerConfig};
+use config::rule::{Action, RuleOptions};
+use config::{Address, ConnectionPoolConfig, HttpReuseConfig, PingURL, ServerConfig};
 use futures_util::stream::FuturesUnordered;
 use parking_lot::{Mutex, RwLock};
+use socks5_client::Socks5UdpAssociations;
 use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tracing::info;
 
+/// New connections queued for each subscriber, eg. a GUI listing recent connections.
+const CONNECTION_SUBSCRIBER_CAPACITY: usize = 256;
+
 #[derive(Clone)]
 pub struct ServerChooser {
     ping_urls: Vec<PingURL>,
     ping_timeout: Duration,
+    /// Timeout of the connections opened to refill the pool.
+    connect_timeout: Duration,
     servers: Arc<Vec<ServerConfig>>,
     candidates: Arc<Mutex<Vec<ServerConfig>>>,
     selected_server: Arc<Mutex<ServerConfig>>,
     dns_client: DnsClient,
     live_connections: Arc<RwLock<Vec<Box<dyn ProxyConnection + Send + Sync>>>>,
+    connection_pool: Arc<ConnectionPool>,
+    idle_http_connections: Arc<IdleHttpConnections>,
+    socks5_associations: Socks5UdpAssociations,
+    connection_subscribers: Arc<Mutex<Vec<Sender<ConnectionStats>>>>,
+    usage: Arc<UsageMeter>,
+    hooks: Arc<Hooks>,
     show_stats: bool,
 }
 
 impl ServerChooser {
-    pub async fn new(
+    /// Create the chooser with the first server selected. Servers are pinged by
+    /// [`Self::run_background_tasks`], so startup doesn't wait for slow servers.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
         servers: Arc<Vec<ServerConfig>>,
         dns_client: DnsClient,
         ping_urls: Vec<PingURL>,
         ping_timeout: Duration,
+        connect_timeout: Duration,
+        connection_pool: ConnectionPoolConfig,
+        http_reuse: HttpReuseConfig,
+        hooks: Arc<Hooks>,
         show_stats: bool,
     ) -> Self {
         let selected = servers.first().cloned().expect("no server available");
-        let chooser = ServerChooser {
+        ServerChooser {
             ping_urls,
             ping_timeout,
+            connect_timeout,
             candidates: Arc::new(Mutex::new(servers.iter().cloned().collect())),
             servers,
             dns_client,
             live_connections: Arc::new(RwLock::new(vec![])),
+            connection_pool: Arc::new(ConnectionPool::new(connection_pool)),
+            idle_http_connections: Arc::new(IdleHttpConnections::new(http_reuse)),
+            socks5_associations: Default::default(),
             selected_server: Arc::new(Mutex::new(selected)),
+            connection_subscribers: Default::default(),
+            usage: Default::default(),
+            hooks,
             show_stats,
-        };
-        chooser.ping_servers().await;
-        chooser
+        }
     }
 
     fn set_server_down(&self, config: &ServerConfig) {
@@ -66,45 +98,87 @@ impl ServerChooser {
     }
 
     fn insert_live_connections(&self, conn: Box<dyn ProxyConnection + Send + Sync>) {
+        let mut subscribers = self.connection_subscribers.lock();
+        if !subscribers.is_empty() {
+            let stats = ConnectionStats::of(conn.as_ref());
+            subscribers
+                .retain(|tx| !matches!(tx.try_send(stats.clone()), Err(TrySendError::Closed(_))));
+        }
+        drop(subscribers);
+        let domain = match conn.remote_addr() {
+            Some(Address::DomainNameAddress(domain, _)) => Some(domain.clone()),
+            _ => conn.sniffed_host(),
+        };
+        if let Some(domain) = domain {
+            self.hooks
+                .on_connection(&domain, conn.config().map(|config| config.name()));
+        }
         self.live_connections.write().push(conn);
     }
 
+    /// Connect to `remote_addr`, each attempt is given up after `connect_timeout`. Failed proxy
+    /// connections are retried through the next candidate, the selected server is left to the
+    /// pings to change. Direct connections are retried when they time out, at most `max_retries`
+    /// times. The mark of `options` is set on direct connections only, `origin` is sent to
+    /// proxies with `proxy_protocol`.
     #[tracing::instrument(skip(self))]
     pub async fn candidate_tcp_stream(
         &self,
         remote_addr: Address,
         action: Action,
+        options: RuleOptions,
+        origin: Option<(SocketAddr, SocketAddr)>,
+        connect_timeout: Duration,
+        max_retries: usize,
     ) -> std::io::Result<ProxyTcpStream> {
-        let stream = match action {
-            Action::Proxy => {
-                let config = self.selected_server.lock().clone();
-                let stream = ProxyTcpStream::connect(
-                    remote_addr.clone(),
-                    Some(&config),
-                    self.dns_client.clone(),
-                )
-                .await;
-                if stream.is_err() {
-                    tracing::error!(
+        let mut retries = 0;
+        let mut server = self.selected_server.lock().clone();
+        let stream = loop {
+            let ret = match action {
+                Action::Proxy => {
+                    self.connect_proxy(&server, &remote_addr, options, origin, connect_timeout)
+                        .await
+                }
+                Action::Direct => {
+                    let ret = timeout(
+                        connect_timeout,
+                        ProxyTcpStream::connect(
+                            remote_addr.clone(),
+                            None,
+                            self.dns_client.clone(),
+                            None,
+                            options,
+                            None,
+                        ),
+                    )
+                    .await;
+                    if let Err(e) = &ret {
+                        tracing::error!(?remote_addr, ?action, "Failed to connect: {}", e);
+                    }
+                    ret
+                }
+                _ => unreachable!(),
+            };
+            match ret {
+                Ok(stream) => break stream,
+                Err(e)
+                    if retries < max_retries
+                        && (action == Action::Proxy || e.kind() == ErrorKind::TimedOut) =>
+                {
+                    retries += 1;
+                    if action == Action::Proxy {
+                        server = self.next_candidate(&server);
+                    }
+                    tracing::warn!(
                         ?remote_addr,
                         ?action,
-                        "Failed to connect to server: {}",
-                        config.addr()
+                        retries,
+                        server = server.name(),
+                        "Retry connecting"
                     );
-                    self.move_to_next_server();
-                }
-                stream?
-            }
-            Action::Direct => {
-                let ret =
-                    ProxyTcpStream::connect(remote_addr.clone(), None, self.dns_client.clone())
-                        .await;
-                if ret.is_err() {
-                    tracing::error!(?remote_addr, ?action, "Failed to connect to server");
                 }
-                ret?
+                Err(e) => return Err(e),
             }
-            _ => unreachable!(),
         };
 
         // store all on-fly connections
@@ -114,13 +188,93 @@ impl ServerChooser {
         Ok(stream)
     }
 
-    pub async fn candidate_udp_socket(&self, action: Action) -> std::io::Result<ProxyUdpSocket> {
+    async fn connect_proxy(
+        &self,
+        config: &ServerConfig,
+        remote_addr: &Address,
+        options: RuleOptions,
+        origin: Option<(SocketAddr, SocketAddr)>,
+        connect_timeout: Duration,
+    ) -> std::io::Result<ProxyTcpStream> {
+        let stream = timeout(
+            connect_timeout,
+            ProxyTcpStream::connect(
+                remote_addr.clone(),
+                Some(config),
+                self.dns_client.clone(),
+                Some(&self.connection_pool),
+                options,
+                origin,
+            ),
+        )
+        .await;
+        self.spawn_refill_connection_pool(config.clone());
+        if let Err(e) = &stream {
+            tracing::error!(
+                ?remote_addr,
+                "Failed to connect to server {}: {}",
+                config.addr(),
+                e
+            );
+            self.dns_client.forget_server(config.addr());
+        }
+        stream
+    }
+
+    /// The candidate after `server` to retry a connection through, `server` itself if there are
+    /// no candidates.
+    fn next_candidate(&self, server: &ServerConfig) -> ServerConfig {
+        let candidates = self.candidates.lock();
+        if candidates.is_empty() {
+            return server.clone();
+        }
+        next_server(&candidates, server).clone()
+    }
+
+    fn spawn_refill_connection_pool(&self, server: ServerConfig) {
+        if !self.connection_pool.is_enabled() {
+            return;
+        }
+        let pool = self.connection_pool.clone();
+        let dns_client = self.dns_client.clone();
+        let connect_timeout = self.connect_timeout;
+        spawn(async move {
+            if let Err(e) = pool.refill(&server, &dns_client, connect_timeout).await {
+                tracing::warn!(?e, server = server.name(), "refill connection pool");
+            }
+        });
+    }
+
+    /// Direct plain HTTP connections parked for reuse.
+    pub(crate) fn idle_http_connections(&self) -> &IdleHttpConnections {
+        &self.idle_http_connections
+    }
+
+    /// A socket to send udp packets directly or through the selected server, the mark of
+    /// `options` is set on direct ones only. Socks5 sockets of the same `source` share the
+    /// association, sockets of `quic` sessions are recorded as such.
+    pub async fn candidate_udp_socket(
+        &self,
+        action: Action,
+        options: RuleOptions,
+        source: SocketAddr,
+        quic: bool,
+    ) -> std::io::Result<ProxyUdpSocket> {
         let socket = match action {
-            Action::Direct => ProxyUdpSocket::new(None, self.dns_client.clone()).await?,
+            Action::Direct => {
+                ProxyUdpSocket::new(None, self.dns_client.clone(), options, None, quic).await?
+            }
             Action::Proxy => {
                 let config = self.selected_server.lock().clone();
                 tracing::info!("Using server: {}", config.addr());
-                let socket = ProxyUdpSocket::new(Some(&config), self.dns_client.clone()).await;
+                let socket = ProxyUdpSocket::new(
+                    Some(&config),
+                    self.dns_client.clone(),
+                    options,
+                    Some((&self.socks5_associations, source)),
+                    quic,
+                )
+                .await;
                 if socket.is_err() {
                     tracing::info!("Failed to connect to server: {}", config.addr());
                     self.move_to_next_server();
@@ -134,6 +288,89 @@ impl ServerChooser {
         Ok(socket)
     }
 
+    /// Forget what was learned on the previous network: all servers are candidates again until
+    /// the next ping, server IPs are resolved again, and pooled and live connections are closed
+    /// so applications reconnect through the new network.
+    pub fn reset(&self) {
+        *self.candidates.lock() = self.servers.iter().cloned().collect();
+        for server in self.servers.iter() {
+            self.dns_client.forget_server(server.addr());
+        }
+        self.connection_pool.clear();
+        self.idle_http_connections.clear();
+        self.socks5_associations.clear();
+        self.live_connections
+            .read()
+            .iter()
+            .for_each(|conn| conn.shutdown());
+        let chooser = self.clone();
+        spawn(async move { chooser.ping_servers().await });
+    }
+
+    /// The selected server and the servers that answered the last ping.
+    pub fn servers(&self) -> (ServerConfig, Vec<ServerConfig>) {
+        let candidates = self.candidates.lock().clone();
+        (self.selected_server.lock().clone(), candidates)
+    }
+
+    /// Select the server named `name`, closing connections through the previous one. Returns
+    /// false if there's no such server.
+    pub fn select_server(&self, name: &str) -> bool {
+        let Some(new) = self.servers.iter().find(|s| s.name() == name) else {
+            return false;
+        };
+        let old = self.selected_server.lock().clone();
+        if &old != new {
+            self.set_server_down(&old);
+            info!(
+                old_name = old.name(),
+                new_name = new.name(),
+                "Select shadowsocks server"
+            );
+            *self.selected_server.lock() = new.clone();
+        }
+        true
+    }
+
+    /// Call `f` with each live connection.
+    pub fn for_each_live_connection(&self, mut f: impl FnMut(&dyn ProxyConnection)) {
+        for conn in self.live_connections.read().iter() {
+            f(conn.as_ref());
+        }
+    }
+
+    /// Shut down the live connection `id`. Returns false if there's no such connection.
+    pub fn kill_connection(&self, id: u64) -> bool {
+        let live_connections = self.live_connections.read();
+        let Some(conn) = live_connections.iter().find(|conn| conn.id() == id) else {
+            return false;
+        };
+        conn.shutdown();
+        true
+    }
+
+    /// Shut down the live connections with `action`. Returns how many there were.
+    pub fn kill_connections(&self, action: Action) -> usize {
+        let live_connections = self.live_connections.read();
+        let mut count = 0;
+        for conn in live_connections
+            .iter()
+            .filter(|conn| conn.action() == action)
+        {
+            conn.shutdown();
+            count += 1;
+        }
+        count
+    }
+
+    /// Receive each new connection. Connections are dropped while the receiver is full, so a
+    /// slow subscriber doesn't hold up the relay.
+    pub fn subscribe_connections(&self) -> Receiver<ConnectionStats> {
+        let (tx, rx) = bounded(CONNECTION_SUBSCRIBER_CAPACITY);
+        self.connection_subscribers.lock().push(tx);
+        rx
+    }
+
     pub fn move_to_next_server(&self) {
         // make sure `candidates` drop after block ends to avoid deadlock.
         let candidates = self.candidates.lock();
@@ -143,7 +380,7 @@ impl ServerChooser {
         }
         let old = self.selected_server.lock().clone();
         self.set_server_down(&old);
-        let new = &candidates[0];
+        let new = next_server(&candidates, &old);
         info!(
             old_name = old.name(),
             old_server = ?old.addr(),
@@ -151,25 +388,48 @@ impl ServerChooser {
             new_server = ?new.addr(),
             "Change shadowsocks server"
         );
-        // use the first server of candidates
         *self.selected_server.lock() = new.clone();
     }
 
     pub async fn run_background_tasks(&self) -> Result<()> {
-        let mut last_updated = Instant::now();
+        let mut last_updated: Option<Instant> = None;
         loop {
-            if last_updated.elapsed() > Duration::from_secs(10) {
+            if last_updated.map_or(true, |t| t.elapsed() > Duration::from_secs(10)) {
                 self.ping_servers().await;
                 if self.show_stats {
                     self.print_connection_stats();
                 }
-                last_updated = Instant::now();
+                self.hooks.check_usage();
+                last_updated = Some(Instant::now());
             }
+            self.record_usage();
             self.recycle_live_connections();
+            // Replace expired idle connections.
+            self.spawn_refill_connection_pool(self.selected_server.lock().clone());
             sleep(Duration::from_secs(1)).await;
         }
     }
 
+    /// Count the traffic through the servers since the last call, before the connections shut
+    /// down are recycled.
+    fn record_usage(&self) {
+        // Sampled first, new connections don't wait for the store.
+        let samples: Vec<_> = self
+            .live_connections
+            .read()
+            .iter()
+            .map(|conn| {
+                (
+                    conn.id(),
+                    conn.config().map(|config| config.name().to_string()),
+                    conn.sent_bytes(),
+                    conn.recv_bytes(),
+                )
+            })
+            .collect();
+        self.usage.record(samples.into_iter());
+    }
+
     fn print_connection_stats(&self) {
         #[derive(Default)]
         struct Stats {
@@ -238,9 +498,11 @@ impl ServerChooser {
                         latency = %duration.as_millis(),
                         "Ping shadowsocks server"
                     );
+                    self.hooks.on_ping(config.name(), true);
                     candidates.push((config, duration));
                 }
                 Err(config) => {
+                    self.hooks.on_ping(config.name(), false);
                     info!(
                         name = config.name(),
                         server = ?config.addr(),
@@ -278,6 +540,7 @@ impl ServerChooser {
 
             if let Err(e) = ret {
                 self.set_server_down(&config);
+                self.dns_client.forget_server(config.addr());
                 return Err(e);
             }
         }
@@ -294,7 +557,15 @@ async fn ping_server(
     let addr = ping_url.address();
     let path = ping_url.path();
     timeout(ping_timeout, async {
-        let stream = ProxyTcpStream::connect(addr.clone(), Some(&config), dns_client).await?;
+        let stream = ProxyTcpStream::connect(
+            addr.clone(),
+            Some(&config),
+            dns_client,
+            None,
+            RuleOptions::default(),
+            None,
+        )
+        .await?;
         if ping_url.port() == 443 {
             let connector = TlsConnector::default();
             let mut conn = connector.connect(ping_url.host(), stream).await?;
@@ -314,6 +585,15 @@ async fn ping_server(
     .await
 }
 
+/// The candidate after `current`, or the first candidate if `current` isn't one. Candidates
+/// must not be empty.
+fn next_server<'a>(candidates: &'a [ServerConfig], current: &ServerConfig) -> &'a ServerConfig {
+    match candidates.iter().position(|c| c == current) {
+        Some(i) => &candidates[(i + 1) % candidates.len()],
+        None => &candidates[0],
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -342,4 +622,23 @@ mod tests {
         .await?;
         Ok(())
     }
+
+    #[test]
+    fn test_next_server() {
+        let server = |name: &str| {
+            ServerConfig::new(
+                name.to_string(),
+                Address::SocketAddress("127.0.0.1:1080".parse().unwrap()),
+                config::ServerProtocol::Socks5,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        let candidates = vec![server("a"), server("b"), server("c")];
+        assert_eq!(next_server(&candidates, &server("a")).name(), "b");
+        assert_eq!(next_server(&candidates, &server("c")).name(), "a");
+        assert_eq!(next_server(&candidates, &server("d")).name(), "a");
+    }
 }
@@ -0,0 +1,171 @@
+//! `seeker service`: run seeker at boot as a launchd daemon on macOS, like the systemd unit
+//! written by `seeker init --systemd-unit` on linux.
+//!
+//! The plist goes to `/Library/LaunchDaemons`, so seeker runs as root and can create the TUN
+//! device and change the system DNS. launchd restarts it when it crashes, but not after a clean
+//! exit, eg. `seeker service stop`.
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub(crate) const DEFAULT_LABEL: &str = "com.gfreezy.seeker";
+const LAUNCH_DAEMONS_DIR: &str = "/Library/LaunchDaemons";
+
+fn plist_path(label: &str) -> PathBuf {
+    Path::new(LAUNCH_DAEMONS_DIR).join(format!("{label}.plist"))
+}
+
+fn launchctl(args: &[&str]) -> Result<()> {
+    let output = Command::new("launchctl")
+        .args(args)
+        .output()
+        .context("Run launchctl error")?;
+    if !output.status.success() {
+        bail!(
+            "launchctl {} error: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn ensure_supported() -> Result<()> {
+    if !cfg!(target_os = "macos") {
+        bail!(
+            "seeker service is only supported on macOS, use `seeker init --systemd-unit` on linux"
+        );
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        bail!("seeker service must be run as root, eg. with sudo");
+    }
+    Ok(())
+}
+
+/// Write the plist running seeker with `config` and load it, which starts seeker right away and
+/// at every boot. Files seeker keeps across runs, eg. `seeker.routes`, are kept in the directory
+/// of the config.
+pub(crate) fn install(label: &str, config: &str, log: &str) -> Result<()> {
+    ensure_supported()?;
+    let config =
+        std::fs::canonicalize(config).with_context(|| format!("Config {config} not found"))?;
+    let working_dir = config.parent().unwrap_or_else(|| Path::new("/"));
+    let exe = std::env::current_exe()?;
+    let path = plist_path(label);
+    if path.exists() {
+        // Replace the loaded daemon, eg. to change the config.
+        let _ = launchctl(&["bootout", &format!("system/{label}")]);
+    }
+    std::fs::write(
+        &path,
+        render_plist(
+            label,
+            &exe.to_string_lossy(),
+            &config.to_string_lossy(),
+            &working_dir.to_string_lossy(),
+            log,
+        ),
+    )
+    .with_context(|| format!("Write {} error", path.display()))?;
+    // launchd refuses plists writable by others.
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+    launchctl(&["bootstrap", "system", &path.to_string_lossy()])?;
+    println!(
+        "Installed {}, seeker is started and runs at boot",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Stop seeker and remove the plist.
+pub(crate) fn uninstall(label: &str) -> Result<()> {
+    ensure_supported()?;
+    let path = plist_path(label);
+    if !path.exists() {
+        bail!(
+            "{} not found, seeker service is not installed",
+            path.display()
+        );
+    }
+    let _ = launchctl(&["bootout", &format!("system/{label}")]);
+    std::fs::remove_file(&path).with_context(|| format!("Remove {} error", path.display()))?;
+    println!("Uninstalled {}", path.display());
+    Ok(())
+}
+
+pub(crate) fn start(label: &str) -> Result<()> {
+    ensure_supported()?;
+    launchctl(&["kickstart", &format!("system/{label}")])
+}
+
+/// Stop seeker with SIGTERM so it cleans up routes and DNS. It stays stopped until `start` or the
+/// next boot.
+pub(crate) fn stop(label: &str) -> Result<()> {
+    ensure_supported()?;
+    launchctl(&["kill", "SIGTERM", &format!("system/{label}")])
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn render_plist(label: &str, exe: &str, config: &str, working_dir: &str, log: &str) -> String {
+    let [label, exe, config, working_dir, log] =
+        [label, exe, config, working_dir, log].map(escape_xml);
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>--config</string>
+        <string>{config}</string>
+        <string>--log</string>
+        <string>{log}</string>
+    </array>
+    <key>WorkingDirectory</key>
+    <string>{working_dir}</string>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_plist() {
+        let plist = render_plist(
+            DEFAULT_LABEL,
+            "/usr/local/bin/seeker",
+            "/etc/seeker/a&b.yml",
+            "/etc/seeker",
+            "/var/log/seeker.log",
+        );
+        assert!(plist.contains("<string>com.gfreezy.seeker</string>"));
+        assert!(plist.contains("<string>/etc/seeker/a&amp;b.yml</string>"));
+        assert!(plist.contains("<key>WorkingDirectory</key>\n    <string>/etc/seeker</string>"));
+        assert_eq!(
+            plist_path(DEFAULT_LABEL).to_str().unwrap(),
+            "/Library/LaunchDaemons/com.gfreezy.seeker.plist"
+        );
+    }
+}
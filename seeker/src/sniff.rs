@@ -0,0 +1,289 @@
+//! The host a connection made to an ip is for, eg. of an app resolving with its own DNS, found in
+//! the HTTP `Host` header or the TLS SNI of the first bytes sent, so listings show more than the
+//! ip. Only looked for, the bytes are relayed as they are whatever is found.
+
+/// Bytes kept looking for the host, a ClientHello rarely takes more.
+const MAX_SNIFF_LEN: usize = 4096;
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 1;
+const TLS_EXT_SERVER_NAME: u16 = 0;
+
+/// Collects the first bytes sent on a connection until the host is found in them.
+#[derive(Debug, Default)]
+pub(crate) struct Sniffer {
+    data: Vec<u8>,
+    done: bool,
+    host: Option<String>,
+}
+
+impl Sniffer {
+    /// Feed bytes sent, returns the host when it's found in them. Nothing is kept once the host
+    /// is found or can't be.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Option<&str> {
+        if self.done || data.is_empty() {
+            return None;
+        }
+        let len = data.len().min(MAX_SNIFF_LEN - self.data.len());
+        self.data.extend_from_slice(&data[..len]);
+        match sniff(&self.data) {
+            Sniffed::Host(host) => {
+                self.finish();
+                self.host = Some(host);
+                self.host.as_deref()
+            }
+            Sniffed::NeedMore if self.data.len() < MAX_SNIFF_LEN => None,
+            _ => {
+                self.finish();
+                None
+            }
+        }
+    }
+
+    pub(crate) fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Whether the host is found or can't be, no more bytes need to be fed.
+    pub(crate) fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        self.data = Vec::new();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    Host(String),
+    /// The data so far is the start of a request, the host may be in the rest.
+    NeedMore,
+    /// Not a request with a host.
+    Unknown,
+}
+
+fn sniff(data: &[u8]) -> Sniffed {
+    match data.first() {
+        Some(&TLS_HANDSHAKE) => tls_server_name(data).unwrap_or_else(|e| e),
+        Some(b'A'..=b'Z') => http_host(data),
+        _ => Sniffed::Unknown,
+    }
+}
+
+fn http_host(data: &[u8]) -> Sniffed {
+    let Some(method_len) = data.iter().position(|&b| !b.is_ascii_uppercase()) else {
+        return Sniffed::NeedMore;
+    };
+    if data[method_len] != b' ' {
+        return Sniffed::Unknown;
+    }
+    let mut lines = data.split(|&b| b == b'\n');
+    // The request line.
+    lines.next();
+    while let Some(line) = lines.next() {
+        // The last one may be cut in the middle.
+        if lines.clone().next().is_none() {
+            return Sniffed::NeedMore;
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Sniffed::Unknown;
+        }
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            return Sniffed::Unknown;
+        };
+        if !line[..colon].eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+        let value = std::str::from_utf8(&line[colon + 1..])
+            .unwrap_or_default()
+            .trim();
+        return match strip_port(value) {
+            Some(host) if is_hostname(host) => Sniffed::Host(host.to_ascii_lowercase()),
+            _ => Sniffed::Unknown,
+        };
+    }
+    Sniffed::NeedMore
+}
+
+/// The host of `host[:port]`, None for ipv6 literals, the host is already an ip then.
+fn strip_port(value: &str) -> Option<&str> {
+    if value.starts_with('[') {
+        return None;
+    }
+    Some(value.rsplit_once(':').map_or(value, |(host, _)| host))
+}
+
+/// Reads the fields of a ClientHello, running out of data means more is needed.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Sniffed> {
+        if self.0.len() < len {
+            return Err(Sniffed::NeedMore);
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<usize, Sniffed> {
+        Ok(self.take(1)?[0] as usize)
+    }
+
+    fn u16(&mut self) -> Result<usize, Sniffed> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> Result<usize, Sniffed> {
+        let b = self.take(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+/// The SNI of a ClientHello in TLS records, see RFC 8446 4.1.2 and RFC 6066 3.
+fn tls_server_name(data: &[u8]) -> Result<Sniffed, Sniffed> {
+    let mut record = Cursor(data);
+    record.take(1)?;
+    if record.u8()? != 3 {
+        return Err(Sniffed::Unknown);
+    }
+    record.take(3)?;
+    // The handshake message may span records, only the first one is looked at, the SNI is
+    // usually in it.
+    if record.u8()? != TLS_CLIENT_HELLO {
+        return Err(Sniffed::Unknown);
+    }
+    record.u24()?;
+    // Version and random.
+    record.take(2 + 32)?;
+    let len = record.u8()?;
+    record.take(len)?;
+    let len = record.u16()?;
+    record.take(len)?;
+    let len = record.u8()?;
+    record.take(len)?;
+    let len = record.u16()?;
+    let complete = record.0.len() >= len;
+    let mut extensions = Cursor(&record.0[..len.min(record.0.len())]);
+    server_name(&mut extensions).or_else(|e| match e {
+        Sniffed::NeedMore if complete => Ok(Sniffed::Unknown),
+        e => Err(e),
+    })
+}
+
+fn server_name(extensions: &mut Cursor) -> Result<Sniffed, Sniffed> {
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()? as u16;
+        let ext_len = extensions.u16()?;
+        let ext = extensions.take(ext_len)?;
+        if ext_type != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+        let mut names = Cursor(ext);
+        let names_len = names.u16()?;
+        let mut names = Cursor(names.take(names_len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name_len = names.u16()?;
+            let name = names.take(name_len)?;
+            // host_name
+            if name_type != 0 {
+                continue;
+            }
+            return Ok(match std::str::from_utf8(name) {
+                Ok(name) if is_hostname(name) => Sniffed::Host(name.to_ascii_lowercase()),
+                _ => Sniffed::Unknown,
+            });
+        }
+        return Ok(Sniffed::Unknown);
+    }
+    Err(Sniffed::NeedMore)
+}
+
+fn is_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.parse::<std::net::IpAddr>().is_err()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let mut sni = vec![];
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name);
+
+        let mut extensions = vec![];
+        // supported_versions before the SNI, as browsers shuffle them.
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        extensions.extend_from_slice(&TLS_EXT_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7; 32]);
+        hello.push(32);
+        hello.extend_from_slice(&[9; 32]);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![TLS_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let hello = client_hello("Www.Example.com");
+        assert_eq!(sniff(&hello), Sniffed::Host("www.example.com".to_string()));
+        for len in 1..hello.len() {
+            assert_eq!(sniff(&hello[..len]), Sniffed::NeedMore, "len {len}");
+        }
+        assert_eq!(sniff(&client_hello("1.2.3.4")), Sniffed::Unknown);
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nhost: example.com:8080\r\n\r\n";
+        assert_eq!(sniff(request), Sniffed::Host("example.com".to_string()));
+        assert_eq!(sniff(&request[..30]), Sniffed::NeedMore);
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\n\r\n"), Sniffed::Unknown);
+        assert_eq!(sniff(b"SSH-2.0-OpenSSH_9.0\r\n"), Sniffed::Unknown);
+        assert_eq!(sniff(b"\x00\x01binary"), Sniffed::Unknown);
+    }
+
+    #[test]
+    fn test_sniffer() {
+        let hello = client_hello("example.com");
+        let mut sniffer = Sniffer::default();
+        assert_eq!(sniffer.feed(&hello[..10]), None);
+        assert_eq!(sniffer.feed(&hello[10..50]), None);
+        assert_eq!(sniffer.feed(&hello[50..]), Some("example.com"));
+        assert_eq!(sniffer.feed(&hello), None);
+        assert_eq!(sniffer.host(), Some("example.com"));
+
+        let mut sniffer = Sniffer::default();
+        assert_eq!(sniffer.feed(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(sniffer.feed(&[b'a'; MAX_SNIFF_LEN]), None);
+        assert_eq!(sniffer.feed(b"Host: example.com\r\n\r\n"), None);
+        assert_eq!(sniffer.host(), None);
+    }
+}
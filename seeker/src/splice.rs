@@ -0,0 +1,173 @@
+//! Relay direct tcp connections with splice(2), moving data from one socket to the other through
+//! a pipe inside the kernel.
+use crate::proxy_tcp_stream::ProxyTcpStream;
+use crate::relay::{Activity, RelayOptions};
+use async_io::Async;
+use async_std::net::TcpStream;
+use async_std::prelude::FutureExt;
+use futures_util::future::try_join;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+/// Max bytes moved by a single splice call, the default capacity of a pipe.
+const PIPE_SIZE: usize = 64 * 1024;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe {
+            Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Register a duplicate of the socket so its readiness can be waited for, the original stays
+/// registered in async-std.
+fn watch(stream: &TcpStream) -> io::Result<Async<std::net::TcpStream>> {
+    let fd = unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Async::new(unsafe { std::net::TcpStream::from_raw_fd(fd) })
+}
+
+/// Move data from `from` to `to` until EOF, then shut down the write half of `to`. Reads and
+/// writes are recorded in `activity` as `direction`, which times them out.
+async fn splice_half(
+    from: &Async<std::net::TcpStream>,
+    to: &Async<std::net::TcpStream>,
+    activity: &Activity,
+    direction: usize,
+    on_transfer: impl Fn(usize) -> bool,
+) -> io::Result<u64> {
+    let pipe = Pipe::new()?;
+    let mut total = 0;
+    loop {
+        let mut pending = from
+            .read_with(|s| splice(s.as_raw_fd(), pipe.write.as_raw_fd(), PIPE_SIZE))
+            .await?;
+        activity.on_read();
+        if pending == 0 {
+            to.get_ref().shutdown(Shutdown::Write)?;
+            return Ok(total);
+        }
+        if !on_transfer(pending) {
+            return Err(io::ErrorKind::ConnectionAborted.into());
+        }
+        while pending > 0 {
+            activity.on_write_blocked(direction);
+            let size = to
+                .write_with(|s| splice(pipe.read.as_raw_fd(), s.as_raw_fd(), pending))
+                .await?;
+            activity.on_write(direction);
+            pending -= size;
+            total += size as u64;
+        }
+    }
+}
+
+/// Relay between the TUN side connection `conn` and the direct connection `remote`, returning
+/// the bytes sent and received. Like the userspace relay, it times out when neither direction
+/// reads for `read_timeout` or a write is blocked for `write_timeout`. Returns `None` if
+/// `remote` is not a direct connection, or its host is still being sniffed from the bytes sent,
+/// which splice doesn't pass through userspace.
+pub(crate) async fn relay(
+    conn: &TcpStream,
+    remote: &ProxyTcpStream,
+    options: RelayOptions,
+    on_update_activity: impl Fn() -> bool,
+) -> Option<io::Result<(u64, u64)>> {
+    if remote.is_sniffing() {
+        return None;
+    }
+    let remote_stream = remote.direct_stream()?;
+    let ret = async {
+        let (conn, remote_stream) = (watch(conn)?, watch(remote_stream)?);
+        let activity = Activity::new();
+        let send = splice_half(&conn, &remote_stream, &activity, 0, |size| {
+            remote.record_sent(size);
+            on_update_activity()
+        });
+        let recv = splice_half(&remote_stream, &conn, &activity, 1, |size| {
+            remote.record_received(size);
+            on_update_activity()
+        });
+        try_join(send, recv).race(activity.watch(&options)).await
+    }
+    .await;
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpListener;
+    use async_std::task::block_on;
+
+    async fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_splice_half() {
+        block_on(async {
+            let (mut a_client, a_server) = stream_pair().await;
+            let (mut b_client, b_server) = stream_pair().await;
+            let data = vec![7; 1024 * 1024];
+            let writer = async {
+                a_client.write_all(&data).await.unwrap();
+                a_client.shutdown(Shutdown::Write).unwrap();
+            };
+            let reader = async {
+                let mut received = vec![];
+                b_client.read_to_end(&mut received).await.unwrap();
+                received
+            };
+            let (from, to) = (watch(&a_server).unwrap(), watch(&b_server).unwrap());
+            let (total, _, received) = futures_util::future::join3(
+                splice_half(&from, &to, &Activity::new(), 0, |_| true),
+                writer,
+                reader,
+            )
+            .await;
+            assert_eq!(total.unwrap(), data.len() as u64);
+            assert_eq!(received, data);
+        });
+    }
+}
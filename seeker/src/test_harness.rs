@@ -0,0 +1,652 @@
+//! The network around the proxy on the loopback, so the relay of TUN sessions is tested end to
+//! end without a TUN device or network access. [`Harness`] runs a Shadowsocks server, an upstream
+//! DNS server answering every A query with 127.0.0.1, TCP and UDP echo servers, and the relay
+//! servers of a [`ProxyClient`]. [`LoopbackTun`] stands in for the TUN device and its NAT: the
+//! connections of the apps come to the relay servers from the ports of their sessions.
+use crate::control::Controller;
+use crate::dns_client::DnsClient;
+use crate::proxy_client::ProxyClient;
+use crate::runtime::ConnectionStats;
+use crate::udp_batch::BatchSocket;
+use async_std::io::{copy, timeout};
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::prelude::*;
+use async_std::task::{spawn, JoinHandle};
+use bytes::BytesMut;
+use config::{Address, Config, DnsServerAddr};
+use crypto::CipherType;
+use ssclient::{decrypt_payload, encrypt_payload, SSTcpStream};
+use std::io::{ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use store::Store;
+use tcp_connection::TcpConnection;
+use tun_nat::SessionManager;
+
+pub(crate) const SS_METHOD: CipherType = CipherType::ChaCha20Ietf;
+pub(crate) const SS_PASSWORD: &str = "seeker-test-password";
+const MAX_UDP_PAYLOAD_SIZE: usize = 65535;
+const SESSION_PORTS: u16 = 100;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Session ports of the harnesses, tests run concurrently so each harness gets its own range.
+static NEXT_SESSION_PORT: AtomicU16 = AtomicU16::new(20000);
+
+pub(crate) struct Harness {
+    /// Port of the TCP and UDP echo servers, reached through `*.direct.test` and `*.proxy.test`.
+    pub echo_port: u16,
+    pub config: Config,
+    pub controller: Controller,
+    pub tun: LoopbackTun,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Harness {
+    /// Start the servers and the proxy. Names under `proxy.test` go through the Shadowsocks
+    /// server, the others go direct.
+    pub async fn start() -> Harness {
+        Store::setup_global_for_test();
+        let mut tasks = vec![];
+
+        let ss_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ss_addr = ss_listener.local_addr().unwrap();
+        let ss_udp = UdpSocket::bind(ss_addr).await.unwrap();
+        let key = SS_METHOD.bytes_to_key(SS_PASSWORD.as_bytes());
+        tasks.push(spawn(serve_ss_tcp(ss_listener, key.clone())));
+        tasks.push(spawn(serve_ss_udp(ss_udp, key)));
+
+        let upstream_dns = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_dns_addr = upstream_dns.local_addr().unwrap();
+        tasks.push(spawn(serve_dns(upstream_dns)));
+
+        let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo_listener.local_addr().unwrap().port();
+        let echo_udp = UdpSocket::bind(("127.0.0.1", echo_port)).await.unwrap();
+        tasks.push(spawn(serve_tcp_echo(echo_listener)));
+        tasks.push(spawn(serve_udp_echo(echo_udp)));
+
+        let dns_listen = free_udp_addr();
+        let config = test_config(upstream_dns_addr, dns_listen, ss_addr);
+        let dns_client = DnsClient::new(&config.dns_servers, config.dns_timeout).await;
+        let begin_port = NEXT_SESSION_PORT.fetch_add(SESSION_PORTS, Ordering::SeqCst);
+        let session_manager = SessionManager::new(begin_port, begin_port + SESSION_PORTS);
+        let client = ProxyClient::with_sessions(
+            config.clone(),
+            None,
+            false,
+            dns_client,
+            Some(session_manager.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+        let controller = client.controller();
+
+        let relay_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_tcp_addr = relay_listener.local_addr().unwrap();
+        let relay_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_udp_addr = relay_udp.local_addr().unwrap();
+        let relay_udp = Arc::new(BatchSocket::new(Arc::new(relay_udp)).unwrap());
+        tasks.push(spawn(async move {
+            let _ = client
+                .relay_tcp_connections(relay_listener)
+                .race(client.relay_udp_sessions(relay_udp))
+                .await;
+        }));
+
+        let tun = LoopbackTun {
+            session_manager,
+            relay_tcp_addr,
+            relay_udp_addr,
+            dns: DnsClient::new(&[DnsServerAddr::UdpSocketAddr(dns_listen)], TIMEOUT).await,
+            next_src_port: AtomicU16::new(40000),
+        };
+        Harness {
+            echo_port,
+            config,
+            controller,
+            tun,
+            tasks,
+        }
+    }
+
+    /// The connections being relayed, like `seeker ctl connections`.
+    pub fn connections(&self) -> Vec<ConnectionStats> {
+        let mut connections = vec![];
+        self.controller
+            .server_chooser
+            .for_each_live_connection(|conn| connections.push(ConnectionStats::of(conn)));
+        connections
+    }
+
+    pub async fn stop(self) {
+        for task in self.tasks {
+            task.cancel().await;
+        }
+    }
+}
+
+/// The TUN device and its NAT for the apps of a test. Apps resolve names through the DNS server
+/// of the proxy and get fake IPs, their connections to the fake IPs get sessions and reach the
+/// relay servers from the session ports.
+pub(crate) struct LoopbackTun {
+    session_manager: SessionManager,
+    relay_tcp_addr: SocketAddr,
+    relay_udp_addr: SocketAddr,
+    dns: DnsClient,
+    next_src_port: AtomicU16,
+}
+
+impl LoopbackTun {
+    /// The fake IP of `domain`.
+    pub async fn resolve(&self, domain: &str) -> Ipv4Addr {
+        match self.dns.lookup(domain).await.unwrap() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => panic!("fake ip of {domain} is ipv6: {ip}"),
+        }
+    }
+
+    /// The session of a new connection to `dest`, like the NAT creates for its first packet.
+    fn new_session(&self, dest: SocketAddrV4) -> u16 {
+        let src_port = self.next_src_port.fetch_add(1, Ordering::SeqCst);
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 255, 1), src_port);
+        self.session_manager.get_or_create_session(src, dest)
+    }
+
+    /// Connect to `dest` through the TCP relay server.
+    pub async fn connect_tcp(&self, dest: SocketAddrV4) -> TcpStream {
+        let session_port = self.new_session(dest);
+        let stream = connect_from(([127, 0, 0, 1], session_port).into(), self.relay_tcp_addr)
+            .unwrap_or_else(|e| panic!("connect from session port {session_port}: {e}"));
+        TcpStream::from(stream)
+    }
+
+    /// A socket sending datagrams to `dest` through the UDP relay server.
+    pub async fn bind_udp(&self, dest: SocketAddrV4) -> UdpSocket {
+        let session_port = self.new_session(dest);
+        let socket = UdpSocket::bind(("127.0.0.1", session_port))
+            .await
+            .unwrap_or_else(|e| panic!("bind session port {session_port}: {e}"));
+        socket.connect(self.relay_udp_addr).await.unwrap();
+        socket
+    }
+}
+
+/// A config proxying `*.proxy.test` through the Shadowsocks server at `ss_addr`, fake IPs come
+/// from the network of [`Store::setup_global_for_test`]. DNS traffic to other servers is
+/// hijacked.
+fn test_config(upstream_dns: SocketAddr, dns_listen: SocketAddr, ss_addr: SocketAddr) -> Config {
+    serde_yaml::from_str(&test_config_yaml(upstream_dns, dns_listen, ss_addr)).unwrap()
+}
+
+fn test_config_yaml(
+    upstream_dns: SocketAddr,
+    dns_listen: SocketAddr,
+    ss_addr: SocketAddr,
+) -> String {
+    format!(
+        r#"
+dns_start_ip: 10.0.0.1
+dns_servers:
+  - {upstream_dns}
+dns_timeout: 1s
+tun_bypass_direct: false
+tun_name: utun-test
+tun_ip: 10.0.0.1
+tun_cidr: 10.0.0.0/16
+dns_listen: {dns_listen}
+ping_urls: []
+probe_timeout: 100ms
+connect_timeout: 1s
+read_timeout: 10s
+write_timeout: 10s
+max_connect_errors: 2
+dns_hijack:
+  enabled: true
+servers:
+  - name: ss
+    addr: {ss_addr}
+    method: chacha20-ietf
+    password: {SS_PASSWORD}
+    protocol: Shadowsocks
+rules:
+  - 'DOMAIN-SUFFIX,proxy.test,PROXY'
+  - 'MATCH,DIRECT'
+"#
+    )
+}
+
+/// An address of the loopback nothing listens on, for servers only binding addresses.
+fn free_udp_addr() -> SocketAddr {
+    std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Connect to `dest` from `src`, the relay servers find the sessions by the source ports.
+fn connect_from(src: SocketAddrV4, dest: SocketAddr) -> Result<std::net::TcpStream> {
+    use nix::sys::socket::{
+        bind, connect, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn,
+    };
+
+    let SocketAddr::V4(dest) = dest else {
+        return Err(ErrorKind::InvalidInput.into());
+    };
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Stream,
+        SockFlag::empty(),
+        None,
+    )?;
+    // Owns the fd from here, so it's closed on errors.
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    setsockopt(fd, sockopt::ReuseAddr, &true)?;
+    bind(fd, &SockaddrIn::from(src))?;
+    connect(fd, &SockaddrIn::from(dest))?;
+    Ok(stream)
+}
+
+/// Where the servers connect to `addr`, every name resolves to the loopback.
+fn loopback_addr(addr: &Address) -> SocketAddr {
+    match addr {
+        Address::SocketAddress(addr) => *addr,
+        Address::DomainNameAddress(_, port) => (Ipv4Addr::LOCALHOST, *port).into(),
+    }
+}
+
+async fn serve_ss_tcp(listener: TcpListener, key: bytes::Bytes) {
+    let mut incoming = listener.incoming();
+    while let Some(Ok(stream)) = incoming.next().await {
+        let key = key.clone();
+        spawn(async move {
+            let mut ss_stream = SSTcpStream::accept(TcpConnection::new(stream), SS_METHOD, key);
+            let Ok(addr) = Address::read_from(&mut ss_stream).await else {
+                return;
+            };
+            let Ok(remote) = TcpStream::connect(loopback_addr(&addr)).await else {
+                return;
+            };
+            relay(ss_stream, remote).await;
+        });
+    }
+}
+
+async fn serve_ss_udp(socket: UdpSocket, key: bytes::Bytes) {
+    let mut buf = vec![0; MAX_UDP_PAYLOAD_SIZE];
+    loop {
+        let Ok((size, client)) = socket.recv_from(&mut buf).await else {
+            return;
+        };
+        if let Ok(reply) = relay_ss_datagram(&buf[..size], &key).await {
+            let _ = socket.send_to(&reply, client).await;
+        }
+    }
+}
+
+/// Send the payload of the encrypted `datagram` to its address, returns the encrypted reply.
+async fn relay_ss_datagram(datagram: &[u8], key: &[u8]) -> Result<BytesMut> {
+    let mut decrypted = BytesMut::with_capacity(MAX_UDP_PAYLOAD_SIZE);
+    let size = decrypt_payload(SS_METHOD, key, datagram, &mut decrypted)?;
+    let addr = Address::read_from(&mut decrypted.as_ref()).await?;
+    let payload = &decrypted[addr.serialized_len()..size];
+
+    let remote = UdpSocket::bind("127.0.0.1:0").await?;
+    remote.send_to(payload, loopback_addr(&addr)).await?;
+    let mut buf = vec![0; MAX_UDP_PAYLOAD_SIZE];
+    let (size, from) = timeout(TIMEOUT, remote.recv_from(&mut buf)).await?;
+
+    let from = Address::SocketAddress(from);
+    let mut reply = BytesMut::with_capacity(from.serialized_len() + size);
+    from.write_to_buf(&mut reply);
+    reply.extend_from_slice(&buf[..size]);
+    let mut encrypted = BytesMut::with_capacity(MAX_UDP_PAYLOAD_SIZE);
+    encrypt_payload(SS_METHOD, key, &reply, &mut encrypted)?;
+    Ok(encrypted)
+}
+
+/// Answer A queries with 127.0.0.1 and other queries with no records.
+async fn serve_dns(socket: UdpSocket) {
+    let mut buf = vec![0; 512];
+    loop {
+        let Ok((size, client)) = socket.recv_from(&mut buf).await else {
+            return;
+        };
+        if let Some(answer) = dns_answer(&buf[..size]) {
+            let _ = socket.send_to(&answer, client).await;
+        }
+    }
+}
+
+fn dns_answer(query: &[u8]) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    const TYPE_A: [u8; 2] = [0, 1];
+
+    // The name is a sequence of labels ending with an empty one, followed by the type and class.
+    let name_len = query.get(HEADER_LEN..)?.iter().position(|&b| b == 0)? + 1;
+    let question = query.get(HEADER_LEN..HEADER_LEN + name_len + 4)?;
+    let is_a = question[name_len..name_len + 2] == TYPE_A;
+
+    let mut answer = query[..2].to_vec();
+    // Response, recursion desired and available, 1 question.
+    answer.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    answer.extend_from_slice(question);
+    if is_a {
+        // Name pointing at the question, type A, class IN, ttl 60s, 127.0.0.1.
+        answer.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+    }
+    Some(answer)
+}
+
+async fn serve_tcp_echo(listener: TcpListener) {
+    let mut incoming = listener.incoming();
+    while let Some(Ok(stream)) = incoming.next().await {
+        spawn(async move {
+            let _ = copy(&mut stream.clone(), &mut stream.clone()).await;
+        });
+    }
+}
+
+async fn serve_udp_echo(socket: UdpSocket) {
+    let mut buf = vec![0; MAX_UDP_PAYLOAD_SIZE];
+    while let Ok((size, client)) = socket.recv_from(&mut buf).await {
+        let _ = socket.send_to(&buf[..size], client).await;
+    }
+}
+
+/// Copy both directions until either ends.
+async fn relay(a: SSTcpStream, b: TcpStream) {
+    let (mut a_reader, mut a_writer) = (a.clone(), a);
+    let (mut b_reader, mut b_writer) = (b.clone(), b);
+    let _ = copy(&mut a_reader, &mut b_writer)
+        .race(copy(&mut b_reader, &mut a_writer))
+        .await;
+}
+
+async fn read_reply(stream: &mut TcpStream, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    timeout(TIMEOUT, stream.read_exact(&mut buf)).await?;
+    Ok(buf)
+}
+
+async fn recv_reply(socket: &UdpSocket) -> Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_UDP_PAYLOAD_SIZE];
+    let size = timeout(TIMEOUT, socket.recv(&mut buf)).await?;
+    buf.truncate(size);
+    Ok(buf)
+}
+
+mod tests {
+    use super::*;
+    use crate::tunnel;
+    use async_std::task::block_on;
+    use config::rule::Action;
+    use config::TunnelConfig;
+    use hermesdns::{DnsPacket, DnsQuestion, QueryType, VectorPacketBuffer};
+
+    const DATA: &[u8] = b"GET / HTTP/1.1\r\nHost: seeker\r\n\r\n";
+
+    /// A DNS server hard coded by an app.
+    fn hardcoded_dns() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53)
+    }
+
+    fn dns_query(domain: &str) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 7;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(domain.to_string(), QueryType::A));
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 512).unwrap();
+        buffer.buffer
+    }
+
+    /// The address in the answer `data`.
+    fn answered_ip(data: Vec<u8>) -> Ipv4Addr {
+        let mut buffer = VectorPacketBuffer {
+            buffer: data,
+            ..Default::default()
+        };
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(packet.header.id, 7);
+        packet.get_random_a().unwrap().parse().unwrap()
+    }
+
+    /// The only connection of `network` relayed by `harness`.
+    fn connection(harness: &Harness, network: &str) -> ConnectionStats {
+        let mut connections: Vec<_> = harness
+            .connections()
+            .into_iter()
+            .filter(|conn| conn.network == network)
+            .collect();
+        assert_eq!(connections.len(), 1, "{network} connections");
+        connections.pop().unwrap()
+    }
+
+    #[test]
+    fn test_tcp_through_shadowsocks() {
+        block_on(async {
+            let harness = Harness::start().await;
+            let fake_ip = harness.tun.resolve("echo.proxy.test").await;
+            assert_eq!(fake_ip.octets()[..2], [10, 0]);
+
+            let mut stream = harness
+                .tun
+                .connect_tcp(SocketAddrV4::new(fake_ip, harness.echo_port))
+                .await;
+            stream.write_all(DATA).await.unwrap();
+            assert_eq!(read_reply(&mut stream, DATA.len()).await.unwrap(), DATA);
+
+            let conn = connection(&harness, "tcp");
+            assert_eq!(conn.action, Action::Proxy);
+            assert_eq!(conn.server.as_deref(), Some("ss"));
+            assert_eq!(conn.sent_bytes, DATA.len());
+            assert_eq!(conn.recv_bytes, DATA.len());
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_tcp_direct() {
+        block_on(async {
+            let harness = Harness::start().await;
+            let fake_ip = harness.tun.resolve("echo.direct.test").await;
+
+            let mut stream = harness
+                .tun
+                .connect_tcp(SocketAddrV4::new(fake_ip, harness.echo_port))
+                .await;
+            for _ in 0..3 {
+                stream.write_all(DATA).await.unwrap();
+                assert_eq!(read_reply(&mut stream, DATA.len()).await.unwrap(), DATA);
+            }
+
+            let conn = connection(&harness, "tcp");
+            assert_eq!(conn.action, Action::Direct);
+            assert_eq!(conn.server, None);
+            assert_eq!(conn.sent_bytes, 3 * DATA.len());
+            assert_eq!(conn.recv_bytes, 3 * DATA.len());
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_tunnel_through_shadowsocks() {
+        block_on(async {
+            let harness = Harness::start().await;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let listen = listener.local_addr().unwrap();
+            let tunnel = TunnelConfig {
+                listen,
+                remote: Address::DomainNameAddress("db.internal".to_string(), harness.echo_port),
+                action: Action::Proxy,
+            };
+            let task = spawn(tunnel::accept(
+                listener,
+                tunnel,
+                harness.config.clone(),
+                harness.controller.server_chooser.clone(),
+            ));
+
+            let mut stream = TcpStream::connect(listen).await.unwrap();
+            stream.write_all(DATA).await.unwrap();
+            assert_eq!(read_reply(&mut stream, DATA.len()).await.unwrap(), DATA);
+
+            let conn = connection(&harness, "tcp");
+            assert_eq!(conn.action, Action::Proxy);
+            assert_eq!(conn.server.as_deref(), Some("ss"));
+            assert_eq!(
+                conn.remote_addr,
+                Some(Address::DomainNameAddress(
+                    "db.internal".to_string(),
+                    harness.echo_port
+                ))
+            );
+            task.cancel().await;
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_fake_ip_is_stable() {
+        block_on(async {
+            let harness = Harness::start().await;
+            let a = harness.tun.resolve("a.proxy.test").await;
+            let b = harness.tun.resolve("b.direct.test").await;
+            assert_ne!(a, b);
+            assert_eq!(harness.tun.resolve("a.proxy.test").await, a);
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_udp_through_shadowsocks() {
+        block_on(async {
+            let harness = Harness::start().await;
+            let fake_ip = harness.tun.resolve("echo.proxy.test").await;
+
+            let socket = harness
+                .tun
+                .bind_udp(SocketAddrV4::new(fake_ip, harness.echo_port))
+                .await;
+            for _ in 0..2 {
+                socket.send(DATA).await.unwrap();
+                assert_eq!(recv_reply(&socket).await.unwrap(), DATA);
+            }
+
+            let conn = connection(&harness, "udp");
+            assert_eq!(conn.action, Action::Proxy);
+            assert_eq!(conn.sent_bytes, 2 * DATA.len());
+            assert_eq!(conn.recv_bytes, 2 * DATA.len());
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_udp_direct() {
+        block_on(async {
+            let harness = Harness::start().await;
+            let fake_ip = harness.tun.resolve("echo.direct.test").await;
+
+            let socket = harness
+                .tun
+                .bind_udp(SocketAddrV4::new(fake_ip, harness.echo_port))
+                .await;
+            socket.send(DATA).await.unwrap();
+            assert_eq!(recv_reply(&socket).await.unwrap(), DATA);
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_paused_goes_direct() {
+        block_on(async {
+            let harness = Harness::start().await;
+            harness.controller.pause("test");
+            assert!(Store::global()
+                .list_audit_log(100)
+                .unwrap()
+                .iter()
+                .any(|entry| entry.source == "test" && entry.action == "pause"));
+            let fake_ip = harness.tun.resolve("paused.proxy.test").await;
+
+            let mut stream = harness
+                .tun
+                .connect_tcp(SocketAddrV4::new(fake_ip, harness.echo_port))
+                .await;
+            stream.write_all(DATA).await.unwrap();
+            assert_eq!(read_reply(&mut stream, DATA.len()).await.unwrap(), DATA);
+            assert_eq!(connection(&harness, "tcp").action, Action::Direct);
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_reload_rules() {
+        block_on(async {
+            let mut harness = Harness::start().await;
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("config.yml");
+            let data = test_config_yaml(free_udp_addr(), free_udp_addr(), free_udp_addr())
+                .replace("proxy.test,PROXY", "proxy.test,DIRECT");
+            std::fs::write(&path, data).unwrap();
+            harness.controller = harness
+                .controller
+                .with_config_file(path.to_str().unwrap(), None);
+            assert!(harness.controller.reload("test").is_ok());
+            assert!(Store::global()
+                .list_audit_log(100)
+                .unwrap()
+                .iter()
+                .any(|entry| entry.source == "test" && entry.action == "reload_rules"));
+            let fake_ip = harness.tun.resolve("reloaded.proxy.test").await;
+
+            let mut stream = harness
+                .tun
+                .connect_tcp(SocketAddrV4::new(fake_ip, harness.echo_port))
+                .await;
+            stream.write_all(DATA).await.unwrap();
+            assert_eq!(read_reply(&mut stream, DATA.len()).await.unwrap(), DATA);
+            assert_eq!(connection(&harness, "tcp").action, Action::Direct);
+            harness.stop().await;
+        });
+    }
+
+    #[test]
+    fn test_hijack_hardcoded_dns() {
+        block_on(async {
+            let harness = Harness::start().await;
+            let fake_ip = harness.tun.resolve("hijacked.proxy.test").await;
+
+            let socket = harness.tun.bind_udp(hardcoded_dns()).await;
+            socket
+                .send(&dns_query("hijacked.proxy.test"))
+                .await
+                .unwrap();
+            assert_eq!(answered_ip(recv_reply(&socket).await.unwrap()), fake_ip);
+
+            let mut stream = harness.tun.connect_tcp(hardcoded_dns()).await;
+            let query = dns_query("hijacked.proxy.test");
+            stream
+                .write_all(&(query.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&query).await.unwrap();
+            let len = read_reply(&mut stream, 2).await.unwrap();
+            let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+            assert_eq!(
+                answered_ip(read_reply(&mut stream, len).await.unwrap()),
+                fake_ip
+            );
+
+            // Answered by the proxy, not relayed.
+            assert!(harness.connections().is_empty());
+            harness.stop().await;
+        });
+    }
+}
@@ -0,0 +1,112 @@
+//! Local ports forwarded to remote hosts, DIRECT or through the selected server, configured by
+//! `tunnels`. Services only reachable through the proxy, eg. a database behind it, are used like
+//! local ones without keeping `ssh -L` running.
+use crate::chaos::{self, ChaosStream};
+use crate::isolate::isolate;
+use crate::proxy_connection::ProxyConnection;
+use crate::relay::{relay, RelayOptions};
+use crate::server_chooser::ServerChooser;
+use async_std::future::pending;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task::spawn;
+use config::rule::RuleOptions;
+use config::{Config, TunnelConfig};
+use futures_util::future::try_join_all;
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Listen on the ports of all tunnels, returns only when one of them can't accept connections.
+pub(crate) async fn serve(config: Config, server_chooser: Arc<ServerChooser>) -> Result<()> {
+    if config.tunnels.is_empty() {
+        return pending().await;
+    }
+    let mut listeners = Vec::with_capacity(config.tunnels.len());
+    for tunnel in &config.tunnels {
+        let listener = TcpListener::bind(tunnel.listen).await.map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("tunnel can't listen on {}: {e}", tunnel.listen),
+            )
+        })?;
+        info!(
+            listen = %tunnel.listen,
+            remote = %tunnel.remote,
+            action = %tunnel.action,
+            "tunnel started"
+        );
+        listeners.push((listener, tunnel.clone()));
+    }
+    try_join_all(listeners.into_iter().map(|(listener, tunnel)| {
+        accept(listener, tunnel, config.clone(), server_chooser.clone())
+    }))
+    .await?;
+    Ok(())
+}
+
+/// Relay the connections accepted by `listener` to the remote of `tunnel`.
+pub(crate) async fn accept(
+    listener: TcpListener,
+    tunnel: TunnelConfig,
+    config: Config,
+    server_chooser: Arc<ServerChooser>,
+) -> Result<()> {
+    loop {
+        let (conn, peer_addr) = listener.accept().await?;
+        let tunnel = tunnel.clone();
+        let config = config.clone();
+        let server_chooser = server_chooser.clone();
+        spawn(async move {
+            let ret = isolate(
+                "tunnel connection",
+                relay_tunnel(conn, peer_addr, &tunnel, &config, &server_chooser),
+            )
+            .await;
+            if let Some(Err(e)) = ret {
+                error!(?e, %peer_addr, remote = %tunnel.remote, "tunnel connection error");
+            }
+        });
+    }
+}
+
+async fn relay_tunnel(
+    conn: TcpStream,
+    peer_addr: SocketAddr,
+    tunnel: &TunnelConfig,
+    config: &Config,
+    server_chooser: &ServerChooser,
+) -> Result<()> {
+    let inbound = config.tcp_inbound();
+    let remote_conn = server_chooser
+        .candidate_tcp_stream(
+            tunnel.remote.clone(),
+            tunnel.action,
+            RuleOptions::default(),
+            Some((peer_addr, tunnel.listen)),
+            config.tcp_connect_timeout(tunnel.action, RuleOptions::default()),
+            config.max_connect_errors,
+        )
+        .await?;
+    let options = RelayOptions {
+        buffer_size: inbound.buffer_size,
+        read_timeout: inbound.read_timeout,
+        write_timeout: inbound.write_timeout,
+    };
+    let ret = match chaos::fault_for(config, &remote_conn) {
+        Some(fault) => {
+            relay(
+                ChaosStream::new(conn, fault),
+                ChaosStream::new(remote_conn.clone(), fault),
+                options,
+                || true,
+            )
+            .await
+        }
+        None => relay(conn, remote_conn.clone(), options, || true).await,
+    };
+    remote_conn.shutdown();
+    let (sent, received) = ret?;
+    info!(remote = %tunnel.remote, sent, received, "tunnel connection closed");
+    Ok(())
+}
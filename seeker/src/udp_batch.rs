@@ -0,0 +1,358 @@
+//! Send and receive UDP datagrams in batches. On linux a batch takes a single
+//! recvmmsg(2)/sendmmsg(2) call, elsewhere datagrams are received one at a time and sent in a
+//! loop.
+use async_std::net::UdpSocket;
+use buffer_pool::Buffer;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Max datagrams in a batch.
+const MAX_BATCH: usize = 32;
+/// Buffer budget of a batch, large buffers get fewer datagrams per batch.
+const BATCH_BYTES: usize = 512 * 1024;
+
+/// Receive buffers for a batch of datagrams.
+pub(crate) struct UdpBatch {
+    bufs: Vec<Buffer>,
+    // Size and source of the datagrams received in `bufs`.
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl UdpBatch {
+    pub(crate) fn new(buffer_size: usize) -> Self {
+        let count = (BATCH_BYTES / buffer_size.max(1)).clamp(1, MAX_BATCH);
+        UdpBatch {
+            bufs: (0..count).map(|_| Buffer::new(buffer_size)).collect(),
+            received: Vec::with_capacity(count),
+        }
+    }
+
+    pub(crate) fn buffer_size(&self) -> usize {
+        self.bufs[0].len()
+    }
+
+    /// Buffer for receiving a single datagram, see [`Self::set_received`].
+    pub(crate) fn first_mut(&mut self) -> &mut [u8] {
+        &mut self.bufs[0]
+    }
+
+    /// Record a single datagram received into [`Self::first_mut`].
+    pub(crate) fn set_received(&mut self, size: usize, addr: SocketAddr) {
+        self.received.clear();
+        self.received.push((size, addr));
+    }
+
+    /// Datagrams received by the last receive.
+    pub(crate) fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .zip(self.bufs.iter())
+            .map(|(&(size, addr), buf)| (&buf[..size], addr))
+    }
+}
+
+/// A UDP socket supporting batched sends and receives.
+pub(crate) struct BatchSocket {
+    socket: Arc<UdpSocket>,
+    #[cfg(target_os = "linux")]
+    watcher: async_io::Async<std::net::UdpSocket>,
+}
+
+impl BatchSocket {
+    pub(crate) fn new(socket: Arc<UdpSocket>) -> io::Result<Self> {
+        Ok(BatchSocket {
+            #[cfg(target_os = "linux")]
+            watcher: linux::watch(&socket)?,
+            socket,
+        })
+    }
+
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Wait for at least one datagram and receive as many as are ready, returning the number
+    /// received.
+    pub(crate) async fn recv_batch(&self, batch: &mut UdpBatch) -> io::Result<usize> {
+        #[cfg(target_os = "linux")]
+        {
+            self.watcher.read_with(|s| linux::recvmmsg(s, batch)).await
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let (size, addr) = self.socket.recv_from(batch.first_mut()).await?;
+            batch.set_received(size, addr);
+            Ok(1)
+        }
+    }
+
+    /// Send all datagrams, returning the bytes sent.
+    pub(crate) async fn send_batch(&self, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let mut total = 0;
+        #[cfg(target_os = "linux")]
+        {
+            let mut sent = 0;
+            while sent < packets.len() {
+                let (count, size) = self
+                    .watcher
+                    .write_with(|s| linux::sendmmsg(s, &packets[sent..]))
+                    .await?;
+                sent += count;
+                total += size;
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        for (data, addr) in packets {
+            total += self.socket.send_to(data, *addr).await?;
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{UdpBatch, MAX_BATCH};
+    use async_io::Async;
+    use async_std::net::UdpSocket;
+    use std::io;
+    use std::mem::{size_of, zeroed};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    /// Register a duplicate of the socket so its readiness can be waited for, the original stays
+    /// registered in async-std.
+    pub(super) fn watch(socket: &UdpSocket) -> io::Result<Async<std::net::UdpSocket>> {
+        let fd = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Async::new(unsafe { std::net::UdpSocket::from_raw_fd(fd) })
+    }
+
+    fn to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported address family {family}"),
+            )),
+        }
+    }
+
+    fn from_socket_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    pub(super) fn recvmmsg(
+        socket: &std::net::UdpSocket,
+        batch: &mut UdpBatch,
+    ) -> io::Result<usize> {
+        let count = batch.bufs.len();
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { zeroed() }; count];
+        let mut iovecs: Vec<libc::iovec> = batch
+            .bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, addr)| {
+                let mut hdr: libc::mmsghdr = unsafe { zeroed() };
+                hdr.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+                hdr.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                hdr.msg_hdr.msg_iov = iovec;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr
+            })
+            .collect();
+        let ret = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as _,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        batch.received.clear();
+        for (msg, addr) in msgs.iter().zip(addrs.iter()).take(ret as usize) {
+            batch
+                .received
+                .push((msg.msg_len as usize, to_socket_addr(addr)?));
+        }
+        Ok(ret as usize)
+    }
+
+    /// Send up to `MAX_BATCH` datagrams, returning the datagrams and bytes sent.
+    pub(super) fn sendmmsg(
+        socket: &std::net::UdpSocket,
+        packets: &[(&[u8], SocketAddr)],
+    ) -> io::Result<(usize, usize)> {
+        let packets = &packets[..packets.len().min(MAX_BATCH)];
+        let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = packets
+            .iter()
+            .map(|(_, addr)| from_socket_addr(addr))
+            .collect();
+        let mut iovecs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|(data, _)| libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, (addr, len))| {
+                let mut hdr: libc::mmsghdr = unsafe { zeroed() };
+                hdr.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+                hdr.msg_hdr.msg_namelen = *len;
+                hdr.msg_hdr.msg_iov = iovec;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr
+            })
+            .collect();
+        let ret = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as _,
+                libc::MSG_DONTWAIT as _,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let count = ret as usize;
+        let size = msgs[..count].iter().map(|m| m.msg_len as usize).sum();
+        Ok((count, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn test_batch() {
+        block_on(async {
+            let bind = || async {
+                let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                BatchSocket::new(Arc::new(socket)).unwrap()
+            };
+            let (a, b) = (bind().await, bind().await);
+            let b_addr = b.socket().local_addr().unwrap();
+            let packets: Vec<(&[u8], SocketAddr)> = vec![
+                (&b"hello"[..], b_addr),
+                (b"udp", b_addr),
+                (b"batch", b_addr),
+            ];
+            assert_eq!(a.send_batch(&packets).await.unwrap(), 13);
+
+            let a_addr = a.socket().local_addr().unwrap();
+            let mut batch = UdpBatch::new(1024);
+            let mut received = vec![];
+            while received.len() < packets.len() {
+                b.recv_batch(&mut batch).await.unwrap();
+                for (data, addr) in batch.packets() {
+                    assert_eq!(addr, a_addr);
+                    received.push(data.to_vec());
+                }
+            }
+            assert_eq!(
+                received,
+                vec![b"hello".to_vec(), b"udp".to_vec(), b"batch".to_vec()]
+            );
+        });
+    }
+
+    /// Run with `cargo test -p seeker --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_udp_batch_loopback() {
+        block_on(async {
+            let bind = || async {
+                let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                BatchSocket::new(Arc::new(socket)).unwrap()
+            };
+            let (a, b) = (bind().await, bind().await);
+            let b_addr = b.socket().local_addr().unwrap();
+            let payload = vec![0u8; 1400];
+            let packets: Vec<(&[u8], SocketAddr)> = (0..MAX_BATCH)
+                .map(|_| (payload.as_slice(), b_addr))
+                .collect();
+            let rounds = 10_000;
+            let mut batch = UdpBatch::new(2048);
+            let start = std::time::Instant::now();
+            let mut received = 0;
+            for _ in 0..rounds {
+                a.send_batch(&packets).await.unwrap();
+                // Datagrams dropped by a full receive buffer are not waited for.
+                let mut round = 0;
+                while round < packets.len() {
+                    match async_std::io::timeout(
+                        std::time::Duration::from_millis(10),
+                        b.recv_batch(&mut batch),
+                    )
+                    .await
+                    {
+                        Ok(n) => round += n,
+                        Err(_) => break,
+                    }
+                }
+                received += round;
+            }
+            let elapsed = start.elapsed();
+            let rate = received as f64 / elapsed.as_secs_f64();
+            println!("udp batch: {received} datagrams in {elapsed:?}, {rate:.0} datagrams/s");
+            // Datagrams dropped by the receive buffer count against the rate, so it's kept low.
+            assert!(
+                rate >= 20_000.0,
+                "udp batch is slower than 20000 datagrams/s"
+            );
+        });
+    }
+}
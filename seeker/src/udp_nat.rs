@@ -0,0 +1,240 @@
+//! NAT table of UDP sessions, keyed by the session port allocated by the TUN session manager.
+//!
+//! The table is shared by the UDP relay server and the per-session relay tasks. It is split into
+//! shards so lookups for different ports don't contend on the same lock. A session expires when
+//! no packet is sent or received for its ttl, its relay task then removes it and exits.
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SHARDS: usize = 16;
+
+struct Entry<T> {
+    id: u64,
+    value: T,
+    // Millis since `Inner::start`.
+    last_active: AtomicU64,
+    ttl: Duration,
+}
+
+struct Inner<T> {
+    start: Instant,
+    ttl: Duration,
+    shards: Vec<RwLock<HashMap<u16, Arc<Entry<T>>>>>,
+    next_id: AtomicU64,
+    expired: AtomicU64,
+    truncated: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UdpNatStats {
+    /// Sessions currently in the table.
+    pub size: usize,
+    /// Sessions created since start.
+    pub created: u64,
+    /// Sessions removed because they were idle for `ttl`.
+    pub expired: u64,
+    /// Datagrams dropped because they didn't fit in the buffer.
+    pub truncated: u64,
+}
+
+pub(crate) struct UdpNatTable<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for UdpNatTable<T> {
+    fn clone(&self) -> Self {
+        UdpNatTable {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Clone> UdpNatTable<T> {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        UdpNatTable {
+            inner: Arc::new(Inner {
+                start: Instant::now(),
+                ttl,
+                shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+                next_id: AtomicU64::new(0),
+                expired: AtomicU64::new(0),
+                truncated: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub(crate) fn ttl(&self) -> Duration {
+        self.inner.ttl
+    }
+
+    fn shard(&self, port: u16) -> &RwLock<HashMap<u16, Arc<Entry<T>>>> {
+        &self.inner.shards[port as usize % SHARDS]
+    }
+
+    fn now(&self) -> u64 {
+        self.inner.start.elapsed().as_millis() as u64
+    }
+
+    /// Get the session of `port` and mark it active.
+    pub(crate) fn get(&self, port: u16) -> Option<T> {
+        let shard = self.shard(port).read();
+        let entry = shard.get(&port)?;
+        entry.last_active.store(self.now(), Ordering::Relaxed);
+        Some(entry.value.clone())
+    }
+
+    /// Insert a session, replacing the previous one of `port`. Returns the session id used to
+    /// remove it later.
+    pub(crate) fn insert(&self, port: u16, value: T) -> u64 {
+        self.insert_with_ttl(port, value, self.inner.ttl)
+    }
+
+    /// Same as [`Self::insert`], for a session expiring after `ttl` instead of the table's.
+    pub(crate) fn insert_with_ttl(&self, port: u16, value: T, ttl: Duration) -> u64 {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry {
+            id,
+            value,
+            last_active: AtomicU64::new(self.now()),
+            ttl,
+        });
+        self.shard(port).write().insert(port, entry);
+        id
+    }
+
+    /// Mark the session active, returns false if it has been removed.
+    pub(crate) fn touch(&self, port: u16, id: u64) -> bool {
+        match self.shard(port).read().get(&port) {
+            Some(entry) if entry.id == id => {
+                entry.last_active.store(self.now(), Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the session if it is idle for its ttl or already removed, returns whether it is
+    /// gone.
+    pub(crate) fn expire(&self, port: u16, id: u64) -> bool {
+        let mut shard = self.shard(port).write();
+        match shard.get(&port) {
+            Some(entry) if entry.id == id => {
+                let idle = self
+                    .now()
+                    .saturating_sub(entry.last_active.load(Ordering::Relaxed));
+                if idle < entry.ttl.as_millis() as u64 {
+                    return false;
+                }
+                shard.remove(&port);
+                self.inner.expired.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Remove the session `id` of `port`. A newer session of the same port is kept.
+    pub(crate) fn remove(&self, port: u16, id: u64) -> Option<T> {
+        let mut shard = self.shard(port).write();
+        match shard.get(&port) {
+            Some(entry) if entry.id == id => shard.remove(&port).map(|e| e.value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Remove the session of `port` whatever its id.
+    pub(crate) fn remove_port(&self, port: u16) -> Option<T> {
+        self.shard(port)
+            .write()
+            .remove(&port)
+            .map(|e| e.value.clone())
+    }
+
+    /// Remove all sessions, eg. after the network changed. The relay tasks exit once they notice
+    /// the removal.
+    pub(crate) fn clear(&self) -> Vec<T> {
+        self.inner
+            .shards
+            .iter()
+            .flat_map(|s| {
+                s.write()
+                    .drain()
+                    .map(|(_, e)| e.value.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub(crate) fn contains(&self, port: u16) -> bool {
+        self.shard(port).read().contains_key(&port)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.shards.iter().map(|s| s.read().len()).sum()
+    }
+
+    pub(crate) fn record_truncated(&self) {
+        self.inner.truncated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> UdpNatStats {
+        UdpNatStats {
+            size: self.len(),
+            created: self.inner.next_id.load(Ordering::Relaxed),
+            expired: self.inner.expired.load(Ordering::Relaxed),
+            truncated: self.inner.truncated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_udp_nat_table() {
+        let table = UdpNatTable::new(Duration::from_millis(50));
+        let id1 = table.insert(1, "a");
+        let id2 = table.insert(17, "b");
+        assert_eq!(table.get(1), Some("a"));
+        assert_eq!(table.get(17), Some("b"));
+        assert_eq!(table.len(), 2);
+        assert!(!table.expire(1, id1));
+
+        sleep(Duration::from_millis(60));
+        assert!(table.touch(17, id2));
+        assert!(table.expire(1, id1));
+        assert!(!table.expire(17, id2));
+        assert_eq!(table.get(1), None);
+
+        // A stale id doesn't remove the new session of the same port.
+        let id3 = table.insert(17, "c");
+        assert_eq!(table.remove(17, id2), None);
+        assert!(!table.touch(17, id2));
+        assert_eq!(table.remove(17, id3), Some("c"));
+
+        let id4 = table.insert(2, "d");
+        assert_eq!(table.clear(), vec!["d"]);
+        assert!(!table.touch(2, id4));
+        assert!(table.expire(2, id4));
+
+        let id5 = table.insert_with_ttl(3, "e", Duration::from_secs(60));
+        sleep(Duration::from_millis(60));
+        assert!(!table.expire(3, id5));
+        table.remove(3, id5);
+        table.record_truncated();
+        assert_eq!(
+            table.stats(),
+            UdpNatStats {
+                size: 0,
+                created: 5,
+                expired: 1,
+                truncated: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Traffic through each server, added up by day in the store so users of metered plans can see
+//! the month to date usage with `seeker ctl usage`. Sampled from the live connections by the
+//! server chooser every second, direct connections aren't counted.
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use store::Store;
+
+#[derive(Default)]
+pub(crate) struct UsageMeter {
+    /// Bytes sent and received by each live connection at the last sample.
+    seen: Mutex<HashMap<u64, (usize, usize)>>,
+}
+
+impl UsageMeter {
+    /// Record the traffic of `samples`, connection ids with their server names and the bytes
+    /// sent and received so far, since the last sample.
+    pub(crate) fn record(
+        &self,
+        samples: impl Iterator<Item = (u64, Option<String>, usize, usize)>,
+    ) {
+        let usage = self.take_usage(samples);
+        if let Err(e) = Store::global().add_server_usage(store::now(), &usage) {
+            tracing::error!(?e, "record server usage error");
+        }
+    }
+
+    fn take_usage(
+        &self,
+        samples: impl Iterator<Item = (u64, Option<String>, usize, usize)>,
+    ) -> Vec<(String, u64, u64)> {
+        let mut seen = self.seen.lock();
+        let mut live = HashMap::with_capacity(seen.len());
+        let mut usage: HashMap<String, (u64, u64)> = HashMap::new();
+        for (id, server, sent, recv) in samples {
+            let Some(server) = server else {
+                continue;
+            };
+            let (last_sent, last_recv) = seen.get(&id).copied().unwrap_or_default();
+            let _ = live.insert(id, (sent, recv));
+            if sent > last_sent || recv > last_recv {
+                let entry = usage.entry(server).or_default();
+                entry.0 += sent.saturating_sub(last_sent) as u64;
+                entry.1 += recv.saturating_sub(last_recv) as u64;
+            }
+        }
+        // Connections gone since the last sample are forgotten.
+        *seen = live;
+        usage
+            .into_iter()
+            .map(|(server, (sent, recv))| (server, sent, recv))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_usage() {
+        let meter = UsageMeter::default();
+        let hk = || Some("hk".to_string());
+        let mut usage = meter
+            .take_usage([(1, hk(), 10, 100), (2, hk(), 1, 1), (3, None, 1000, 1000)].into_iter());
+        usage.sort();
+        assert_eq!(usage, vec![("hk".to_string(), 11, 101)]);
+
+        let usage = meter.take_usage([(1, hk(), 15, 100), (4, hk(), 0, 0)].into_iter());
+        assert_eq!(usage, vec![("hk".to_string(), 5, 0)]);
+        assert!(!meter.seen.lock().contains_key(&2));
+
+        assert!(meter
+            .take_usage([(1, hk(), 15, 100)].into_iter())
+            .is_empty());
+    }
+}
@@ -4,4 +4,4 @@ mod udp;
 
 pub use tcp::Socks5TcpStream;
 pub use types::Address;
-pub use udp::Socks5UdpSocket;
+pub use udp::{Socks5UdpAssociation, Socks5UdpAssociations, Socks5UdpSocket};
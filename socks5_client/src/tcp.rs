@@ -15,7 +15,7 @@ pub struct Socks5TcpStream {
 
 impl Socks5TcpStream {
     pub async fn connect(socks5_server: SocketAddr, addr: Address) -> Result<Self> {
-        let mut conn = TcpStream::connect(socks5_server).await?;
+        let mut conn = tcp_connection::connect(socks5_server).await?;
         let handshake_req = HandshakeRequest::new(vec![SOCKS5_AUTH_METHOD_NONE]);
         handshake_req.write_to(&mut conn).await?;
         let handshake_resp = HandshakeResponse::read_from(&mut conn).await?;
@@ -1,34 +1,84 @@
+//! UDP through a SOCKS5 server with UDP ASSOCIATE. The sockets of one local source share an
+//! association, so every destination sees the same address on the server like behind a full
+//! cone NAT, which games and WebRTC rely on.
 use crate::types::{
     Address, Command, HandshakeRequest, HandshakeResponse, Reply, TcpRequestHeader,
     TcpResponseHeader, UdpAssociateHeader, SOCKS5_AUTH_METHOD_NONE,
 };
-use async_std::io;
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::io::{self, ReadExt};
 use async_std::net::{TcpStream, UdpSocket};
+use async_std::task::spawn;
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 
-#[derive(Debug)]
-pub struct Socks5UdpSocket {
-    socket: UdpSocket,
-    #[allow(dead_code)]
+/// Max size of a UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Datagrams queued for each socket, more are dropped until it's read.
+const SOCKET_QUEUE_SIZE: usize = 128;
+/// Idle time before the control connection is probed, so NATs on the way don't drop it and
+/// the association with it.
+const CONTROL_KEEPALIVE: Duration = Duration::from_secs(30);
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Default)]
+struct Routes {
+    next_id: u64,
+    sockets: HashMap<u64, Sender<Datagram>>,
+    /// The socket that last sent to each address, replies from the address go to it.
+    by_addr: HashMap<SocketAddr, u64>,
+}
+
+/// A UDP association with a SOCKS5 server, it lasts as long as the control connection.
+pub struct Socks5UdpAssociation {
+    socket: Arc<UdpSocket>,
     associate_conn: TcpStream,
+    routes: Arc<Mutex<Routes>>,
+    closed: Arc<AtomicBool>,
 }
 
-impl Socks5UdpSocket {
-    pub async fn new(socks5_server: SocketAddr) -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let mut conn =
-            io::timeout(Duration::from_secs(1), TcpStream::connect(socks5_server)).await?;
+impl Socks5UdpAssociation {
+    pub async fn new(socks5_server: SocketAddr) -> Result<Arc<Self>> {
+        let socket = UdpSocket::bind(match socks5_server {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        })
+        .await?;
+        let mut conn = io::timeout(
+            Duration::from_secs(1),
+            tcp_connection::connect(socks5_server),
+        )
+        .await?;
+        let sock = SockRef::from(&conn);
+        if !sock.keepalive()? {
+            sock.set_tcp_keepalive(&TcpKeepalive::new().with_time(CONTROL_KEEPALIVE))?;
+        }
         let handshake_req = HandshakeRequest::new(vec![SOCKS5_AUTH_METHOD_NONE]);
         handshake_req.write_to(&mut conn).await?;
         let handshake_resp = HandshakeResponse::read_from(&mut conn).await?;
         if handshake_resp.chosen_method != SOCKS5_AUTH_METHOD_NONE {
             return Err(Error::new(ErrorKind::InvalidData, "response methods error"));
         }
+        // The port datagrams are sent from, the server may only accept those from it. The
+        // address is left unspecified, it's the one of the control connection.
+        let local_port = socket.local_addr()?.port();
         let req_header = TcpRequestHeader::new(
             Command::UdpAssociate,
-            Address::SocketAddress("0.0.0.0:0".parse().expect("never error")),
+            Address::SocketAddress(SocketAddr::new(
+                match socks5_server {
+                    SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                },
+                local_port,
+            )),
         );
         req_header.write_to(&mut conn).await?;
         let resp_header = TcpResponseHeader::read_from(&mut conn).await?;
@@ -38,7 +88,7 @@ impl Socks5UdpSocket {
                 format!("reply error: {:?}", resp_header.reply),
             ));
         }
-        let server_bind_addr = match resp_header.address {
+        let mut server_bind_addr = match resp_header.address {
             Address::SocketAddress(addr) => addr,
             Address::DomainNameAddress(_, _) => {
                 return Err(Error::new(
@@ -47,69 +97,278 @@ impl Socks5UdpSocket {
                 ));
             }
         };
+        // Most servers answer with an unspecified address, the relay is on the server itself.
+        if server_bind_addr.ip().is_unspecified() {
+            server_bind_addr.set_ip(socks5_server.ip());
+        }
         socket.connect(server_bind_addr).await?;
-        Ok(Socks5UdpSocket {
-            socket,
+
+        let association = Arc::new(Socks5UdpAssociation {
+            socket: Arc::new(socket),
             associate_conn: conn,
-        })
+            routes: Default::default(),
+            closed: Default::default(),
+        });
+        spawn(dispatch(
+            association.socket.clone(),
+            association.associate_conn.clone(),
+            association.routes.clone(),
+            association.closed.clone(),
+        ));
+        Ok(association)
+    }
+
+    /// A new socket on the association.
+    pub fn socket(self: &Arc<Self>) -> Socks5UdpSocket {
+        let (tx, rx) = bounded(SOCKET_QUEUE_SIZE);
+        let mut routes = self.routes.lock().unwrap();
+        let id = routes.next_id;
+        routes.next_id += 1;
+        // The dispatcher drops the senders once the association is closed.
+        if !self.is_closed() {
+            routes.sockets.insert(id, tx);
+        }
+        Socks5UdpSocket {
+            association: self.clone(),
+            id,
+            rx,
+        }
+    }
+
+    /// Whether the server ended the association by closing the control connection.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Socks5UdpAssociation {
+    fn drop(&mut self) {
+        // Ends the association on the server and stops the dispatcher.
+        let _ = self.associate_conn.shutdown(Shutdown::Both);
+    }
+}
+
+/// Deliver the datagrams of the association to the sockets until the control connection is
+/// closed.
+async fn dispatch(
+    socket: Arc<UdpSocket>,
+    conn: TcpStream,
+    routes: Arc<Mutex<Routes>>,
+    closed: Arc<AtomicBool>,
+) {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let size = {
+            let recv = socket.recv(&mut buffer);
+            let wait = wait_closed(&conn);
+            pin_mut!(recv, wait);
+            match select(recv, wait).await {
+                Either::Left((Ok(size), _)) => size,
+                Either::Left((Err(_), _)) | Either::Right(_) => break,
+            }
+        };
+        let Ok((payload, addr)) = parse_datagram(&buffer[..size]).await else {
+            continue;
+        };
+        let routes = routes.lock().unwrap();
+        if let Some(tx) = routes
+            .by_addr
+            .get(&addr)
+            .and_then(|id| routes.sockets.get(id))
+        {
+            // Like a socket buffer, datagrams are dropped while the queue is full.
+            let _ = tx.try_send((payload.to_vec(), addr));
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+    let mut routes = routes.lock().unwrap();
+    routes.sockets.clear();
+    routes.by_addr.clear();
+}
+
+/// Returns once the server closes the control connection, nothing else is sent on it.
+async fn wait_closed(conn: &TcpStream) {
+    let mut buf = [0; 64];
+    let mut conn = conn;
+    while let Ok(1..) = conn.read(&mut buf).await {}
+}
+
+async fn parse_datagram(datagram: &[u8]) -> Result<(&[u8], SocketAddr)> {
+    let udp_header = UdpAssociateHeader::read_from(&mut &datagram[..]).await?;
+    if udp_header.frag != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "frag is not allowed"));
+    }
+    let payload = &datagram[udp_header.serialized_len()..];
+    match udp_header.address {
+        Address::SocketAddress(addr) => Ok((payload, addr)),
+        Address::DomainNameAddress(_, _) => {
+            Err(Error::new(ErrorKind::InvalidData, "invalid addr format"))
+        }
+    }
+}
+
+/// A socket on an association, it receives the replies from the addresses it sent to last.
+pub struct Socks5UdpSocket {
+    association: Arc<Socks5UdpAssociation>,
+    id: u64,
+    rx: Receiver<Datagram>,
+}
+
+impl Socks5UdpSocket {
+    /// A socket on a new association.
+    pub async fn new(socks5_server: SocketAddr) -> Result<Self> {
+        Ok(Socks5UdpAssociation::new(socks5_server).await?.socket())
     }
 
     pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
-        let mut buffer = vec![0; 1500];
+        if self.association.is_closed() {
+            return Err(association_closed());
+        }
+        self.association
+            .routes
+            .lock()
+            .unwrap()
+            .by_addr
+            .insert(addr, self.id);
         let udp_header = UdpAssociateHeader::new(0, Address::SocketAddress(addr));
-        let mut size = 0;
-        udp_header.write_to_buf(&mut buffer[size..].as_mut());
-        size += udp_header.serialized_len();
-        buffer[size..size + buf.len()].copy_from_slice(buf);
-        size += buf.len();
-        let send_size = self.socket.send(&buffer[..size]).await?;
-        assert_eq!(send_size, size);
+        let mut buffer = buffer_pool::take_vec(udp_header.serialized_len() + buf.len());
+        udp_header.write_to_buf(&mut buffer);
+        buffer.extend_from_slice(buf);
+        let ret = self.association.socket.send(&buffer).await;
+        let size = buffer.len();
+        buffer_pool::recycle_vec(buffer);
+        assert_eq!(ret?, size);
         Ok(buf.len())
     }
 
     pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
-        let mut buffer = vec![0; 1500];
-        let size = self.socket.recv(&mut buffer).await?;
-        let udp_header = UdpAssociateHeader::read_from(&mut buffer.as_slice()).await?;
-        if udp_header.frag != 0 {
-            return Err(Error::new(ErrorKind::InvalidData, "frag is not allowed"));
-        }
-        let udp_header_len = udp_header.serialized_len();
-        let addr = udp_header.address;
-        buf[..size - udp_header_len].copy_from_slice(&buffer[udp_header_len..size]);
-        let socket_addr = match addr {
-            Address::SocketAddress(socket_addr) => socket_addr,
-            Address::DomainNameAddress(_, _) => {
-                return Err(Error::new(ErrorKind::InvalidData, "invalid addr format"))
-            }
-        };
-        Ok((size - udp_header_len, socket_addr))
+        let (payload, addr) = self.rx.recv().await.map_err(|_| association_closed())?;
+        // Truncate like a plain UDP socket when `buf` is too small.
+        let payload_len = payload.len().min(buf.len());
+        buf[..payload_len].copy_from_slice(&payload[..payload_len]);
+        Ok((payload_len, addr))
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.socket.local_addr()
+        self.association.socket.local_addr()
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use async_std::task::block_on;
-//     use std::str::FromStr;
-//
-//     #[test]
-//     fn test_udp() -> Result<()> {
-//         block_on(async {
-//             let server = "127.0.0.1:1086".parse().unwrap();
-//             let udp = Socks5UdpSocket::connect(server).await?;
-//             let mut buf = vec![0; 1500];
-//             let to_addr = Address::SocketAddress("118.145.8.14:10240".parse().unwrap());
-//             let size = udp.send_to(b"hello", to_addr.clone()).await?;
-//             let (s, addr) = udp.recv_from(&mut buf).await?;
-//             assert_eq!(s, size);
-//             assert_eq!(addr, to_addr);
-//             assert_eq!(&buf[..s], b"hello");
-//             Ok(())
-//         })
-//     }
-// }
+impl Drop for Socks5UdpSocket {
+    fn drop(&mut self) {
+        let mut routes = self.association.routes.lock().unwrap();
+        routes.sockets.remove(&self.id);
+        routes.by_addr.retain(|_, id| *id != self.id);
+    }
+}
+
+fn association_closed() -> Error {
+    Error::new(
+        ErrorKind::ConnectionAborted,
+        "udp association closed by the socks5 server",
+    )
+}
+
+/// The live associations of each local source through each server, new sockets of a source
+/// join its association instead of starting another one.
+#[derive(Clone, Default)]
+pub struct Socks5UdpAssociations {
+    map: Arc<Mutex<HashMap<(SocketAddr, SocketAddr), Weak<Socks5UdpAssociation>>>>,
+}
+
+impl Socks5UdpAssociations {
+    pub async fn socket(
+        &self,
+        socks5_server: SocketAddr,
+        source: SocketAddr,
+    ) -> Result<Socks5UdpSocket> {
+        let key = (socks5_server, source);
+        let live = self
+            .map
+            .lock()
+            .unwrap()
+            .get(&key)
+            .and_then(Weak::upgrade)
+            .filter(|association| !association.is_closed());
+        if let Some(association) = live {
+            return Ok(association.socket());
+        }
+        let association = Socks5UdpAssociation::new(socks5_server).await?;
+        let mut map = self.map.lock().unwrap();
+        map.retain(|_, association| association.strong_count() > 0);
+        map.insert(key, Arc::downgrade(&association));
+        Ok(association.socket())
+    }
+
+    /// Forget the associations, the sockets on them keep working until they are dropped.
+    pub fn clear(&self) {
+        self.map.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::WriteExt;
+    use async_std::net::TcpListener;
+
+    /// A SOCKS5 server accepting one UDP association, its relay echoes the datagrams back with
+    /// the header unchanged. Returns the server address and the control connection.
+    async fn socks5_server() -> (SocketAddr, async_std::task::JoinHandle<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let (size, peer) = relay.recv_from(&mut buf).await.unwrap();
+                relay.send_to(&buf[..size], peer).await.unwrap();
+            }
+        });
+        let handle = spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 3];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&[5, SOCKS5_AUTH_METHOD_NONE]).await.unwrap();
+            let req = TcpRequestHeader::read_from(&mut conn).await.unwrap();
+            assert_eq!(req.command, Command::UdpAssociate);
+            let resp = TcpResponseHeader::new(
+                Reply::Succeeded,
+                Address::SocketAddress((Ipv4Addr::UNSPECIFIED, relay_port).into()),
+            );
+            resp.write_to(&mut conn).await.unwrap();
+            conn
+        });
+        (addr, handle)
+    }
+
+    #[async_std::test]
+    async fn test_shared_association() {
+        let (server, control) = socks5_server().await;
+        let associations = Socks5UdpAssociations::default();
+        let source: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let a = associations.socket(server, source).await.unwrap();
+        let b = associations.socket(server, source).await.unwrap();
+        assert_eq!(a.local_addr().unwrap(), b.local_addr().unwrap());
+
+        // Each socket gets the replies from the address it sent to.
+        let peer_a: SocketAddr = "1.1.1.1:3478".parse().unwrap();
+        let peer_b: SocketAddr = "8.8.8.8:3478".parse().unwrap();
+        a.send_to(b"to a", peer_a).await.unwrap();
+        b.send_to(b"to b", peer_b).await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(b.recv_from(&mut buf).await.unwrap(), (4, peer_b));
+        assert_eq!(&buf[..4], b"to b");
+        assert_eq!(a.recv_from(&mut buf[..2]).await.unwrap(), (2, peer_a));
+        assert_eq!(&buf[..2], b"to");
+
+        // The association ends with the control connection.
+        drop(control.await);
+        assert_eq!(
+            a.recv_from(&mut buf).await.unwrap_err().kind(),
+            ErrorKind::ConnectionAborted
+        );
+        assert!(a.send_to(b"again", peer_a).await.is_err());
+    }
+}
@@ -3,6 +3,9 @@ mod udp_io;
 
 const BUFFER_SIZE: usize = 8 * 1024; // 8K buffer
 
+/// The decoder of AEAD streams, for the fuzz targets in `fuzz/`.
+#[cfg(feature = "fuzzing")]
+pub use tcp_io::AeadDecryptedReader;
 pub use tcp_io::SSTcpStream;
 pub use udp_io::crypto_io::{decrypt_payload, encrypt_payload};
 pub use udp_io::SSUdpSocket;
@@ -1,6 +1,9 @@
 mod aead;
 mod stream;
 
+#[cfg(feature = "fuzzing")]
+pub use aead::DecryptedReader as AeadDecryptedReader;
+
 use async_std::io::{Read, Write};
 use async_std::prelude::*;
 use std::io::{ErrorKind, Result};
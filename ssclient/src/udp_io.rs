@@ -15,7 +15,8 @@ use async_std::net::UdpSocket;
 use config::Address;
 use crypto::CipherType;
 
-pub const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 1500;
+/// Max size of a UDP datagram.
+pub const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 65535;
 
 /// UDP client for communicating with ShadowSocks' server
 pub struct SSUdpSocket {
@@ -60,12 +61,14 @@ impl SSUdpSocket {
         );
 
         // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
-        let mut send_buf = Vec::with_capacity(addr.serialized_len() + payload.len());
+        let mut send_buf = buffer_pool::take_vec(addr.serialized_len() + payload.len());
         addr.write_to_buf(&mut send_buf);
         send_buf.extend_from_slice(payload);
 
         let mut encrypt_buf = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
-        encrypt_payload(self.method, &self.key, &send_buf, &mut encrypt_buf)?;
+        let ret = encrypt_payload(self.method, &self.key, &send_buf, &mut encrypt_buf);
+        buffer_pool::recycle_vec(send_buf);
+        ret?;
 
         let send_len = self.socket.send(&encrypt_buf[..]).await?;
 
@@ -77,7 +80,7 @@ impl SSUdpSocket {
     /// Receive packet from Shadowsocks' UDP server
     pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         // Waiting for response from server SERVER -> CLIENT
-        let mut recv_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut recv_buf = buffer_pool::Buffer::new(MAXIMUM_UDP_PAYLOAD_SIZE);
 
         let recv_n = self.socket.recv(&mut recv_buf).await?;
         let mut decrypt_buf = BytesMut::with_capacity(MAXIMUM_UDP_PAYLOAD_SIZE);
@@ -90,6 +93,8 @@ impl SSUdpSocket {
         )?;
         let addr = Address::read_from(&mut decrypt_buf.as_ref()).await?;
         let payload = &decrypt_buf[addr.serialized_len()..decrypt_size];
+        // Truncate like a plain UDP socket when `buf` is too small.
+        let payload = &payload[..payload.len().min(buf.len())];
         buf[..payload.len()].copy_from_slice(payload);
 
         debug!(
@@ -0,0 +1,25 @@
+//! Fake IP allocation benchmarks, run with `cargo bench -p store`.
+#[path = "../../benches/harness.rs"]
+mod harness;
+
+use harness::bench;
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use store::Store;
+
+fn main() {
+    let store = Store::new_in_memory(Ipv4Addr::new(11, 0, 0, 10)).expect("init store");
+    let count = 10_000;
+    let budget = Duration::from_millis(1);
+    bench("allocate fake ip", count, budget, |i| {
+        black_box(store.get_ipv4_by_host(&format!("host{i}.com")).unwrap());
+    });
+    bench("fake ip of allocated host", count, budget, |i| {
+        black_box(store.get_ipv4_by_host(&format!("host{i}.com")).unwrap());
+    });
+    let ip = store.get_ipv4_by_host("host0.com").unwrap();
+    bench("host of fake ip", count, budget, |_| {
+        black_box(store.get_host_by_ipv4(black_box(ip)).unwrap());
+    });
+}
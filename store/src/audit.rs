@@ -0,0 +1,79 @@
+use crate::{now, Store};
+use anyhow::Result;
+use rusqlite::params;
+
+/// A control action, eg. switching the server or pausing, with who did it, so the admins of a
+/// shared router can tell why the proxy behaves differently.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub time: u64,
+    /// Where the action came from, eg. `ctl uid=0 pid=42` for `seeker ctl`.
+    pub source: String,
+    pub action: String,
+    pub detail: String,
+}
+
+// region: audit_log
+impl Store {
+    pub fn new_audit_entry(&self, source: &str, action: &str, detail: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        let _ = conn.execute(
+            &format!(
+                "INSERT INTO {} (time, source, action, detail) VALUES (?, ?, ?, ?)",
+                Self::TABLE_AUDIT_LOG,
+            ),
+            params![now(), source, action, detail],
+        )?;
+        Ok(())
+    }
+
+    /// The last `limit` entries, oldest first.
+    pub fn list_audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT id, time, source, action, detail FROM {} ORDER BY id DESC LIMIT ?",
+            Self::TABLE_AUDIT_LOG,
+        ))?;
+        let mut rows = stmt.query(params![limit as u64])?;
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            entries.push(AuditEntry {
+                id: row.get(0)?,
+                time: row.get(1)?,
+                source: row.get(2)?,
+                action: row.get(3)?,
+                detail: row.get(4)?,
+            });
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+}
+// endregion: audit_log
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() -> Result<()> {
+        let store = Store::store_for_test();
+        store.new_audit_entry("ctl uid=0", "switch", "hk")?;
+        store.new_audit_entry("api", "pause", "")?;
+        store.new_audit_entry("ctl uid=1000", "kill", "42")?;
+
+        let entries = store.list_audit_log(10)?;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].source, "ctl uid=0");
+        assert_eq!(entries[0].action, "switch");
+        assert_eq!(entries[0].detail, "hk");
+        assert!(entries[0].time > 0);
+
+        let entries = store.list_audit_log(2)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, "pause");
+        assert_eq!(entries[1].action, "kill");
+        Ok(())
+    }
+}
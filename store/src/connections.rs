@@ -1,6 +1,20 @@
 use crate::{now, Store};
 use anyhow::Result;
-use rusqlite::params;
+use parking_lot::Mutex;
+use rusqlite::{params, OptionalExtension};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Ids are handed out from blocks reserved in the db, so they stay unique across restarts, eg.
+/// for frontends remembering the connections they have seen, without a write per connection.
+const CONNECTION_ID_BLOCK: u64 = 1024;
+const KEY_CONNECTION_ID: &str = "connection_id_high_water";
+
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionIds {
+    next: AtomicU64,
+    /// Ids below it are reserved in the db.
+    reserved: Mutex<u64>,
+}
 
 #[derive(Debug, Default, PartialEq)]
 pub struct Connection {
@@ -14,12 +28,72 @@ pub struct Connection {
     pub connect_time: u64,
     pub last_update: u64,
     pub is_alive: bool,
+    /// HTTP Host or TLS SNI seen in the first bytes sent, empty if none was.
+    pub sniffed_host: String,
 }
 
 impl Store {
+    /// Continue with the ids after the ones reserved by the previous run.
+    pub(crate) fn init_connection_ids(&self) -> Result<()> {
+        let conn = self.conn.lock();
+        let high_water: Option<u64> = conn
+            .query_row(
+                &format!("SELECT value FROM {} WHERE key = ?", Self::TABLE_META),
+                params![KEY_CONNECTION_ID],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let high_water = high_water.unwrap_or(0);
+        self.connection_ids.next.store(high_water, Ordering::SeqCst);
+        *self.connection_ids.reserved.lock() = high_water;
+        Ok(())
+    }
+
+    /// A connection id no other connection has, including those of previous runs.
+    pub fn next_connection_id(&self) -> u64 {
+        let id = self.connection_ids.next.fetch_add(1, Ordering::SeqCst);
+        let mut reserved = self.connection_ids.reserved.lock();
+        if id >= *reserved {
+            let end = id + CONNECTION_ID_BLOCK;
+            // The id is still unique in this run if the db can't be written.
+            if let Err(e) = self.reserve_connection_ids(end) {
+                tracing::error!("Failed to reserve connection ids: {}", e);
+            }
+            *reserved = end;
+        }
+        id
+    }
+
+    fn reserve_connection_ids(&self, end: u64) -> Result<()> {
+        let conn = self.conn.lock();
+        let _ = conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES (?, ?)",
+                Self::TABLE_META
+            ),
+            params![KEY_CONNECTION_ID, end],
+        )?;
+        Ok(())
+    }
+
+    /// Create a connection with a new id, which is returned.
+    pub fn new_connection(
+        &self,
+        host: &str,
+        network: &str,
+        conn_type: &str,
+        proxy_server: &str,
+    ) -> Result<u64> {
+        let id = self.next_connection_id();
+        self.insert_connection(id, host, network, conn_type, proxy_server)?;
+        Ok(id)
+    }
+
     // create connection with the following data:
     // | id | host | network | type | recv_bytes | sent_bytes | proxy_server | connect_time | last_update | is_alive |
-    pub fn new_connection(
+    /// `id` must be from [`Store::next_connection_id`], for connections needing it before they
+    /// are recorded.
+    pub fn insert_connection(
         &self,
         id: u64,
         host: &str,
@@ -58,6 +132,8 @@ impl Store {
         sent_bytes: u64,
         last_update: Option<u64>,
     ) -> Result<()> {
+        // The totals replace the bytes counted so far.
+        let _ = self.counters.remove(id);
         let conn = self.conn.lock();
         let _ = conn.execute(
             &format!(
@@ -72,47 +148,48 @@ impl Store {
         Ok(())
     }
 
+    /// Count received bytes in memory, they are written to the db by
+    /// [`Store::flush_connection_counters`].
     pub fn incr_connection_recv_bytes(
         &self,
         id: u64,
         bytes: u64,
         last_update: Option<u64>,
     ) -> Result<()> {
-        let conn = self.conn.lock();
-        let _ = conn.execute(
-            &format!(
-                r#"
-            UPDATE {} SET recv_bytes = recv_bytes + ?, last_update = ?
-            WHERE id = ?
-            "#,
-                Self::TABLE_CONNECTIONS,
-            ),
-            params![bytes, last_update.unwrap_or_else(now), id],
-        )?;
+        self.counters
+            .incr(id, bytes, 0, last_update.unwrap_or_else(now));
         Ok(())
     }
 
+    /// Count sent bytes in memory, they are written to the db by
+    /// [`Store::flush_connection_counters`].
     pub fn incr_connection_sent_bytes(
         &self,
         id: u64,
         bytes: u64,
         last_update: Option<u64>,
     ) -> Result<()> {
+        self.counters
+            .incr(id, 0, bytes, last_update.unwrap_or_else(now));
+        Ok(())
+    }
+
+    pub fn set_connection_sniffed_host(&self, id: u64, sniffed_host: &str) -> Result<()> {
         let conn = self.conn.lock();
         let _ = conn.execute(
             &format!(
-                r#"
-            UPDATE {} SET sent_bytes = sent_bytes + ?, last_update = ?
-            WHERE id = ?
-            "#,
+                "UPDATE {} SET sniffed_host = ? WHERE id = ?",
                 Self::TABLE_CONNECTIONS,
             ),
-            params![bytes, last_update.unwrap_or_else(now), id],
+            params![sniffed_host, id],
         )?;
         Ok(())
     }
 
     pub fn shutdown_connection(&self, id: u64) -> Result<()> {
+        if let Some(pending) = self.counters.remove(id) {
+            self.write_pending(vec![(id, pending)])?;
+        }
         let conn = self.conn.lock();
         let _ = conn.execute(
             &format!(
@@ -142,11 +219,28 @@ impl Store {
         Ok(())
     }
 
+    /// Delete the connections whose host or sniffed host matches `host_glob`, a case sensitive
+    /// sqlite GLOB like `*.example.com`, the number deleted is returned. Live ones are deleted
+    /// too, their traffic isn't recorded any more.
+    pub fn delete_connections_matching(&self, host_glob: &str) -> Result<usize> {
+        self.flush_connection_counters()?;
+        let conn = self.conn.lock();
+        let deleted = conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE host GLOB ?1 OR sniffed_host GLOB ?1",
+                Self::TABLE_CONNECTIONS,
+            ),
+            params![host_glob],
+        )?;
+        Ok(deleted)
+    }
+
     pub fn list_connections(&self) -> Result<Vec<Connection>> {
+        self.flush_connection_counters()?;
         let conn = self.conn.lock();
         let mut stmt = conn.prepare_cached(&format!(
             r#"
-            SELECT id, host, network, type, recv_bytes, sent_bytes, proxy_server, connect_time, last_update, is_alive
+            SELECT id, host, network, type, recv_bytes, sent_bytes, proxy_server, connect_time, last_update, is_alive, sniffed_host
             FROM {}
             "#,
             Self::TABLE_CONNECTIONS,
@@ -165,6 +259,7 @@ impl Store {
                 connect_time: row.get(7)?,
                 last_update: row.get(8)?,
                 is_alive: row.get(9)?,
+                sniffed_host: row.get(10)?,
             };
             connections.push(connection);
         }
@@ -176,25 +271,48 @@ impl Store {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
 
     // insert a new connection and check if it is inserted correctly
     #[test]
     fn test_new_connection() {
         let store = Store::store_for_test();
-        let id = 1;
         let host = "baidu.com";
         let network = "tcp";
         let conn_type = "client";
         let proxy_server = "proxy.com";
-        store
-            .new_connection(id, host, network, conn_type, proxy_server)
+        let id = store
+            .new_connection(host, network, conn_type, proxy_server)
+            .unwrap();
+        let other = store
+            .new_connection(host, network, conn_type, proxy_server)
             .unwrap();
+        assert_ne!(id, other);
         let connections = store.list_connections().unwrap();
-        assert_eq!(connections.len(), 1);
+        assert_eq!(connections.len(), 2);
         let connection = &connections[0];
         assert_eq!(connection.id, id);
     }
 
+    // ids of a new run continue after the ones of the previous run
+    #[test]
+    fn test_connection_ids_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seeker.sqlite");
+        let store = Store::new(&path, Ipv4Addr::new(127, 0, 0, 1)).unwrap();
+        let ids: Vec<u64> = (0..3).map(|_| store.next_connection_id()).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        drop(store);
+
+        let store = Store::new(&path, Ipv4Addr::new(127, 0, 0, 1)).unwrap();
+        let id = store.next_connection_id();
+        assert!(id > 2, "id {id}");
+        drop(store);
+
+        let store = Store::new(&path, Ipv4Addr::new(127, 0, 0, 1)).unwrap();
+        assert!(store.next_connection_id() > id);
+    }
+
     // update a connection and check if it is updated correctly
     #[test]
     fn test_update_connection() {
@@ -205,7 +323,7 @@ mod tests {
         let conn_type = "client";
         let proxy_server = "proxy.com";
         store
-            .new_connection(id, host, network, conn_type, proxy_server)
+            .insert_connection(id, host, network, conn_type, proxy_server)
             .unwrap();
         let recv_bytes = 100;
         let sent_bytes = 200;
@@ -220,6 +338,41 @@ mod tests {
         assert_eq!(connection.sent_bytes, sent_bytes);
     }
 
+    // increment traffic and check if it is visible after flushing
+    #[test]
+    fn test_incr_connection_bytes() {
+        let store = Store::store_for_test();
+        store
+            .insert_connection(1, "baidu.com", "tcp", "client", "proxy.com")
+            .unwrap();
+        store.incr_connection_recv_bytes(1, 100, None).unwrap();
+        store.incr_connection_sent_bytes(1, 20, None).unwrap();
+        store.incr_connection_recv_bytes(1, 1, None).unwrap();
+        let connections = store.list_connections().unwrap();
+        assert_eq!(connections[0].recv_bytes, 101);
+        assert_eq!(connections[0].sent_bytes, 20);
+
+        store.incr_connection_sent_bytes(1, 5, None).unwrap();
+        store.shutdown_connection(1).unwrap();
+        let connections = store.list_connections().unwrap();
+        assert_eq!(connections[0].sent_bytes, 25);
+    }
+
+    // record the sniffed host of a connection made to an ip
+    #[test]
+    fn test_set_connection_sniffed_host() {
+        let store = Store::store_for_test();
+        store
+            .insert_connection(1, "1.1.1.1:443", "tcp", "ss", "proxy.com")
+            .unwrap();
+        assert_eq!(store.list_connections().unwrap()[0].sniffed_host, "");
+        store.set_connection_sniffed_host(1, "example.com").unwrap();
+        assert_eq!(
+            store.list_connections().unwrap()[0].sniffed_host,
+            "example.com"
+        );
+    }
+
     // shutdown a connection and check if it is shutdown correctly
     #[test]
     fn test_shutdown_connection() {
@@ -230,7 +383,7 @@ mod tests {
         let conn_type = "client";
         let proxy_server = "proxy.com";
         store
-            .new_connection(id, host, network, conn_type, proxy_server)
+            .insert_connection(id, host, network, conn_type, proxy_server)
             .unwrap();
         store.shutdown_connection(id).unwrap();
         let connections = store.list_connections().unwrap();
@@ -250,20 +403,40 @@ mod tests {
         let conn_type = "client";
         let proxy_server = "proxy.com";
         store
-            .new_connection(id, host, network, conn_type, proxy_server)
+            .insert_connection(id, host, network, conn_type, proxy_server)
             .unwrap();
         store
-            .new_connection(id + 1, host, network, conn_type, proxy_server)
+            .insert_connection(id + 1, host, network, conn_type, proxy_server)
             .unwrap();
         store
-            .new_connection(id + 2, host, network, conn_type, proxy_server)
+            .insert_connection(id + 2, host, network, conn_type, proxy_server)
             .unwrap();
         store
-            .new_connection(id + 3, host, network, conn_type, proxy_server)
+            .insert_connection(id + 3, host, network, conn_type, proxy_server)
             .unwrap();
         store.shutdown_connection(id).unwrap();
         store.clear_dead_connections(0).unwrap();
         let connections = store.list_connections().unwrap();
         assert_eq!(connections.len(), 3);
     }
+
+    #[test]
+    fn test_delete_connections_matching() -> Result<()> {
+        let store = Store::store_for_test();
+        store.insert_connection(1, "www.example.com", "tcp", "client", "")?;
+        store.insert_connection(2, "example.com", "tcp", "client", "")?;
+        store.insert_connection(3, "1.2.3.4", "tcp", "client", "")?;
+        store.set_connection_sniffed_host(3, "cdn.example.com")?;
+        store.insert_connection(4, "example.org", "udp", "client", "")?;
+
+        assert_eq!(store.delete_connections_matching("*.example.com")?, 2);
+        let hosts: Vec<String> = store
+            .list_connections()?
+            .into_iter()
+            .map(|c| c.host)
+            .collect();
+        assert_eq!(hosts, vec!["example.com", "example.org"]);
+        assert_eq!(store.delete_connections_matching("*.net")?, 0);
+        Ok(())
+    }
 }
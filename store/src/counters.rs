@@ -0,0 +1,170 @@
+//! Traffic counters of connections. Relays only update atomics, the pending bytes are added to
+//! the connections table by [`Store::flush_connection_counters`], every second and once more
+//! when seeker stops.
+use crate::Store;
+use anyhow::Result;
+use parking_lot::RwLock;
+use rusqlite::params;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SHARDS: usize = 16;
+
+#[derive(Debug, Default)]
+struct Counter {
+    recv_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    last_update: AtomicU64,
+}
+
+/// Bytes counted since the last flush, with the time of the last update.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Pending {
+    pub recv_bytes: u64,
+    pub sent_bytes: u64,
+    pub last_update: u64,
+}
+
+impl Counter {
+    fn add(&self, recv_bytes: u64, sent_bytes: u64, last_update: u64) {
+        self.recv_bytes.fetch_add(recv_bytes, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(sent_bytes, Ordering::Relaxed);
+        self.last_update.fetch_max(last_update, Ordering::Relaxed);
+    }
+
+    fn into_pending(self) -> Pending {
+        Pending {
+            recv_bytes: self.recv_bytes.into_inner(),
+            sent_bytes: self.sent_bytes.into_inner(),
+            last_update: self.last_update.into_inner(),
+        }
+    }
+}
+
+/// Counters are only updated under the read lock of their shard, a shard swapped out under the
+/// write lock has no update in progress and none of its bytes can be lost.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    shards: [RwLock<HashMap<u64, Counter>>; SHARDS],
+}
+
+impl Counters {
+    fn shard(&self, id: u64) -> &RwLock<HashMap<u64, Counter>> {
+        &self.shards[id as usize % SHARDS]
+    }
+
+    pub(crate) fn incr(&self, id: u64, recv_bytes: u64, sent_bytes: u64, last_update: u64) {
+        if let Some(counter) = self.shard(id).read().get(&id) {
+            counter.add(recv_bytes, sent_bytes, last_update);
+            return;
+        }
+        self.shard(id)
+            .write()
+            .entry(id)
+            .or_default()
+            .add(recv_bytes, sent_bytes, last_update);
+    }
+
+    /// Take the pending bytes of all connections, connections without new bytes are skipped.
+    /// The counters are removed, the next update of a connection adds it again.
+    pub(crate) fn take_all(&self) -> Vec<(u64, Pending)> {
+        let mut pending = vec![];
+        for shard in &self.shards {
+            let counters = std::mem::take(&mut *shard.write());
+            pending.extend(
+                counters
+                    .into_iter()
+                    .map(|(id, counter)| (id, counter.into_pending()))
+                    .filter(|(_, p)| p.recv_bytes > 0 || p.sent_bytes > 0),
+            );
+        }
+        pending
+    }
+
+    /// Stop counting connection `id`, returning its pending bytes.
+    pub(crate) fn remove(&self, id: u64) -> Option<Pending> {
+        self.shard(id)
+            .write()
+            .remove(&id)
+            .map(Counter::into_pending)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().len()).sum()
+    }
+}
+
+impl Store {
+    /// Add the bytes counted since the last flush to the connections table.
+    pub fn flush_connection_counters(&self) -> Result<()> {
+        self.write_pending(self.counters.take_all())
+    }
+
+    pub(crate) fn write_pending(&self, pending: Vec<(u64, Pending)>) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let conn = self.conn.lock();
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(&format!(
+                r#"
+            UPDATE {} SET recv_bytes = recv_bytes + ?, sent_bytes = sent_bytes + ?,
+                last_update = max(last_update, ?)
+            WHERE id = ?
+            "#,
+                Self::TABLE_CONNECTIONS,
+            ))?;
+            for (id, p) in pending {
+                let _ = stmt.execute(params![p.recv_bytes, p.sent_bytes, p.last_update, id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::default();
+        counters.incr(1, 10, 0, 100);
+        counters.incr(1, 5, 3, 99);
+        counters.incr(17, 0, 7, 101);
+        let mut pending = counters.take_all();
+        pending.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            pending,
+            vec![
+                (
+                    1,
+                    Pending {
+                        recv_bytes: 15,
+                        sent_bytes: 3,
+                        last_update: 100,
+                    }
+                ),
+                (
+                    17,
+                    Pending {
+                        recv_bytes: 0,
+                        sent_bytes: 7,
+                        last_update: 101,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(counters.len(), 0);
+        assert!(counters.take_all().is_empty());
+        counters.incr(1, 1, 0, 102);
+        assert_eq!(counters.remove(1).map(|p| p.recv_bytes), Some(1));
+        assert_eq!(counters.remove(1), None);
+        counters.incr(2, 0, 0, 103);
+        assert!(counters.take_all().is_empty());
+        assert_eq!(counters.len(), 0);
+    }
+}
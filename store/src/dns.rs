@@ -1,8 +1,50 @@
 use anyhow::Result;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 use crate::Store;
 
+/// A warning event is recorded once this much of the fake ip pool is allocated.
+const WARN_UTILIZATION: f64 = 0.8;
+
+/// Use of the fake ip pool, from the initial ip of the store to the end set by
+/// [`Store::set_fake_ip_end`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FakeIpStats {
+    pub size: u64,
+    /// Ips allocated to domains. They are never freed, a domain keeps its ip as long as the db,
+    /// so the pool only fills up.
+    pub used: u64,
+    /// Ips allocated since the store was opened, how fast the pool fills up.
+    pub allocated: u64,
+}
+
+impl FakeIpStats {
+    pub fn utilization(&self) -> f64 {
+        if self.size == 0 {
+            return 1.0;
+        }
+        self.used as f64 / self.size as f64
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FakeIpPool {
+    end: AtomicU32,
+    allocated: AtomicU64,
+    warned: AtomicBool,
+}
+
+impl Default for FakeIpPool {
+    fn default() -> Self {
+        FakeIpPool {
+            end: AtomicU32::new(u32::MAX),
+            allocated: AtomicU64::new(0),
+            warned: AtomicBool::new(false),
+        }
+    }
+}
+
 // region: host and ip mapping
 impl Store {
     pub fn get_host_by_ipv4(&self, ip: Ipv4Addr) -> Result<Option<String>> {
@@ -30,6 +72,8 @@ impl Store {
             Err(rusqlite::Error::QueryReturnedNoRows) => {
                 let next_ip = self.next_ip()?;
                 self.associate_ipv4_and_host(next_ip, host)?;
+                self.fake_ips.allocated.fetch_add(1, Ordering::Relaxed);
+                self.check_fake_ip_utilization(next_ip);
                 Ok(next_ip)
             }
             Err(e) => Err(e.into()),
@@ -43,11 +87,59 @@ impl Store {
             Self::TABLE_HOST_IP
         ))?;
         match stmt.query_row((), |row| row.get::<_, u32>("ip")) {
-            Ok(v) => Ok(Ipv4Addr::from(v.checked_add(1).expect("ip addr overflow"))),
+            Ok(v) => match v.checked_add(1) {
+                Some(next) if next <= self.fake_ips.end.load(Ordering::Relaxed) => {
+                    Ok(Ipv4Addr::from(next))
+                }
+                _ => Err(anyhow::anyhow!("fake ip addresses exhausted")),
+            },
             Err(e) => Err(e.into()),
         }
     }
 
+    /// The last ip allocated to domains, eg. the last host address of the TUN network. Unset,
+    /// ips are allocated up to 255.255.255.255.
+    pub fn set_fake_ip_end(&self, end: Ipv4Addr) {
+        self.fake_ips.end.store(end.into(), Ordering::Relaxed);
+    }
+
+    pub fn fake_ip_stats(&self) -> Result<FakeIpStats> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(&format!(
+            r#"SELECT MAX(ip) AS ip FROM {}"#,
+            Self::TABLE_HOST_IP
+        ))?;
+        let last = stmt.query_row((), |row| row.get::<_, u32>("ip"))?;
+        Ok(self.fake_ip_stats_at(last.into()))
+    }
+
+    /// Ips are allocated in order, up to `last` are used.
+    fn fake_ip_stats_at(&self, last: Ipv4Addr) -> FakeIpStats {
+        let start = u32::from(self.initial_ip) as u64;
+        let end = self.fake_ips.end.load(Ordering::Relaxed) as u64;
+        FakeIpStats {
+            size: (end + 1).saturating_sub(start),
+            used: (u32::from(last) as u64 + 1).saturating_sub(start),
+            allocated: self.fake_ips.allocated.load(Ordering::Relaxed),
+        }
+    }
+
+    fn check_fake_ip_utilization(&self, allocated: Ipv4Addr) {
+        let stats = self.fake_ip_stats_at(allocated);
+        if stats.utilization() < WARN_UTILIZATION
+            || self.fake_ips.warned.swap(true, Ordering::Relaxed)
+        {
+            return;
+        }
+        let message = format!(
+            "{} of {} fake ips allocated, new domains fail to resolve once all are, \
+             enlarge tun_cidr or delete seeker.sqlite to start over",
+            stats.used, stats.size
+        );
+        tracing::warn!("{}", message);
+        let _ = self.new_event(Self::EVENT_FAKE_IP_POOL, &message);
+    }
+
     fn associate_ipv4_and_host(&self, ip: Ipv4Addr, host: &str) -> Result<()> {
         let conn = self.conn.lock();
         let mut stmt = conn.prepare_cached(&format!(
@@ -78,4 +170,46 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_fake_ip_exhausted() -> Result<()> {
+        let store = Store::new_in_memory(Ipv4Addr::BROADCAST)?;
+        assert_eq!(store.get_ipv4_by_host("a.com")?, Ipv4Addr::BROADCAST);
+        assert!(store.get_ipv4_by_host("b.com").is_err());
+        // The failure isn't recorded, the existing mapping still works.
+        assert_eq!(store.get_ipv4_by_host("a.com")?, Ipv4Addr::BROADCAST);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_ip_stats() -> Result<()> {
+        let store = Store::new_in_memory("11.0.0.10".parse().unwrap())?;
+        store.set_fake_ip_end("11.0.0.19".parse().unwrap());
+        assert_eq!(
+            store.fake_ip_stats()?,
+            FakeIpStats {
+                size: 10,
+                used: 0,
+                allocated: 0,
+            }
+        );
+        for i in 0..8 {
+            store.get_ipv4_by_host(&format!("host{i}.com"))?;
+        }
+        store.get_ipv4_by_host("host0.com")?;
+        let stats = store.fake_ip_stats()?;
+        assert_eq!((stats.used, stats.allocated), (8, 8));
+        assert!((stats.utilization() - 0.8).abs() < f64::EPSILON);
+        let events = store.list_events()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, Store::EVENT_FAKE_IP_POOL);
+
+        store.get_ipv4_by_host("host8.com")?;
+        assert_eq!(store.list_events()?.len(), 1);
+        store.get_ipv4_by_host("host9.com")?;
+        // Past the end of the pool.
+        assert!(store.get_ipv4_by_host("host10.com").is_err());
+        assert_eq!(store.fake_ip_stats()?.used, 10);
+        Ok(())
+    }
 }
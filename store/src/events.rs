@@ -0,0 +1,78 @@
+use crate::{now, Store};
+use anyhow::Result;
+use rusqlite::params;
+
+/// Something worth telling the user about that isn't tied to a single connection, eg. new
+/// connections being rejected while seeker is overloaded.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    pub id: u64,
+    pub kind: String,
+    pub message: String,
+    pub time: u64,
+}
+
+impl Store {
+    pub const EVENT_OVERLOAD: &str = "overload";
+    /// A DNS query or connection failed because of bad data, eg. a broken fake ip mapping.
+    pub const EVENT_DNS_ERROR: &str = "dns_error";
+    /// The system clock is wrong, encrypted connections fail until it's synced.
+    pub const EVENT_CLOCK: &str = "clock";
+    /// Most of the fake ip pool is allocated, new domains will fail to resolve.
+    pub const EVENT_FAKE_IP_POOL: &str = "fake_ip_pool";
+    /// A destination failing through the proxy is connected directly for a while.
+    pub const EVENT_PROXY_FALLBACK: &str = "proxy_fallback";
+
+    pub fn new_event(&self, kind: &str, message: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        let _ = conn.execute(
+            &format!(
+                "INSERT INTO {} (kind, message, time) VALUES (?, ?, ?)",
+                Self::TABLE_EVENTS,
+            ),
+            params![kind, message, now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_events(&self) -> Result<Vec<Event>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT id, kind, message, time FROM {} ORDER BY id",
+            Self::TABLE_EVENTS,
+        ))?;
+        let mut rows = stmt.query(params![])?;
+        let mut events = Vec::new();
+        while let Some(row) = rows.next()? {
+            events.push(Event {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                message: row.get(2)?,
+                time: row.get(3)?,
+            });
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_event() {
+        let store = Store::store_for_test();
+        store
+            .new_event(Store::EVENT_OVERLOAD, "rejecting new connections")
+            .unwrap();
+        store
+            .new_event(Store::EVENT_OVERLOAD, "rejected 3")
+            .unwrap();
+        let events = store.list_events().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, Store::EVENT_OVERLOAD);
+        assert_eq!(events[0].message, "rejecting new connections");
+        assert_eq!(events[1].message, "rejected 3");
+        assert!(events[0].id < events[1].id);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{now, Store};
+use anyhow::Result;
+use rusqlite::params;
+use std::time::Duration;
+
+// region: fallbacks
+impl Store {
+    /// Count a failure to connect to `dest` through the proxy. At `threshold` failures in a row
+    /// `dest` is connected directly until `cooldown` is over, returns whether that just started.
+    pub fn record_proxy_failure(
+        &self,
+        dest: &str,
+        threshold: u64,
+        cooldown: Duration,
+    ) -> Result<bool> {
+        let conn = self.conn.lock();
+        let failures: u64 = conn.query_row(
+            &format!(
+                r#"
+            INSERT INTO {} (dest, failures, fallback_until) VALUES (?, 1, 0)
+            ON CONFLICT (dest) DO UPDATE SET failures = failures + 1
+            RETURNING failures
+            "#,
+                Self::TABLE_PROXY_FAILURES,
+            ),
+            params![dest],
+            |row| row.get(0),
+        )?;
+        if failures < threshold {
+            return Ok(false);
+        }
+        // Counted again from zero, so the proxy gets as many tries once the cooldown is over.
+        let _ = conn.execute(
+            &format!(
+                "UPDATE {} SET failures = 0, fallback_until = ? WHERE dest = ?",
+                Self::TABLE_PROXY_FAILURES,
+            ),
+            params![now() + cooldown.as_secs(), dest],
+        )?;
+        Ok(true)
+    }
+
+    /// Forget the failures of `dest` once it's connected through the proxy again.
+    pub fn record_proxy_success(&self, dest: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        let _ = conn.execute(
+            &format!("DELETE FROM {} WHERE dest = ?", Self::TABLE_PROXY_FAILURES),
+            params![dest],
+        )?;
+        Ok(())
+    }
+
+    /// Whether `dest` is connected directly instead of through the proxy for now.
+    pub fn is_proxy_fallback(&self, dest: &str) -> Result<bool> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT fallback_until FROM {} WHERE dest = ?",
+            Self::TABLE_PROXY_FAILURES,
+        ))?;
+        let ret = stmt.query_row(params![dest], |row| row.get::<_, u64>(0));
+        match ret {
+            Ok(until) => Ok(until > now()),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+// endregion: fallbacks
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_fallback() -> Result<()> {
+        let store = Store::store_for_test();
+        let cooldown = Duration::from_secs(600);
+        let dest = "example.com:443";
+        assert!(!store.record_proxy_failure(dest, 3, cooldown)?);
+        assert!(!store.record_proxy_failure(dest, 3, cooldown)?);
+        assert!(!store.is_proxy_fallback(dest)?);
+        assert!(store.record_proxy_failure(dest, 3, cooldown)?);
+        assert!(store.is_proxy_fallback(dest)?);
+        assert!(!store.is_proxy_fallback("example.org:443")?);
+
+        store.record_proxy_success(dest)?;
+        assert!(!store.is_proxy_fallback(dest)?);
+        assert!(!store.record_proxy_failure(dest, 3, cooldown)?);
+
+        // Over as soon as it starts.
+        assert!(store.record_proxy_failure("example.org:443", 1, Duration::ZERO)?);
+        assert!(!store.is_proxy_fallback("example.org:443")?);
+        Ok(())
+    }
+}
@@ -1,21 +1,38 @@
+mod audit;
 mod config;
 mod connections;
+mod counters;
 mod dns;
+mod events;
+mod fallbacks;
+mod usage;
+
+pub use audit::AuditEntry;
+use connections::ConnectionIds;
+use counters::Counters;
+use dns::FakeIpPool;
+pub use dns::FakeIpStats;
+pub use events::Event;
+pub use usage::{MonthlyUsage, ServerUsage};
 
 use parking_lot::ReentrantMutex;
 use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use once_cell::sync::OnceCell;
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
 
 #[derive(Debug)]
 pub struct Store {
     conn: ReentrantMutex<Connection>,
     initial_ip: Ipv4Addr,
     db_path: PathBuf,
+    counters: Arc<Counters>,
+    connection_ids: Arc<ConnectionIds>,
+    fake_ips: Arc<FakeIpPool>,
 }
 
 static INSTANCE: OnceCell<Store> = OnceCell::new();
@@ -26,6 +43,9 @@ impl Clone for Store {
             conn: ReentrantMutex::new(Connection::open(&self.db_path).expect("open db")),
             initial_ip: self.initial_ip,
             db_path: self.db_path.clone(),
+            counters: self.counters.clone(),
+            connection_ids: self.connection_ids.clone(),
+            fake_ips: self.fake_ips.clone(),
         }
     }
 }
@@ -34,6 +54,12 @@ impl Store {
     const TABLE_HOST_IP: &str = "host_ip";
     const TABLE_REMOTE_CONFIG_CACHE: &str = "remote_config_cache";
     const TABLE_CONNECTIONS: &str = "connections";
+    const TABLE_EVENTS: &str = "events";
+    const TABLE_META: &str = "meta";
+    const TABLE_PROXY_FAILURES: &str = "proxy_failures";
+    const TABLE_AUDIT_LOG: &str = "audit_log";
+    const TABLE_SERVER_USAGE: &str = "server_usage";
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
 
     pub fn setup_global(path: impl AsRef<Path>, initial_ip: Ipv4Addr) {
         Self::try_setup_global(path, initial_ip).expect("init global store")
@@ -41,7 +67,17 @@ impl Store {
 
     pub fn try_setup_global(path: impl AsRef<Path>, initial_ip: Ipv4Addr) -> Result<(), Self> {
         let store = Store::new(path, initial_ip).expect("init store");
-        INSTANCE.set(store)
+        INSTANCE.set(store)?;
+        std::thread::Builder::new()
+            .name("store-flush".to_string())
+            .spawn(|| loop {
+                std::thread::sleep(Self::FLUSH_INTERVAL);
+                if let Err(e) = Self::global().flush_connection_counters() {
+                    tracing::error!("Failed to flush connection counters: {}", e);
+                }
+            })
+            .expect("spawn store flush thread");
+        Ok(())
     }
 
     pub fn setup_global_for_test() {
@@ -53,6 +89,11 @@ impl Store {
         INSTANCE.get().expect("global store is not initialized")
     }
 
+    /// The global store, None if it isn't set up yet.
+    pub fn try_global() -> Option<&'static Self> {
+        INSTANCE.get()
+    }
+
     pub fn new(db_path: impl AsRef<Path>, initial_ip: Ipv4Addr) -> Result<Self> {
         let path = db_path.as_ref().to_path_buf();
         let conn = match Connection::open(&path) {
@@ -76,11 +117,50 @@ impl Store {
             db_path: path,
             conn: ReentrantMutex::new(conn),
             initial_ip,
+            counters: Default::default(),
+            connection_ids: Default::default(),
+            fake_ips: Default::default(),
         };
         store.init_tables()?;
         Ok(store)
     }
 
+    /// Open the db for caching remote config data only.
+    ///
+    /// The config is not loaded yet at this point, so `initial_ip` is unknown and
+    /// the host ip table must not be touched.
+    pub fn open_config_cache(db_path: impl AsRef<Path>) -> Result<Self> {
+        let path = db_path.as_ref().to_path_buf();
+        let conn = Connection::open(&path)?;
+        let store = Store {
+            db_path: path,
+            conn: ReentrantMutex::new(conn),
+            initial_ip: Ipv4Addr::UNSPECIFIED,
+            counters: Default::default(),
+            connection_ids: Default::default(),
+            fake_ips: Default::default(),
+        };
+        store.init_remote_config_cache_table()?;
+        Ok(store)
+    }
+
+    /// Open the db of a previous or running seeker without creating or resetting any table, eg.
+    /// to purge the history of some domains.
+    pub fn open_existing(db_path: impl AsRef<Path>) -> Result<Self> {
+        let path = db_path.as_ref().to_path_buf();
+        let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        // The running seeker writes every second.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(Store {
+            db_path: path,
+            conn: ReentrantMutex::new(conn),
+            initial_ip: Ipv4Addr::UNSPECIFIED,
+            counters: Default::default(),
+            connection_ids: Default::default(),
+            fake_ips: Default::default(),
+        })
+    }
+
     #[cfg(test)]
     pub fn store_for_test() -> Self {
         Store::new_in_memory(Ipv4Addr::new(127, 0, 0, 1)).expect("init store")
@@ -92,6 +172,9 @@ impl Store {
             db_path: PathBuf::new(),
             conn: ReentrantMutex::new(conn),
             initial_ip,
+            counters: Default::default(),
+            connection_ids: Default::default(),
+            fake_ips: Default::default(),
         };
         store.init_tables()?;
         Ok(store)
@@ -113,18 +196,7 @@ impl Store {
         )?;
 
         // region: remote_config_cache
-        conn.execute_batch(&format!(
-            r#"
-            CREATE TABLE IF NOT EXISTS {table} (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                url TEXT NOT NULL UNIQUE,
-                data BLOB NOT NULL,
-                last_update INTEGER NOT NULL
-            );
-            CREATE INDEX IF NOT EXISTS {table}_last_update ON {table} (last_update);
-            "#,
-            table = Self::TABLE_REMOTE_CONFIG_CACHE,
-        ))?;
+        self.init_remote_config_cache_table()?;
         let mut stmt = conn.prepare_cached(&format!(
             r#"INSERT OR IGNORE INTO {} (ip, host) VALUES (?, ?)"#,
             Self::TABLE_HOST_IP
@@ -136,7 +208,7 @@ impl Store {
         // endregion: remote_config_cache
 
         // region: connections
-        // | id | host | network | type | recv_bytes | sent_bytes | proxy_server | connect_time | last_update | is_alive |
+        // | id | host | network | type | recv_bytes | sent_bytes | proxy_server | connect_time | last_update | is_alive | sniffed_host |
         // connection data is cleared whenever the process starts.
         conn.execute_batch(&format!(
             r#"
@@ -151,12 +223,110 @@ impl Store {
                 proxy_server TEXT NOT NULL,
                 connect_time INTEGER NOT NULL,
                 last_update INTEGER NOT NULL,
-                is_alive INTEGER NOT NULL
+                is_alive INTEGER NOT NULL,
+                sniffed_host TEXT NOT NULL DEFAULT ''
             );
             "#,
             table = Self::TABLE_CONNECTIONS,
         ))?;
         // endregion: connections
+
+        // region: events
+        // | id | kind | message | time |
+        // events are cleared whenever the process starts.
+        conn.execute_batch(&format!(
+            r#"
+            DROP TABLE IF EXISTS {table};
+            CREATE TABLE IF NOT EXISTS {table} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                message TEXT NOT NULL,
+                time INTEGER NOT NULL
+            );
+            "#,
+            table = Self::TABLE_EVENTS,
+        ))?;
+        // endregion: events
+
+        // region: meta
+        // | key | value |
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {table} (
+                key TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );
+            "#,
+            table = Self::TABLE_META,
+        ))?;
+        self.init_connection_ids()?;
+        // endregion: meta
+
+        // region: proxy_failures
+        // | dest | failures | fallback_until |
+        // kept across restarts, so destinations failing through the proxy stay direct.
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {table} (
+                dest TEXT PRIMARY KEY,
+                failures INTEGER NOT NULL,
+                fallback_until INTEGER NOT NULL
+            );
+            "#,
+            table = Self::TABLE_PROXY_FAILURES,
+        ))?;
+        // endregion: proxy_failures
+
+        // region: audit_log
+        // | id | time | source | action | detail |
+        // append only and kept across restarts.
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {table} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                time INTEGER NOT NULL,
+                source TEXT NOT NULL,
+                action TEXT NOT NULL,
+                detail TEXT NOT NULL
+            );
+            "#,
+            table = Self::TABLE_AUDIT_LOG,
+        ))?;
+        // endregion: audit_log
+
+        // region: server_usage
+        // | day | server | sent_bytes | recv_bytes |
+        // traffic through each server by day since the epoch, kept across restarts.
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {table} (
+                day INTEGER NOT NULL,
+                server TEXT NOT NULL,
+                sent_bytes INTEGER NOT NULL,
+                recv_bytes INTEGER NOT NULL,
+                PRIMARY KEY (day, server)
+            );
+            "#,
+            table = Self::TABLE_SERVER_USAGE,
+        ))?;
+        // endregion: server_usage
+        Ok(())
+    }
+
+    fn init_remote_config_cache_table(&self) -> Result<()> {
+        let conn = self.conn.lock();
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {table} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL UNIQUE,
+                data BLOB NOT NULL,
+                last_update INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {table}_last_update ON {table} (last_update);
+            "#,
+            table = Self::TABLE_REMOTE_CONFIG_CACHE,
+        ))?;
         Ok(())
     }
 }
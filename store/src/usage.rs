@@ -0,0 +1,180 @@
+use crate::Store;
+use anyhow::Result;
+use rusqlite::params;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Traffic through a server in a month.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ServerUsage {
+    pub server: String,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+}
+
+impl ServerUsage {
+    pub fn total_bytes(&self) -> u64 {
+        self.sent_bytes + self.recv_bytes
+    }
+}
+
+/// Month to date traffic of the servers, months are calendar months in UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonthlyUsage {
+    /// Like `2024-03`.
+    pub month: String,
+    /// Unix times of the start of the month and of the next month.
+    pub start: u64,
+    pub end: u64,
+    /// When the usage was read, the projections assume the rate so far goes on until `end`.
+    pub time: u64,
+    pub servers: Vec<ServerUsage>,
+}
+
+impl MonthlyUsage {
+    /// `bytes` used so far extrapolated to the whole month.
+    pub fn projected(&self, bytes: u64) -> u64 {
+        let elapsed = self.time.saturating_sub(self.start).max(1);
+        (bytes as u128 * u128::from(self.end - self.start) / u128::from(elapsed)) as u64
+    }
+}
+
+// region: server_usage
+impl Store {
+    /// Add the traffic of `usage`, server names with sent and received bytes, to the day of
+    /// `time`.
+    pub fn add_server_usage(&self, time: u64, usage: &[(String, u64, u64)]) -> Result<()> {
+        if usage.is_empty() {
+            return Ok(());
+        }
+        let conn = self.conn.lock();
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(&format!(
+                r#"
+            INSERT INTO {} (day, server, sent_bytes, recv_bytes) VALUES (?, ?, ?, ?)
+            ON CONFLICT (day, server) DO UPDATE SET
+                sent_bytes = sent_bytes + excluded.sent_bytes,
+                recv_bytes = recv_bytes + excluded.recv_bytes
+            "#,
+                Self::TABLE_SERVER_USAGE,
+            ))?;
+            for (server, sent, recv) in usage {
+                let _ = stmt.execute(params![time / SECS_PER_DAY, server, sent, recv])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Traffic of each server in the month of `time`, most used first.
+    pub fn monthly_usage(&self, time: u64) -> Result<MonthlyUsage> {
+        let (year, month, _) = civil_from_days(time / SECS_PER_DAY);
+        let start = days_from_civil(year, month, 1);
+        let end = match month {
+            12 => days_from_civil(year + 1, 1, 1),
+            _ => days_from_civil(year, month + 1, 1),
+        };
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(&format!(
+            r#"
+            SELECT server, sum(sent_bytes), sum(recv_bytes) FROM {}
+            WHERE day >= ? AND day < ? GROUP BY server
+            ORDER BY sum(sent_bytes) + sum(recv_bytes) DESC, server
+            "#,
+            Self::TABLE_SERVER_USAGE,
+        ))?;
+        let mut rows = stmt.query(params![start, end])?;
+        let mut servers = Vec::new();
+        while let Some(row) = rows.next()? {
+            servers.push(ServerUsage {
+                server: row.get(0)?,
+                sent_bytes: row.get(1)?,
+                recv_bytes: row.get(2)?,
+            });
+        }
+        Ok(MonthlyUsage {
+            month: format!("{year}-{month:02}"),
+            start: start * SECS_PER_DAY,
+            end: end * SECS_PER_DAY,
+            time,
+            servers,
+        })
+    }
+}
+// endregion: server_usage
+
+/// Year, month and day of the days since the unix epoch, see
+/// http://howardhinnant.github.io/date_algorithms.html.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 2024-02-29
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(days_from_civil(2024, 3, 1), 19783);
+        assert_eq!(days_from_civil(2025, 1, 1), 20089);
+    }
+
+    #[test]
+    fn test_monthly_usage() -> Result<()> {
+        let store = Store::store_for_test();
+        // 2024-03-11 12:00 UTC, a third of the way through March.
+        let time = days_from_civil(2024, 3, 11) * SECS_PER_DAY + SECS_PER_DAY / 2;
+        let hk = "hk".to_string();
+        store.add_server_usage(time, &[(hk.clone(), 10, 100), ("jp".to_string(), 1, 1)])?;
+        store.add_server_usage(time - SECS_PER_DAY, &[(hk.clone(), 5, 5)])?;
+        // February.
+        store.add_server_usage(time - 20 * SECS_PER_DAY, &[(hk.clone(), 1000, 1000)])?;
+
+        let usage = store.monthly_usage(time)?;
+        assert_eq!(usage.month, "2024-03");
+        assert_eq!(usage.end - usage.start, 31 * SECS_PER_DAY);
+        assert_eq!(
+            usage.servers,
+            vec![
+                ServerUsage {
+                    server: hk,
+                    sent_bytes: 15,
+                    recv_bytes: 105,
+                },
+                ServerUsage {
+                    server: "jp".to_string(),
+                    sent_bytes: 1,
+                    recv_bytes: 1,
+                },
+            ]
+        );
+        assert_eq!(usage.projected(120), 120 * 31 * 2 / 21);
+
+        assert_eq!(store.monthly_usage(usage.start - 1)?.month, "2024-02");
+        assert_eq!(store.monthly_usage(usage.end)?.servers, vec![]);
+        Ok(())
+    }
+}
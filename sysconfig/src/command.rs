@@ -21,3 +21,56 @@ pub fn run_cmd(cmd: &str, args: &[&str]) -> String {
         .expect("utf8")
         .to_string()
 }
+
+/// Like [`run_cmd`], but returns an error instead of panicking if the command fails.
+pub fn try_run_cmd(cmd: &str, args: &[&str]) -> std::io::Result<String> {
+    let output = Command::new(cmd).args(args).output()?;
+    debug!("{} {:?}", cmd, args);
+
+    if !output.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "{} {}: {}",
+                cmd,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Like [`try_run_cmd`], writing `input` to the stdin of the command.
+#[cfg(target_os = "linux")]
+pub fn try_run_cmd_with_input(cmd: &str, args: &[&str], input: &str) -> std::io::Result<String> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    debug!("{} {:?}", cmd, args);
+    child
+        .stdin
+        .take()
+        .expect("stdin")
+        .write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "{} {}: {}",
+                cmd,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -1,14 +1,33 @@
 mod command;
 mod iptables;
 mod net;
-#[cfg(target_arch = "x86_64")]
+#[cfg(target_os = "linux")]
+mod nftables;
+#[cfg(target_os = "linux")]
+mod privilege;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 mod proc;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod systemd;
 mod ulimit;
 
 pub use iptables::IptablesSetup;
-pub use net::{setup_ip, DNSSetup, IpForward};
-#[cfg(target_arch = "x86_64")]
+pub use net::{
+    list_interface_addrs, list_routes, set_mtu, setup_ip, source_origin, DNSSetup, InterfaceAddr,
+    IpForward, NetworkMonitor, Route, RouteSetup, SourceOrigin,
+};
+#[cfg(target_os = "linux")]
+pub use nftables::{KillSwitch, NatSetup};
+#[cfg(target_os = "linux")]
+pub use privilege::{drop_privileges, CAP_DAC_OVERRIDE, CAP_NET_ADMIN};
+#[cfg(target_os = "linux")]
+pub use proc::socket_cgroup;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 pub use proc::sys::{list_system_proc_socks, list_user_proc_socks};
-#[cfg(target_arch = "x86_64")]
-pub use proc::SocketInfo;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub use proc::{find_user_socket_owner, socket_belongs_to_user, ProcessSockets, SocketInfo};
+#[cfg(target_os = "linux")]
+pub use sandbox::{restrict_filesystem, restrict_syscalls};
+pub use systemd::{listen_fds, sd_notify, watchdog_interval};
 pub use ulimit::{get_rlimit_no_file, set_rlimit_no_file};
@@ -1,44 +1,71 @@
-use crate::command::run_cmd;
-use std::net::IpAddr;
-use tracing::info;
+use crate::command::{run_cmd, try_run_cmd};
+use crate::net::Route;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use tracing::{info, warn};
 
+/// Points the primary network service at seeker's DNS server on [`DNSSetup::start`] and
+/// restores its DNS servers when dropped. The original servers are saved in `state_path`
+/// meanwhile, so they are restored by the next run if seeker crashes.
 pub struct DNSSetup {
     primary_network: String,
     // DNS servers from networksetup. DHCP dns servers are not included.
-    original_real_dns: Vec<String>,
-    // DNS servers from scutil. Real used DNS servers.
     original_manual_dns: Vec<String>,
+    // DNS servers from scutil. Real used DNS servers.
+    original_real_dns: Vec<String>,
     // DNS servers to be set.
     dns: String,
+    state_path: PathBuf,
+    started: bool,
 }
 
 impl DNSSetup {
     #[allow(clippy::new_without_default)]
-    pub fn new(dns: String) -> Self {
+    pub fn new(dns: String, state_path: impl Into<PathBuf>) -> Self {
+        let state_path = state_path.into();
         let network = get_primary_network();
         info!("Primary netowrk service is {}", &network);
-        let original_manual_dns = run_cmd("networksetup", &["-getdnsservers", &network])
-            .lines()
-            .filter(|l| *l != "127.0.0.1" && *l != dns)
-            .filter_map(|l| l.parse::<IpAddr>().ok())
-            .map(|ip| ip.to_string())
-            .collect::<Vec<_>>();
+        let original_manual_dns = match std::fs::read_to_string(&state_path) {
+            Ok(backup) => {
+                info!("Restore DNS saved by a previous run");
+                let original_manual_dns: Vec<String> =
+                    backup.lines().map(|l| l.to_string()).collect();
+                restore_dns(&network, &original_manual_dns);
+                let _ = std::fs::remove_file(&state_path);
+                original_manual_dns
+            }
+            Err(_) => run_cmd("networksetup", &["-getdnsservers", &network])
+                .lines()
+                .filter(|l| *l != "127.0.0.1" && *l != dns)
+                .filter_map(|l| l.parse::<IpAddr>().ok())
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>(),
+        };
 
         // Get macos dns servers from terminal
         let lines = run_cmd("scutil", &["--dns"]);
-        let original_dns = parse_scutil_dns(&lines);
+        let original_dns = parse_scutil_dns(&lines)
+            .into_iter()
+            .filter(|ip| ip != "127.0.0.1" && *ip != dns)
+            .collect();
 
         DNSSetup {
             primary_network: network,
             original_real_dns: original_dns,
             original_manual_dns,
             dns,
+            state_path,
+            started: false,
         }
     }
 
-    pub fn start(&self) {
+    pub fn start(&mut self, _tun_name: &str) {
         let original_dns = &self.original_manual_dns;
         let network = &self.primary_network;
+        let backup: String = original_dns.iter().map(|dns| format!("{dns}\n")).collect();
+        if let Err(e) = std::fs::write(&self.state_path, backup) {
+            warn!("Save original DNS error: {}", e);
+        }
         if self.dns.is_empty() {
             let _ = run_cmd("networksetup", &["-setdnsservers", network, "127.0.0.1"]);
         } else {
@@ -47,40 +74,112 @@ impl DNSSetup {
                 &["-setdnsservers", network, "127.0.0.1", &self.dns],
             );
         }
+        self.started = true;
 
         info!(
             "Setup DNS: {}, Original DNS is {:?}, Original real DNS is {:?}",
-            &self.dns, &self.original_manual_dns, &original_dns,
+            &self.dns, &self.original_manual_dns, &self.original_real_dns,
         );
     }
 
     pub fn original_dns(&self) -> Vec<String> {
         self.original_real_dns.clone()
     }
+
+    /// Always false, the DNS is set with `networksetup`.
+    pub fn rewrites_resolv_conf(&self) -> bool {
+        false
+    }
 }
 
 impl Drop for DNSSetup {
     fn drop(&mut self) {
-        let mut args = vec!["-setdnsservers", &self.primary_network];
-        if self.original_manual_dns.is_empty() {
-            args.push("empty");
-        } else {
-            for dns in &self.original_manual_dns {
-                args.push(dns);
-            }
-        };
+        if !self.started {
+            return;
+        }
         info!("Restore original DNS: {:?}", self.original_manual_dns);
-
-        let _ = run_cmd("networksetup", &args);
+        restore_dns(&self.primary_network, &self.original_manual_dns);
+        let _ = std::fs::remove_file(&self.state_path);
     }
 }
 
-pub fn setup_ip(tun_name: &str, ip: &str, cidr: &str, additional_cidrs: Vec<String>) {
+fn restore_dns(network: &str, dns: &[String]) {
+    let mut args = vec!["-setdnsservers", network];
+    if dns.is_empty() {
+        args.push("empty");
+    } else {
+        for dns in dns {
+            args.push(dns);
+        }
+    };
+    let _ = run_cmd("networksetup", &args);
+}
+
+pub fn setup_ip(tun_name: &str, ip: &str) {
     let _ = run_cmd("ifconfig", &[tun_name, ip, ip]);
-    let _ = run_cmd("route", &["add", cidr, ip]);
-    for additional_cidr in additional_cidrs {
-        let _ = run_cmd("route", &["add", additional_cidr.as_str(), ip]);
+}
+
+/// Route `cidr` to the TUN device, replacing a stale route of a previous run if there is one.
+pub(crate) fn add_route(cidr: &str, gateway: &str, tun_name: &str) -> std::io::Result<()> {
+    if try_run_cmd("route", &["-n", "add", "-net", cidr, gateway]).is_ok() {
+        return Ok(());
     }
+    // `File exists`, left by a previous run.
+    let _ = delete_route(cidr, gateway, tun_name);
+    try_run_cmd("route", &["-n", "add", "-net", cidr, gateway]).map(|_| ())
+}
+
+pub(crate) fn delete_route(cidr: &str, gateway: &str, _tun_name: &str) -> std::io::Result<()> {
+    try_run_cmd("route", &["-n", "delete", "-net", cidr, gateway]).map(|_| ())
+}
+
+pub fn set_mtu(tun_name: &str, mtu: u16) {
+    let _ = run_cmd("ifconfig", &[tun_name, "mtu", &mtu.to_string()]);
+}
+
+/// List IPv4 routes with `netstat -rn -f inet`.
+pub fn list_routes() -> std::io::Result<Vec<Route>> {
+    Ok(parse_netstat_routes(&run_cmd(
+        "netstat",
+        &["-rn", "-f", "inet"],
+    )))
+}
+
+fn parse_netstat_routes(content: &str) -> Vec<Route> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (destination, interface) = (fields.first()?, fields.get(3)?);
+            let (destination, prefix_len) = if *destination == "default" {
+                (Ipv4Addr::UNSPECIFIED, 0)
+            } else {
+                // Destinations are abbreviated, eg. `10/8`, `192.168.1` or `192.168.1.1`.
+                let (addr, prefix_len) = match destination.split_once('/') {
+                    Some((addr, len)) => (addr, Some(len.parse::<u8>().ok()?)),
+                    None => (*destination, None),
+                };
+                let octets = addr
+                    .split('.')
+                    .map(|o| o.parse::<u8>().ok())
+                    .collect::<Option<Vec<_>>>()?;
+                if octets.is_empty() || octets.len() > 4 {
+                    return None;
+                }
+                let mut addr = [0; 4];
+                addr[..octets.len()].copy_from_slice(&octets);
+                (
+                    Ipv4Addr::from(addr),
+                    prefix_len.unwrap_or(octets.len() as u8 * 8),
+                )
+            };
+            Some(Route {
+                destination,
+                prefix_len,
+                interface: interface.to_string(),
+            })
+        })
+        .collect()
 }
 
 fn get_primary_network() -> String {
@@ -135,6 +234,34 @@ fn parse_scutil_dns(lines: &str) -> Vec<String> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_netstat_routes() {
+        let content = r#"Routing tables
+
+Internet:
+Destination        Gateway            Flags           Netif Expire
+default            192.168.2.1        UGScg             en0
+10/8               link#20            UCS             utun4
+127                127.0.0.1          UCS               lo0
+192.168.2          link#15            UCS               en0      !
+192.168.2.1/32     link#15            UCS               en0      !
+"#;
+        let routes = parse_netstat_routes(content);
+        assert_eq!(routes.len(), 5);
+        assert!(routes[0].is_default());
+        assert_eq!(
+            routes[1],
+            Route {
+                destination: Ipv4Addr::new(10, 0, 0, 0),
+                prefix_len: 8,
+                interface: "utun4".to_string(),
+            }
+        );
+        assert_eq!(routes[3].destination, Ipv4Addr::new(192, 168, 2, 0));
+        assert_eq!(routes[3].prefix_len, 24);
+        assert_eq!(routes[4].prefix_len, 32);
+    }
+
     #[test]
     fn test_parse_scutil_dns() {
         let lines = r#"DNS configuration
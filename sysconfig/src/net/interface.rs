@@ -0,0 +1,119 @@
+//! IPv4 addresses of the network interfaces, to tell connections from this machine apart from
+//! those forwarded from containers, other network namespaces or the LAN.
+use std::ffi::CStr;
+use std::io;
+use std::net::Ipv4Addr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterfaceAddr {
+    pub name: String,
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+/// Where packets from a source address come from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SourceOrigin {
+    /// An address of this machine, the socket is in the current network namespace.
+    Local,
+    /// In the subnet of the interface, eg. a container behind the `docker0` bridge.
+    Interface(String),
+    /// Routed from elsewhere.
+    Unknown,
+}
+
+pub fn list_interface_addrs() -> io::Result<Vec<InterfaceAddr>> {
+    let mut addrs = vec![];
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut cur = ifaddrs;
+    while let Some(ifa) = unsafe { cur.as_ref() } {
+        cur = ifa.ifa_next;
+        let addr = unsafe { ifa.ifa_addr.as_ref() };
+        let netmask = unsafe { ifa.ifa_netmask.as_ref() };
+        let (Some(addr), Some(netmask)) = (addr, netmask) else {
+            continue;
+        };
+        if addr.sa_family as libc::c_int != libc::AF_INET {
+            continue;
+        }
+        let to_ipv4 = |sa: &libc::sockaddr| {
+            let sin = unsafe { &*(sa as *const libc::sockaddr as *const libc::sockaddr_in) };
+            Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))
+        };
+        addrs.push(InterfaceAddr {
+            name: unsafe { CStr::from_ptr(ifa.ifa_name) }
+                .to_string_lossy()
+                .into_owned(),
+            addr: to_ipv4(addr),
+            netmask: to_ipv4(netmask),
+        });
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// Where packets from `ip` come from, by the addresses of the interfaces.
+pub fn source_origin(ip: Ipv4Addr) -> io::Result<SourceOrigin> {
+    Ok(origin_in(&list_interface_addrs()?, ip))
+}
+
+fn origin_in(addrs: &[InterfaceAddr], ip: Ipv4Addr) -> SourceOrigin {
+    if ip.is_loopback() || addrs.iter().any(|a| a.addr == ip) {
+        return SourceOrigin::Local;
+    }
+    // The most specific subnet, a /32 of a point-to-point link is more specific than a bridge.
+    addrs
+        .iter()
+        .filter(|a| {
+            let mask = u32::from(a.netmask);
+            u32::from(a.addr) & mask == u32::from(ip) & mask
+        })
+        .max_by_key(|a| u32::from(a.netmask).count_ones())
+        .map_or(SourceOrigin::Unknown, |a| {
+            SourceOrigin::Interface(a.name.clone())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(name: &str, addr: &str, netmask: &str) -> InterfaceAddr {
+        InterfaceAddr {
+            name: name.to_string(),
+            addr: addr.parse().unwrap(),
+            netmask: netmask.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_origin_in() {
+        let addrs = [
+            addr("lo", "127.0.0.1", "255.0.0.0"),
+            addr("eth0", "192.168.1.10", "255.255.255.0"),
+            addr("docker0", "172.17.0.1", "255.255.0.0"),
+            addr("utun4", "11.0.0.1", "255.255.0.0"),
+        ];
+        let origin = |ip: &str| origin_in(&addrs, ip.parse().unwrap());
+        assert_eq!(origin("11.0.0.1"), SourceOrigin::Local);
+        assert_eq!(origin("127.0.0.2"), SourceOrigin::Local);
+        assert_eq!(
+            origin("172.17.0.2"),
+            SourceOrigin::Interface("docker0".to_string())
+        );
+        assert_eq!(
+            origin("192.168.1.20"),
+            SourceOrigin::Interface("eth0".to_string())
+        );
+        assert_eq!(origin("10.1.0.2"), SourceOrigin::Unknown);
+    }
+
+    #[test]
+    fn test_list_interface_addrs() {
+        let addrs = list_interface_addrs().unwrap();
+        assert!(addrs.iter().any(|a| a.addr == Ipv4Addr::LOCALHOST));
+    }
+}
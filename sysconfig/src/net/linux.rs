@@ -1,86 +1,206 @@
-use crate::command::run_cmd;
-use std::fs::OpenOptions;
-use std::io::{Read, Seek, Write};
-use std::net::IpAddr;
-use tracing::info;
+use crate::command::{run_cmd, try_run_cmd};
+use crate::net::Route;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use tracing::{info, warn};
 
+/// Points the system resolver at seeker's DNS server on [`DNSSetup::start`] and restores the
+/// previous configuration when dropped.
+///
+/// With systemd-resolved the DNS server is set on the TUN link, so it goes away with the link even
+/// if seeker crashes. Otherwise `/etc/resolv.conf` is rewritten and backed up first, a backup left
+/// by a crash is restored by the next [`DNSSetup::new`].
 pub struct DNSSetup {
     original_dns: Vec<String>,
+    dns: String,
+    takeover: Option<Takeover>,
+}
+
+enum Takeover {
+    ResolvConf,
+    Resolved { link: String },
 }
 
 const RESOLV_PATH: &str = "/etc/resolv.conf";
+const RESOLV_BACKUP_PATH: &str = "/etc/resolv.conf.seeker";
+/// Upstream servers of systemd-resolved, `/etc/resolv.conf` only lists its stub listener.
+const RESOLVED_UPSTREAM_PATH: &str = "/run/systemd/resolve/resolv.conf";
+
 impl DNSSetup {
-    pub fn new(dns: String) -> Self {
-        info!("setup dns");
-        let mut resolv = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(RESOLV_PATH)
-            .unwrap();
-        let mut buf = vec![];
-        let _ = resolv.read_to_end(&mut buf).unwrap();
-
-        let content = std::str::from_utf8(&buf).unwrap();
-        let original_dns = get_original_dns(content, &dns);
+    /// `_state_path` is only used on macOS, `/etc/resolv.conf` is backed up next to itself.
+    pub fn new(dns: String, _state_path: impl Into<PathBuf>) -> Self {
+        match restore_resolv_conf() {
+            Ok(true) => info!("Restored {} left by a previous run", RESOLV_PATH),
+            Ok(false) => {}
+            Err(e) => warn!(
+                "Restore {} left by a previous run error: {}",
+                RESOLV_PATH, e
+            ),
+        }
+        let path = if uses_resolved() {
+            RESOLVED_UPSTREAM_PATH
+        } else {
+            RESOLV_PATH
+        };
+        let content = std::fs::read_to_string(path).unwrap_or_else(|e| {
+            warn!("Read {} error: {}", path, e);
+            String::new()
+        });
+        let original_dns = get_original_dns(&content, &dns);
         info!("original dns: {:?}", &original_dns);
 
-        resolv.set_len(0).unwrap();
-        resolv.rewind().unwrap();
-        resolv
-            .write_all(generate_resolve_file(&["127.0.0.1", &dns]).as_slice())
-            .unwrap();
-
-        DNSSetup { original_dns }
+        DNSSetup {
+            original_dns,
+            dns,
+            takeover: None,
+        }
     }
 
     pub fn original_dns(&self) -> Vec<String> {
         self.original_dns.clone()
     }
 
-    pub fn start(&self) {}
+    /// Whether `/etc/resolv.conf` was rewritten, restoring it needs write access to `/etc`.
+    pub fn rewrites_resolv_conf(&self) -> bool {
+        matches!(self.takeover, Some(Takeover::ResolvConf))
+    }
+
+    /// Point the system resolver at seeker, through the `tun_name` link with systemd-resolved.
+    pub fn start(&mut self, tun_name: &str) {
+        if uses_resolved() {
+            match set_link_dns(tun_name) {
+                Ok(()) => {
+                    info!("setup dns of link {} with systemd-resolved", tun_name);
+                    self.takeover = Some(Takeover::Resolved {
+                        link: tun_name.to_string(),
+                    });
+                    return;
+                }
+                Err(e) => warn!("setup dns with systemd-resolved error: {}", e),
+            }
+        }
+
+        info!("setup dns");
+        // Moving the file keeps it as is, even if it's a symlink to a file managed by
+        // NetworkManager or resolvconf.
+        if std::fs::rename(RESOLV_PATH, RESOLV_BACKUP_PATH).is_err() {
+            // Bind mounted, eg. in docker, it can only be rewritten in place.
+            if let Err(e) = std::fs::copy(RESOLV_PATH, RESOLV_BACKUP_PATH) {
+                warn!("Backup {} error, dns is not set up: {}", RESOLV_PATH, e);
+                return;
+            }
+        }
+        // Restored on drop even if writing fails, the file may have been moved already.
+        self.takeover = Some(Takeover::ResolvConf);
+        if let Err(e) = std::fs::write(
+            RESOLV_PATH,
+            generate_resolve_file(&["127.0.0.1", &self.dns]),
+        ) {
+            warn!("Write {} error: {}", RESOLV_PATH, e);
+        }
+    }
 }
 
 impl Drop for DNSSetup {
     fn drop(&mut self) {
-        info!("Restore original DNS: {:?}", self.original_dns);
-        let mut resolv = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(RESOLV_PATH)
-            .unwrap();
-        resolv
-            .write_all(
-                generate_resolve_file(
-                    self.original_dns
-                        .iter()
-                        .map(|s| s.as_str())
-                        .collect::<Vec<_>>()
-                        .as_slice(),
-                )
-                .as_slice(),
-            )
-            .unwrap();
+        match self.takeover.take() {
+            None => {}
+            Some(Takeover::Resolved { link }) => {
+                info!("Restore dns of link {}", link);
+                // Fails if the link is already gone, which removes its settings too.
+                let _ = try_run_cmd("resolvectl", &["revert", &link]);
+            }
+            Some(Takeover::ResolvConf) => {
+                info!("Restore original DNS: {:?}", self.original_dns);
+                if let Err(e) = restore_resolv_conf() {
+                    warn!(
+                        "Restore {} error, the original is at {}: {}",
+                        RESOLV_PATH, RESOLV_BACKUP_PATH, e
+                    );
+                }
+            }
+        }
     }
 }
 
-pub fn setup_ip(tun_name: &str, ip: &str, cidr: &str, additional_cidrs: Vec<String>) {
+/// Move the backup of `/etc/resolv.conf` back if there is one.
+fn restore_resolv_conf() -> std::io::Result<bool> {
+    if std::fs::symlink_metadata(RESOLV_BACKUP_PATH).is_err() {
+        return Ok(false);
+    }
+    if std::fs::rename(RESOLV_BACKUP_PATH, RESOLV_PATH).is_err() {
+        std::fs::write(RESOLV_PATH, std::fs::read(RESOLV_BACKUP_PATH)?)?;
+        std::fs::remove_file(RESOLV_BACKUP_PATH)?;
+    }
+    Ok(true)
+}
+
+/// Whether `/etc/resolv.conf` is managed by systemd-resolved.
+fn uses_resolved() -> bool {
+    let Ok(target) = std::fs::read_link(RESOLV_PATH) else {
+        return false;
+    };
+    target.to_string_lossy().contains("systemd/resolve")
+        && std::path::Path::new(RESOLVED_UPSTREAM_PATH).exists()
+}
+
+fn set_link_dns(link: &str) -> std::io::Result<()> {
+    let _ = try_run_cmd("resolvectl", &["dns", link, "127.0.0.1"])?;
+    // Route all domains to the link.
+    let _ = try_run_cmd("resolvectl", &["domain", link, "~."])?;
+    // Not supported by old versions, the `~.` domain is enough there.
+    let _ = try_run_cmd("resolvectl", &["default-route", link, "yes"]);
+    Ok(())
+}
+
+pub fn setup_ip(tun_name: &str, ip: &str) {
     let _ = run_cmd("ip", &["addr", "add", ip, "dev", tun_name]);
     let _ = run_cmd("ip", &["link", "set", tun_name, "up"]);
-    let _ = run_cmd("ip", &["route", "add", cidr, "via", ip, "dev", tun_name]);
-    for additional_cidr in additional_cidrs {
-        let _ = run_cmd(
-            "ip",
-            &[
-                "route",
-                "add",
-                additional_cidr.as_str(),
-                "via",
-                ip,
-                "dev",
-                tun_name,
-            ],
-        );
-    }
+}
+
+/// Route `cidr` to the TUN device, replacing a stale route of a previous run if there is one.
+pub(crate) fn add_route(cidr: &str, gateway: &str, tun_name: &str) -> std::io::Result<()> {
+    try_run_cmd(
+        "ip",
+        &["route", "replace", cidr, "via", gateway, "dev", tun_name],
+    )
+    .map(|_| ())
+}
+
+pub(crate) fn delete_route(cidr: &str, _gateway: &str, tun_name: &str) -> std::io::Result<()> {
+    try_run_cmd("ip", &["route", "del", cidr, "dev", tun_name]).map(|_| ())
+}
+
+pub fn set_mtu(tun_name: &str, mtu: u16) {
+    let _ = run_cmd(
+        "ip",
+        &["link", "set", "dev", tun_name, "mtu", &mtu.to_string()],
+    );
+}
+
+/// List IPv4 routes from `/proc/net/route`.
+pub fn list_routes() -> std::io::Result<Vec<Route>> {
+    Ok(parse_proc_net_route(&std::fs::read_to_string(
+        "/proc/net/route",
+    )?))
+}
+
+fn parse_proc_net_route(content: &str) -> Vec<Route> {
+    // Addresses are printed as hex of the u32 in host byte order.
+    let parse_addr = |s: &str| u32::from_str_radix(s, 16).ok().map(u32::to_ne_bytes);
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (interface, destination, mask) = (fields.first()?, fields.get(1)?, fields.get(7)?);
+            Some(Route {
+                destination: Ipv4Addr::from(parse_addr(destination)?),
+                prefix_len: u32::from_ne_bytes(parse_addr(mask)?).count_ones() as u8,
+                interface: interface.to_string(),
+            })
+        })
+        .collect()
 }
 
 fn get_original_dns(content: &str, dns: &str) -> Vec<String> {
@@ -107,3 +227,32 @@ fn generate_resolve_file(dns: &[&str]) -> Vec<u8> {
     }
     content
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_net_route() {
+        let first = u32::from_ne_bytes([192, 168, 1, 0]);
+        let mask = u32::from_ne_bytes([255, 255, 255, 0]);
+        let content = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+             eth0\t{first:08X}\t00000000\t0001\t0\t0\t100\t{mask:08X}\t0\t0\t0\n"
+        );
+        let routes = parse_proc_net_route(&content);
+        assert_eq!(routes.len(), 2);
+        assert!(routes[0].is_default());
+        assert_eq!(
+            routes[1],
+            Route {
+                destination: Ipv4Addr::new(192, 168, 1, 0),
+                prefix_len: 24,
+                interface: "eth0".to_string(),
+            }
+        );
+        assert!(routes[1].overlaps(Ipv4Addr::new(192, 168, 0, 0), 16));
+        assert!(!routes[1].overlaps(Ipv4Addr::new(11, 0, 0, 0), 16));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::command::run_cmd;
+use std::net::Ipv4Addr;
 
 #[cfg(any(
     target_os = "macos",
@@ -46,4 +47,33 @@ pub mod sys;
 #[path = "linux.rs"]
 pub mod sys;
 
-pub use sys::{setup_ip, DNSSetup};
+mod interface;
+mod monitor;
+mod route;
+
+pub use interface::{list_interface_addrs, source_origin, InterfaceAddr, SourceOrigin};
+pub use monitor::NetworkMonitor;
+pub use route::RouteSetup;
+pub use sys::{list_routes, set_mtu, setup_ip, DNSSetup};
+
+/// An IPv4 route in the system routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Ipv4Addr,
+    pub prefix_len: u8,
+    pub interface: String,
+}
+
+impl Route {
+    /// Whether the route is the default route.
+    pub fn is_default(&self) -> bool {
+        self.prefix_len == 0
+    }
+
+    /// Whether the route overlaps with the `network/prefix_len` subnet.
+    pub fn overlaps(&self, network: Ipv4Addr, prefix_len: u8) -> bool {
+        let prefix_len = prefix_len.min(self.prefix_len);
+        let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        u32::from(self.destination) & mask == u32::from(network) & mask
+    }
+}
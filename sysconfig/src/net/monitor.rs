@@ -0,0 +1,236 @@
+//! Notifications of interface, address and route changes, through a netlink socket on linux and a
+//! routing socket on macos.
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+/// Blocks in [`NetworkMonitor::wait`] until the network of the machine changes. Changes of the
+/// ignored interface, ie. the TUN device whose routes seeker manages itself, are skipped.
+pub struct NetworkMonitor {
+    fd: OwnedFd,
+    ignore_index: u32,
+}
+
+impl NetworkMonitor {
+    pub fn new(ignore_interface: &str) -> io::Result<Self> {
+        let name = CString::new(ignore_interface)?;
+        let ignore_index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        let fd = sys::open_socket()?;
+        Ok(NetworkMonitor {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            ignore_index,
+        })
+    }
+
+    /// Wait for the next change.
+    pub fn wait(&self) -> io::Result<()> {
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            let size = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if size < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    // The kernel dropped messages, something has changed anyway.
+                    _ if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+                    _ => return Err(e),
+                }
+            }
+            if sys::is_relevant(&buf[..size as usize], self.ignore_index) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+
+    const RTMGRP_LINK: u32 = 0x1;
+    const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+    const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+    const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+    const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+
+    const RTM_NEWLINK: u16 = 16;
+    const RTM_DELLINK: u16 = 17;
+    const RTM_NEWADDR: u16 = 20;
+    const RTM_DELADDR: u16 = 21;
+    const RTM_NEWROUTE: u16 = 24;
+    const RTM_DELROUTE: u16 = 25;
+    const RTA_OIF: u16 = 4;
+
+    const NLMSG_HDRLEN: usize = 16;
+    /// Size of `struct rtmsg`, followed by the route attributes.
+    const RTMSG_LEN: usize = 12;
+
+    pub(super) fn open_socket() -> io::Result<libc::c_int> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_LINK
+            | RTMGRP_IPV4_IFADDR
+            | RTMGRP_IPV4_ROUTE
+            | RTMGRP_IPV6_IFADDR
+            | RTMGRP_IPV6_ROUTE;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(fd)
+    }
+
+    fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+        Some(u16::from_ne_bytes(
+            buf.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+        Some(u32::from_ne_bytes(
+            buf.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    }
+
+    fn align(len: usize) -> usize {
+        (len + 3) & !3
+    }
+
+    /// Output interface of a route, from its `RTA_OIF` attribute.
+    fn route_interface(payload: &[u8]) -> Option<u32> {
+        let mut offset = RTMSG_LEN;
+        while let (Some(len), Some(ty)) = (u16_at(payload, offset), u16_at(payload, offset + 2)) {
+            let len = len as usize;
+            if len < 4 {
+                return None;
+            }
+            if ty == RTA_OIF {
+                return u32_at(payload, offset + 4);
+            }
+            offset += align(len);
+        }
+        None
+    }
+
+    /// Whether the netlink messages in `buf` change anything besides interface `ignore_index`.
+    pub(super) fn is_relevant(buf: &[u8], ignore_index: u32) -> bool {
+        let mut offset = 0;
+        while let (Some(len), Some(ty)) = (u32_at(buf, offset), u16_at(buf, offset + 4)) {
+            let len = len as usize;
+            if len < NLMSG_HDRLEN || offset + len > buf.len() {
+                break;
+            }
+            let payload = &buf[offset + NLMSG_HDRLEN..offset + len];
+            let index = match ty {
+                // `ifi_index` of `struct ifinfomsg` and `ifa_index` of `struct ifaddrmsg`.
+                RTM_NEWLINK | RTM_DELLINK | RTM_NEWADDR | RTM_DELADDR => u32_at(payload, 4),
+                RTM_NEWROUTE | RTM_DELROUTE => route_interface(payload),
+                _ => {
+                    offset += align(len);
+                    continue;
+                }
+            };
+            if index != Some(ignore_index) {
+                return true;
+            }
+            offset += align(len);
+        }
+        false
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn message(ty: u16, payload: &[u8]) -> Vec<u8> {
+            let mut buf = vec![];
+            buf.extend(((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+            buf.extend(ty.to_ne_bytes());
+            buf.extend([0; 10]);
+            buf.extend(payload);
+            buf
+        }
+
+        fn route(oif: u32) -> Vec<u8> {
+            let mut payload = vec![0; RTMSG_LEN];
+            payload.extend(8u16.to_ne_bytes());
+            payload.extend(RTA_OIF.to_ne_bytes());
+            payload.extend(oif.to_ne_bytes());
+            message(RTM_NEWROUTE, &payload)
+        }
+
+        #[test]
+        fn test_is_relevant() {
+            let mut link = vec![0; 16];
+            link[4..8].copy_from_slice(&7u32.to_ne_bytes());
+            assert!(!is_relevant(&message(RTM_NEWLINK, &link), 7));
+            assert!(is_relevant(&message(RTM_NEWLINK, &link), 8));
+            assert!(is_relevant(&message(RTM_DELADDR, &link), 8));
+
+            assert!(!is_relevant(&route(7), 7));
+            assert!(is_relevant(&route(2), 7));
+            let mut both = route(7);
+            both.extend(route(2));
+            assert!(is_relevant(&both, 7));
+
+            assert!(!is_relevant(&message(3, &[]), 7));
+            assert!(!is_relevant(&[1, 2], 7));
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod sys {
+    use std::io;
+
+    pub(super) fn open_socket() -> io::Result<libc::c_int> {
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        Ok(fd)
+    }
+
+    /// Whether the routing message in `buf` is about an interface other than `ignore_index`.
+    pub(super) fn is_relevant(buf: &[u8], ignore_index: u32) -> bool {
+        let Some(&ty) = buf.get(3) else {
+            return false;
+        };
+        // `rtm_index` of `struct rt_msghdr`, `ifm_index`/`ifam_index` of the interface messages.
+        let offset = match ty as libc::c_int {
+            libc::RTM_ADD | libc::RTM_DELETE | libc::RTM_CHANGE => 4,
+            libc::RTM_NEWADDR | libc::RTM_DELADDR | libc::RTM_IFINFO => 12,
+            _ => return false,
+        };
+        match buf.get(offset..offset + 2) {
+            Some(index) => u16::from_ne_bytes([index[0], index[1]]) as u32 != ignore_index,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use super::sys::{add_route, delete_route};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Routes sending traffic of `cidrs` to the TUN device, removed when dropped.
+///
+/// The routes are recorded in `state_path` while they exist. If seeker crashes before removing
+/// them, the next start removes the recorded routes before adding its own.
+pub struct RouteSetup {
+    tun_name: String,
+    gateway: String,
+    cidrs: Vec<String>,
+    state_path: PathBuf,
+}
+
+impl RouteSetup {
+    pub fn new(
+        tun_name: String,
+        gateway: String,
+        cidrs: Vec<String>,
+        state_path: impl Into<PathBuf>,
+    ) -> Self {
+        RouteSetup {
+            tun_name,
+            gateway,
+            cidrs,
+            state_path: state_path.into(),
+        }
+    }
+
+    pub fn start(&self) -> std::io::Result<()> {
+        cleanup_stale_routes(&self.state_path);
+        // Recorded before being added, so a crash in between doesn't leak any of them.
+        std::fs::write(&self.state_path, self.state())?;
+        for cidr in &self.cidrs {
+            info!(
+                "Add route {} via {} dev {}",
+                cidr, self.gateway, self.tun_name
+            );
+            add_route(cidr, &self.gateway, &self.tun_name)?;
+        }
+        Ok(())
+    }
+
+    /// Add the routes again, eg. when they are flushed after the network changed.
+    pub fn reapply(&self) -> std::io::Result<()> {
+        for cidr in &self.cidrs {
+            add_route(cidr, &self.gateway, &self.tun_name)?;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> String {
+        self.cidrs
+            .iter()
+            .map(|cidr| format!("{} {} {}\n", self.tun_name, self.gateway, cidr))
+            .collect()
+    }
+}
+
+impl Drop for RouteSetup {
+    fn drop(&mut self) {
+        for cidr in &self.cidrs {
+            info!("Remove route {}", cidr);
+            if let Err(e) = delete_route(cidr, &self.gateway, &self.tun_name) {
+                warn!("Remove route {} error: {}", cidr, e);
+            }
+        }
+        let _ = std::fs::remove_file(&self.state_path);
+    }
+}
+
+/// Remove the routes recorded by a previous run that didn't exit cleanly.
+fn cleanup_stale_routes(state_path: &Path) {
+    let Ok(content) = std::fs::read_to_string(state_path) else {
+        return;
+    };
+    for (tun_name, gateway, cidr) in parse_state(&content) {
+        info!(
+            "Remove stale route {} via {} dev {}",
+            cidr, gateway, tun_name
+        );
+        // The route is already gone if the TUN device was destroyed.
+        let _ = delete_route(cidr, gateway, tun_name);
+    }
+    let _ = std::fs::remove_file(state_path);
+}
+
+fn parse_state(content: &str) -> impl Iterator<Item = (&str, &str, &str)> {
+    content.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        Some((fields.next()?, fields.next()?, fields.next()?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let setup = RouteSetup::new(
+            "utun4".to_string(),
+            "11.0.0.1".to_string(),
+            vec!["11.0.0.0/16".to_string(), "8.8.8.0/24".to_string()],
+            "seeker.routes",
+        );
+        let state = setup.state();
+        // Nothing was added, so dropping must not try to remove the recorded routes.
+        std::mem::forget(setup);
+        assert_eq!(
+            parse_state(&state).collect::<Vec<_>>(),
+            vec![
+                ("utun4", "11.0.0.1", "11.0.0.0/16"),
+                ("utun4", "11.0.0.1", "8.8.8.0/24")
+            ]
+        );
+        assert_eq!(parse_state("utun4 11.0.0.1\n").count(), 0);
+    }
+}
@@ -0,0 +1,217 @@
+//! NAT for traffic forwarded from LAN devices in gateway mode, and the kill switch.
+use crate::command::{try_run_cmd, try_run_cmd_with_input};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+const TABLE: &str = "seeker";
+const KILL_SWITCH_TABLE: &str = "seeker_killswitch";
+/// Sets of the kill switch table holding the allowed addresses.
+const ALLOWED_V4: &str = "allowed_v4";
+const ALLOWED_V6: &str = "allowed_v6";
+/// Private, link local and multicast ranges reachable with `allow_lan`.
+const LAN_V4: &str = "10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, 169.254.0.0/16, 224.0.0.0/4, \
+                      255.255.255.255";
+const LAN_V6: &str = "fc00::/7, fe80::/10, ff00::/8";
+/// Marks packets forwarded from LAN devices to the physical interfaces.
+const FORWARD_MARK: u32 = 0x5eeb;
+
+/// Masquerades traffic LAN devices send through this machine to addresses not routed into the TUN
+/// device, eg. direct connections to real IPs, so replies come back through this machine instead
+/// of going to the devices from the router directly.
+///
+/// The rules live in their own nftables table, which is removed when dropped, and replaced on
+/// start if a crash left it behind.
+pub struct NatSetup {
+    tun_name: String,
+}
+
+impl NatSetup {
+    pub fn new(tun_name: String) -> Self {
+        NatSetup { tun_name }
+    }
+
+    pub fn start(&self) -> std::io::Result<()> {
+        if try_run_cmd("nft", &["delete", "table", "ip", TABLE]).is_ok() {
+            info!("Removed nftables table {} left by a previous run", TABLE);
+        }
+        info!("Setup nftables table {}", TABLE);
+        let _ = try_run_cmd_with_input("nft", &["-f", "/dev/stdin"], &self.ruleset())?;
+        Ok(())
+    }
+
+    fn ruleset(&self) -> String {
+        let tun = &self.tun_name;
+        format!(
+            r#"table ip {TABLE} {{
+    chain forward {{
+        type filter hook forward priority 0; policy accept;
+        iifname != "{tun}" oifname != "{tun}" meta mark set {FORWARD_MARK:#x}
+    }}
+    chain postrouting {{
+        type nat hook postrouting priority 100; policy accept;
+        meta mark {FORWARD_MARK:#x} masquerade
+    }}
+}}
+"#
+        )
+    }
+}
+
+impl Drop for NatSetup {
+    fn drop(&mut self) {
+        info!("Remove nftables table {}", TABLE);
+        if let Err(e) = try_run_cmd("nft", &["delete", "table", "ip", TABLE]) {
+            warn!("Remove nftables table {} error: {}", TABLE, e);
+        }
+    }
+}
+
+/// Drops traffic leaving through any interface other than loopback and the TUN device, except to
+/// `allowed` addresses (the servers and DNS upstreams), so nothing leaks if a rule sends traffic
+/// direct by mistake. Forwarded traffic of LAN devices is dropped the same way.
+///
+/// The table is removed when dropped, but deliberately kept if seeker crashes. The next start
+/// replaces it, or remove it with `nft delete table inet seeker_killswitch`.
+pub struct KillSwitch {
+    tun_name: String,
+    allowed: Vec<IpAddr>,
+    /// Addresses added by [`Self::allow`] since it started.
+    added: Mutex<HashSet<IpAddr>>,
+    allow_lan: bool,
+}
+
+impl KillSwitch {
+    pub fn new(tun_name: String, allowed: Vec<IpAddr>, allow_lan: bool) -> Self {
+        KillSwitch {
+            tun_name,
+            allowed,
+            added: Default::default(),
+            allow_lan,
+        }
+    }
+
+    /// Let traffic to `ip` through too, eg. a new address of a server domain. Addresses allowed
+    /// already are skipped.
+    pub fn allow(&self, ip: IpAddr) -> std::io::Result<()> {
+        if self.allowed.contains(&ip) || !self.added.lock().unwrap().insert(ip) {
+            return Ok(());
+        }
+        info!("Allow {} through the kill switch", ip);
+        let set = if ip.is_ipv4() { ALLOWED_V4 } else { ALLOWED_V6 };
+        let addr = ip.to_string();
+        let args = [
+            "add",
+            "element",
+            "inet",
+            KILL_SWITCH_TABLE,
+            set,
+            "{",
+            &addr,
+            "}",
+        ];
+        if let Err(e) = try_run_cmd("nft", &args) {
+            // Retried the next time it's resolved.
+            self.added.lock().unwrap().remove(&ip);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn start(&self) -> std::io::Result<()> {
+        let _ = try_run_cmd("nft", &["delete", "table", "inet", KILL_SWITCH_TABLE]);
+        info!(
+            "Setup kill switch, allowed destinations: {:?}",
+            self.allowed
+        );
+        let _ = try_run_cmd_with_input("nft", &["-f", "/dev/stdin"], &self.ruleset())?;
+        Ok(())
+    }
+
+    fn ruleset(&self) -> String {
+        let tun = &self.tun_name;
+        let join = |ips: &[&IpAddr]| {
+            ips.iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let (v4, v6): (Vec<_>, Vec<_>) = self.allowed.iter().partition(|ip| ip.is_ipv4());
+        let elements = |ips: &[&IpAddr]| match ips {
+            [] => String::new(),
+            ips => format!(" elements = {{ {} }};", join(ips)),
+        };
+        let (v4, v6) = (elements(&v4), elements(&v6));
+        let mut lan = String::new();
+        if self.allow_lan {
+            lan.push_str(&format!("        ip daddr {{ {LAN_V4} }} accept\n"));
+            lan.push_str(&format!("        ip6 daddr {{ {LAN_V6} }} accept\n"));
+        }
+        format!(
+            r#"table inet {KILL_SWITCH_TABLE} {{
+    set {ALLOWED_V4} {{ type ipv4_addr;{v4} }}
+    set {ALLOWED_V6} {{ type ipv6_addr;{v6} }}
+    chain output {{
+        type filter hook output priority 0; policy drop;
+        oifname "lo" accept
+        oifname "{tun}" accept
+        ct state established,related accept
+        udp sport 68 udp dport 67 accept
+        icmpv6 type {{ nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert }} accept
+        ip daddr @{ALLOWED_V4} accept
+        ip6 daddr @{ALLOWED_V6} accept
+{lan}    }}
+    chain forward {{
+        type filter hook forward priority 0; policy drop;
+        iifname "{tun}" accept
+        oifname "{tun}" accept
+        ct state established,related accept
+{lan}    }}
+}}
+"#
+        )
+    }
+}
+
+impl Drop for KillSwitch {
+    fn drop(&mut self) {
+        info!("Remove kill switch");
+        if let Err(e) = try_run_cmd("nft", &["delete", "table", "inet", KILL_SWITCH_TABLE]) {
+            warn!("Remove kill switch error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        let setup = std::mem::ManuallyDrop::new(NatSetup::new("utun4".to_string()));
+        let ruleset = setup.ruleset();
+        assert!(ruleset.starts_with("table ip seeker {"));
+        assert!(ruleset.contains(r#"iifname != "utun4" oifname != "utun4" meta mark set 0x5eeb"#));
+        assert!(ruleset.contains("meta mark 0x5eeb masquerade"));
+    }
+
+    #[test]
+    fn test_kill_switch_ruleset() {
+        let allowed = vec!["1.2.3.4".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        let setup =
+            std::mem::ManuallyDrop::new(KillSwitch::new("utun4".to_string(), allowed, false));
+        let ruleset = setup.ruleset();
+        assert!(ruleset.contains("policy drop;"));
+        assert!(ruleset.contains(r#"oifname "utun4" accept"#));
+        assert!(ruleset.contains("set allowed_v4 { type ipv4_addr; elements = { 1.2.3.4 }; }"));
+        assert!(ruleset.contains("set allowed_v6 { type ipv6_addr; elements = { 2001:db8::1 }; }"));
+        assert!(ruleset.contains("ip daddr @allowed_v4 accept"));
+        assert!(!ruleset.contains("192.168.0.0/16"));
+
+        let setup = std::mem::ManuallyDrop::new(KillSwitch::new("utun4".to_string(), vec![], true));
+        let ruleset = setup.ruleset();
+        assert!(ruleset.contains("set allowed_v4 { type ipv4_addr; }"));
+        assert!(ruleset.contains("192.168.0.0/16"));
+    }
+}
@@ -0,0 +1,132 @@
+//! Switch to an unprivileged user once the TUN device, routes and DNS are set up.
+use std::ffi::CString;
+use std::io;
+
+/// Capabilities that can be kept, see capabilities(7).
+pub const CAP_DAC_OVERRIDE: u32 = 1;
+pub const CAP_NET_ADMIN: u32 = 12;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Switch to `user` and `group`, defaulting to the primary group of the user, keeping only the
+/// `keep` capabilities.
+///
+/// Capabilities are kept by the calling thread only, and are also raised as ambient ones so
+/// commands it runs, eg. `ip route del` when restoring the system config, get them too. Threads
+/// already running lose all of them.
+pub fn drop_privileges(user: &str, group: Option<&str>, keep: &[u32]) -> io::Result<()> {
+    let (uid, user_gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => user_gid,
+    };
+
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) })?;
+    check(unsafe { libc::setgroups(1, &gid) })?;
+    check(unsafe { libc::setgid(gid) })?;
+    check(unsafe { libc::setuid(uid) })?;
+
+    let mut data = [CapData::default(); 2];
+    for cap in keep {
+        let d = &mut data[*cap as usize / 32];
+        let bit = 1 << (cap % 32);
+        d.effective |= bit;
+        d.permitted |= bit;
+        d.inheritable |= bit;
+    }
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapHeader,
+            data.as_ptr(),
+        )
+    } as libc::c_int)?;
+    for cap in keep {
+        check(unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                *cap as libc::c_ulong,
+                0,
+                0,
+            )
+        })?;
+    }
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) })?;
+    Ok(())
+}
+
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    if let Ok(uid) = user.parse::<libc::uid_t>() {
+        let passwd = unsafe { libc::getpwuid(uid) };
+        let gid = if passwd.is_null() {
+            uid
+        } else {
+            unsafe { (*passwd).pw_gid }
+        };
+        return Ok((uid, gid));
+    }
+    let name = CString::new(user)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("user {user} not found"),
+        ));
+    }
+    Ok(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) })
+}
+
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("group {group} not found"),
+        ));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_user("0").unwrap(), (0, 0));
+        assert!(lookup_user("no-such-user-for-seeker").is_err());
+        assert_eq!(lookup_group("0").unwrap(), 0);
+        assert!(lookup_group("no-such-group-for-seeker").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Paths of cgroup v2 ids. The id of a cgroup is the inode number of its directory under the
+//! cgroup2 mount, so the paths are found by walking the hierarchy. The result of a walk is kept
+//! until an unknown id is looked up, cgroups are created far less often than connections.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+static PATHS: Mutex<Option<HashMap<u64, String>>> = Mutex::new(None);
+
+/// Path of cgroup `id` relative to the root of the hierarchy, eg.
+/// `user.slice/user-1000.slice/app-firefox.scope`. The root cgroup is the empty path.
+pub fn cgroup_path(id: u64) -> io::Result<Option<String>> {
+    let mut paths = PATHS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(path) = paths.as_ref().and_then(|paths| paths.get(&id)) {
+        return Ok(Some(path.clone()));
+    }
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let Some(root) = cgroup2_mount(&mountinfo) else {
+        return Ok(None);
+    };
+    let mut walked = HashMap::new();
+    walk(Path::new(root), "", &mut walked)?;
+    let path = walked.get(&id).cloned();
+    *paths = Some(walked);
+    Ok(path)
+}
+
+fn walk(dir: &Path, path: &str, paths: &mut HashMap<u64, String>) -> io::Result<()> {
+    paths.insert(fs::metadata(dir)?.ino(), path.to_string());
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let child = if path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{path}/{name}")
+        };
+        // The cgroup may be removed while walking.
+        let _ = walk(&entry.path(), &child, paths);
+    }
+    Ok(())
+}
+
+/// Mount point of the cgroup2 filesystem in `/proc/self/mountinfo`.
+fn cgroup2_mount(mountinfo: &str) -> Option<&str> {
+    mountinfo.lines().find_map(|line| {
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split(' ').next()? != "cgroup2" {
+            return None;
+        }
+        mount.split(' ').nth(4)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgroup2_mount() {
+        let mountinfo = "\
+35 24 0:30 / /sys/fs/cgroup/cpu rw,relatime shared:10 - cgroup cgroup rw,cpu
+36 24 0:31 / /sys/fs/cgroup/unified rw,relatime shared:11 - cgroup2 cgroup2 rw
+";
+        assert_eq!(cgroup2_mount(mountinfo), Some("/sys/fs/cgroup/unified"));
+        assert_eq!(cgroup2_mount("35 24 0:30 / /sys rw - sysfs sysfs rw"), None);
+    }
+}
@@ -1,36 +1,38 @@
 #![allow(dead_code)]
-use super::SocketInfo;
+use super::{ProcessSockets, SocketInfo};
 use libproc::libproc::proc_pid::{
-    listpidinfo, listpids, pidfdinfo, InSockInfo, ListFDs, ProcFDType, ProcType, SocketFDInfo,
-    SocketInfoKind,
+    listpidinfo, listpids, name, pidfdinfo, InSockInfo, ListFDs, ProcFDType, ProcType,
+    SocketFDInfo, SocketInfoKind,
 };
-use std::collections::HashMap;
 use std::io::Result;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
-pub fn list_system_proc_socks() -> Result<HashMap<i32, Vec<SocketInfo>>> {
-    let pids = listpids(ProcType::ProcAllPIDS, 0)?;
-    let mut pid_sockaddr_map = HashMap::new();
-    for pid in pids {
-        let pid = pid as i32;
-        pid_sockaddr_map.insert(pid, list_sockaddr(pid)?);
-    }
+pub fn list_system_proc_socks() -> Result<Vec<ProcessSockets>> {
+    list_proc_socks(listpids(ProcType::ProcAllPIDS, 0)?)
+}
 
-    Ok(pid_sockaddr_map)
+pub fn list_user_proc_socks(uid: u32) -> Result<Vec<ProcessSockets>> {
+    list_proc_socks(listpids(ProcType::ProcUIDOnly, uid)?)
 }
 
-pub fn list_user_proc_socks(uid: u32) -> Result<HashMap<i32, Vec<SocketInfo>>> {
-    let pids = listpids(ProcType::ProcUIDOnly, uid)?;
-    let mut pid_sockaddr_map = HashMap::new();
+fn list_proc_socks(pids: Vec<u32>) -> Result<Vec<ProcessSockets>> {
+    let mut procs = vec![];
     for pid in pids {
         let pid = pid as i32;
-        let socket_infos = list_sockaddr(pid)?;
-        if !socket_infos.is_empty() {
-            pid_sockaddr_map.insert(pid, socket_infos);
+        // The process may exit while being listed.
+        let Ok(sockets) = list_sockaddr(pid) else {
+            continue;
+        };
+        if sockets.is_empty() {
+            continue;
         }
+        procs.push(ProcessSockets {
+            pid,
+            name: name(pid).unwrap_or_default(),
+            sockets,
+        });
     }
-
-    Ok(pid_sockaddr_map)
+    Ok(procs)
 }
 
 fn list_sockaddr(pid: i32) -> Result<Vec<SocketInfo>> {
@@ -109,9 +111,11 @@ mod test {
         let uid = unsafe { libc::getuid() };
         let _socket = std::net::TcpListener::bind("0.0.0.0:8888").unwrap();
         let s = list_user_proc_socks(uid).unwrap();
-        assert!(s
-            .values()
-            .find(|sockets| sockets.iter().find(|s| s.local.port() == 8888).is_some())
-            .is_some());
+        let p = s
+            .iter()
+            .find(|p| p.sockets.iter().any(|s| s.local.port() == 8888))
+            .unwrap();
+        assert_eq!(p.pid, std::process::id() as i32);
+        assert!(!p.name.is_empty());
     }
 }
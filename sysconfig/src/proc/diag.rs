@@ -0,0 +1,221 @@
+//! Owner of a tcp socket through `NETLINK_SOCK_DIAG`. The kernel looks the socket up by its
+//! address and port pair, so it's one request however many processes are running, instead of
+//! reading the fds of every process in /proc.
+//!
+//! The reply also carries the id of the cgroup v2 the socket was created in (linux 5.7+), which
+//! is the inode number of the cgroup directory.
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+const NETLINK_SOCK_DIAG: libc::c_int = 4;
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLMSG_HDRLEN: usize = 16;
+/// Size of `struct inet_diag_req_v2`.
+const REQ_LEN: usize = 56;
+/// Offset of `idiag_uid` in `struct inet_diag_msg`.
+const UID_OFFSET: usize = 64;
+/// Size of `struct inet_diag_msg`, followed by the attributes.
+const MSG_LEN: usize = 72;
+const INET_DIAG_CGROUP_ID: u16 = 21;
+const INET_DIAG_NOCOOKIE: u32 = !0;
+const ALL_STATES: u32 = !0;
+
+/// The uid owning the tcp socket from `local` to `remote`, None if there is no such socket.
+pub fn socket_uid(local: SocketAddr, remote: SocketAddr) -> io::Result<Option<u32>> {
+    Ok(query(local, remote)?.and_then(|msg| u32_at(&msg, UID_OFFSET)))
+}
+
+/// The cgroup v2 id of the tcp socket from `local` to `remote`, None if there is no such socket
+/// or the kernel doesn't report it.
+pub fn socket_cgroup_id(local: SocketAddr, remote: SocketAddr) -> io::Result<Option<u64>> {
+    Ok(query(local, remote)?.and_then(|msg| cgroup_id(&msg)))
+}
+
+/// The `struct inet_diag_msg` and attributes of the socket.
+fn query(local: SocketAddr, remote: SocketAddr) -> io::Result<Option<Vec<u8>>> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            NETLINK_SOCK_DIAG,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let req = request(local, remote)?;
+    if unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            req.as_ptr() as *const libc::c_void,
+            req.len(),
+            0,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = [0u8; 1024];
+    let size = loop {
+        let size = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if size >= 0 {
+            break size as usize;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+    parse_response(&buf[..size])
+}
+
+fn request(local: SocketAddr, remote: SocketAddr) -> io::Result<Vec<u8>> {
+    let family = match (local.ip(), remote.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => libc::AF_INET,
+        (IpAddr::V6(_), IpAddr::V6(_)) => libc::AF_INET6,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "addresses of different families",
+            ))
+        }
+    };
+    let mut buf = Vec::with_capacity(NLMSG_HDRLEN + REQ_LEN);
+    buf.extend(((NLMSG_HDRLEN + REQ_LEN) as u32).to_ne_bytes());
+    buf.extend(SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    buf.extend(NLM_F_REQUEST.to_ne_bytes());
+    // Sequence number and port id.
+    buf.extend([0; 8]);
+
+    buf.push(family as u8);
+    buf.push(libc::IPPROTO_TCP as u8);
+    // Extensions and padding.
+    buf.extend([0; 2]);
+    buf.extend(ALL_STATES.to_ne_bytes());
+    // `struct inet_diag_sockid`, ports and addresses are in network order.
+    buf.extend(local.port().to_be_bytes());
+    buf.extend(remote.port().to_be_bytes());
+    for addr in [local.ip(), remote.ip()] {
+        let mut bytes = [0; 16];
+        match addr {
+            IpAddr::V4(ip) => bytes[..4].copy_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => bytes.copy_from_slice(&ip.octets()),
+        }
+        buf.extend(bytes);
+    }
+    // Any interface.
+    buf.extend(0u32.to_ne_bytes());
+    buf.extend(INET_DIAG_NOCOOKIE.to_ne_bytes());
+    buf.extend(INET_DIAG_NOCOOKIE.to_ne_bytes());
+    Ok(buf)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn parse_response(buf: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid sock_diag response");
+    let len = u32_at(buf, 0).ok_or_else(invalid)? as usize;
+    let ty = buf
+        .get(4..6)
+        .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+        .ok_or_else(invalid)?;
+    match ty {
+        NLMSG_ERROR => match u32_at(buf, NLMSG_HDRLEN).ok_or_else(invalid)? as i32 {
+            0 => Ok(None),
+            e if -e == libc::ENOENT => Ok(None),
+            e => Err(io::Error::from_raw_os_error(-e)),
+        },
+        SOCK_DIAG_BY_FAMILY if len >= NLMSG_HDRLEN + MSG_LEN && len <= buf.len() => {
+            Ok(Some(buf[NLMSG_HDRLEN..len].to_vec()))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// The `INET_DIAG_CGROUP_ID` attribute of the message.
+fn cgroup_id(msg: &[u8]) -> Option<u64> {
+    let mut offset = MSG_LEN;
+    while let Some(header) = msg.get(offset..offset + 4) {
+        let len = u16::from_ne_bytes([header[0], header[1]]) as usize;
+        let ty = u16::from_ne_bytes([header[2], header[3]]);
+        if len < 4 {
+            return None;
+        }
+        if ty == INET_DIAG_CGROUP_ID {
+            return Some(u64::from_ne_bytes(
+                msg.get(offset + 4..offset + 12)?.try_into().ok()?,
+            ));
+        }
+        offset += (len + 3) & !3;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_socket_uid() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let local = stream.local_addr().unwrap();
+        let remote = stream.peer_addr().unwrap();
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(socket_uid(local, remote).unwrap(), Some(uid));
+        if let Some(id) = socket_cgroup_id(local, remote).unwrap() {
+            assert!(id > 0);
+        }
+
+        drop(stream);
+        drop(listener);
+        let unused: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert_eq!(socket_uid(unused, remote).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut error = vec![0; NLMSG_HDRLEN + 4];
+        let len = error.len() as u32;
+        error[..4].copy_from_slice(&len.to_ne_bytes());
+        error[4..6].copy_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        error[NLMSG_HDRLEN..].copy_from_slice(&(-libc::ENOENT).to_ne_bytes());
+        assert_eq!(parse_response(&error).unwrap(), None);
+        error[NLMSG_HDRLEN..].copy_from_slice(&(-libc::EPERM).to_ne_bytes());
+        assert!(parse_response(&error).is_err());
+
+        let mut msg = vec![0; NLMSG_HDRLEN + MSG_LEN];
+        msg[NLMSG_HDRLEN + UID_OFFSET..][..4].copy_from_slice(&1000u32.to_ne_bytes());
+        // An attribute before the cgroup id.
+        msg.extend(8u16.to_ne_bytes());
+        msg.extend(3u16.to_ne_bytes());
+        msg.extend([0; 4]);
+        msg.extend(12u16.to_ne_bytes());
+        msg.extend(INET_DIAG_CGROUP_ID.to_ne_bytes());
+        msg.extend(42u64.to_ne_bytes());
+        let len = msg.len() as u32;
+        msg[..4].copy_from_slice(&len.to_ne_bytes());
+        msg[4..6].copy_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+        let payload = parse_response(&msg).unwrap().unwrap();
+        assert_eq!(u32_at(&payload, UID_OFFSET), Some(1000));
+        assert_eq!(cgroup_id(&payload), Some(42));
+        assert_eq!(cgroup_id(&payload[..MSG_LEN]), None);
+        assert!(parse_response(&msg[..NLMSG_HDRLEN + 8]).is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use crate::SocketInfo;
-use procfs::process::FDTarget;
+use super::{ProcessSockets, SocketInfo};
+use procfs::process::{FDTarget, Process};
 use procfs::{ProcError, ProcResult};
 use std::collections::HashMap;
 use std::io::Result;
@@ -7,81 +7,58 @@ use std::io::Result;
 fn to_io_error(e: ProcError) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, e)
 }
-pub fn list_system_proc_socks() -> Result<HashMap<i32, Vec<SocketInfo>>> {
-    _list_system_proc_socks().map_err(to_io_error)
-}
-
-fn _list_system_proc_socks() -> ProcResult<HashMap<i32, Vec<SocketInfo>>> {
-    let all_procs = procfs::process::all_processes()?;
-
-    // build up a map between socket inodes and processes:
-    let mut map = HashMap::new();
-    for process in all_procs {
-        let process = process?;
-        let pid = process.pid();
-        for fd in process.fd()? {
-            if let FDTarget::Socket(inode) = fd?.target {
-                map.insert(inode, pid);
-            }
-        }
-    }
 
-    let mut socks_map = HashMap::new();
-    // get the tcp table
-    let tcp = procfs::net::tcp().unwrap();
-    let tcp6 = procfs::net::tcp6().unwrap();
-    for entry in tcp.into_iter().chain(tcp6) {
-        // find the process (if any) that has an open FD to this entry's inode
-        if let Some(pid) = map.get(&entry.inode) {
-            let item = socks_map.entry(*pid).or_insert(vec![]);
-            item.push(SocketInfo {
-                local: entry.local_address,
-                remote: entry.remote_address,
-            });
-        } else {
-        }
-    }
-    Ok(socks_map)
+pub fn list_system_proc_socks() -> Result<Vec<ProcessSockets>> {
+    list_proc_socks(|_| true).map_err(to_io_error)
 }
 
-pub fn list_user_proc_socks(expected_uid: u32) -> Result<HashMap<i32, Vec<SocketInfo>>> {
-    _list_user_proc_socks(expected_uid).map_err(to_io_error)
+pub fn list_user_proc_socks(expected_uid: u32) -> Result<Vec<ProcessSockets>> {
+    list_proc_socks(|p| matches!(p.uid(), Ok(uid) if uid == expected_uid)).map_err(to_io_error)
 }
 
-fn _list_user_proc_socks(expected_uid: u32) -> ProcResult<HashMap<i32, Vec<SocketInfo>>> {
-    let all_procs = procfs::process::all_processes()?;
-
+fn list_proc_socks(filter: impl Fn(&Process) -> bool) -> ProcResult<Vec<ProcessSockets>> {
     // build up a map between socket inodes and processes:
-    let mut map = HashMap::new();
-    for process in all_procs {
-        let p = process?;
-        let pid = p.pid();
-        if expected_uid != p.uid()? {
+    let mut inodes = HashMap::new();
+    let mut procs = vec![];
+    for process in procfs::process::all_processes()? {
+        // The process may exit while being listed.
+        let Ok(p) = process else {
+            continue;
+        };
+        if !filter(&p) {
             continue;
         }
-
-        for fd in p.fd()? {
-            if let FDTarget::Socket(inode) = fd?.target {
-                map.insert(inode, pid);
+        let Ok(fds) = p.fd() else {
+            continue;
+        };
+        let index = procs.len();
+        for fd in fds {
+            if let Ok(FDTarget::Socket(inode)) = fd.map(|fd| fd.target) {
+                inodes.insert(inode, index);
             }
         }
+        let name = p.stat().map(|stat| stat.comm).unwrap_or_default();
+        procs.push(ProcessSockets {
+            pid: p.pid(),
+            name,
+            sockets: vec![],
+        });
     }
-    let mut socks_map = HashMap::new();
+
     // get the tcp table
-    let tcp = procfs::net::tcp().unwrap();
-    let tcp6 = procfs::net::tcp6().unwrap();
+    let tcp = procfs::net::tcp()?;
+    let tcp6 = procfs::net::tcp6()?;
     for entry in tcp.into_iter().chain(tcp6) {
         // find the process (if any) that has an open FD to this entry's inode
-        if let Some(pid) = map.get(&entry.inode) {
-            let item = socks_map.entry(*pid).or_insert(vec![]);
-            item.push(SocketInfo {
+        if let Some(index) = inodes.get(&entry.inode) {
+            procs[*index].sockets.push(SocketInfo {
                 local: entry.local_address,
                 remote: entry.remote_address,
             });
-        } else {
         }
     }
-    Ok(socks_map)
+    procs.retain(|p| !p.sockets.is_empty());
+    Ok(procs)
 }
 
 #[cfg(test)]
@@ -94,8 +71,11 @@ mod test {
         let uid = unsafe { libc::getuid() };
         let _socket = std::net::TcpListener::bind("0.0.0.0:65532").unwrap();
         let s = list_user_proc_socks(uid).unwrap();
-        assert!(s
-            .values()
-            .any(|sockets| sockets.iter().any(|s| s.local.port() == 65532)));
+        let p = s
+            .iter()
+            .find(|p| p.sockets.iter().any(|s| s.local.port() == 65532))
+            .unwrap();
+        assert_eq!(p.pid, std::process::id() as i32);
+        assert!(!p.name.is_empty());
     }
 }
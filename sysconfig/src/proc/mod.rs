@@ -1,4 +1,10 @@
+use std::io::Result;
 use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "linux")]
+static SOCK_DIAG_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct SocketInfo {
@@ -6,10 +12,61 @@ pub struct SocketInfo {
     pub remote: SocketAddr,
 }
 
+/// Tcp sockets opened by a process.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProcessSockets {
+    pub pid: i32,
+    /// Name of the executable, truncated to 15 bytes on linux.
+    pub name: String,
+    pub sockets: Vec<SocketInfo>,
+}
+
+/// Whether the tcp socket from `local` to `remote` belongs to user `uid`.
+///
+/// On linux the owner is looked up with a single sock_diag request. Processes are scanned instead
+/// on other systems, or when the kernel doesn't support sock_diag for tcp.
+pub fn socket_belongs_to_user(uid: u32, local: SocketAddr, remote: SocketAddr) -> Result<bool> {
+    #[cfg(target_os = "linux")]
+    if !SOCK_DIAG_UNSUPPORTED.load(Ordering::Relaxed) {
+        match diag::socket_uid(local, remote) {
+            Ok(owner) => return Ok(owner == Some(uid)),
+            Err(e) => {
+                tracing::warn!(?e, "sock_diag unavailable, fallback to scanning /proc");
+                SOCK_DIAG_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = remote;
+    Ok(find_user_socket_owner(uid, local)?.is_some())
+}
+
+/// The cgroup v2 path of the tcp socket from `local` to `remote`, relative to the root of the
+/// hierarchy. None if there is no such socket, or cgroup v2 isn't available.
+#[cfg(target_os = "linux")]
+pub fn socket_cgroup(local: SocketAddr, remote: SocketAddr) -> Result<Option<String>> {
+    match diag::socket_cgroup_id(local, remote)? {
+        Some(id) => cgroup::cgroup_path(id),
+        None => Ok(None),
+    }
+}
+
+/// The process of user `uid` owning the tcp socket bound to `local`.
+pub fn find_user_socket_owner(uid: u32, local: SocketAddr) -> Result<Option<ProcessSockets>> {
+    Ok(sys::list_user_proc_socks(uid)?
+        .into_iter()
+        .find(|p| p.sockets.iter().any(|s| s.local == local)))
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup;
+#[cfg(target_os = "linux")]
+mod diag;
+
 #[cfg(target_os = "macos")]
 #[path = "darwin.rs"]
 pub mod sys;
 
-#[cfg(all(target_os = "linux"))]
+#[cfg(target_os = "linux")]
 #[path = "linux.rs"]
 pub mod sys;
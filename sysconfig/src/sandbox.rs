@@ -0,0 +1,323 @@
+//! Restrict what the process can do once it's set up, so a bug in the DNS or protocol decoders
+//! can't be used to take over the machine.
+//!
+//! Landlock limits the files that can be opened to the given paths. It applies to the calling
+//! thread and the threads and commands it starts afterwards. The seccomp filter denies syscalls
+//! seeker never makes, eg. loading kernel modules, tracing other processes and creating
+//! namespaces, and applies to all threads.
+#![cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    allow(dead_code)
+)]
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// The rights of ABI 1, from `EXECUTE` to `MAKE_SYM`.
+const ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+/// Rights that apply to files, others only to directories.
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Only allow reading and executing files under `read_only`, and also creating, writing and
+/// removing them under `read_write`. Paths that don't exist are skipped.
+///
+/// Returns false if the kernel doesn't support landlock, the process is left unrestricted then.
+pub fn restrict_filesystem(read_only: &[&Path], read_write: &[&Path]) -> io::Result<bool> {
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Ok(false),
+            _ => Err(e),
+        };
+    }
+    // Rights added later are only handled if the kernel knows them. Device ioctls (ABI 5) are
+    // left alone, the TUN device is configured with them.
+    let handled = ACCESS_FS_ABI_1
+        | if abi >= 2 { ACCESS_FS_REFER } else { 0 }
+        | if abi >= 3 { ACCESS_FS_TRUNCATE } else { 0 };
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset = check(unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    })?;
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as libc::c_int) };
+    let rules = read_only
+        .iter()
+        .map(|path| (path, ACCESS_READ))
+        .chain(read_write.iter().map(|path| (path, handled)));
+    for (path, access) in rules {
+        add_path_rule(&ruleset, path, access & handled)?;
+    }
+    set_no_new_privs()?;
+    check(unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) })?;
+    Ok(true)
+}
+
+fn add_path_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::NotFound {
+            return Ok(());
+        }
+        return Err(e);
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let access = if path.is_dir() {
+        access
+    } else {
+        access & ACCESS_FILE
+    };
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd.as_raw_fd(),
+    };
+    check(unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    })
+    .map_err(|e| io::Error::new(e.kind(), format!("landlock {}: {e}", path.display())))?;
+    Ok(())
+}
+
+const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_JMP_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets in `struct seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARG0_LOW: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+/// Syscalls of the x32 ABI, which has its own numbers.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// `CLONE_NEWNS` and the other `CLONE_NEW*` flags.
+const CLONE_NEW_NAMESPACES: u32 = 0x7e02_0000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+/// Syscalls denied with `EPERM`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_mut))]
+fn denied_syscalls() -> Vec<libc::c_long> {
+    let mut syscalls = vec![
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_acct,
+        libc::SYS_quotactl,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_adjtimex,
+        libc::SYS_sethostname,
+        libc::SYS_setdomainname,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_personality,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend([libc::SYS_iopl, libc::SYS_ioperm]);
+    syscalls
+}
+
+/// The seccomp filter program, see the module doc.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter() -> Vec<SockFilter> {
+    let mut filter = vec![
+        // Syscall numbers of other architectures mean something else.
+        stmt(BPF_LD_W_ABS, DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+    ]);
+    for nr in denied_syscalls() {
+        filter.extend([
+            jump(BPF_JMP_JEQ_K, nr as u32, 0, 1),
+            stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        ]);
+    }
+    // The flags of `clone3` are behind a pointer the filter can't read, libc falls back to
+    // `clone` on ENOSYS.
+    filter.extend([
+        jump(BPF_JMP_JEQ_K, libc::SYS_clone3 as u32, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        jump(BPF_JMP_JEQ_K, libc::SYS_clone as u32, 0, 3),
+        stmt(BPF_LD_W_ABS, DATA_ARG0_LOW),
+        jump(BPF_JMP_JSET_K, CLONE_NEW_NAMESPACES, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+    ]);
+    filter
+}
+
+/// Install the seccomp filter in all threads of the process.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn restrict_syscalls() -> io::Result<()> {
+    let filter = filter();
+    let prog = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+    set_no_new_privs()?;
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const SockFprog,
+        )
+    })?;
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn restrict_syscalls() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "seccomp filter is only supported on x86_64 and aarch64",
+    ))
+}
+
+/// Required to install the filters without `CAP_SYS_ADMIN`. Capabilities already held, eg. the
+/// ones kept by `drop_privileges`, are still passed to the commands run on exit.
+fn set_no_new_privs() -> io::Result<()> {
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } as libc::c_long)?;
+    Ok(())
+}
+
+fn check(ret: libc::c_long) -> io::Result<libc::c_long> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = filter();
+        assert!(filter.len() < u16::MAX as usize);
+        assert_eq!(filter.last().unwrap().k, SECCOMP_RET_ALLOW);
+        // Every jump lands inside the program.
+        for (i, f) in filter.iter().enumerate() {
+            if f.code & 0x07 == 0x05 {
+                assert!(i + 1 + (f.jt.max(f.jf) as usize) < filter.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Integration with systemd: readiness and watchdog notifications of `Type=notify` services, and
+//! sockets passed by socket activation. Everything is a no-op when not started by systemd.
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Send `state` (eg. `READY=1`) to the service manager.
+pub fn sd_notify(state: &str) -> io::Result<()> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_to(&path, state),
+        None => Ok(()),
+    }
+}
+
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract notify socket",
+            ))
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Interval in which `WATCHDOG=1` must be sent, if the service has `WatchdogSec` set.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn watchdog_interval_from(
+    usec: Option<&str>,
+    pid: Option<&str>,
+    self_pid: u32,
+) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != self_pid {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Take the file descriptors passed by socket activation. The environment variables are removed
+/// so they are not inherited by child processes, calling it again returns nothing.
+pub fn listen_fds() -> Vec<RawFd> {
+    let fds = listen_fds_from(
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_PID").ok().as_deref(),
+        std::process::id(),
+    );
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDNAMES");
+    for fd in &fds {
+        unsafe {
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    fds
+}
+
+fn listen_fds_from(fds: Option<&str>, pid: Option<&str>, self_pid: u32) -> Vec<RawFd> {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(self_pid) {
+        return vec![];
+    }
+    match fds.and_then(|fds| fds.parse::<RawFd>().ok()) {
+        Some(n) if n > 0 => (LISTEN_FDS_START..LISTEN_FDS_START + n).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_to() {
+        let path = env::temp_dir().join(format!("seeker-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let size = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            watchdog_interval_from(Some("2000000"), None, 1),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("2000000"), Some("1"), 1),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(watchdog_interval_from(Some("2000000"), Some("2"), 1), None);
+        assert_eq!(watchdog_interval_from(Some("0"), None, 1), None);
+        assert_eq!(watchdog_interval_from(None, None, 1), None);
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds_from(Some("2"), Some("7"), 7), vec![3, 4]);
+        assert_eq!(
+            listen_fds_from(Some("2"), Some("8"), 7),
+            Vec::<RawFd>::new()
+        );
+        assert_eq!(
+            listen_fds_from(Some("0"), Some("7"), 7),
+            Vec::<RawFd>::new()
+        );
+        assert_eq!(listen_fds_from(None, None, 7), Vec::<RawFd>::new());
+    }
+}
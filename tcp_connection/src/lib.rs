@@ -1,5 +1,6 @@
 mod obfs_http;
 mod obfs_tls;
+mod tcp_options;
 
 use async_std::{
     io::{Read, Write},
@@ -10,6 +11,9 @@ use dyn_clone::DynClone;
 use obfs_http::ObfsHttpTcpStream;
 use obfs_tls::ObfsTlsTcpStream;
 use serde::Deserialize;
+pub use tcp_options::{
+    connect, set_tcp_options, PortRange, PreparedSocket, SocketMark, TcpOptions,
+};
 
 use std::{
     fmt::Debug,
@@ -56,7 +60,7 @@ impl TcpConnection {
     }
 
     pub async fn connect_tcp(addr: SocketAddr) -> std::io::Result<Self> {
-        let conn = Box::new(TcpStream::connect(addr).await?);
+        let conn = Box::new(connect(addr).await?);
 
         Ok(TcpConnection { inner: conn })
     }
@@ -34,7 +34,7 @@ impl Connection for ObfsHttpTcpStream {}
 
 impl ObfsHttpTcpStream {
     pub(crate) async fn connect(addr: SocketAddr, host: String) -> std::io::Result<Self> {
-        let conn = TcpStream::connect(addr).await?;
+        let conn = crate::connect(addr).await?;
 
         Ok(ObfsHttpTcpStream {
             addr,
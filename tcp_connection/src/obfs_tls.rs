@@ -35,7 +35,7 @@ impl Connection for ObfsTlsTcpStream {}
 
 impl ObfsTlsTcpStream {
     pub(crate) async fn connect(addr: SocketAddr, host: String) -> std::io::Result<Self> {
-        let conn = TcpStream::connect(addr).await?;
+        let conn = crate::connect(addr).await?;
 
         Ok(Self {
             conn,
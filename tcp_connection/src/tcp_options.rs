@@ -0,0 +1,443 @@
+//! Socket options applied to all outgoing tcp connections, direct ones and ones to proxy servers.
+use async_io::Async;
+use async_std::net::TcpStream;
+use nanorand::{tls_rng, Rng};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::time::Duration;
+
+static TCP_OPTIONS: OnceCell<TcpOptions> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    pub nodelay: bool,
+    /// Send data of the first write in the SYN, only supported on linux.
+    pub fast_open: bool,
+    /// Idle time before sending keepalive probes, `None` disables keepalive.
+    pub keepalive: Option<Duration>,
+    /// Interval between keepalive probes.
+    pub keepalive_interval: Option<Duration>,
+    /// Idle time before sending keepalive probes on connections to proxy servers, instead of
+    /// `keepalive`. Shorter than the NAT timeout on the path, idle proxied connections and pooled
+    /// ones aren't dropped silently by NAT devices on the way.
+    pub server_keepalive: Option<Duration>,
+    /// TCP_NOTSENT_LOWAT, the unsent bytes queued before the socket stops being writable. Only
+    /// supported on linux and macos.
+    pub notsent_lowat: Option<u32>,
+    /// The local ports connections are made from, any port chosen by the kernel if `None`.
+    pub source_ports: Option<PortRange>,
+}
+
+/// An inclusive range of ports, `40000-40999` in config, or a single port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    fn len(&self) -> u32 {
+        u32::from(self.end - self.start) + 1
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid port range `{s}`, eg. 40000-40999"),
+            )
+        };
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+        if start == 0 || start > end {
+            return Err(invalid());
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            nodelay: true,
+            fast_open: false,
+            keepalive: None,
+            keepalive_interval: None,
+            server_keepalive: None,
+            notsent_lowat: None,
+            source_ports: None,
+        }
+    }
+}
+
+/// Marks set on the sockets of direct connections matched by a rule with `mark=` or `dscp=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct SocketMark {
+    /// SO_MARK, for policy routing by the firewall mark, only supported on linux.
+    pub fwmark: Option<u32>,
+    /// DSCP code point of the IP header, for QoS on the router.
+    pub dscp: Option<u8>,
+}
+
+impl SocketMark {
+    pub fn is_empty(&self) -> bool {
+        self.fwmark.is_none() && self.dscp.is_none()
+    }
+
+    /// Set the marks on `socket`, before connecting so the first packet is marked too.
+    pub fn apply<S: AsRawFd>(&self, socket: &S, ipv6: bool) -> Result<()> {
+        let socket = SockRef::from(socket);
+        #[cfg(target_os = "linux")]
+        if let Some(fwmark) = self.fwmark {
+            socket.set_mark(fwmark)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.fwmark.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "socket mark is only supported on linux",
+            ));
+        }
+        if let Some(dscp) = self.dscp {
+            // The DSCP is the high 6 bits of the TOS byte, the low 2 bits are ECN.
+            let tos = u32::from(dscp) << 2;
+            if ipv6 {
+                set_traffic_class(&socket, tos)?;
+            } else {
+                socket.set_tos(tos)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn set_traffic_class(socket: &Socket, tclass: u32) -> Result<()> {
+    let tclass = tclass as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &tclass as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&tclass) as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the options used by [`connect`]. Only the first call takes effect.
+pub fn set_tcp_options(options: TcpOptions) -> bool {
+    TCP_OPTIONS.set(options).is_ok()
+}
+
+fn tcp_options() -> TcpOptions {
+    TCP_OPTIONS.get().copied().unwrap_or_default()
+}
+
+/// Connect to the proxy server `addr` with the options set by [`set_tcp_options`].
+pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    PreparedSocket::with_options(addr.is_ipv6(), &server_options(tcp_options()))?
+        .connect(addr)
+        .await
+}
+
+/// The options of connections to proxy servers, their keepalive is `server_keepalive` if set.
+fn server_options(mut options: TcpOptions) -> TcpOptions {
+    if options.server_keepalive.is_some() {
+        options.keepalive = options.server_keepalive;
+    }
+    options
+}
+
+/// A socket with the options set by [`set_tcp_options`] applied, created before the address to
+/// connect is known, eg. while the domain is being resolved.
+pub struct PreparedSocket {
+    socket: Socket,
+    ipv6: bool,
+}
+
+impl PreparedSocket {
+    pub fn new(ipv6: bool) -> Result<Self> {
+        Self::with_options(ipv6, &tcp_options())
+    }
+
+    fn with_options(ipv6: bool, options: &TcpOptions) -> Result<Self> {
+        let domain = if ipv6 { Domain::IPV6 } else { Domain::IPV4 };
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+        apply(&socket, options)?;
+        if let Some(ports) = options.source_ports {
+            bind_source_port(&socket, ports, ipv6)?;
+        }
+        #[cfg(target_os = "linux")]
+        if options.fast_open {
+            set_fast_open_connect(&socket)?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(PreparedSocket { socket, ipv6 })
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.ipv6
+    }
+
+    pub fn set_mark(&self, mark: SocketMark) -> Result<()> {
+        mark.apply(&self.socket, self.ipv6)
+    }
+
+    /// Connect to `addr`, which must be of the address family of the socket.
+    pub async fn connect(self, addr: SocketAddr) -> Result<TcpStream> {
+        match self.socket.connect(&addr.into()) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) => return Err(e),
+        }
+        let stream = Async::new(std::net::TcpStream::from(self.socket))?;
+        stream.writable().await?;
+        if let Some(e) = stream.get_ref().take_error()? {
+            return Err(e);
+        }
+        Ok(TcpStream::from(stream.into_inner()?))
+    }
+}
+
+fn apply(socket: &Socket, options: &TcpOptions) -> Result<()> {
+    socket.set_nodelay(options.nodelay)?;
+    if let Some(time) = options.keepalive {
+        #[allow(unused_mut)]
+        let mut keepalive = TcpKeepalive::new().with_time(time);
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Some(interval) = options.keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    if let Some(lowat) = options.notsent_lowat {
+        set_notsent_lowat(socket, lowat)?;
+    }
+    Ok(())
+}
+
+/// Bind `socket` to a free port of `ports`, trying them in turn from a random one so concurrent
+/// connections don't all race for the first port.
+fn bind_source_port(socket: &Socket, ports: PortRange, ipv6: bool) -> Result<()> {
+    // Ports in TIME_WAIT can be reused, connect fails if the connection to the same address
+    // isn't over yet.
+    socket.set_reuse_address(true)?;
+    let offset = tls_rng().generate_range(0..ports.len());
+    for i in 0..ports.len() {
+        let port = ports.start + ((offset + i) % ports.len()) as u16;
+        let addr: SocketAddr = if ipv6 {
+            (Ipv6Addr::UNSPECIFIED, port).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, port).into()
+        };
+        match socket.bind(&addr.into()) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::new(
+        ErrorKind::AddrInUse,
+        format!("no free source port in {ports}"),
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_notsent_lowat(socket: &Socket, lowat: u32) -> Result<()> {
+    let lowat = lowat as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_NOTSENT_LOWAT,
+            &lowat as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&lowat) as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// With TCP_FASTOPEN_CONNECT, connect returns immediately and the SYN is sent with the data of
+/// the first write, falling back to a normal handshake if the server has no cookie yet.
+#[cfg(target_os = "linux")]
+fn set_fast_open_connect(socket: &Socket) -> Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enable as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+
+    #[async_std::test]
+    async fn test_apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let options = TcpOptions {
+            nodelay: true,
+            fast_open: false,
+            keepalive: Some(Duration::from_secs(30)),
+            keepalive_interval: Some(Duration::from_secs(5)),
+            server_keepalive: None,
+            notsent_lowat: Some(16 * 1024),
+            source_ports: None,
+        };
+        apply(&SockRef::from(&stream), &options).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn test_server_options() {
+        let options = TcpOptions {
+            keepalive: Some(Duration::from_secs(600)),
+            ..Default::default()
+        };
+        assert_eq!(server_options(options), options);
+        let options = TcpOptions {
+            server_keepalive: Some(Duration::from_secs(30)),
+            ..options
+        };
+        assert_eq!(
+            server_options(options).keepalive,
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_std::test]
+    async fn test_connect_fast_open() {
+        use async_std::prelude::*;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = TcpOptions {
+            fast_open: true,
+            ..Default::default()
+        };
+        let mut stream = PreparedSocket::with_options(false, &options)
+            .unwrap()
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[async_std::test]
+    async fn test_prepared_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = PreparedSocket::new(false).unwrap();
+        assert!(!socket.is_ipv6());
+        let stream = socket.connect(addr).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert!(stream.nodelay().unwrap());
+
+        // Nothing listens on the port after the listener is dropped.
+        drop(listener);
+        let socket = PreparedSocket::new(false).unwrap();
+        assert!(socket.connect(addr).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_source_ports() {
+        assert_eq!(
+            "40000-40999".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 40000,
+                end: 40999
+            }
+        );
+        assert_eq!("5000".parse::<PortRange>().unwrap().len(), 1);
+        for s in ["0-10", "2000-1000", "1000-", "a-b"] {
+            assert!(s.parse::<PortRange>().is_err(), "{s}");
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let options = TcpOptions {
+            source_ports: Some(PortRange {
+                start: port,
+                end: port,
+            }),
+            ..Default::default()
+        };
+        let stream = PreparedSocket::with_options(false, &options)
+            .unwrap()
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(stream.local_addr().unwrap().port(), port);
+    }
+
+    #[async_std::test]
+    async fn test_set_dscp() {
+        let mark = SocketMark {
+            fwmark: None,
+            dscp: Some(46),
+        };
+        let socket = PreparedSocket::new(false).unwrap();
+        socket.set_mark(mark).unwrap();
+        assert_eq!(socket.socket.tos().unwrap(), 46 << 2);
+        let udp = async_std::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        mark.apply(&udp, false).unwrap();
+        assert_eq!(SockRef::from(&udp).tos().unwrap(), 46 << 2);
+        assert!(SocketMark::default().is_empty());
+    }
+}
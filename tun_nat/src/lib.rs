@@ -1,26 +1,45 @@
+mod offload;
 mod tun_socket;
+#[cfg(target_os = "linux")]
+mod uring;
 
 use crate::tun_socket::TunSocket;
 use bitvec::vec::BitVec;
 use parking_lot::RwLock;
-use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Cidr, Ipv4Packet, TcpPacket, UdpPacket};
+use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket};
 use std::collections::HashMap;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::SystemTime;
-use sysconfig::setup_ip;
+use sysconfig::{set_mtu, setup_ip};
 
 const BEGIN_PORT: u16 = 50000;
 const END_PORT: u16 = 60000;
 const EXPIRE_SECONDS: u64 = 24 * 60 * 60;
+const DEFAULT_BUFFER_SIZE: usize = 2000;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+/// Max frames read from the TUN device per wakeup.
+const MAX_FRAMES_PER_WAKEUP: usize = 32;
+
+/// Decides whether tcp connections to a destination address and port are rejected. Their SYN
+/// segments are answered with a RST right away instead of being relayed.
+pub type SynFilter = Arc<dyn Fn(SocketAddrV4) -> bool + Send + Sync>;
 
 macro_rules! route_packet {
-    ($packet_ty: tt, $ipv4_packet: expr, $session_manager: expr, $relay_addr: expr, $relay_port: expr) => {{
+    ($packet_ty: tt, $ipv4_packet: expr, $session_manager: expr, $relay_addr: expr, $relay_port: expr, $partial_csum: expr) => {{
         let src_addr = $ipv4_packet.src_addr().into();
         let dest_addr = $ipv4_packet.dst_addr().into();
+        let protocol = u8::from($ipv4_packet.protocol());
+        let segment_len = $ipv4_packet.payload_mut().len();
         let mut packet = $packet_ty::new_checked($ipv4_packet.payload_mut()).unwrap();
         let src_port = packet.src_port();
         let dest_port = packet.dst_port();
@@ -46,10 +65,19 @@ macro_rules! route_packet {
         {
             packet.set_src_port(new_src_port);
             packet.set_dst_port(new_dest_port);
-            packet.fill_checksum(
-                &IpAddress::Ipv4(new_src_addr),
-                &IpAddress::Ipv4(new_dst_addr),
-            );
+            if $partial_csum {
+                packet.set_checksum(offload::pseudo_header_checksum(
+                    new_src_addr,
+                    new_dst_addr,
+                    protocol,
+                    segment_len,
+                ));
+            } else {
+                packet.fill_checksum(
+                    &IpAddress::Ipv4(new_src_addr),
+                    &IpAddress::Ipv4(new_dst_addr),
+                );
+            }
             $ipv4_packet.set_src_addr(new_src_addr);
             $ipv4_packet.set_dst_addr(new_dst_addr);
 
@@ -61,73 +89,410 @@ macro_rules! route_packet {
     }};
 }
 
+/// Lower the MSS option of a tcp SYN segment to `max_mss`. The checksum is not updated.
+fn clamp_tcp_mss(segment: &mut [u8], max_mss: u16) {
+    if segment.len() < 20 || segment[13] & TCP_FLAG_SYN == 0 {
+        return;
+    }
+    let header_len = ((segment[12] >> 4) as usize * 4).min(segment.len());
+    let mut i = 20;
+    while i < header_len {
+        match segment[i] {
+            TCP_OPTION_END => break,
+            TCP_OPTION_NOP => i += 1,
+            kind => {
+                let Some(&len) = segment.get(i + 1) else {
+                    break;
+                };
+                let len = len as usize;
+                if len < 2 || i + len > header_len {
+                    break;
+                }
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([segment[i + 2], segment[i + 3]]);
+                    if mss > max_mss {
+                        segment[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+                    }
+                }
+                i += len;
+            }
+        }
+    }
+}
+
+/// Whether the tcp segment is the SYN opening a connection.
+fn is_tcp_syn(segment: &[u8]) -> bool {
+    segment.len() >= 20 && segment[13] & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN
+}
+
+/// Turn the IPv4 packet in `buf` carrying a tcp SYN into the RST refusing the connection, in
+/// place. Its checksum is left partial if `partial_csum`. Returns the length of the RST packet.
+fn reset_syn(buf: &mut [u8], partial_csum: bool) -> Option<usize> {
+    let mut ipv4_packet = Ipv4Packet::new_checked(buf).ok()?;
+    let src_addr = ipv4_packet.src_addr();
+    let dst_addr = ipv4_packet.dst_addr();
+    let segment = ipv4_packet.payload_mut();
+    let data_offset = (*segment.get(12)? >> 4) as usize * 4;
+    if data_offset < 20 || data_offset > segment.len() {
+        return None;
+    }
+    let seq = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+    // SYN takes one sequence number, followed by the data of the segment if any.
+    let ack = seq
+        .wrapping_add(1)
+        .wrapping_add((segment.len() - data_offset) as u32);
+    let src_port = [segment[0], segment[1]];
+    segment.copy_within(2..4, 0);
+    segment[2..4].copy_from_slice(&src_port);
+    segment[4..8].copy_from_slice(&0u32.to_be_bytes());
+    segment[8..12].copy_from_slice(&ack.to_be_bytes());
+    segment[12] = 5 << 4;
+    segment[13] = TCP_FLAG_RST | TCP_FLAG_ACK;
+    // Window, checksum and urgent pointer
+    segment[14..20].fill(0);
+
+    let total_len = ipv4_packet.header_len() as usize + 20;
+    ipv4_packet.set_total_len(total_len as u16);
+    ipv4_packet.set_src_addr(dst_addr);
+    ipv4_packet.set_dst_addr(src_addr);
+    ipv4_packet.set_hop_limit(64);
+    ipv4_packet.fill_checksum();
+    let mut rst = TcpPacket::new_checked(ipv4_packet.payload_mut()).ok()?;
+    if partial_csum {
+        rst.set_checksum(offload::pseudo_header_checksum(
+            dst_addr,
+            src_addr,
+            IpProtocol::Tcp.into(),
+            20,
+        ));
+    } else {
+        rst.fill_checksum(&IpAddress::Ipv4(dst_addr), &IpAddress::Ipv4(src_addr));
+    }
+    Some(total_len)
+}
+
+/// Rewrite the addresses and ports of the IPv4 packet in `buf` in place, between the original
+/// connection and the relay server. SYN segments to destinations rejected by `reject_syn` are
+/// replaced with the RST answering them. With `partial_csum` the tcp and udp checksums only
+/// cover the pseudo header, like the packets read with checksum offload. Returns the length of
+/// the packet to write back, or None if the packet should be dropped.
+fn translate_packet(
+    buf: &mut [u8],
+    session_manager: &RwLock<InnerSessionManager>,
+    relay_addr: Ipv4Addr,
+    relay_port: u16,
+    tcp_mss: Option<u16>,
+    reject_syn: Option<&SynFilter>,
+    partial_csum: bool,
+) -> Option<usize> {
+    let len = buf.len();
+    let mut ipv4_packet = match Ipv4Packet::new_checked(&mut *buf) {
+        Err(e) => {
+            eprint!("tun_nat: new packet error: {:?}", e);
+            return None;
+        }
+        Ok(p) => p,
+    };
+
+    match ipv4_packet.protocol() {
+        IpProtocol::Udp => route_packet!(
+            UdpPacket,
+            ipv4_packet,
+            session_manager,
+            relay_addr,
+            relay_port,
+            partial_csum
+        )
+        .map(|_| len),
+        IpProtocol::Tcp => {
+            if let Some(reject_syn) = reject_syn {
+                let dst_addr = ipv4_packet.dst_addr().into();
+                let segment = ipv4_packet.payload_mut();
+                if is_tcp_syn(segment)
+                    && reject_syn(SocketAddrV4::new(
+                        dst_addr,
+                        u16::from_be_bytes([segment[2], segment[3]]),
+                    ))
+                {
+                    return reset_syn(buf, partial_csum);
+                }
+            }
+            if let Some(tcp_mss) = tcp_mss {
+                // Checksum is filled when routing the packet.
+                clamp_tcp_mss(ipv4_packet.payload_mut(), tcp_mss);
+            }
+            route_packet!(
+                TcpPacket,
+                ipv4_packet,
+                session_manager,
+                relay_addr,
+                relay_port,
+                partial_csum
+            )
+            .map(|_| len)
+        }
+        _ => None,
+    }
+}
+
+/// Block until `fd` is ready for one of `events`, eg. `libc::POLLIN`.
+fn wait_for(fd: RawFd, events: libc::c_short) -> Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    loop {
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+            return Ok(());
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+fn set_non_blocking(fd: RawFd) -> Result<()> {
+    match unsafe { libc::fcntl(fd, libc::F_GETFL) } {
+        -1 => Err(Error::last_os_error()),
+        flags => match unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Read and write frames of `tun` until a read returns 0. After each wakeup all queued frames,
+/// up to `MAX_FRAMES_PER_WAKEUP`, are read before the translated ones are written back.
+/// `on_packet` returns the length of the translated packet to write, if it should be written.
+fn run_batched<T: AsRawFd>(
+    tun: &T,
+    buffer_size: usize,
+    mut on_packet: impl FnMut(&mut [u8]) -> Option<usize>,
+) -> Result<()>
+where
+    for<'a> &'a T: Read + Write,
+{
+    set_non_blocking(tun.as_raw_fd())?;
+    let mut io = tun;
+    let mut bufs: Vec<_> = (0..MAX_FRAMES_PER_WAKEUP)
+        .map(|_| buffer_pool::Buffer::new(buffer_size))
+        .collect();
+    let mut sizes = Vec::with_capacity(MAX_FRAMES_PER_WAKEUP);
+    loop {
+        wait_for(tun.as_raw_fd(), libc::POLLIN)?;
+        sizes.clear();
+        let mut eof = false;
+        while sizes.len() < bufs.len() {
+            match io.read(&mut bufs[sizes.len()]) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(size) => sizes.push(size),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut errors = 0;
+        let mut last_error = None;
+        for (buf, &size) in bufs.iter_mut().zip(&sizes) {
+            if let Some(len) = on_packet(&mut buf[..size]) {
+                if let Err(e) = write_packet(tun.as_raw_fd(), &mut io, &buf[..len]) {
+                    errors += 1;
+                    last_error = Some(e);
+                }
+            }
+        }
+        if let Some(e) = last_error {
+            tracing::warn!(errors, ?e, "tun_nat: write packet error");
+        }
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Write `packet` to the non blocking `io`, waiting for `fd` to be writable while the queue of
+/// the device is full.
+fn write_packet(fd: RawFd, mut io: impl Write, packet: &[u8]) -> Result<()> {
+    loop {
+        match io.write(packet) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => wait_for(fd, libc::POLLOUT)?,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Pin the current thread to one of the CPUs it may run on, picked by `index`.
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(index: usize) {
+    unsafe {
+        let mut allowed: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut allowed) != 0 {
+            return;
+        }
+        let cpus: Vec<usize> = (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &allowed))
+            .collect();
+        if cpus.is_empty() {
+            return;
+        }
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpus[index % cpus.len()], &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            tracing::warn!(e = ?Error::last_os_error(), "tun_nat: pin queue thread error");
+        }
+    }
+}
+
+/// Open the queues of the TUN device, a single one unless `queues` is more than 1. With
+/// `offload` the device is opened with checksum offload and TSO on linux, whether it could is
+/// returned along with the queues.
+fn open_tun(tun_name: &str, queues: usize, offload: bool) -> Result<(Vec<TunSocket>, bool)> {
+    #[cfg(target_os = "linux")]
+    if offload {
+        match TunSocket::new_offload(tun_name, queues) {
+            Ok(tuns) => return Ok((tuns, true)),
+            Err(e) => tracing::warn!(?e, "tun_nat: offload unavailable, fallback to plain frames"),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    if offload {
+        tracing::warn!("tun_nat: offload is only supported on linux");
+    }
+    Ok((open_plain_tun(tun_name, queues)?, false))
+}
+
+fn open_plain_tun(tun_name: &str, queues: usize) -> Result<Vec<TunSocket>> {
+    if queues <= 1 {
+        return Ok(vec![TunSocket::new(tun_name)?]);
+    }
+    #[cfg(target_os = "linux")]
+    return TunSocket::new_multi_queue(tun_name, queues);
+    #[cfg(not(target_os = "linux"))]
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "multiple TUN queues are only supported on linux",
+    ))
+}
+
+/// Translate packets of one queue of the TUN device until it's closed.
+fn run_queue(
+    tun: &TunSocket,
+    buffer_size: usize,
+    io_uring: bool,
+    mut on_packet: impl FnMut(&mut [u8]) -> Option<usize>,
+) {
+    #[cfg(target_os = "linux")]
+    if io_uring {
+        match uring::run(tun, buffer_size, &mut on_packet) {
+            Ok(()) => return,
+            Err(e) => {
+                tracing::warn!(?e, "tun_nat: io_uring unavailable, fallback to read/write")
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = io_uring;
+
+    match run_batched(tun, buffer_size, on_packet) {
+        Ok(()) => eprintln!("tun read return 0, exit now"),
+        Err(e) => eprintln!("tun_nat: read packet error: {:?}", e),
+    }
+}
+
+/// Create the TUN device and start translating packets between it and the relay server.
+///
+/// `mtu` is set on the TUN device if given, and the MSS of tcp SYN segments passing through is
+/// clamped to `tcp_mss` if given. With `io_uring` the device is read and written through
+/// io_uring on linux, falling back to plain read/write if the kernel doesn't support it.
+/// Connections to destinations rejected by `reject_syn` are reset at their SYN.
+///
+/// With more than one of `queues`, the device is opened with `IFF_MULTI_QUEUE` on linux and each
+/// queue is read by its own thread, pinned to a CPU. The returned handle finishes when all of
+/// them have stopped.
+///
+/// With `offload` on linux the device is opened with `IFF_VNET_HDR` and checksum offload and
+/// TSO are turned on, so the kernel passes tcp segments of up to 64KB at once without
+/// checksumming them. Plain frames are used if the kernel doesn't support it.
+#[allow(clippy::too_many_arguments)]
 pub fn run_nat(
     tun_name: &str,
     tun_ip: Ipv4Addr,
-    tun_cidr: Ipv4Cidr,
     relay_port: u16,
-    addition_cidrs: &[Ipv4Cidr],
+    mtu: Option<u16>,
+    tcp_mss: Option<u16>,
+    io_uring: bool,
+    queues: usize,
+    reject_syn: Option<SynFilter>,
+    offload: bool,
 ) -> Result<(SessionManager, JoinHandle<()>)> {
-    let mut tun = TunSocket::new(tun_name)?;
-    let tun_name = tun.name()?;
-    if cfg!(target_os = "macos") {
-        setup_ip(
-            &tun_name,
-            tun_ip.to_string().as_str(),
-            tun_cidr.to_string().as_str(),
-            addition_cidrs.iter().map(|cidr| cidr.to_string()).collect(),
-        );
-    } else {
-        setup_ip(
-            &tun_name,
-            tun_ip.to_string().as_str(),
-            tun_cidr.to_string().as_str(),
-            addition_cidrs.iter().map(|cidr| cidr.to_string()).collect(),
-        );
+    let (tuns, offload) = open_tun(tun_name, queues, offload)?;
+    let tun_name = tuns[0].name()?;
+    // Routes to the device are set up by the caller with `sysconfig::RouteSetup`.
+    setup_ip(&tun_name, tun_ip.to_string().as_str());
+
+    if let Some(mtu) = mtu {
+        set_mtu(&tun_name, mtu);
     }
 
     let relay_addr = tun_ip;
+    // Frames are at most the MTU of the device, the system default is used if not configured.
+    let device_mtu = mtu.map(usize::from).or_else(|| tuns[0].mtu().ok());
+    let buffer_size = if offload {
+        // Super-packets are larger than the MTU.
+        offload::VNET_HDR_LEN + offload::MAX_PACKET_SIZE
+    } else {
+        device_mtu.map_or(DEFAULT_BUFFER_SIZE, |mtu| DEFAULT_BUFFER_SIZE.max(mtu))
+    };
 
     let session_manager = Arc::new(RwLock::new(InnerSessionManager::new(BEGIN_PORT, END_PORT)));
     let sesion_mamager_clone = session_manager.clone();
-    let handle = thread::spawn(move || {
-        let mut buf = vec![0; 2000];
-
-        loop {
-            let size = tun.read(&mut buf).unwrap();
-            if size == 0 {
-                eprintln!("tun read return 0, exit now");
-                break;
-            }
-            let mut ipv4_packet = match Ipv4Packet::new_checked(&mut buf[..size]) {
-                Err(e) => {
-                    eprint!("tun_nat: new packet error: {:?}", e);
-                    continue;
-                }
-                Ok(p) => p,
-            };
-
-            if let Some(packet) = match ipv4_packet.protocol() {
-                IpProtocol::Udp => route_packet!(
-                    UdpPacket,
-                    ipv4_packet,
-                    session_manager,
-                    relay_addr,
-                    relay_port
-                ),
-                IpProtocol::Tcp => route_packet!(
-                    TcpPacket,
-                    ipv4_packet,
-                    session_manager,
-                    relay_addr,
-                    relay_port
-                ),
-                _ => continue,
-            } {
-                let ret = tun.write(packet.as_ref());
-                if let Err(err) = ret {
-                    eprintln!("tun_nat: write packet error: {:?}", err);
+    let multi_queue = tuns.len() > 1;
+    let mut handles = Vec::with_capacity(tuns.len());
+    for (index, tun) in tuns.into_iter().enumerate() {
+        let session_manager = session_manager.clone();
+        let reject_syn = reject_syn.clone();
+        let handle = thread::Builder::new()
+            .name(format!("tun-queue-{index}"))
+            .spawn(move || {
+                #[cfg(target_os = "linux")]
+                if multi_queue {
+                    pin_to_cpu(index);
                 }
+                #[cfg(not(target_os = "linux"))]
+                let _ = multi_queue;
+
+                let translate = |packet: &mut [u8], partial_csum| {
+                    translate_packet(
+                        packet,
+                        &session_manager,
+                        relay_addr,
+                        relay_port,
+                        tcp_mss,
+                        reject_syn.as_ref(),
+                        partial_csum,
+                    )
+                };
+                run_queue(&tun, buffer_size, io_uring, |frame| {
+                    if offload {
+                        offload::translate_frame(frame, translate)
+                    } else {
+                        translate(frame, false)
+                    }
+                })
+            })?;
+        handles.push(handle);
+    }
+    let handle = thread::spawn(move || {
+        for handle in handles {
+            if let Err(e) = handle.join() {
+                std::panic::resume_unwind(e);
             }
         }
     });
@@ -154,6 +519,22 @@ pub struct SessionManager {
 }
 
 impl SessionManager {
+    /// A session table not attached to a TUN device, eg. to simulate one in tests. Sessions get
+    /// ports in `begin_port..end_port`.
+    pub fn new(begin_port: u16, end_port: u16) -> Self {
+        SessionManager {
+            inner: Arc::new(RwLock::new(InnerSessionManager::new(begin_port, end_port))),
+        }
+    }
+
+    /// Port of the session from `src` to `dest`, created if there's none. This is the source
+    /// port the relay server sees for the session, like for the packets rewritten by the NAT.
+    pub fn get_or_create_session(&self, src: SocketAddrV4, dest: SocketAddrV4) -> u16 {
+        self.inner
+            .write()
+            .get_or_create_session(*src.ip(), src.port(), *dest.ip(), dest.port())
+    }
+
     pub fn get_by_port(&self, port: u16) -> Option<(SocketAddr, SocketAddr)> {
         let inner = self.inner.read();
         inner.map.get(&port).map(|assoc| {
@@ -351,3 +732,147 @@ fn now() -> u64 {
         .unwrap()
         .as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    /// Datagrams keep frame boundaries like a TUN device.
+    struct Frames(UnixDatagram);
+
+    impl AsRawFd for Frames {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    impl Read for &Frames {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    impl Write for &Frames {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_batched() {
+        let (tun, peer) = UnixDatagram::pair().unwrap();
+        for frame in [&b"drop"[..], b"abc", b"de", b""] {
+            peer.send(frame).unwrap();
+        }
+        let tun = Frames(tun);
+        run_batched(&tun, 16, |packet| {
+            packet.make_ascii_uppercase();
+            (packet != b"DROP").then_some(packet.len())
+        })
+        .unwrap();
+
+        let mut buf = [0; 16];
+        let size = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"ABC");
+        let size = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"DE");
+    }
+
+    #[test]
+    fn test_write_packet_waits_for_writable() {
+        let (tun, peer) = UnixDatagram::pair().unwrap();
+        tun.set_nonblocking(true).unwrap();
+        let mut queued = 0;
+        while tun.send(b"fill").is_ok() {
+            queued += 1;
+        }
+        let reader = std::thread::spawn(move || {
+            let mut buf = [0; 16];
+            for _ in 0..=queued {
+                peer.recv(&mut buf).unwrap();
+            }
+            buf
+        });
+        let tun = Frames(tun);
+        write_packet(tun.0.as_raw_fd(), &tun, b"last").unwrap();
+        assert_eq!(&reader.join().unwrap()[..4], b"last");
+    }
+
+    fn syn_segment(mss: u16) -> Vec<u8> {
+        let mut segment = vec![0; 28];
+        // data offset: 7 words
+        segment[12] = 7 << 4;
+        segment[13] = TCP_FLAG_SYN;
+        segment[20] = TCP_OPTION_NOP;
+        segment[21] = TCP_OPTION_MSS;
+        segment[22] = 4;
+        segment[23..25].copy_from_slice(&mss.to_be_bytes());
+        segment[25] = TCP_OPTION_END;
+        segment
+    }
+
+    #[test]
+    fn test_clamp_tcp_mss() {
+        let mut segment = syn_segment(1460);
+        clamp_tcp_mss(&mut segment, 1400);
+        assert_eq!(u16::from_be_bytes([segment[23], segment[24]]), 1400);
+
+        let mut segment = syn_segment(1300);
+        clamp_tcp_mss(&mut segment, 1400);
+        assert_eq!(u16::from_be_bytes([segment[23], segment[24]]), 1300);
+
+        let mut segment = syn_segment(1460);
+        segment[13] = 0x10; // ACK only
+        clamp_tcp_mss(&mut segment, 1400);
+        assert_eq!(u16::from_be_bytes([segment[23], segment[24]]), 1460);
+    }
+
+    #[test]
+    fn test_reset_syn() {
+        let segment = syn_segment(1460);
+        let mut buf = vec![0; 20 + segment.len()];
+        let mut packet = Ipv4Packet::new_unchecked(&mut buf);
+        packet.set_version(4);
+        packet.set_header_len(20);
+        packet.set_total_len((20 + segment.len()) as u16);
+        packet.set_hop_limit(64);
+        packet.set_protocol(IpProtocol::Tcp);
+        packet.set_src_addr(Ipv4Addr::new(10, 0, 0, 1).into());
+        packet.set_dst_addr(Ipv4Addr::new(11, 0, 0, 2).into());
+        packet.payload_mut().copy_from_slice(&segment);
+        let mut tcp = TcpPacket::new_unchecked(packet.payload_mut());
+        tcp.set_src_port(50000);
+        tcp.set_dst_port(443);
+        tcp.set_seq_number(smoltcp::wire::TcpSeqNumber(100));
+        packet.fill_checksum();
+        assert!(is_tcp_syn(packet.payload_mut()));
+
+        let len = reset_syn(&mut buf, false).unwrap();
+        assert_eq!(len, 40);
+        let packet = Ipv4Packet::new_checked(&buf[..len]).unwrap();
+        assert!(packet.verify_checksum());
+        assert_eq!(
+            Ipv4Addr::from(packet.src_addr()),
+            Ipv4Addr::new(11, 0, 0, 2)
+        );
+        assert_eq!(
+            Ipv4Addr::from(packet.dst_addr()),
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+        let rst = TcpPacket::new_checked(packet.payload()).unwrap();
+        assert!(rst.rst() && rst.ack() && !rst.syn());
+        assert_eq!(rst.src_port(), 443);
+        assert_eq!(rst.dst_port(), 50000);
+        assert_eq!(rst.ack_number(), smoltcp::wire::TcpSeqNumber(101));
+        assert!(rst.verify_checksum(
+            &IpAddress::Ipv4(packet.src_addr()),
+            &IpAddress::Ipv4(packet.dst_addr())
+        ));
+        assert!(!is_tcp_syn(packet.payload()));
+    }
+}
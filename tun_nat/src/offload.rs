@@ -0,0 +1,110 @@
+//! Offloads of a TUN device opened with `IFF_VNET_HDR`. Every frame starts with a virtio-net
+//! header, tcp and udp checksums may be left to the other end and tcp segments up to 64KB may
+//! be passed in one super-packet. The NAT rewrites the headers of a super-packet once and writes
+//! it back whole, it's only split, if at all, when the kernel sends it out.
+use smoltcp::wire::Ipv4Address;
+
+/// Size of `struct virtio_net_hdr`, without `num_buffers` as `TUNSETVNETHDRSZ` isn't set.
+pub(crate) const VNET_HDR_LEN: usize = 10;
+/// Largest IPv4 packet, the size of super-packets is limited by the total length field.
+pub(crate) const MAX_PACKET_SIZE: usize = 65535;
+/// The checksum at `csum_start + csum_offset` only covers the pseudo header, the rest is left
+/// to the receiver.
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+/// Translate the frame read from the TUN device in place with `translate`, which gets the
+/// packet after the virtio-net header and whether its checksum is partial. Partial checksums
+/// must stay partial, filled with `pseudo_header_checksum` of the translated addresses.
+pub(crate) fn translate_frame(
+    frame: &mut [u8],
+    translate: impl FnOnce(&mut [u8], bool) -> Option<usize>,
+) -> Option<usize> {
+    if frame.len() < VNET_HDR_LEN {
+        return None;
+    }
+    let partial = frame[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0;
+    let packet_len = frame.len() - VNET_HDR_LEN;
+    let len = translate(&mut frame[VNET_HDR_LEN..], partial)?;
+    if len != packet_len {
+        // Replaced with another packet, eg. the RST of a SYN, which isn't segmented.
+        // gso_type, hdr_len and gso_size
+        frame[1..6].fill(0);
+    }
+    Some(VNET_HDR_LEN + len)
+}
+
+/// Sum of the IPv4 pseudo header of a tcp or udp segment of `len` bytes, folded but not
+/// complemented. This is what partial checksums hold.
+pub(crate) fn pseudo_header_checksum(
+    src_addr: Ipv4Address,
+    dst_addr: Ipv4Address,
+    protocol: u8,
+    len: usize,
+) -> u16 {
+    let mut sum: u32 = [src_addr.as_bytes(), dst_addr.as_bytes()]
+        .iter()
+        .flat_map(|addr| addr.chunks(2))
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    sum += u32::from(protocol) + len as u32;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pseudo_header_checksum() {
+        // 0x0a00 + 0x0001 + 0x0b00 + 0x0002 + 6 + 0x14
+        assert_eq!(
+            pseudo_header_checksum(
+                Ipv4Address::new(10, 0, 0, 1),
+                Ipv4Address::new(11, 0, 0, 2),
+                6,
+                20
+            ),
+            0x151d
+        );
+        // Carries are folded back.
+        assert_eq!(
+            pseudo_header_checksum(
+                Ipv4Address::new(255, 255, 255, 255),
+                Ipv4Address::new(255, 255, 255, 255),
+                17,
+                65535
+            ),
+            0x11
+        );
+    }
+
+    #[test]
+    fn test_translate_frame() {
+        let mut frame = vec![0; VNET_HDR_LEN + 100];
+        frame[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        // TCPV4 super-packet
+        frame[1] = 1;
+        frame[4..6].copy_from_slice(&1448u16.to_ne_bytes());
+        let len = translate_frame(&mut frame, |packet, partial| {
+            assert!(partial);
+            Some(packet.len())
+        });
+        assert_eq!(len, Some(frame.len()));
+        assert_eq!(frame[1], 1);
+
+        let len = translate_frame(&mut frame, |_, _| Some(40));
+        assert_eq!(len, Some(VNET_HDR_LEN + 40));
+        assert_eq!(frame[0], VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        assert_eq!(&frame[1..6], &[0; 5]);
+
+        frame[0] = 0;
+        assert_eq!(
+            translate_frame(&mut frame, |_, partial| (!partial).then_some(1)),
+            Some(11)
+        );
+        assert_eq!(translate_frame(&mut [0; 4], |_, _| Some(0)), None);
+    }
+}
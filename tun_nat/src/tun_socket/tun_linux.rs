@@ -6,6 +6,12 @@ use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const IFF_MULTI_QUEUE: c_int = 0x0100;
+const IFF_VNET_HDR: c_int = 0x4000;
+const TUN_F_CSUM: c_uint = 0x01;
+const TUN_F_TSO4: c_uint = 0x02;
+const TUN_F_TSO_ECN: c_uint = 0x08;
 
 #[repr(C)]
 union IfrIfru {
@@ -55,6 +61,38 @@ impl AsRawFd for TunSocket {
 
 impl TunSocket {
     pub fn new(name: &str) -> Result<TunSocket> {
+        TunSocket::open(name, IFF_TUN | IFF_NO_PI)
+    }
+
+    /// Open `queues` queues of the multi-queue TUN device `name`. The kernel spreads packets
+    /// over the queues by flow, so each queue can be read by its own thread.
+    pub fn new_multi_queue(name: &str, queues: usize) -> Result<Vec<TunSocket>> {
+        (0..queues)
+            .map(|_| TunSocket::open(name, IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE))
+            .collect()
+    }
+
+    /// Open `queues` queues of the TUN device `name` with `IFF_VNET_HDR`, taking checksum
+    /// offload and TSO for IPv4. Frames are prefixed with a virtio-net header and tcp segments
+    /// may be coalesced into super-packets of up to 64KB.
+    pub fn new_offload(name: &str, queues: usize) -> Result<Vec<TunSocket>> {
+        let flags = match queues {
+            0 | 1 => IFF_TUN | IFF_NO_PI | IFF_VNET_HDR,
+            _ => IFF_TUN | IFF_NO_PI | IFF_VNET_HDR | IFF_MULTI_QUEUE,
+        };
+        (0..queues.max(1))
+            .map(|_| {
+                let tun = TunSocket::open(name, flags)?;
+                let offload = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO_ECN;
+                if unsafe { ioctl(tun.fd, TUNSETOFFLOAD as _, offload as c_ulong) } < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(tun)
+            })
+            .collect()
+    }
+
+    fn open(name: &str, flags: c_int) -> Result<TunSocket> {
         let fd = match unsafe { open(b"/dev/net/tun\0".as_ptr() as _, O_RDWR) } {
             -1 => return Err(Error::last_os_error()),
             fd => fd,
@@ -64,18 +102,21 @@ impl TunSocket {
         let mut ifr = ifreq {
             ifr_name: [0; IFNAMSIZ],
             ifr_ifru: IfrIfru {
-                ifru_flags: (IFF_TUN | IFF_NO_PI) as _,
+                ifru_flags: flags as _,
             },
         };
 
         if iface_name.len() >= ifr.ifr_name.len() {
+            unsafe { close(fd) };
             return Err(Error::new(ErrorKind::Other, "Invalid tun name"));
         }
 
         ifr.ifr_name[..iface_name.len()].copy_from_slice(iface_name);
 
         if unsafe { ioctl(fd, TUNSETIFF as _, &ifr) } < 0 {
-            return Err(Error::last_os_error());
+            let e = Error::last_os_error();
+            unsafe { close(fd) };
+            return Err(e);
         }
 
         let name = name.to_string();
@@ -0,0 +1,454 @@
+//! TUN packet loop on io_uring. The write of a translated packet and the read of the next one are
+//! submitted with a single `io_uring_enter`, instead of a `read` and a `write` syscall for each
+//! packet. Only one read is in flight at a time so packets keep their order.
+use buffer_pool::Buffer;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const QUEUE_DEPTH: u32 = 64;
+/// Buffers of the reads and the writes in flight, fewer than `QUEUE_DEPTH` so the queues never
+/// fill up.
+const BUFFERS: usize = 32;
+const WRITE_TAG: u64 = 1 << 63;
+/// User data of cancel entries, their completions are ignored.
+const CANCEL_TAG: u64 = u64::MAX;
+
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+/// Added in linux 5.6 together with `IORING_OP_READ` and `IORING_OP_WRITE`.
+const IORING_FEAT_RW_CUR_POS: u32 = 1 << 3;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+// Layouts of the kernel structures in linux/io_uring.h, not all fields are used.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+#[allow(dead_code)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+struct Uring {
+    // Unmapped before the ring fd is closed.
+    _sq_ring: Mmap,
+    _cq_ring: Option<Mmap>,
+    _sqes_map: Mmap,
+    fd: OwnedFd,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    to_submit: u32,
+}
+
+impl Uring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        if params.features & IORING_FEAT_RW_CUR_POS == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring read/write requires linux 5.6",
+            ));
+        }
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let (sq_ring, cq_ring) = if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            let ring = Mmap::new(fd.as_raw_fd(), sq_len.max(cq_len), IORING_OFF_SQ_RING)?;
+            (ring, None)
+        } else {
+            let sq_ring = Mmap::new(fd.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?;
+            let cq_ring = Mmap::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?;
+            (sq_ring, Some(cq_ring))
+        };
+        let sqes_map = Mmap::new(
+            fd.as_raw_fd(),
+            params.sq_entries as usize * std::mem::size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        let cq = cq_ring.as_ref().unwrap_or(&sq_ring);
+        let (sq_off, cq_off) = (&params.sq_off, &params.cq_off);
+        Ok(Uring {
+            sq_head: sq_ring.at(sq_off.head),
+            sq_tail: sq_ring.at(sq_off.tail),
+            sq_mask: unsafe { *sq_ring.at::<u32>(sq_off.ring_mask) },
+            sq_entries: params.sq_entries,
+            sq_array: sq_ring.at(sq_off.array),
+            sqes: sqes_map.at(0),
+            cq_head: cq.at(cq_off.head),
+            cq_tail: cq.at(cq_off.tail),
+            cq_mask: unsafe { *cq.at::<u32>(cq_off.ring_mask) },
+            cqes: cq.at(cq_off.cqes),
+            to_submit: 0,
+            _sq_ring: sq_ring,
+            _cq_ring: cq_ring,
+            _sqes_map: sqes_map,
+            fd,
+        })
+    }
+
+    /// Queue `sqe`. The buffer it points to must stay alive until its completion is popped.
+    fn push(&mut self, sqe: Sqe) {
+        let (head, tail) = unsafe {
+            (
+                (*self.sq_head).load(Ordering::Acquire),
+                (*self.sq_tail).load(Ordering::Relaxed),
+            )
+        };
+        assert!(
+            tail.wrapping_sub(head) < self.sq_entries,
+            "io_uring submission queue is full"
+        );
+        let idx = tail & self.sq_mask;
+        unsafe {
+            *self.sqes.add(idx as usize) = sqe;
+            *self.sq_array.add(idx as usize) = idx;
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.to_submit += 1;
+    }
+
+    /// Submit queued entries and wait for at least one completion.
+    fn submit_and_wait(&mut self) -> io::Result<()> {
+        loop {
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    self.to_submit,
+                    1u32,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            self.to_submit -= ret as u32;
+            return Ok(());
+        }
+    }
+
+    /// Pop a completion, returning its user data and result.
+    fn pop(&mut self) -> Option<(u64, i32)> {
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            if head == tail {
+                return None;
+            }
+            let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+            let ret = (cqe.user_data, cqe.res);
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            Some(ret)
+        }
+    }
+}
+
+fn rw_sqe(opcode: u8, fd: RawFd, buf: &[u8], user_data: u64) -> Sqe {
+    Sqe {
+        opcode,
+        fd,
+        // Use and advance the file position, the TUN device doesn't have one anyway.
+        off: u64::MAX,
+        addr: buf.as_ptr() as u64,
+        len: buf.len() as u32,
+        user_data,
+        ..Default::default()
+    }
+}
+
+/// Read packets from `tun`, translate them with `on_packet` in place and write back those it
+/// returns a length for, until the device is closed.
+///
+/// Returns an error without reading anything if io_uring is not supported.
+pub(crate) fn run(
+    tun: &impl AsRawFd,
+    buffer_size: usize,
+    on_packet: impl FnMut(&mut [u8]) -> Option<usize>,
+) -> io::Result<()> {
+    // Declared before the ring so they are dropped after it, in-flight entries point into them.
+    let mut bufs: Vec<Buffer> = (0..BUFFERS).map(|_| Buffer::new(buffer_size)).collect();
+    let mut ring = Uring::new(QUEUE_DEPTH)?;
+    let mut in_flight = [None; BUFFERS];
+    let ret = relay(
+        &mut ring,
+        tun.as_raw_fd(),
+        &mut bufs,
+        &mut in_flight,
+        on_packet,
+    );
+    if let Err(e) = drain(&mut ring, &mut in_flight) {
+        tracing::error!(?e, "tun_nat: cancel io_uring entries error");
+        // The kernel may still write into the buffers, leak them rather than free them.
+        std::mem::forget(bufs);
+    }
+    ret
+}
+
+/// The packet loop of [`run`], `in_flight` holds the user data of the entry submitted for each
+/// buffer.
+fn relay(
+    ring: &mut Uring,
+    fd: RawFd,
+    bufs: &mut [Buffer],
+    in_flight: &mut [Option<u64>; BUFFERS],
+    mut on_packet: impl FnMut(&mut [u8]) -> Option<usize>,
+) -> io::Result<()> {
+    let mut free: Vec<usize> = (1..BUFFERS).collect();
+    push(ring, in_flight, rw_sqe(IORING_OP_READ, fd, &bufs[0], 0), 0);
+    loop {
+        ring.submit_and_wait()?;
+        while let Some((user_data, res)) = ring.pop() {
+            let idx = (user_data & !WRITE_TAG) as usize;
+            in_flight[idx] = None;
+            if user_data & WRITE_TAG != 0 {
+                if res < 0 {
+                    let err = io::Error::from_raw_os_error(-res);
+                    eprintln!("tun_nat: write packet error: {:?}", err);
+                }
+                free.push(idx);
+                continue;
+            }
+            if res == 0 {
+                eprintln!("tun read return 0, exit now");
+                return Ok(());
+            }
+            if res < 0 {
+                let err = io::Error::from_raw_os_error(-res);
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+                push(
+                    ring,
+                    in_flight,
+                    rw_sqe(IORING_OP_READ, fd, &bufs[idx], idx as u64),
+                    idx,
+                );
+                continue;
+            }
+            let size = res as usize;
+            let next = match on_packet(&mut bufs[idx][..size]) {
+                None => idx,
+                Some(len) => {
+                    if let Some(next) = free.pop() {
+                        let sqe = rw_sqe(
+                            IORING_OP_WRITE,
+                            fd,
+                            &bufs[idx][..len],
+                            idx as u64 | WRITE_TAG,
+                        );
+                        push(ring, in_flight, sqe, idx);
+                        next
+                    } else {
+                        // All buffers are waiting for writes, write this one synchronously.
+                        let packet = &bufs[idx][..len];
+                        if unsafe { libc::write(fd, packet.as_ptr().cast(), len) } < 0 {
+                            let err = io::Error::last_os_error();
+                            eprintln!("tun_nat: write packet error: {:?}", err);
+                        }
+                        idx
+                    }
+                }
+            };
+            push(
+                ring,
+                in_flight,
+                rw_sqe(IORING_OP_READ, fd, &bufs[next], next as u64),
+                next,
+            );
+        }
+    }
+}
+
+/// Queue `sqe` for the buffer `idx`.
+fn push(ring: &mut Uring, in_flight: &mut [Option<u64>; BUFFERS], sqe: Sqe, idx: usize) {
+    in_flight[idx] = Some(sqe.user_data);
+    ring.push(sqe);
+}
+
+/// Cancel the entries still in flight and wait for their completions, so the kernel is done
+/// with their buffers. Writes usually complete before they can be cancelled.
+fn drain(ring: &mut Uring, in_flight: &mut [Option<u64>; BUFFERS]) -> io::Result<()> {
+    for user_data in in_flight.iter().flatten() {
+        ring.push(Sqe {
+            opcode: IORING_OP_ASYNC_CANCEL,
+            addr: *user_data,
+            user_data: CANCEL_TAG,
+            ..Default::default()
+        });
+    }
+    while in_flight.iter().any(Option::is_some) {
+        ring.submit_and_wait()?;
+        while let Some((user_data, _)) = ring.pop() {
+            if user_data != CANCEL_TAG {
+                in_flight[(user_data & !WRITE_TAG) as usize] = None;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_pipe() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        for packet in [&b"hello"[..], b"io_uring"] {
+            assert_eq!(
+                unsafe { libc::write(write.as_raw_fd(), packet.as_ptr().cast(), packet.len()) },
+                packet.len() as isize
+            );
+        }
+        drop(write);
+
+        let mut received = vec![];
+        let ret = run(&read, 1500, |packet| {
+            received.extend_from_slice(packet);
+            None
+        });
+        match ret {
+            Ok(()) => assert_eq!(received, b"helloio_uring"),
+            // io_uring is not available in some sandboxes and old kernels.
+            Err(e) => eprintln!("skip test_run_pipe: {e}"),
+        }
+    }
+}